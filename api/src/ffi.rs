@@ -0,0 +1,770 @@
+//! C ABI between the host and plugin libraries.
+//!
+//! Trait objects have no stable layout, so a `Box<dyn ContainerEncoder>` built by one rustc
+//! cannot be used by a host built with another. Plugins therefore export plain `extern "C"`
+//! functions returning `#[repr(C)]` tables of function pointers; only pointers, integers and
+//! UTF-8 byte slices cross the boundary. Memory is freed by the side that allocated it:
+//! strings a plugin hands out stay owned by the plugin object, and messages it reports are
+//! copied into host-owned sinks.
+//!
+//! Plugin authors implement the traits as before and call [`export_plugin!`](crate::export_plugin).
+//! The host checks [`ABI_VERSION_SYMBOL`] before touching anything else, then wraps the tables
+//! in [`ForeignEncoder`] / [`ForeignDecoder`], which implement the traits again.
+
+use anyhow::{anyhow, Result};
+use std::ffi::c_void;
+use std::io::Read;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use crate::{ByteSource, ContainerDecoder, ContainerEncoder, PluginMetadata};
+
+/// Bumped on any change to the tables or symbols below.
+pub const ABI_VERSION: u32 = 2;
+
+/// `extern "C" fn() -> u32` returning the plugin's `ABI_VERSION`.
+pub const ABI_VERSION_SYMBOL: &[u8] = b"spng_plugin_abi_version\0";
+/// `extern "C" fn() -> FfiEncoder`, optional.
+pub const ENCODER_SYMBOL: &[u8] = b"spng_plugin_encoder\0";
+/// `extern "C" fn() -> FfiDecoder`, optional.
+pub const DECODER_SYMBOL: &[u8] = b"spng_plugin_decoder\0";
+
+pub type AbiVersionFn = extern "C" fn() -> u32;
+pub type EncoderFn = extern "C" fn() -> FfiEncoder;
+pub type DecoderFn = extern "C" fn() -> FfiDecoder;
+
+/// Status returned by the table functions; on `STATUS_ERROR` the message went to the error sink.
+pub const STATUS_OK: i32 = 0;
+pub const STATUS_ERROR: i32 = 1;
+
+/// Bytes fetched from the host per call when a plugin reads the byte stream.
+const SOURCE_CHUNK: usize = 64 * 1024;
+
+type ProgressFn = Box<dyn Fn(f32) + Send + Sync>;
+
+/// Borrowed UTF-8 string.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct FfiStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl FfiStr {
+    fn new(s: &str) -> Self {
+        Self { ptr: s.as_ptr(), len: s.len() }
+    }
+
+    /// # Safety
+    /// `ptr` must point to `len` readable bytes for the duration of the call.
+    unsafe fn into_string(self) -> String {
+        if self.ptr.is_null() {
+            return String::new();
+        }
+        String::from_utf8_lossy(std::slice::from_raw_parts(self.ptr, self.len)).into_owned()
+    }
+}
+
+/// Host-owned string the other side appends to (error messages).
+#[repr(C)]
+pub struct FfiSink {
+    ctx: *mut c_void,
+    write: extern "C" fn(*mut c_void, *const u8, usize),
+}
+
+impl FfiSink {
+    fn new(target: &mut String) -> Self {
+        extern "C" fn write(ctx: *mut c_void, ptr: *const u8, len: usize) {
+            let target = unsafe { &mut *(ctx as *mut String) };
+            target.push_str(&String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(ptr, len) }));
+        }
+        Self { ctx: target as *mut String as *mut c_void, write }
+    }
+
+    fn report(&self, message: &str) {
+        (self.write)(self.ctx, message.as_ptr(), message.len());
+    }
+}
+
+/// Progress callback owned by the host. Valid until the call returns, or for `decode` until
+/// the returned reader is dropped.
+#[repr(C)]
+pub struct FfiProgress {
+    ctx: *mut c_void,
+    call: extern "C" fn(*mut c_void, f32),
+}
+
+// The host callback behind `ctx` is `Send + Sync`
+unsafe impl Send for FfiProgress {}
+unsafe impl Sync for FfiProgress {}
+
+impl FfiProgress {
+    fn new(callback: &ProgressFn) -> Self {
+        extern "C" fn call(ctx: *mut c_void, p: f32) {
+            let callback = unsafe { &*(ctx as *const ProgressFn) };
+            callback(p);
+        }
+        Self { ctx: callback as *const ProgressFn as *mut c_void, call }
+    }
+
+    fn notify(&self, p: f32) {
+        (self.call)(self.ctx, p)
+    }
+
+    fn into_callback(self) -> ProgressFn {
+        Box::new(move |p| self.notify(p))
+    }
+}
+
+/// The host's byte stream. `read` fills the whole buffer, with zeros past the end.
+#[repr(C)]
+pub struct FfiByteSource {
+    ctx: *mut c_void,
+    total_len: u64,
+    read: extern "C" fn(*mut c_void, *mut u8, usize),
+}
+
+/// Payload reader returned by a plugin decoder; owned by the plugin until `drop`.
+#[repr(C)]
+pub struct FfiReader {
+    this: *mut c_void,
+    read: extern "C" fn(*mut c_void, *mut u8, usize, *mut usize, FfiSink) -> i32,
+    drop: extern "C" fn(*mut c_void),
+}
+
+/// Metadata and supported extensions; the strings live as long as the plugin object.
+#[repr(C)]
+pub struct FfiPluginInfo {
+    name: FfiStr,
+    description: FfiStr,
+    version: FfiStr,
+    author: FfiStr,
+    extensions: *const FfiStr,
+    extension_count: usize,
+}
+
+#[repr(C)]
+pub struct FfiEncoder {
+    this: *mut c_void,
+    info: extern "C" fn(*mut c_void) -> FfiPluginInfo,
+    encode: extern "C" fn(*mut c_void, FfiStr, FfiStr, FfiByteSource, FfiProgress, FfiSink) -> i32,
+    capacity: extern "C" fn(*mut c_void, FfiStr, *mut u64, FfiSink) -> i32,
+    drop: extern "C" fn(*mut c_void),
+}
+
+#[repr(C)]
+pub struct FfiDecoder {
+    this: *mut c_void,
+    info: extern "C" fn(*mut c_void) -> FfiPluginInfo,
+    decode: extern "C" fn(*mut c_void, FfiStr, FfiProgress, *mut FfiReader, FfiSink) -> i32,
+    restore: extern "C" fn(*mut c_void, FfiStr, FfiStr, FfiProgress, FfiSink) -> i32,
+    drop: extern "C" fn(*mut c_void),
+}
+
+// ---------------------------------------------------------------------------------------------
+// Plugin side
+// ---------------------------------------------------------------------------------------------
+
+/// A trait object together with the strings its `FfiPluginInfo` points into.
+struct Exported<T: ?Sized> {
+    inner: Box<T>,
+    strings: [String; 4],
+    extensions: Vec<String>,
+    extension_refs: Vec<FfiStr>,
+}
+
+impl<T: ?Sized> Exported<T> {
+    fn into_raw(inner: Box<T>, metadata: PluginMetadata, extensions: Vec<String>) -> *mut c_void {
+        let mut exported = Box::new(Self {
+            inner,
+            strings: [metadata.name, metadata.description, metadata.version, metadata.author],
+            extensions,
+            extension_refs: Vec::new(),
+        });
+        exported.extension_refs = exported.extensions.iter().map(|e| FfiStr::new(e)).collect();
+        Box::into_raw(exported) as *mut c_void
+    }
+
+    /// # Safety
+    /// `this` must come from `into_raw` with the same `T` and not be dropped yet.
+    unsafe fn get<'a>(this: *mut c_void) -> &'a Self {
+        &*(this as *const Self)
+    }
+}
+
+extern "C" fn exported_info<T: ?Sized>(this: *mut c_void) -> FfiPluginInfo {
+    let exported = unsafe { Exported::<T>::get(this) };
+    let [name, description, version, author] = &exported.strings;
+    FfiPluginInfo {
+        name: FfiStr::new(name),
+        description: FfiStr::new(description),
+        version: FfiStr::new(version),
+        author: FfiStr::new(author),
+        extensions: exported.extension_refs.as_ptr(),
+        extension_count: exported.extension_refs.len(),
+    }
+}
+
+extern "C" fn exported_drop<T: ?Sized>(this: *mut c_void) {
+    drop(unsafe { Box::from_raw(this as *mut Exported<T>) });
+}
+
+/// Runs plugin code, turning errors and panics into a status and a reported message.
+/// Unwinding into the host would abort it.
+fn guarded(error: FfiSink, f: impl FnOnce() -> Result<()>) -> i32 {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => STATUS_OK,
+        Ok(Err(e)) => {
+            error.report(&format!("{:#}", e));
+            STATUS_ERROR
+        }
+        Err(panic) => {
+            let message = panic.downcast_ref::<&str>().copied()
+                .or_else(|| panic.downcast_ref::<String>().map(|s| s.as_str()))
+                .unwrap_or("unknown panic");
+            error.report(&format!("Plugin panicked: {}", message));
+            STATUS_ERROR
+        }
+    }
+}
+
+/// Plugin-side view of the host byte stream, fetched in chunks.
+struct SourceReader {
+    raw: FfiByteSource,
+    buffer: Vec<u8>,
+    pos: usize,
+    fetched: u64,
+}
+
+impl ByteSource for SourceReader {
+    fn next_byte(&mut self) -> u8 {
+        if self.pos == self.buffer.len() {
+            let remaining = self.raw.total_len.saturating_sub(self.fetched);
+            if remaining == 0 {
+                return 0;
+            }
+            let len = (remaining as usize).min(SOURCE_CHUNK);
+            self.buffer.resize(len, 0);
+            (self.raw.read)(self.raw.ctx, self.buffer.as_mut_ptr(), len);
+            self.fetched += len as u64;
+            self.pos = 0;
+        }
+        let b = self.buffer[self.pos];
+        self.pos += 1;
+        b
+    }
+
+    fn total_len(&self) -> u64 {
+        self.raw.total_len
+    }
+}
+
+impl FfiEncoder {
+    /// Table for a plugin encoder. Called by `export_plugin!`.
+    pub fn export(encoder: Box<dyn ContainerEncoder>) -> Self {
+        type E = dyn ContainerEncoder;
+
+        extern "C" fn encode(this: *mut c_void, container: FfiStr, output: FfiStr, source: FfiByteSource, progress: FfiProgress, error: FfiSink) -> i32 {
+            let encoder = &unsafe { Exported::<E>::get(this) }.inner;
+            guarded(error, || {
+                let (container, output) = unsafe { (container.into_string(), output.into_string()) };
+                let mut source = SourceReader { raw: source, buffer: Vec::new(), pos: 0, fetched: 0 };
+                encoder.encode(Path::new(&container), Path::new(&output), &mut source, progress.into_callback())
+            })
+        }
+
+        extern "C" fn capacity(this: *mut c_void, container: FfiStr, out: *mut u64, error: FfiSink) -> i32 {
+            let encoder = &unsafe { Exported::<E>::get(this) }.inner;
+            guarded(error, || {
+                let container = unsafe { container.into_string() };
+                let capacity = encoder.capacity(Path::new(&container))?;
+                unsafe { *out = capacity };
+                Ok(())
+            })
+        }
+
+        let (metadata, extensions) = (encoder.metadata(), encoder.supported_extensions());
+        Self {
+            this: Exported::into_raw(encoder, metadata, extensions),
+            info: exported_info::<E>,
+            encode,
+            capacity,
+            drop: exported_drop::<E>,
+        }
+    }
+}
+
+impl FfiDecoder {
+    /// Table for a plugin decoder. Called by `export_plugin!`.
+    pub fn export(decoder: Box<dyn ContainerDecoder>) -> Self {
+        type D = dyn ContainerDecoder;
+        type R = Box<dyn Read + Send>;
+
+        extern "C" fn read(this: *mut c_void, buf: *mut u8, len: usize, read: *mut usize, error: FfiSink) -> i32 {
+            let reader = unsafe { &mut *(this as *mut R) };
+            guarded(error, || {
+                let n = reader.read(unsafe { std::slice::from_raw_parts_mut(buf, len) })?;
+                unsafe { *read = n };
+                Ok(())
+            })
+        }
+
+        extern "C" fn drop_reader(this: *mut c_void) {
+            drop(unsafe { Box::from_raw(this as *mut R) });
+        }
+
+        extern "C" fn decode(this: *mut c_void, input: FfiStr, progress: FfiProgress, out: *mut FfiReader, error: FfiSink) -> i32 {
+            let decoder = &unsafe { Exported::<D>::get(this) }.inner;
+            guarded(error, || {
+                let input = unsafe { input.into_string() };
+                let reader: R = decoder.decode(Path::new(&input), progress.into_callback())?;
+                let reader = Box::into_raw(Box::new(reader)) as *mut c_void;
+                unsafe { out.write(FfiReader { this: reader, read, drop: drop_reader }) };
+                Ok(())
+            })
+        }
+
+        extern "C" fn restore(this: *mut c_void, input: FfiStr, output: FfiStr, progress: FfiProgress, error: FfiSink) -> i32 {
+            let decoder = &unsafe { Exported::<D>::get(this) }.inner;
+            guarded(error, || {
+                let (input, output) = unsafe { (input.into_string(), output.into_string()) };
+                decoder.restore_container(Path::new(&input), Path::new(&output), progress.into_callback())
+            })
+        }
+
+        let (metadata, extensions) = (decoder.metadata(), decoder.supported_extensions());
+        Self {
+            this: Exported::into_raw(decoder, metadata, extensions),
+            info: exported_info::<D>,
+            decode,
+            restore,
+            drop: exported_drop::<D>,
+        }
+    }
+}
+
+/// Exports a plugin's encoder and/or decoder through the C ABI, together with the
+/// ABI version symbol the host checks first.
+///
+/// ```ignore
+/// sound_png_api::export_plugin!(encoder: MyPlugin, decoder: MyPlugin);
+/// ```
+#[macro_export]
+macro_rules! export_plugin {
+    (@version) => {
+        #[no_mangle]
+        pub extern "C" fn spng_plugin_abi_version() -> u32 {
+            $crate::ffi::ABI_VERSION
+        }
+    };
+    (@encoder $encoder:expr) => {
+        #[no_mangle]
+        pub extern "C" fn spng_plugin_encoder() -> $crate::ffi::FfiEncoder {
+            $crate::ffi::FfiEncoder::export(Box::new($encoder))
+        }
+    };
+    (@decoder $decoder:expr) => {
+        #[no_mangle]
+        pub extern "C" fn spng_plugin_decoder() -> $crate::ffi::FfiDecoder {
+            $crate::ffi::FfiDecoder::export(Box::new($decoder))
+        }
+    };
+    (encoder: $encoder:expr, decoder: $decoder:expr $(,)?) => {
+        $crate::export_plugin!(@version);
+        $crate::export_plugin!(@encoder $encoder);
+        $crate::export_plugin!(@decoder $decoder);
+    };
+    (encoder: $encoder:expr $(,)?) => {
+        $crate::export_plugin!(@version);
+        $crate::export_plugin!(@encoder $encoder);
+    };
+    (decoder: $decoder:expr $(,)?) => {
+        $crate::export_plugin!(@version);
+        $crate::export_plugin!(@decoder $decoder);
+    };
+}
+
+// ---------------------------------------------------------------------------------------------
+// Host side
+// ---------------------------------------------------------------------------------------------
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str().ok_or_else(|| anyhow!("Path {:?} is not valid UTF-8 and cannot be passed to a plugin.", path))
+}
+
+fn check(status: i32, error: String) -> Result<()> {
+    if status == STATUS_OK {
+        Ok(())
+    } else {
+        Err(anyhow!(if error.is_empty() { "Plugin call failed.".to_string() } else { error }))
+    }
+}
+
+/// # Safety
+/// `info` must point to valid strings (it comes from a live plugin object).
+unsafe fn read_info(info: FfiPluginInfo) -> (PluginMetadata, Vec<String>) {
+    let metadata = PluginMetadata {
+        name: info.name.into_string(),
+        description: info.description.into_string(),
+        version: info.version.into_string(),
+        author: info.author.into_string(),
+    };
+    let extensions = if info.extensions.is_null() {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(info.extensions, info.extension_count).iter().map(|e| e.into_string()).collect()
+    };
+    (metadata, extensions)
+}
+
+/// A plugin encoder loaded through the C ABI.
+pub struct ForeignEncoder {
+    raw: FfiEncoder,
+    metadata: PluginMetadata,
+    extensions: Vec<String>,
+}
+
+// Plugin encoders are `Send + Sync` on their side of the boundary
+unsafe impl Send for ForeignEncoder {}
+unsafe impl Sync for ForeignEncoder {}
+
+impl ForeignEncoder {
+    /// # Safety
+    /// `raw` must come from the `ENCODER_SYMBOL` of a plugin whose ABI version matched, and
+    /// the library must stay loaded while this value lives.
+    pub unsafe fn from_raw(raw: FfiEncoder) -> Self {
+        let (metadata, extensions) = read_info((raw.info)(raw.this));
+        Self { raw, metadata, extensions }
+    }
+}
+
+impl Drop for ForeignEncoder {
+    fn drop(&mut self) {
+        (self.raw.drop)(self.raw.this);
+    }
+}
+
+impl ContainerEncoder for ForeignEncoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.extensions.clone()
+    }
+
+    fn encode(
+        &self,
+        container_path: &Path,
+        output_path: &Path,
+        byte_stream: &mut dyn ByteSource,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>
+    ) -> Result<()> {
+        extern "C" fn read(ctx: *mut c_void, buf: *mut u8, len: usize) {
+            let source = unsafe { &mut *(ctx as *mut &mut dyn ByteSource) };
+            for b in unsafe { std::slice::from_raw_parts_mut(buf, len) } {
+                *b = source.next_byte();
+            }
+        }
+
+        let (container, output) = (path_str(container_path)?, path_str(output_path)?);
+        let total_len = byte_stream.total_len();
+        let mut byte_stream = byte_stream;
+        let source = FfiByteSource { ctx: &mut byte_stream as *mut &mut dyn ByteSource as *mut c_void, total_len, read };
+        let mut error = String::new();
+        let status = (self.raw.encode)(self.raw.this, FfiStr::new(container), FfiStr::new(output), source, FfiProgress::new(&on_progress), FfiSink::new(&mut error));
+        check(status, error)
+    }
+
+    fn capacity(&self, container_path: &Path) -> Result<u64> {
+        let mut capacity = 0u64;
+        let mut error = String::new();
+        let status = (self.raw.capacity)(self.raw.this, FfiStr::new(path_str(container_path)?), &mut capacity, FfiSink::new(&mut error));
+        check(status, error)?;
+        Ok(capacity)
+    }
+}
+
+/// A plugin decoder loaded through the C ABI.
+pub struct ForeignDecoder {
+    raw: FfiDecoder,
+    metadata: PluginMetadata,
+    extensions: Vec<String>,
+}
+
+unsafe impl Send for ForeignDecoder {}
+unsafe impl Sync for ForeignDecoder {}
+
+impl ForeignDecoder {
+    /// # Safety
+    /// As for [`ForeignEncoder::from_raw`], with the `DECODER_SYMBOL`.
+    pub unsafe fn from_raw(raw: FfiDecoder) -> Self {
+        let (metadata, extensions) = read_info((raw.info)(raw.this));
+        Self { raw, metadata, extensions }
+    }
+}
+
+impl Drop for ForeignDecoder {
+    fn drop(&mut self) {
+        (self.raw.drop)(self.raw.this);
+    }
+}
+
+/// Reader handed out by a plugin decoder. Keeps the progress callback the plugin may still call.
+struct ForeignReader {
+    raw: FfiReader,
+    _progress: Box<ProgressFn>,
+}
+
+// Plugin readers are `Send` on their side of the boundary
+unsafe impl Send for ForeignReader {}
+
+impl Read for ForeignReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut n = 0usize;
+        let mut error = String::new();
+        let status = (self.raw.read)(self.raw.this, buf.as_mut_ptr(), buf.len(), &mut n, FfiSink::new(&mut error));
+        check(status, error).map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok(n.min(buf.len()))
+    }
+}
+
+impl Drop for ForeignReader {
+    fn drop(&mut self) {
+        // Runs before `_progress` is freed
+        (self.raw.drop)(self.raw.this);
+    }
+}
+
+impl ContainerDecoder for ForeignDecoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.metadata.clone()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.extensions.clone()
+    }
+
+    fn decode(
+        &self,
+        input_path: &Path,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>
+    ) -> Result<Box<dyn Read + Send>> {
+        let progress: Box<ProgressFn> = Box::new(on_progress);
+        let mut reader = std::mem::MaybeUninit::<FfiReader>::uninit();
+        let mut error = String::new();
+        let status = (self.raw.decode)(self.raw.this, FfiStr::new(path_str(input_path)?), FfiProgress::new(&progress), reader.as_mut_ptr(), FfiSink::new(&mut error));
+        check(status, error)?;
+        Ok(Box::new(ForeignReader { raw: unsafe { reader.assume_init() }, _progress: progress }))
+    }
+
+    fn restore_container(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>
+    ) -> Result<()> {
+        let (input, output) = (path_str(input_path)?, path_str(output_path)?);
+        let mut error = String::new();
+        let status = (self.raw.restore)(self.raw.this, FfiStr::new(input), FfiStr::new(output), FfiProgress::new(&on_progress), FfiSink::new(&mut error));
+        check(status, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Stores the stream in memory; the container path picks the failure mode.
+    struct Memory {
+        stored: Arc<Mutex<Vec<u8>>>,
+    }
+
+    /// Fails its reads after the first byte.
+    struct FailingReader(bool);
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if std::mem::replace(&mut self.0, true) {
+                return Err(std::io::Error::other("reader broke"));
+            }
+            buf[0] = 7;
+            Ok(1)
+        }
+    }
+
+    fn metadata() -> PluginMetadata {
+        PluginMetadata {
+            name: "Memory".into(),
+            description: "Test plugin".into(),
+            version: "1.2.3".into(),
+            author: "ü".into(),
+        }
+    }
+
+    fn act(path: &Path) -> Result<()> {
+        match path.to_str() {
+            Some("fail") => Err(anyhow!("no room").context("Memory plugin")),
+            Some("panic") => panic!("boom at {}", 3),
+            _ => Ok(()),
+        }
+    }
+
+    impl ContainerEncoder for Memory {
+        fn metadata(&self) -> PluginMetadata {
+            metadata()
+        }
+
+        fn supported_extensions(&self) -> Vec<String> {
+            vec!["mem".into(), "ram".into()]
+        }
+
+        fn encode(&self, container_path: &Path, _output_path: &Path, byte_stream: &mut dyn ByteSource, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<()> {
+            act(container_path)?;
+            let bytes = (0..byte_stream.total_len()).map(|_| byte_stream.next_byte()).collect();
+            // Reads past the end give zeros
+            assert_eq!(byte_stream.next_byte(), 0);
+            *self.stored.lock().unwrap() = bytes;
+            on_progress(1.0);
+            Ok(())
+        }
+
+        fn capacity(&self, container_path: &Path) -> Result<u64> {
+            act(container_path)?;
+            Ok(42)
+        }
+    }
+
+    impl ContainerDecoder for Memory {
+        fn metadata(&self) -> PluginMetadata {
+            metadata()
+        }
+
+        fn supported_extensions(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        fn decode(&self, input_path: &Path, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<Box<dyn Read + Send>> {
+            act(input_path)?;
+            if input_path == Path::new("broken") {
+                return Ok(Box::new(FailingReader(false)));
+            }
+            on_progress(0.5);
+            Ok(Box::new(Cursor::new(self.stored.lock().unwrap().clone())))
+        }
+
+        fn restore_container(&self, input_path: &Path, _output_path: &Path, _on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<()> {
+            act(input_path)
+        }
+    }
+
+    struct Bytes(Cursor<Vec<u8>>);
+
+    impl ByteSource for Bytes {
+        fn next_byte(&mut self) -> u8 {
+            let mut b = [0u8];
+            self.0.read_exact(&mut b).map_or(0, |_| b[0])
+        }
+
+        fn total_len(&self) -> u64 {
+            self.0.get_ref().len() as u64
+        }
+    }
+
+    fn foreign() -> (ForeignEncoder, ForeignDecoder) {
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let encoder = FfiEncoder::export(Box::new(Memory { stored: stored.clone() }));
+        let decoder = FfiDecoder::export(Box::new(Memory { stored }));
+        unsafe { (ForeignEncoder::from_raw(encoder), ForeignDecoder::from_raw(decoder)) }
+    }
+
+    fn encode(encoder: &ForeignEncoder, container: &str, data: &[u8]) -> Result<()> {
+        let mut source = Bytes(Cursor::new(data.to_vec()));
+        encoder.encode(Path::new(container), Path::new("out"), &mut source, Box::new(|_| {}))
+    }
+
+    #[test]
+    fn test_metadata() {
+        let (encoder, decoder) = foreign();
+        let metadata = encoder.metadata();
+        assert_eq!((metadata.name.as_str(), metadata.version.as_str(), metadata.author.as_str()), ("Memory", "1.2.3", "ü"));
+        assert_eq!(metadata.description, "Test plugin");
+        assert_eq!(encoder.supported_extensions(), ["mem", "ram"]);
+        assert_eq!(decoder.metadata().name, "Memory");
+        assert!(decoder.supported_extensions().is_empty());
+    }
+
+    #[test]
+    fn test_roundtrip() -> Result<()> {
+        let (encoder, decoder) = foreign();
+        // Longer than one source chunk, so the plugin fetches several times
+        let data: Vec<u8> = (0..SOURCE_CHUNK * 2 + 77).map(|i| (i * 31 % 251) as u8).collect();
+        for data in [&data[..], &[], &[0xAB]] {
+            let calls = Arc::new(AtomicU32::new(0));
+            let counter = calls.clone();
+            let mut source = Bytes(Cursor::new(data.to_vec()));
+            encoder.encode(Path::new("in.mem"), Path::new("out.mem"), &mut source, Box::new(move |p| {
+                assert_eq!(p, 1.0);
+                counter.fetch_add(1, Ordering::SeqCst);
+            }))?;
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            let counter = calls.clone();
+            let mut reader = decoder.decode(Path::new("out.mem"), Box::new(move |_| { counter.fetch_add(1, Ordering::SeqCst); }))?;
+            let mut decoded = Vec::new();
+            reader.read_to_end(&mut decoded)?;
+            assert_eq!(decoded, data);
+            assert_eq!(calls.load(Ordering::SeqCst), 2);
+        }
+        assert_eq!(encoder.capacity(Path::new("in.mem"))?, 42);
+        decoder.restore_container(Path::new("out.mem"), Path::new("clean.mem"), Box::new(|_| {}))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors_cross_boundary() {
+        let (encoder, decoder) = foreign();
+        let expected = "Memory plugin: no room";
+        assert_eq!(encode(&encoder, "fail", b"abc").unwrap_err().to_string(), expected);
+        assert_eq!(encoder.capacity(Path::new("fail")).unwrap_err().to_string(), expected);
+        assert_eq!(decoder.decode(Path::new("fail"), Box::new(|_| {})).err().unwrap().to_string(), expected);
+        assert_eq!(decoder.restore_container(Path::new("fail"), Path::new("out"), Box::new(|_| {})).unwrap_err().to_string(), expected);
+
+        // Reader errors surface as io errors
+        let mut reader = decoder.decode(Path::new("broken"), Box::new(|_| {})).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 7);
+        assert_eq!(reader.read(&mut buf).unwrap_err().to_string(), "reader broke");
+    }
+
+    #[test]
+    fn test_panics_are_caught() -> Result<()> {
+        let (encoder, decoder) = foreign();
+        let expected = "Plugin panicked: boom at 3";
+        assert_eq!(encode(&encoder, "panic", b"abc").unwrap_err().to_string(), expected);
+        assert_eq!(encoder.capacity(Path::new("panic")).unwrap_err().to_string(), expected);
+        assert_eq!(decoder.decode(Path::new("panic"), Box::new(|_| {})).err().unwrap().to_string(), expected);
+        assert_eq!(decoder.restore_container(Path::new("panic"), Path::new("out"), Box::new(|_| {})).unwrap_err().to_string(), expected);
+
+        // The plugin objects stay usable afterwards
+        encode(&encoder, "in.mem", b"still here")?;
+        let mut decoded = String::new();
+        decoder.decode(Path::new("out.mem"), Box::new(|_| {}))?.read_to_string(&mut decoded)?;
+        assert_eq!(decoded, "still here");
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_utf8_path_is_rejected() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let (encoder, _) = foreign();
+            let path = Path::new(std::ffi::OsStr::from_bytes(b"\xFF.mem"));
+            assert!(encoder.capacity(path).unwrap_err().to_string().contains("not valid UTF-8"));
+        }
+    }
+}
@@ -4,6 +4,10 @@ use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use rand::RngCore;
 
+pub mod ffi;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm_guest;
+
 /// Helper to stream bytes from Header + Encrypted Payload
 /// Moved from stream_encoder.rs to be shared
 pub struct ByteStream<R: Read> {
@@ -97,6 +101,25 @@ impl<R: Read> ByteStream<R> {
     }
 }
 
+/// The header + payload bytes a container encoder embeds, one at a time.
+/// Implemented by `ByteStream` in the host and by the C ABI adapter inside plugins.
+pub trait ByteSource {
+    /// Next byte; `0` once the stream is exhausted (padding).
+    fn next_byte(&mut self) -> u8;
+    /// Header + payload length in bytes.
+    fn total_len(&self) -> u64;
+}
+
+impl<R: Read> ByteSource for ByteStream<R> {
+    fn next_byte(&mut self) -> u8 {
+        ByteStream::next_byte(self)
+    }
+
+    fn total_len(&self) -> u64 {
+        ByteStream::total_len(self)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PluginMetadata {
     pub name: String,
@@ -119,9 +142,15 @@ pub trait ContainerEncoder: Send + Sync {
         &self, 
         container_path: &std::path::Path, 
         output_path: &std::path::Path, 
-        byte_stream: &mut ByteStream<std::fs::File>,
+        byte_stream: &mut dyn ByteSource,
         on_progress: Box<dyn Fn(f32) + Send + Sync>
     ) -> Result<()>;
+
+    /// Returns how many raw bytes (header + payload) the container can carry.
+    /// `0` means the plugin cannot tell in advance; the core then skips the pre-check.
+    fn capacity(&self, _container_path: &std::path::Path) -> Result<u64> {
+        Ok(0)
+    }
 }
 
 /// Trait for Plugins that can decode data from a container
@@ -139,4 +168,114 @@ pub trait ContainerDecoder: Send + Sync {
         input_path: &std::path::Path,
         on_progress: Box<dyn Fn(f32) + Send + Sync>
     ) -> Result<Box<dyn Read + Send>>;
-}
\ No newline at end of file
+
+    /// Returns the first `len` bytes of the raw stream (the header) without extracting the rest,
+    /// so the host can inspect a container before decoding it. Fewer bytes mean the stream ended.
+    /// The default reads them through `decode`; plugins that can reach the header more cheaply override it.
+    fn peek_header(&self, input_path: &std::path::Path, len: usize) -> Result<Vec<u8>> {
+        let reader = self.decode(input_path, Box::new(|_| {}))?;
+        let mut header = Vec::with_capacity(len);
+        reader.take(len as u64).read_to_end(&mut header)?;
+        Ok(header)
+    }
+
+    /// Writes the clean cover (payload bits removed) of `input_path` to `output_path`.
+    /// Plugins that cannot restore their containers keep the default.
+    fn restore_container(
+        &self,
+        _input_path: &std::path::Path,
+        _output_path: &std::path::Path,
+        _on_progress: Box<dyn Fn(f32) + Send + Sync>
+    ) -> Result<()> {
+        Err(anyhow!("Container restoration is not supported by this plugin."))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the bytes pulled from the stream it wraps.
+    struct Counted(io::Cursor<Vec<u8>>, Arc<AtomicUsize>);
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.0.read(buf)?;
+            self.1.fetch_add(n, Ordering::SeqCst);
+            Ok(n)
+        }
+    }
+
+    /// Implements only the required methods, so the defaults are under test.
+    struct Minimal {
+        stream: Vec<u8>,
+        pulled: Arc<AtomicUsize>,
+    }
+
+    fn metadata() -> PluginMetadata {
+        PluginMetadata { name: "Minimal".into(), description: String::new(), version: "0.1.0".into(), author: String::new() }
+    }
+
+    impl ContainerEncoder for Minimal {
+        fn metadata(&self) -> PluginMetadata {
+            metadata()
+        }
+
+        fn supported_extensions(&self) -> Vec<String> {
+            vec!["min".into()]
+        }
+
+        fn encode(&self, _: &Path, _: &Path, _: &mut dyn ByteSource, _: Box<dyn Fn(f32) + Send + Sync>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ContainerDecoder for Minimal {
+        fn metadata(&self) -> PluginMetadata {
+            metadata()
+        }
+
+        fn supported_extensions(&self) -> Vec<String> {
+            vec!["min".into()]
+        }
+
+        fn decode(&self, input_path: &Path, _: Box<dyn Fn(f32) + Send + Sync>) -> Result<Box<dyn Read + Send>> {
+            if input_path == Path::new("missing") {
+                return Err(anyhow!("No such container."));
+            }
+            Ok(Box::new(Counted(io::Cursor::new(self.stream.clone()), self.pulled.clone())))
+        }
+    }
+
+    fn minimal(len: usize) -> Minimal {
+        Minimal { stream: (0..len).map(|i| (i * 13) as u8).collect(), pulled: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    #[test]
+    fn test_default_peek_header() -> Result<()> {
+        let plugin = minimal(10_000);
+        let header = plugin.peek_header(Path::new("c.min"), 100)?;
+        assert_eq!(header, plugin.stream[..100]);
+        // Only the header is pulled from the stream
+        assert_eq!(plugin.pulled.load(Ordering::SeqCst), 100);
+
+        // A stream shorter than the header comes back whole
+        let short = minimal(40);
+        assert_eq!(short.peek_header(Path::new("c.min"), 100)?, short.stream);
+        assert!(short.peek_header(Path::new("c.min"), 0)?.is_empty());
+
+        assert_eq!(plugin.peek_header(Path::new("missing"), 100).unwrap_err().to_string(), "No such container.");
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_defaults() {
+        let plugin = minimal(0);
+        // Unknown capacity, and no restoration
+        assert_eq!(plugin.capacity(Path::new("c.min")).unwrap(), 0);
+        let err = plugin.restore_container(Path::new("c.min"), Path::new("out.min"), Box::new(|_| {})).unwrap_err();
+        assert_eq!(err.to_string(), "Container restoration is not supported by this plugin.");
+    }
+}
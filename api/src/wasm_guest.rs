@@ -0,0 +1,348 @@
+//! Plugin side of the WebAssembly plugin ABI.
+//!
+//! A `.wasm` plugin is built for `wasm32-unknown-unknown` and runs sandboxed in the host: it
+//! has no WASI and no `std::fs`, only the `spng` imports, which reach nothing but the paths
+//! of the current call. Plugin authors implement the traits as for native plugins, do their
+//! file access through [`File`], [`read_dir`], [`file_size`] and [`create_dir_all`], and call
+//! [`export_wasm_plugin!`](crate::export_wasm_plugin). Every call runs in a fresh instance,
+//! so memory handed to the host is never freed.
+
+use anyhow::Result;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::{ByteSource, ContainerDecoder, ContainerEncoder, PluginMetadata};
+
+/// Must match the host's `wasm_plugin::ABI_VERSION`.
+pub const ABI_VERSION: u32 = 2;
+
+/// Bytes fetched from the host per `stream_read`.
+const SOURCE_CHUNK: usize = 64 * 1024;
+
+#[link(wasm_import_module = "spng")]
+extern "C" {
+    fn stream_read(ptr: *mut u8, len: usize) -> i32;
+    fn progress(value: f32);
+    fn set_error(ptr: *const u8, len: usize);
+    fn error_message(ptr: *mut u8, len: usize) -> i32;
+    #[link_name = "log"]
+    fn host_log(ptr: *const u8, len: usize);
+    fn fs_open(ptr: *const u8, len: usize, mode: i32) -> i32;
+    fn fs_read(handle: i32, ptr: *mut u8, len: usize) -> i32;
+    fn fs_write(handle: i32, ptr: *const u8, len: usize) -> i32;
+    fn fs_close(handle: i32);
+    fn fs_size(ptr: *const u8, len: usize) -> i64;
+    fn fs_list(ptr: *const u8, len: usize, out: *mut u8, out_len: usize) -> i32;
+    fn fs_mkdir(ptr: *const u8, len: usize) -> i32;
+}
+
+/// Writes a line to the host's log.
+pub fn log(message: &str) {
+    unsafe { host_log(message.as_ptr(), message.len()) }
+}
+
+/// Progress callback handed to the plugin traits.
+pub fn progress_callback() -> Box<dyn Fn(f32) + Send + Sync> {
+    Box::new(|value| unsafe { progress(value) })
+}
+
+/// Calls `fill(ptr, len)` with growing buffers until the result fits; returns the bytes.
+fn fetch(mut fill: impl FnMut(*mut u8, usize) -> i32) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; 256];
+    loop {
+        let needed = fill(buf.as_mut_ptr(), buf.len());
+        if needed < 0 {
+            return None;
+        }
+        if needed as usize <= buf.len() {
+            buf.truncate(needed as usize);
+            return Some(buf);
+        }
+        buf.resize(needed as usize, 0);
+    }
+}
+
+/// The error the host recorded for the last refused or failed import call.
+fn host_error() -> io::Error {
+    let message = fetch(|ptr, len| unsafe { error_message(ptr, len) }).unwrap_or_default();
+    io::Error::other(String::from_utf8_lossy(&message).into_owned())
+}
+
+fn path_str(path: &Path) -> io::Result<&str> {
+    path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path is not valid UTF-8"))
+}
+
+/// A file opened through the host.
+pub struct File(i32);
+
+impl File {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_mode(path.as_ref(), 0)
+    }
+
+    /// Creates or truncates the file.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_mode(path.as_ref(), 1)
+    }
+
+    fn with_mode(path: &Path, mode: i32) -> io::Result<Self> {
+        let path = path_str(path)?;
+        match unsafe { fs_open(path.as_ptr(), path.len(), mode) } {
+            handle if handle >= 0 => Ok(Self(handle)),
+            _ => Err(host_error()),
+        }
+    }
+}
+
+impl Read for File {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match unsafe { fs_read(self.0, buf.as_mut_ptr(), buf.len()) } {
+            n if n >= 0 => Ok(n as usize),
+            _ => Err(host_error()),
+        }
+    }
+}
+
+impl Write for File {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unsafe { fs_write(self.0, buf.as_ptr(), buf.len()) } {
+            n if n >= 0 => Ok(n as usize),
+            _ => Err(host_error()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe { fs_close(self.0) }
+    }
+}
+
+pub fn file_size(path: impl AsRef<Path>) -> io::Result<u64> {
+    let path = path_str(path.as_ref())?;
+    match unsafe { fs_size(path.as_ptr(), path.len()) } {
+        n if n >= 0 => Ok(n as u64),
+        _ => Err(host_error()),
+    }
+}
+
+/// Entries of a directory as full paths, sorted by name.
+pub fn read_dir(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let dir = path.as_ref();
+    let path = path_str(dir)?;
+    let list = fetch(|ptr, len| unsafe { fs_list(path.as_ptr(), path.len(), ptr, len) }).ok_or_else(host_error)?;
+    let list = String::from_utf8_lossy(&list).into_owned();
+    Ok(list.split('\n').filter(|name| !name.is_empty()).map(|name| dir.join(name)).collect())
+}
+
+pub fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path_str(path.as_ref())?;
+    match unsafe { fs_mkdir(path.as_ptr(), path.len()) } {
+        0 => Ok(()),
+        _ => Err(host_error()),
+    }
+}
+
+/// The byte stream of the current encode call.
+pub struct StreamSource {
+    total_len: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    exhausted: bool,
+}
+
+impl StreamSource {
+    #[doc(hidden)]
+    pub fn new(total_len: u64) -> Self {
+        Self { total_len, chunk: Vec::new(), pos: 0, exhausted: false }
+    }
+}
+
+impl ByteSource for StreamSource {
+    fn next_byte(&mut self) -> u8 {
+        if self.pos == self.chunk.len() {
+            if self.exhausted {
+                return 0;
+            }
+            self.chunk.resize(SOURCE_CHUNK, 0);
+            let n = unsafe { stream_read(self.chunk.as_mut_ptr(), SOURCE_CHUNK) }.max(0) as usize;
+            self.chunk.truncate(n);
+            self.pos = 0;
+            if n == 0 {
+                self.exhausted = true;
+                return 0;
+            }
+        }
+        self.pos += 1;
+        self.chunk[self.pos - 1]
+    }
+
+    fn total_len(&self) -> u64 {
+        self.total_len
+    }
+}
+
+// Helpers for `export_wasm_plugin!`; not part of the API.
+
+#[doc(hidden)]
+pub static READER: Mutex<Option<Box<dyn Read + Send>>> = Mutex::new(None);
+
+#[doc(hidden)]
+pub fn alloc(len: usize) -> *mut u8 {
+    Box::leak(vec![0u8; len].into_boxed_slice()).as_mut_ptr()
+}
+
+/// # Safety
+/// `ptr` must point to `len` bytes written by the host.
+#[doc(hidden)]
+pub unsafe fn path_arg(ptr: *const u8, len: usize) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(std::slice::from_raw_parts(ptr, len)).into_owned())
+}
+
+#[doc(hidden)]
+pub fn no_stream() -> anyhow::Error {
+    anyhow::anyhow!("No decode stream is open.")
+}
+
+/// Reports `error` to the host and returns the matching status.
+#[doc(hidden)]
+pub fn status(result: Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            let message = format!("{:#}", e);
+            unsafe { set_error(message.as_ptr(), message.len()) };
+            1
+        }
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn side_json(metadata: PluginMetadata, extensions: Vec<String>) -> String {
+    let extensions: Vec<String> = extensions.iter().map(|e| json_str(e)).collect();
+    format!(
+        "{{\"name\":{},\"description\":{},\"version\":{},\"author\":{},\"extensions\":[{}]}}",
+        json_str(&metadata.name), json_str(&metadata.description), json_str(&metadata.version),
+        json_str(&metadata.author), extensions.join(",")
+    )
+}
+
+/// The `spng_info` answer, packed as `(ptr << 32) | len`.
+#[doc(hidden)]
+pub fn info(encoder: Option<&dyn ContainerEncoder>, decoder: Option<&dyn ContainerDecoder>) -> i64 {
+    let encoder = encoder.map_or("null".to_string(), |e| side_json(e.metadata(), e.supported_extensions()));
+    let decoder = decoder.map_or("null".to_string(), |d| side_json(d.metadata(), d.supported_extensions()));
+    let json = Box::leak(format!("{{\"encoder\":{},\"decoder\":{}}}", encoder, decoder).into_boxed_str());
+    ((json.as_ptr() as usize as i64) << 32) | json.len() as i64
+}
+
+/// Exports a WebAssembly plugin built from trait implementations; same forms as
+/// [`export_plugin!`](crate::export_plugin). The expressions are evaluated on every call.
+///
+/// ```ignore
+/// sound_png_api::export_wasm_plugin!(encoder: MyPlugin, decoder: MyPlugin);
+/// ```
+#[macro_export]
+macro_rules! export_wasm_plugin {
+    (@common $encoder:expr, $decoder:expr) => {
+        #[no_mangle]
+        pub extern "C" fn spng_abi_version() -> i32 {
+            $crate::wasm_guest::ABI_VERSION as i32
+        }
+
+        #[no_mangle]
+        pub extern "C" fn spng_alloc(len: usize) -> *mut u8 {
+            $crate::wasm_guest::alloc(len)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn spng_info() -> i64 {
+            $crate::wasm_guest::info($encoder, $decoder)
+        }
+    };
+    (@encoder $encoder:expr) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn spng_encode(container: *const u8, container_len: usize, output: *const u8, output_len: usize, total_len: i64) -> i32 {
+            let container = $crate::wasm_guest::path_arg(container, container_len);
+            let output = $crate::wasm_guest::path_arg(output, output_len);
+            let mut source = $crate::wasm_guest::StreamSource::new(total_len as u64);
+            let plugin = $encoder;
+            $crate::wasm_guest::status($crate::ContainerEncoder::encode(&plugin, &container, &output, &mut source, $crate::wasm_guest::progress_callback()))
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn spng_capacity(container: *const u8, container_len: usize) -> i64 {
+            let container = $crate::wasm_guest::path_arg(container, container_len);
+            match $crate::ContainerEncoder::capacity(&$encoder, &container) {
+                Ok(capacity) => capacity as i64,
+                Err(e) => {
+                    $crate::wasm_guest::status(Err(e));
+                    -1
+                }
+            }
+        }
+    };
+    (@decoder $decoder:expr) => {
+        #[no_mangle]
+        pub unsafe extern "C" fn spng_decode(input: *const u8, input_len: usize) -> i32 {
+            let input = $crate::wasm_guest::path_arg(input, input_len);
+            let reader = $crate::ContainerDecoder::decode(&$decoder, &input, $crate::wasm_guest::progress_callback());
+            $crate::wasm_guest::status(reader.map(|reader| {
+                *$crate::wasm_guest::READER.lock().unwrap() = Some(reader);
+            }))
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn spng_read(ptr: *mut u8, len: usize) -> i32 {
+            let buf = std::slice::from_raw_parts_mut(ptr, len);
+            let read = match $crate::wasm_guest::READER.lock().unwrap().as_mut() {
+                Some(reader) => std::io::Read::read(reader, buf).map_err(Into::into),
+                None => Err($crate::wasm_guest::no_stream()),
+            };
+            match read {
+                Ok(n) => n as i32,
+                Err(e) => {
+                    $crate::wasm_guest::status(Err(e));
+                    -1
+                }
+            }
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn spng_restore(input: *const u8, input_len: usize, output: *const u8, output_len: usize) -> i32 {
+            let input = $crate::wasm_guest::path_arg(input, input_len);
+            let output = $crate::wasm_guest::path_arg(output, output_len);
+            $crate::wasm_guest::status($crate::ContainerDecoder::restore_container(&$decoder, &input, &output, $crate::wasm_guest::progress_callback()))
+        }
+    };
+    (encoder: $encoder:expr, decoder: $decoder:expr $(,)?) => {
+        $crate::export_wasm_plugin!(@common Some(&$encoder), Some(&$decoder));
+        $crate::export_wasm_plugin!(@encoder $encoder);
+        $crate::export_wasm_plugin!(@decoder $decoder);
+    };
+    (encoder: $encoder:expr $(,)?) => {
+        $crate::export_wasm_plugin!(@common Some(&$encoder), None);
+        $crate::export_wasm_plugin!(@encoder $encoder);
+    };
+    (decoder: $decoder:expr $(,)?) => {
+        $crate::export_wasm_plugin!(@common None, Some(&$decoder));
+        $crate::export_wasm_plugin!(@decoder $decoder);
+    };
+}
@@ -0,0 +1,387 @@
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavSpec};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Layout of an AIFF / AIFF-C file as found by `read_info`.
+#[derive(Debug, Clone)]
+pub struct AiffInfo {
+    pub spec: WavSpec,
+    /// Sample frames (samples per channel).
+    pub frames: u64,
+    pub aifc: bool,
+    little_endian: bool,
+    data_start: u64,
+}
+
+/// Scans the chunks of an AIFF / AIFF-C file. COMM may appear before or after SSND.
+pub fn read_info(path: &Path) -> Result<AiffInfo> {
+    let mut file = BufReader::new(File::open(path).context("Failed to open AIFF file.")?);
+    let mut head = [0u8; 12];
+    file.read_exact(&mut head)?;
+    if &head[0..4] != b"FORM" || !(&head[8..12] == b"AIFF" || &head[8..12] == b"AIFC") {
+        return Err(anyhow!("Not an AIFF file."));
+    }
+    let aifc = &head[8..12] == b"AIFC";
+
+    let mut comm = None;
+    let mut data_start = None;
+    loop {
+        let mut chunk = [0u8; 8];
+        if file.read_exact(&mut chunk).is_err() {
+            break;
+        }
+        let size = u32::from_be_bytes(chunk[4..8].try_into().unwrap()) as u64;
+        let start = file.stream_position()?;
+        match &chunk[0..4] {
+            b"COMM" => {
+                let mut body = vec![0u8; size as usize];
+                file.read_exact(&mut body)?;
+                comm = Some(body);
+            }
+            b"SSND" => {
+                let mut offset = [0u8; 4];
+                file.read_exact(&mut offset)?;
+                data_start = Some(start + 8 + u32::from_be_bytes(offset) as u64);
+            }
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        file.seek(SeekFrom::Start(start + size + (size & 1)))?;
+    }
+
+    let comm = comm.ok_or_else(|| anyhow!("AIFF file has no COMM chunk."))?;
+    let data_start = data_start.ok_or_else(|| anyhow!("AIFF file has no SSND chunk."))?;
+    if comm.len() < 18 {
+        return Err(anyhow!("AIFF COMM chunk is too short."));
+    }
+    let channels = u16::from_be_bytes([comm[0], comm[1]]);
+    let frames = u32::from_be_bytes(comm[2..6].try_into().unwrap()) as u64;
+    let bits = u16::from_be_bytes([comm[6], comm[7]]);
+    let sample_rate = read_extended(comm[8..18].try_into().unwrap()).round() as u32;
+
+    let compression: &[u8] = if aifc && comm.len() >= 22 { &comm[18..22] } else { b"NONE" };
+    let (sample_format, little_endian) = match compression {
+        b"NONE" | b"twos" | b"in24" | b"in32" => (SampleFormat::Int, false),
+        b"sowt" => (SampleFormat::Int, true),
+        b"fl32" | b"FL32" if bits == 32 => (SampleFormat::Float, false),
+        other => return Err(anyhow!("Unsupported AIFF-C compression: {}", String::from_utf8_lossy(other))),
+    };
+    if sample_format == SampleFormat::Int && !(1..=32).contains(&bits) {
+        return Err(anyhow!("Unsupported AIFF sample size: {}", bits));
+    }
+
+    Ok(AiffInfo {
+        spec: WavSpec { channels, sample_rate, bits_per_sample: bits, sample_format },
+        frames,
+        aifc,
+        little_endian,
+        data_start,
+    })
+}
+
+/// Interleaved raw sample bits: integers sign-extended to i32, floats as f32 bits.
+pub struct AiffSamples {
+    reader: BufReader<File>,
+    remaining: u64,
+    bytes: usize,
+    shift: u32,
+    little_endian: bool,
+}
+
+impl AiffSamples {
+    pub fn open(path: &Path, info: &AiffInfo) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        reader.seek(SeekFrom::Start(info.data_start))?;
+        let bytes = (info.spec.bits_per_sample as usize).div_ceil(8);
+        Ok(Self {
+            reader,
+            remaining: info.frames * info.spec.channels as u64,
+            bytes,
+            // Sample points are left-justified in their bytes
+            shift: (bytes * 8) as u32 - info.spec.bits_per_sample as u32,
+            little_endian: info.little_endian,
+        })
+    }
+}
+
+impl Iterator for AiffSamples {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Result<u32>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut buf = [0u8; 4];
+        let slot = &mut buf[..self.bytes];
+        if let Err(e) = self.reader.read_exact(slot) {
+            self.remaining = 0;
+            return Some(Err(e.into()));
+        }
+        if self.little_endian {
+            slot.reverse();
+        }
+        // Big-endian bytes into the top of a u32, then an arithmetic shift to sign-extend
+        let value = (u32::from_be_bytes(buf) as i32) >> (32 - self.bytes as u32 * 8 + self.shift);
+        Some(Ok(value as u32))
+    }
+}
+
+/// Streaming AIFF writer. Float samples (and `aifc`) produce AIFF-C; sizes are patched on `finalize`.
+pub struct AiffWriter {
+    out: BufWriter<File>,
+    spec: WavSpec,
+    bytes: usize,
+    comm_frames_pos: u64,
+    ssnd_size_pos: u64,
+    data_bytes: u64,
+}
+
+impl AiffWriter {
+    pub fn create(path: &Path, spec: WavSpec, aifc: bool) -> Result<Self> {
+        let float = spec.sample_format == SampleFormat::Float;
+        if float && spec.bits_per_sample != 32 {
+            return Err(anyhow!("AIFF-C float must be 32-bit."));
+        }
+        if !matches!(spec.bits_per_sample, 8 | 16 | 24 | 32) {
+            return Err(anyhow!("Unsupported AIFF sample size: {}", spec.bits_per_sample));
+        }
+        let aifc = aifc || float;
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(b"FORM")?;
+        out.write_all(&0u32.to_be_bytes())?;
+        out.write_all(if aifc { b"AIFC" } else { b"AIFF" })?;
+        if aifc {
+            out.write_all(b"FVER")?;
+            out.write_all(&4u32.to_be_bytes())?;
+            out.write_all(&0xA280_5140u32.to_be_bytes())?; // AIFF-C version 1
+        }
+
+        let (compression, name): (&[u8; 4], &str) = if float { (b"fl32", "32-bit floating point") } else { (b"NONE", "not compressed") };
+        let mut pstring = vec![name.len() as u8];
+        pstring.extend_from_slice(name.as_bytes());
+        if pstring.len() % 2 == 1 {
+            pstring.push(0);
+        }
+        let comm_size = 18 + if aifc { 4 + pstring.len() } else { 0 };
+        out.write_all(b"COMM")?;
+        out.write_all(&(comm_size as u32).to_be_bytes())?;
+        out.write_all(&spec.channels.to_be_bytes())?;
+        let comm_frames_pos = out.stream_position()?;
+        out.write_all(&0u32.to_be_bytes())?;
+        out.write_all(&spec.bits_per_sample.to_be_bytes())?;
+        out.write_all(&write_extended(spec.sample_rate))?;
+        if aifc {
+            out.write_all(compression)?;
+            out.write_all(&pstring)?;
+        }
+
+        out.write_all(b"SSND")?;
+        let ssnd_size_pos = out.stream_position()?;
+        out.write_all(&8u32.to_be_bytes())?;
+        out.write_all(&[0u8; 8])?; // offset, block size
+
+        Ok(Self {
+            out,
+            spec,
+            bytes: spec.bits_per_sample as usize / 8,
+            comm_frames_pos,
+            ssnd_size_pos,
+            data_bytes: 0,
+        })
+    }
+
+    /// Writes one sample given as raw bits (i32 value or f32 bits).
+    pub fn write_raw(&mut self, bits: u32) -> Result<()> {
+        let be = bits.to_be_bytes();
+        self.out.write_all(&be[4 - self.bytes..])?;
+        self.data_bytes += self.bytes as u64;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> Result<()> {
+        if self.data_bytes % 2 == 1 {
+            self.out.write_all(&[0])?;
+        }
+        let file_len = self.out.stream_position()?;
+        let frames = self.data_bytes / (self.bytes as u64 * self.spec.channels as u64);
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&((file_len - 8) as u32).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(self.comm_frames_pos))?;
+        self.out.write_all(&(frames as u32).to_be_bytes())?;
+        self.out.seek(SeekFrom::Start(self.ssnd_size_pos))?;
+        self.out.write_all(&((self.data_bytes + 8) as u32).to_be_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// 80-bit IEEE 754 extended float (the COMM sample rate).
+fn read_extended(b: [u8; 10]) -> f64 {
+    let negative = b[0] & 0x80 != 0;
+    let exponent = (((b[0] & 0x7F) as i32) << 8) | b[1] as i32;
+    let mantissa = u64::from_be_bytes(b[2..10].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    let value = mantissa as f64 * 2f64.powi(exponent - 16383 - 63);
+    if negative { -value } else { value }
+}
+
+fn write_extended(value: u32) -> [u8; 10] {
+    let mut out = [0u8; 10];
+    if value == 0 {
+        return out;
+    }
+    let shift = (value as u64).leading_zeros();
+    let exponent = 16383 + 63 - shift as u16;
+    out[0..2].copy_from_slice(&exponent.to_be_bytes());
+    out[2..10].copy_from_slice(&((value as u64) << shift).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn spec(channels: u16, bits: u16) -> WavSpec {
+        WavSpec { channels, sample_rate: 44100, bits_per_sample: bits, sample_format: SampleFormat::Int }
+    }
+
+    /// Full-scale test samples for `bits`, including both extremes.
+    fn samples(bits: u16, count: usize) -> Vec<u32> {
+        let max = 1i64 << (bits - 1);
+        (0..count as i64).map(|i| match i {
+            0 => (max - 1) as i32 as u32,
+            1 => (-max) as i32 as u32,
+            _ => ((i * 2654435761) % (2 * max) - max) as i32 as u32,
+        }).collect()
+    }
+
+    #[test]
+    fn test_writer_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        for (bits, aifc) in [(8, false), (16, false), (24, false), (32, false), (16, true), (24, true)] {
+            for channels in [1u16, 2] {
+                let path = dir.path().join("test.aiff");
+                // An odd frame count leaves an odd SSND length for 8/24-bit mono
+                let expected = samples(bits, 1001 * channels as usize);
+                let mut writer = AiffWriter::create(&path, spec(channels, bits), aifc)?;
+                for &s in &expected {
+                    writer.write_raw(s)?;
+                }
+                writer.finalize()?;
+
+                let info = read_info(&path)?;
+                assert_eq!((info.spec, info.frames, info.aifc), (spec(channels, bits), 1001, aifc));
+                let read = AiffSamples::open(&path, &info)?.collect::<Result<Vec<_>>>()?;
+                assert_eq!(read, expected, "{} bits, {} channels, aifc {}", bits, channels, aifc);
+                let len = std::fs::metadata(&path)?.len();
+                assert_eq!(len % 2, 0);
+                assert_eq!(std::fs::read(&path)?[4..8], ((len - 8) as u32).to_be_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_float_is_aifc() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.aiff");
+        let spec = WavSpec { sample_format: SampleFormat::Float, ..spec(2, 32) };
+        let expected: Vec<u32> = [0.0f32, -1.0, 0.5, 1.0e-7].iter().map(|v| v.to_bits()).collect();
+        let mut writer = AiffWriter::create(&path, spec, false)?;
+        for &s in &expected {
+            writer.write_raw(s)?;
+        }
+        writer.finalize()?;
+        let info = read_info(&path)?;
+        assert!(info.aifc);
+        assert_eq!(info.spec, spec);
+        assert_eq!(AiffSamples::open(&path, &info)?.collect::<Result<Vec<_>>>()?, expected);
+        assert!(AiffWriter::create(&path, WavSpec { bits_per_sample: 64, ..spec }, true).is_err());
+        Ok(())
+    }
+
+    /// A hand-built AIFF-C file with little-endian `sowt` samples and SSND before COMM.
+    pub(crate) fn sowt_file(bits: u16, channels: u16, samples: &[u32]) -> Vec<u8> {
+        let bytes = bits as usize / 8;
+        let mut ssnd = vec![0u8; 8]; // offset, block size
+        for &s in samples {
+            ssnd.extend_from_slice(&s.to_le_bytes()[..bytes]);
+        }
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&channels.to_be_bytes());
+        comm.extend_from_slice(&((samples.len() / channels as usize) as u32).to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        comm.extend_from_slice(&write_extended(48000));
+        comm.extend_from_slice(b"sowt");
+        comm.extend_from_slice(b"\x00\x00"); // empty pascal string, padded
+        let mut body = b"AIFC".to_vec();
+        for (id, chunk) in [(b"SSND", ssnd), (b"COMM", comm)] {
+            body.extend_from_slice(id);
+            body.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            body.extend_from_slice(&chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        [b"FORM".as_slice(), &(body.len() as u32).to_be_bytes(), &body].concat()
+    }
+
+    #[test]
+    fn test_sowt() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.aifc");
+        for bits in [16u16, 24] {
+            let expected = samples(bits, 333 * 2);
+            std::fs::write(&path, sowt_file(bits, 2, &expected))?;
+            let info = read_info(&path)?;
+            assert_eq!(info.spec, WavSpec { sample_rate: 48000, ..spec(2, bits) });
+            assert_eq!((info.frames, info.aifc), (333, true));
+            assert_eq!(AiffSamples::open(&path, &info)?.collect::<Result<Vec<_>>>()?, expected, "{} bits", bits);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended() {
+        // Reference encodings of the common rates
+        let cases: [(u32, [u8; 10]); 4] = [
+            (44100, [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0]),
+            (48000, [0x40, 0x0E, 0xBB, 0x80, 0, 0, 0, 0, 0, 0]),
+            (8000, [0x40, 0x0B, 0xFA, 0, 0, 0, 0, 0, 0, 0]),
+            (1, [0x3F, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+        ];
+        for (rate, bytes) in cases {
+            assert_eq!(write_extended(rate), bytes, "{}", rate);
+            assert_eq!(read_extended(bytes), rate as f64, "{}", rate);
+        }
+        assert_eq!(read_extended(write_extended(0)), 0.0);
+        assert_eq!(read_extended(write_extended(u32::MAX)), u32::MAX as f64);
+    }
+
+    #[test]
+    fn test_rejects_malformed() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.aiff");
+        let valid = sowt_file(16, 1, &[1, 2, 3]);
+        std::fs::write(&path, b"RIFF\0\0\0\0WAVE")?;
+        assert!(read_info(&path).is_err());
+        // COMM missing
+        std::fs::write(&path, &valid[..12 + 8 + 14])?;
+        assert_eq!(read_info(&path).unwrap_err().to_string(), "AIFF file has no COMM chunk.");
+        // Unknown compression
+        let mut ulaw = valid.clone();
+        let at = ulaw.windows(4).position(|w| w == b"sowt").unwrap();
+        ulaw[at..at + 4].copy_from_slice(b"ulaw");
+        std::fs::write(&path, ulaw)?;
+        assert!(read_info(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+use anyhow::{anyhow, Result};
+use png::{Encoder, FrameControl, Info, Reader, StreamWriter, Writer};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Number of images stored in a PNG: 1 for still images, the animation frames plus the
+/// separate default image (IDAT without fcTL) for APNG. All of them carry payload.
+pub fn images(info: &Info) -> u32 {
+    match &info.animation_control {
+        Some(actl) => actl.num_frames + info.frame_control.is_none() as u32,
+        None => 1,
+    }
+}
+
+/// Total pixels of all images in the PNG and the image count.
+pub fn pixels(path: &Path) -> Result<(u64, u32)> {
+    let mut reader = png::Decoder::new(File::open(path)?).read_info()?;
+    let images = images(reader.info());
+    let mut pixels = reader.info().width as u64 * reader.info().height as u64;
+    for _ in 1..images {
+        let fc = reader.next_frame_info()?;
+        pixels += fc.width as u64 * fc.height as u64;
+    }
+    Ok((pixels, images))
+}
+
+pub fn is_animated(path: &Path) -> Result<bool> {
+    let reader = png::Decoder::new(File::open(path)?).read_info()?;
+    Ok(reader.info().animation_control.is_some())
+}
+
+/// Copies the animation settings of `source` (loop count, default image, timing of the
+/// first frame) to an encoder whose header has not been written yet.
+pub fn start<W: Write>(encoder: &mut Encoder<W>, source: &Info) -> Result<()> {
+    if let Some(actl) = &source.animation_control {
+        encoder.set_animated(actl.num_frames, actl.num_plays)?;
+        match &source.frame_control {
+            // The IDAT image is the first frame (always full size at 0,0)
+            Some(fc) => {
+                encoder.set_frame_delay(fc.delay_num, fc.delay_den)?;
+                encoder.set_dispose_op(fc.dispose_op)?;
+                encoder.set_blend_op(fc.blend_op)?;
+            }
+            None => encoder.set_sep_def_img(true)?,
+        }
+    }
+    Ok(())
+}
+
+/// Image data sink of a PNG writer. Still images are streamed row by row; APNG frames are
+/// collected and written whole, since `StreamWriter` cannot write frames narrower than
+/// the canvas.
+pub enum Output<'a, W: Write> {
+    Still(Box<StreamWriter<'a, W>>),
+    Animated { writer: &'a mut Writer<W>, frame: Vec<u8> },
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    pub fn new(writer: &'a mut Writer<W>, animated: bool) -> Result<Self> {
+        Ok(if animated {
+            Output::Animated { writer, frame: Vec::new() }
+        } else {
+            Output::Still(Box::new(writer.stream_writer()?))
+        })
+    }
+
+    /// Writes the current frame, moves `reader` to its next frame and sets up the same
+    /// region, timing, disposal and blending for it. Returns the frame size.
+    pub fn next_frame(&mut self, reader: &mut Reader<File>) -> Result<(u32, u32)> {
+        let Output::Animated { writer, frame } = self else {
+            return Err(anyhow!("Not an animated PNG."));
+        };
+        writer.write_image_data(frame)?;
+        frame.clear();
+        let fc: FrameControl = *reader.next_frame_info()?;
+        // Position is checked against the current size, so shrink to the origin first
+        writer.reset_frame_position()?;
+        writer.set_frame_dimension(fc.width, fc.height)?;
+        writer.set_frame_position(fc.x_offset, fc.y_offset)?;
+        writer.set_frame_delay(fc.delay_num, fc.delay_den)?;
+        writer.set_dispose_op(fc.dispose_op)?;
+        writer.set_blend_op(fc.blend_op)?;
+        Ok((fc.width, fc.height))
+    }
+
+    pub fn write_row(&mut self, row: &[u8]) -> Result<()> {
+        match self {
+            Output::Still(stream_writer) => stream_writer.write_all(row)?,
+            Output::Animated { frame, .. } => frame.extend_from_slice(row),
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Output::Still(stream_writer) => stream_writer.finish()?,
+            Output::Animated { writer, frame } => writer.write_image_data(&frame)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_loader::PluginManager;
+    use crate::{stream_decoder, stream_encoder};
+    use png::{BlendOp, DisposeOp};
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    const CANVAS: (u32, u32) = (20, 16);
+    /// (width, height, x, y, delay, dispose, blend) of each animation frame; the later
+    /// frames are smaller than the canvas.
+    const FRAMES: [(u32, u32, u32, u32, u16, DisposeOp, BlendOp); 3] = [
+        (20, 16, 0, 0, 10, DisposeOp::None, BlendOp::Source),
+        (8, 6, 3, 4, 25, DisposeOp::Background, BlendOp::Over),
+        (12, 3, 8, 13, 40, DisposeOp::Previous, BlendOp::Source),
+    ];
+
+    fn rgba(width: u32, height: u32, seed: usize) -> Vec<u8> {
+        (0..(width * height * 4) as usize).map(|i| (((i + seed) * 2654435761) >> 9) as u8).collect()
+    }
+
+    /// Writes an 8-bit RGBA APNG, with or without a default image outside the animation.
+    fn write_apng(path: &Path, separate_default: bool) -> Result<()> {
+        let mut encoder = Encoder::new(File::create(path)?, CANVAS.0, CANVAS.1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(FRAMES.len() as u32, 2)?;
+        encoder.set_sep_def_img(separate_default)?;
+        let mut writer = encoder.write_header()?;
+        if separate_default {
+            writer.write_image_data(&rgba(CANVAS.0, CANVAS.1, 99))?;
+        }
+        for (i, &(width, height, x, y, delay, dispose, blend)) in FRAMES.iter().enumerate() {
+            writer.reset_frame_position()?;
+            writer.set_frame_dimension(width, height)?;
+            writer.set_frame_position(x, y)?;
+            writer.set_frame_delay(delay, 100)?;
+            writer.set_dispose_op(dispose)?;
+            writer.set_blend_op(blend)?;
+            writer.write_image_data(&rgba(width, height, i))?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// Region, timing, disposal and blending of a frame.
+    type Control = (u32, u32, u32, u32, u16, u16, DisposeOp, BlendOp);
+    /// Frame control (`None` for a separate default image) and pixels of each image.
+    type Images = Vec<(Option<Control>, Vec<u8>)>;
+
+    /// Every image of a PNG with its pixels as 8-bit RGBA (16-bit images: high bytes).
+    fn read_images(path: &Path) -> Result<(Option<png::AnimationControl>, Images)> {
+        let mut reader = png::Decoder::new(File::open(path)?).read_info()?;
+        let actl = reader.info().animation_control;
+        let count = images(reader.info());
+        let mut out = Vec::new();
+        for _ in 0..count {
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            let frame = reader.next_frame(&mut buf)?;
+            buf.truncate(frame.buffer_size());
+            if frame.bit_depth == png::BitDepth::Sixteen {
+                buf = buf.chunks_exact(2).map(|b| b[0]).collect();
+            }
+            let fc = reader.info().frame_control.map(|fc| {
+                (fc.width, fc.height, fc.x_offset, fc.y_offset, fc.delay_num, fc.delay_den, fc.dispose_op, fc.blend_op)
+            });
+            out.push((fc, buf));
+        }
+        Ok((actl, out))
+    }
+
+    #[test]
+    fn test_images_and_pixels() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("anim.png");
+        let frame_pixels: u64 = FRAMES.iter().map(|f| f.0 as u64 * f.1 as u64).sum();
+        for separate_default in [false, true] {
+            write_apng(&path, separate_default)?;
+            assert!(is_animated(&path)?);
+            let canvas = CANVAS.0 as u64 * CANVAS.1 as u64;
+            let expected = if separate_default { (canvas + frame_pixels, 4) } else { (frame_pixels, 3) };
+            assert_eq!(pixels(&path)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_embed_keeps_animation() -> Result<()> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = tempdir()?;
+        let container = dir.path().join("container.png");
+        let output = dir.path().join("output.png");
+        let restored_payload = dir.path().join("payload.bin");
+        let restored_container = dir.path().join("restored.png");
+        for separate_default in [false, true] {
+            write_apng(&container, separate_default)?;
+            let payload: Vec<u8> = (0..500u32).map(|i| (i.wrapping_mul(2654435761) >> 7) as u8).collect();
+            stream_encoder::encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+            assert_eq!(std::fs::read(&restored_payload)?, payload);
+
+            // Loop count, frame regions, timing, disposal and blending survive the embedding
+            // and the restore; the restored pixels are the original ones
+            let (actl, original) = read_images(&container)?;
+            for path in [&output, &restored_container] {
+                let (out_actl, images) = read_images(path)?;
+                assert_eq!(out_actl.map(|a| (a.num_frames, a.num_plays)), actl.map(|a| (a.num_frames, a.num_plays)));
+                assert_eq!(images.len(), original.len());
+                for ((fc, _), (out_fc, _)) in original.iter().zip(&images) {
+                    assert_eq!(fc, out_fc, "separate default {}", separate_default);
+                }
+            }
+            let (_, restored) = read_images(&restored_container)?;
+            for ((_, a), (_, b)) in original.iter().zip(&restored) {
+                assert_eq!(a, b);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavSpec};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b".snd";
+/// Data size value meaning "read until end of file".
+const UNKNOWN_SIZE: u32 = 0xFFFF_FFFF;
+
+/// Header of a Sun AU file as found by `read_info`.
+#[derive(Debug, Clone)]
+pub struct AuInfo {
+    pub spec: WavSpec,
+    /// Interleaved samples (all channels).
+    pub samples: u64,
+    data_start: u64,
+}
+
+/// Reads the `.snd` header. Only linear PCM (encodings 2-5) and 32-bit float (6) are supported.
+pub fn read_info(path: &Path) -> Result<AuInfo> {
+    let mut file = File::open(path).context("Failed to open AU file.")?;
+    let mut head = [0u8; 24];
+    file.read_exact(&mut head)?;
+    if &head[0..4] != MAGIC {
+        return Err(anyhow!("Not a Sun AU file."));
+    }
+    let field = |i: usize| u32::from_be_bytes(head[i * 4..i * 4 + 4].try_into().unwrap());
+    let (data_start, data_size, encoding, sample_rate, channels) = (field(1) as u64, field(2), field(3), field(4), field(5));
+
+    let (bits_per_sample, sample_format) = match encoding {
+        2 => (8, SampleFormat::Int),
+        3 => (16, SampleFormat::Int),
+        4 => (24, SampleFormat::Int),
+        5 => (32, SampleFormat::Int),
+        6 => (32, SampleFormat::Float),
+        1 | 27 => return Err(anyhow!("Companded AU audio (mu-law/A-law) is not supported.")),
+        other => return Err(anyhow!("Unsupported AU encoding: {}", other)),
+    };
+    if channels == 0 || channels > u16::MAX as u32 {
+        return Err(anyhow!("Invalid AU channel count: {}", channels));
+    }
+
+    let available = file.metadata()?.len().saturating_sub(data_start);
+    let data_bytes = if data_size == UNKNOWN_SIZE { available } else { (data_size as u64).min(available) };
+    let bytes = bits_per_sample as u64 / 8;
+    let channels = channels as u16;
+    Ok(AuInfo {
+        spec: WavSpec { channels, sample_rate, bits_per_sample, sample_format },
+        // Whole frames only
+        samples: data_bytes / (bytes * channels as u64) * channels as u64,
+        data_start,
+    })
+}
+
+/// Interleaved raw sample bits: integers sign-extended to i32, floats as f32 bits.
+pub struct AuSamples {
+    reader: BufReader<File>,
+    remaining: u64,
+    bytes: usize,
+}
+
+impl AuSamples {
+    pub fn open(path: &Path, info: &AuInfo) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        reader.seek(SeekFrom::Start(info.data_start))?;
+        Ok(Self {
+            reader,
+            remaining: info.samples,
+            bytes: info.spec.bits_per_sample as usize / 8,
+        })
+    }
+}
+
+impl Iterator for AuSamples {
+    type Item = Result<u32>;
+
+    fn next(&mut self) -> Option<Result<u32>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut buf = [0u8; 4];
+        if let Err(e) = self.reader.read_exact(&mut buf[..self.bytes]) {
+            self.remaining = 0;
+            return Some(Err(e.into()));
+        }
+        let value = (u32::from_be_bytes(buf) as i32) >> (32 - self.bytes as u32 * 8);
+        Some(Ok(value as u32))
+    }
+}
+
+/// Streaming AU writer; the data size is patched on `finalize`.
+pub struct AuWriter {
+    out: BufWriter<File>,
+    bytes: usize,
+    data_bytes: u64,
+}
+
+impl AuWriter {
+    pub fn create(path: &Path, spec: WavSpec) -> Result<Self> {
+        let encoding: u32 = match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 8) => 2,
+            (SampleFormat::Int, 16) => 3,
+            (SampleFormat::Int, 24) => 4,
+            (SampleFormat::Int, 32) => 5,
+            (SampleFormat::Float, 32) => 6,
+            (format, bits) => return Err(anyhow!("Unsupported AU format: {:?} {} bits", format, bits)),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        for value in [24, UNKNOWN_SIZE, encoding, spec.sample_rate, spec.channels as u32] {
+            out.write_all(&value.to_be_bytes())?;
+        }
+        Ok(Self { out, bytes: spec.bits_per_sample as usize / 8, data_bytes: 0 })
+    }
+
+    /// Writes one sample given as raw bits (i32 value or f32 bits).
+    pub fn write_raw(&mut self, bits: u32) -> Result<()> {
+        let be = bits.to_be_bytes();
+        self.out.write_all(&be[4 - self.bytes..])?;
+        self.data_bytes += self.bytes as u64;
+        Ok(())
+    }
+
+    pub fn finalize(mut self) -> Result<()> {
+        // Files over 4 GiB keep the "unknown" size and are read to the end
+        if self.data_bytes < UNKNOWN_SIZE as u64 {
+            self.out.seek(SeekFrom::Start(8))?;
+            self.out.write_all(&(self.data_bytes as u32).to_be_bytes())?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn spec(channels: u16, bits: u16, sample_format: SampleFormat) -> WavSpec {
+        WavSpec { channels, sample_rate: 48000, bits_per_sample: bits, sample_format }
+    }
+
+    fn write(path: &Path, spec: WavSpec, samples: &[u32]) -> Result<()> {
+        let mut writer = AuWriter::create(path, spec)?;
+        for &s in samples {
+            writer.write_raw(s)?;
+        }
+        writer.finalize()
+    }
+
+    #[test]
+    fn test_writer_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.au");
+        for bits in [8u16, 16, 24, 32] {
+            for channels in [1u16, 2] {
+                let max = 1i64 << (bits - 1);
+                let expected: Vec<u32> = (0..777 * channels as i64).map(|i| match i {
+                    0 => (max - 1) as i32 as u32,
+                    1 => (-max) as i32 as u32,
+                    _ => ((i * 2654435761) % (2 * max) - max) as i32 as u32,
+                }).collect();
+                let spec = spec(channels, bits, SampleFormat::Int);
+                write(&path, spec, &expected)?;
+                let info = read_info(&path)?;
+                assert_eq!((info.spec, info.samples), (spec, expected.len() as u64));
+                assert_eq!(AuSamples::open(&path, &info)?.collect::<Result<Vec<_>>>()?, expected, "{} bits, {} channels", bits, channels);
+            }
+        }
+
+        let spec = spec(1, 32, SampleFormat::Float);
+        let expected: Vec<u32> = [0.25f32, -1.0, 1.0e-9].iter().map(|v| v.to_bits()).collect();
+        write(&path, spec, &expected)?;
+        let info = read_info(&path)?;
+        assert_eq!(info.spec, spec);
+        assert_eq!(AuSamples::open(&path, &info)?.collect::<Result<Vec<_>>>()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_size() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.au");
+        // The header starts out with the unknown size and is patched on finish
+        let mut writer = AuWriter::create(&path, spec(2, 24, SampleFormat::Int))?;
+        for i in 0..10 {
+            writer.write_raw(i)?;
+        }
+        writer.out.flush()?;
+        assert_eq!(std::fs::read(&path)?[8..12], UNKNOWN_SIZE.to_be_bytes());
+        writer.finalize()?;
+        let bytes = std::fs::read(&path)?;
+        assert_eq!(bytes[8..12], 30u32.to_be_bytes());
+        assert_eq!(bytes.len(), 24 + 30);
+
+        // Trailing bytes after the recorded size are ignored; a partial frame is dropped
+        let mut extended = bytes.clone();
+        extended.extend_from_slice(&[0xAA; 7]);
+        std::fs::write(&path, &extended)?;
+        assert_eq!(read_info(&path)?.samples, 10);
+
+        // "Unknown" reads to the end of the file, whole frames only
+        extended[8..12].copy_from_slice(&UNKNOWN_SIZE.to_be_bytes());
+        std::fs::write(&path, &extended)?;
+        assert_eq!(read_info(&path)?.samples, 12);
+
+        // A recorded size past the end is clamped
+        let mut truncated = bytes;
+        truncated.truncate(24 + 12);
+        std::fs::write(&path, &truncated)?;
+        assert_eq!(read_info(&path)?.samples, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_unsupported() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.au");
+        assert!(AuWriter::create(&path, spec(1, 12, SampleFormat::Int)).is_err());
+        assert!(AuWriter::create(&path, spec(1, 16, SampleFormat::Float)).is_err());
+        write(&path, spec(1, 16, SampleFormat::Int), &[1, 2])?;
+        let mut bytes = std::fs::read(&path)?;
+        bytes[12..16].copy_from_slice(&1u32.to_be_bytes()); // mu-law
+        std::fs::write(&path, &bytes)?;
+        assert!(read_info(&path).unwrap_err().to_string().contains("mu-law"));
+        bytes[0] = b'X';
+        std::fs::write(&path, &bytes)?;
+        assert_eq!(read_info(&path).unwrap_err().to_string(), "Not a Sun AU file.");
+        Ok(())
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Result};
+use hound::{SampleFormat, WavSpec};
+use std::path::Path;
+use crate::flac::{self, FlacLayout, FlacSamples, FlacWriter};
+use crate::pcm::{self, PcmFormat, PcmSource, PcmWriter};
+use crate::utils::WavLayout;
+
+/// Audio containers embedded sample by sample: the uncompressed PCM formats and FLAC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Pcm(PcmFormat),
+    Flac,
+}
+
+impl AudioFormat {
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        match PcmFormat::from_ext(ext) {
+            Some(format) => Some(Self::Pcm(format)),
+            None if ext.eq_ignore_ascii_case("flac") => Some(Self::Flac),
+            None => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Pcm(PcmFormat::Wav) => "wav",
+            Self::Pcm(PcmFormat::Aiff) => "aiff",
+            Self::Pcm(PcmFormat::Au) => "au",
+            Self::Flac => "flac",
+        }
+    }
+}
+
+/// Encoding of the audio output, chosen independently of the embedding mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioOutput {
+    /// Container format; 16-bit audio is widened (32-bit PCM, 24-bit FLAC) for more capacity.
+    #[default]
+    Auto,
+    /// Container format and sample depth; 16-bit audio carries 2 payload bits per sample.
+    SameAsInput,
+    /// 32-bit int WAV with the audio in the high 16 bits (`WavLayout::Expand16`).
+    Wav32,
+    /// FLAC as with `Auto` (24-bit), also for PCM containers with integer samples.
+    Flac,
+}
+
+impl AudioOutput {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Self::SameAsInput,
+            2 => Self::Wav32,
+            3 => Self::Flac,
+            _ => Self::Auto,
+        }
+    }
+
+    /// Format written for a container in `source` format.
+    pub fn format(self, source: AudioFormat) -> AudioFormat {
+        match self {
+            Self::Auto | Self::SameAsInput => source,
+            Self::Wav32 => AudioFormat::Pcm(PcmFormat::Wav),
+            Self::Flac => AudioFormat::Flac,
+        }
+    }
+}
+
+/// Output format and payload layout of an audio container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioLayout {
+    Pcm(PcmFormat, WavLayout),
+    Flac(FlacLayout),
+}
+
+impl AudioLayout {
+    /// Bits of each output sample (raw bits) that carry payload.
+    pub fn mask(self) -> u32 {
+        match self {
+            Self::Pcm(_, layout) => layout.mask(),
+            Self::Flac(layout) => layout.mask(),
+        }
+    }
+
+    /// Payload bytes carried by `samples` samples.
+    pub fn capacity(self, samples: u64) -> u64 {
+        match self {
+            Self::Pcm(_, layout) => layout.capacity(samples),
+            Self::Flac(layout) => layout.capacity(samples),
+        }
+    }
+
+    /// Samples needed to carry `bytes` payload bytes.
+    pub fn samples_for(self, bytes: u64) -> u64 {
+        match self {
+            Self::Pcm(_, layout) => layout.samples_for(bytes),
+            Self::Flac(layout) => layout.samples_for(bytes),
+        }
+    }
+
+    /// Output samples are floats.
+    pub fn float(self) -> bool {
+        matches!(self, Self::Pcm(_, WavLayout::Float32))
+    }
+
+    pub fn describe(self) -> String {
+        match self {
+            Self::Pcm(format, layout) => format!("{} {}", format.name(), layout.describe()),
+            Self::Flac(layout) => layout.describe().to_string(),
+        }
+    }
+}
+
+/// How an audio container is read and written when embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioTarget {
+    pub source: AudioFormat,
+    pub layout: AudioLayout,
+}
+
+impl AudioTarget {
+    /// Target for a `source` container with `spec`, written as chosen by `output`.
+    pub fn resolve(source: AudioFormat, spec: &WavSpec, output: AudioOutput) -> Result<Self> {
+        let int16 = spec.sample_format == SampleFormat::Int && spec.bits_per_sample == 16;
+        let layout = match output.format(source) {
+            AudioFormat::Pcm(format) => AudioLayout::Pcm(format, match output {
+                AudioOutput::Wav32 => WavLayout::Expand16,
+                AudioOutput::SameAsInput if int16 => WavLayout::Int16,
+                _ => WavLayout::for_container(spec),
+            }),
+            AudioFormat::Flac => {
+                if spec.sample_format == SampleFormat::Float {
+                    return Err(anyhow!("FLAC output needs integer samples (the container is floating point)."));
+                }
+                AudioLayout::Flac(match output {
+                    AudioOutput::SameAsInput if int16 => FlacLayout::Int16,
+                    _ => FlacLayout::for_container(spec.bits_per_sample as u32)?,
+                })
+            }
+        };
+        Ok(Self { source, layout })
+    }
+
+    /// Output sample (before embedding) for a raw source sample in `spec`.
+    pub fn base(&self, raw: u32, spec: &WavSpec) -> Result<u32> {
+        Ok(match self.layout {
+            AudioLayout::Pcm(_, WavLayout::Expand16) => ((pcm::to_i16(raw, spec)? as i32) << 16) as u32,
+            AudioLayout::Pcm(..) => raw,
+            AudioLayout::Flac(layout) => ((raw as i32) << (layout.output_bits() - spec.bits_per_sample)) as u32,
+        })
+    }
+}
+
+/// Opens a PCM or FLAC container as interleaved raw samples (FLAC samples as i32 bits).
+pub fn open(path: &Path, format: AudioFormat) -> Result<PcmSource> {
+    match format {
+        AudioFormat::Pcm(format) => pcm::open(path, format),
+        AudioFormat::Flac => {
+            let reader = claxon::FlacReader::open(path)?;
+            let spec = flac::spec_of(&reader);
+            let frames = reader.streaminfo().samples.ok_or_else(|| anyhow!("FLAC container does not state its length."))?;
+            let iter = Box::new(FlacSamples::new(reader).map(|s| s.map(|v| v as u32)));
+            Ok(PcmSource { spec, samples: frames * spec.channels as u64, iter, aifc: false })
+        }
+    }
+}
+
+/// Spec and interleaved sample count without reading the samples.
+pub fn probe(path: &Path, format: AudioFormat) -> Result<(WavSpec, u64)> {
+    let source = open(path, format)?;
+    Ok((source.spec, source.samples))
+}
+
+/// Streaming writer for the output of an `AudioTarget`.
+pub enum AudioWriter {
+    Pcm(PcmWriter),
+    Flac(FlacWriter),
+}
+
+impl AudioWriter {
+    /// Writer for `layout`, embedding into a source with `spec`. FLAC output records the layout.
+    pub fn create(path: &Path, layout: AudioLayout, spec: WavSpec, aifc: bool) -> Result<Self> {
+        Ok(match layout {
+            AudioLayout::Pcm(format, layout) => Self::Pcm(PcmWriter::create(path, format, layout.output_spec(spec), aifc)?),
+            AudioLayout::Flac(layout) => {
+                let spec = WavSpec { bits_per_sample: layout.output_bits(), ..spec };
+                Self::Flac(FlacWriter::create(path, spec, &[(flac::LAYOUT_TAG, layout.tag())])?)
+            }
+        })
+    }
+
+    /// Writes one sample given as raw bits (i32 value or f32 bits).
+    pub fn write_raw(&mut self, bits: u32) -> Result<()> {
+        match self {
+            Self::Pcm(writer) => writer.write_raw(bits),
+            Self::Flac(writer) => writer.write_sample(bits as i32),
+        }
+    }
+
+    pub fn finalize(self) -> Result<()> {
+        match self {
+            Self::Pcm(writer) => writer.finalize(),
+            Self::Flac(writer) => writer.finalize(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_loader::PluginManager;
+    use crate::stream_decoder::decode_stream;
+    use crate::stream_encoder::{encode_stream, EncodeOptions};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use tempfile::tempdir;
+
+    const WAV: AudioFormat = AudioFormat::Pcm(PcmFormat::Wav);
+
+    fn int_spec(bits: u16) -> WavSpec {
+        WavSpec { channels: 2, sample_rate: 8000, bits_per_sample: bits, sample_format: SampleFormat::Int }
+    }
+
+    fn float_spec() -> WavSpec {
+        WavSpec { bits_per_sample: 32, sample_format: SampleFormat::Float, ..int_spec(32) }
+    }
+
+    /// Writes 4000 stereo frames of scattered 16-bit values, scaled to the depth of `spec`,
+    /// and returns the integer samples.
+    fn write_wav(path: &Path, spec: WavSpec) -> Result<Vec<i32>> {
+        let audio: Vec<i32> = (0..8000i64).map(|i| (i * 2_654_435 % 65_536) as i32 - 32_768).collect();
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        let mut samples = Vec::with_capacity(audio.len());
+        for &s in &audio {
+            match spec.sample_format {
+                SampleFormat::Float => writer.write_sample(s as f32 / 32_768.0)?,
+                SampleFormat::Int => {
+                    samples.push(s << (spec.bits_per_sample - 16));
+                    writer.write_sample(s << (spec.bits_per_sample - 16))?;
+                }
+            }
+        }
+        writer.finalize()?;
+        Ok(samples)
+    }
+
+    /// Embeds a payload into `cover` as chosen by `output`, checks it decodes back and returns
+    /// the file actually written.
+    fn roundtrip(cover: &Path, output: AudioOutput) -> Result<PathBuf> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = cover.parent().unwrap();
+        let payload: Vec<u8> = (0..900u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8).collect();
+        let options = EncodeOptions { output, ..Default::default() };
+        let requested = dir.join("output.wav");
+        encode_stream(&mut &payload[..], &cover.to_path_buf(), None, &requested, false, Some("bin"), 64, &plugins, "wav".into(), &options, |_| {})?;
+        let written = requested.with_extension(output.format(WAV).extension());
+        let restored = dir.join("payload.bin");
+        let ext = written.extension().unwrap().to_string_lossy().into_owned();
+        decode_stream(&written, &restored, None, None, 64, &plugins, ext, &Default::default(), |_| {})?;
+        assert_eq!(fs::read(&restored)?, payload, "{:?}", output);
+        Ok(written)
+    }
+
+    fn read_flac(path: &Path) -> Result<(u32, Option<String>, Vec<i32>)> {
+        let mut reader = claxon::FlacReader::open(path)?;
+        let tag = reader.get_tag(flac::LAYOUT_TAG).next().map(str::to_string);
+        let bits = reader.streaminfo().bits_per_sample;
+        let samples = reader.samples().collect::<std::result::Result<_, _>>()?;
+        Ok((bits, tag, samples))
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let pcm = |layout| AudioLayout::Pcm(PcmFormat::Wav, layout);
+        let cases = [
+            (16, AudioOutput::Auto, pcm(WavLayout::Expand16)),
+            (16, AudioOutput::SameAsInput, pcm(WavLayout::Int16)),
+            (16, AudioOutput::Wav32, pcm(WavLayout::Expand16)),
+            (16, AudioOutput::Flac, AudioLayout::Flac(FlacLayout::Expand8)),
+            (24, AudioOutput::Auto, pcm(WavLayout::Int24)),
+            (24, AudioOutput::SameAsInput, pcm(WavLayout::Int24)),
+            (24, AudioOutput::Wav32, pcm(WavLayout::Expand16)),
+            (24, AudioOutput::Flac, AudioLayout::Flac(FlacLayout::Int24)),
+            (32, AudioOutput::SameAsInput, pcm(WavLayout::Int32)),
+        ];
+        for (bits, output, layout) in cases {
+            let target = AudioTarget::resolve(WAV, &int_spec(bits), output)?;
+            assert_eq!(target, AudioTarget { source: WAV, layout }, "{} bits, {:?}", bits, output);
+        }
+        // FLAC containers keep 16 bits only when asked to, and can be written as WAV
+        let flac = |bits, output| AudioTarget::resolve(AudioFormat::Flac, &int_spec(bits), output).map(|t| t.layout);
+        assert_eq!(flac(16, AudioOutput::Auto)?, AudioLayout::Flac(FlacLayout::Expand8));
+        assert_eq!(flac(16, AudioOutput::SameAsInput)?, AudioLayout::Flac(FlacLayout::Int16));
+        assert_eq!(flac(16, AudioOutput::Wav32)?, pcm(WavLayout::Expand16));
+
+        assert_eq!(AudioTarget::resolve(WAV, &float_spec(), AudioOutput::SameAsInput)?.layout, pcm(WavLayout::Float32));
+        let err = AudioTarget::resolve(WAV, &float_spec(), AudioOutput::Flac).unwrap_err();
+        assert_eq!(err.to_string(), "FLAC output needs integer samples (the container is floating point).");
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_as_input_keeps_16_bits() -> Result<()> {
+        let dir = tempdir()?;
+        let cover = dir.path().join("cover.wav");
+        let original = write_wav(&cover, int_spec(16))?;
+        let written = roundtrip(&cover, AudioOutput::SameAsInput)?;
+        let mut reader = hound::WavReader::open(&written)?;
+        assert_eq!(reader.spec(), int_spec(16));
+        let samples: Vec<i32> = reader.samples().collect::<std::result::Result<_, _>>()?;
+        assert!(samples.iter().zip(&original).all(|(s, o)| s & !0x03 == o & !0x03));
+        Ok(())
+    }
+
+    #[test]
+    fn test_wav32_from_24_bits() -> Result<()> {
+        let dir = tempdir()?;
+        let cover = dir.path().join("cover.wav");
+        let original = write_wav(&cover, int_spec(24))?;
+        let written = roundtrip(&cover, AudioOutput::Wav32)?;
+        let mut reader = hound::WavReader::open(&written)?;
+        assert_eq!(reader.spec(), int_spec(32));
+        // The audio, reduced to 16 bits, sits in the high half
+        let samples: Vec<i32> = reader.samples().collect::<std::result::Result<_, _>>()?;
+        assert!(samples.iter().zip(&original).all(|(s, o)| s >> 16 == o >> 8));
+        Ok(())
+    }
+
+    #[test]
+    fn test_flac_from_wav() -> Result<()> {
+        for (bits, layout, shift) in [(16, FlacLayout::Expand8, 8), (24, FlacLayout::Int24, 0)] {
+            let dir = tempdir()?;
+            let cover = dir.path().join("cover.wav");
+            let original = write_wav(&cover, int_spec(bits))?;
+            let written = roundtrip(&cover, AudioOutput::Flac)?;
+            assert!(!dir.path().join("output.wav").exists());
+            let (flac_bits, tag, samples) = read_flac(&written)?;
+            assert_eq!((flac_bits, tag.as_deref()), (24, Some(layout.tag())));
+            let audio = !(layout.mask() as i32);
+            assert!(samples.iter().zip(&original).all(|(s, o)| s & audio == (o << shift) & audio));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_flac_from_float_fails() -> Result<()> {
+        let dir = tempdir()?;
+        let cover = dir.path().join("cover.wav");
+        write_wav(&cover, float_spec())?;
+        let err = roundtrip(&cover, AudioOutput::Flac).unwrap_err();
+        assert_eq!(err.to_string(), "FLAC output needs integer samples (the container is floating point).");
+        assert!(!dir.path().join("output.flac").exists());
+        // The float container itself is still usable
+        let written = roundtrip(&cover, AudioOutput::Auto)?;
+        assert_eq!(hound::WavReader::open(&written)?.spec(), float_spec());
+        Ok(())
+    }
+}
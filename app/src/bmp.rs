@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bytes per row including the padding to a multiple of 4.
+fn stride(width: u32, bits: u16) -> u64 {
+    (width as u64 * bits as u64).div_ceil(32) * 4
+}
+
+/// Row reader for uncompressed 24-bit and 32-bit BMPs (top-down or bottom-up).
+pub struct BmpRows {
+    reader: BufReader<File>,
+    pub width: u32,
+    pub height: u32,
+    bytes_per_pixel: usize,
+    data_start: u64,
+    stride: u64,
+    bottom_up: bool,
+    row: u32,
+}
+
+impl BmpRows {
+    /// Returns `None` for BMP variants this reader does not stream (palettes, RLE, 16-bit, odd masks).
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut head = [0u8; 66];
+        let read = reader.read(&mut head)?;
+        if read < 54 || &head[0..2] != b"BM" {
+            return Err(anyhow!("Not a BMP file."));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(head[i..i + 4].try_into().unwrap());
+        let data_start = u32_at(10) as u64;
+        let dib_size = u32_at(14);
+        if dib_size < 40 {
+            return Ok(None); // OS/2 core header
+        }
+        let width = u32_at(18) as i32;
+        let height = u32_at(22) as i32;
+        let bits = u16::from_le_bytes([head[28], head[29]]);
+        let compression = u32_at(30);
+
+        let supported = match (bits, compression) {
+            (24, 0) | (32, 0) => true,
+            // BI_BITFIELDS with the usual BGRA masks (after the 40-byte header or inside V4/V5)
+            (32, 3) => read >= 66 && (u32_at(54), u32_at(58), u32_at(62)) == (0x00FF_0000, 0x0000_FF00, 0x0000_00FF),
+            _ => false,
+        };
+        if !supported || width <= 0 || height == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            reader,
+            width: width as u32,
+            height: height.unsigned_abs(),
+            bytes_per_pixel: bits as usize / 8,
+            data_start,
+            stride: stride(width as u32, bits),
+            bottom_up: height > 0,
+            row: 0,
+        }))
+    }
+}
+
+impl Iterator for BmpRows {
+    /// One row of 8-bit RGB values.
+    type Item = Result<Vec<u16>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u16>>> {
+        if self.row == self.height {
+            return None;
+        }
+        let stored = if self.bottom_up { self.height - 1 - self.row } else { self.row };
+        self.row += 1;
+        let mut line = vec![0u8; self.stride as usize];
+        let read = self.reader
+            .seek(SeekFrom::Start(self.data_start + stored as u64 * self.stride))
+            .and_then(|_| self.reader.read_exact(&mut line));
+        if let Err(e) = read {
+            self.row = self.height;
+            return Some(Err(e.into()));
+        }
+        let rgb = line
+            .chunks_exact(self.bytes_per_pixel)
+            .take(self.width as usize)
+            .flat_map(|px| [px[2] as u16, px[1] as u16, px[0] as u16])
+            .collect();
+        Some(Ok(rgb))
+    }
+}
+
+/// Streaming 24-bit BMP writer. Rows are stored top-down (negative height), so they can be
+/// written in order.
+pub struct BmpWriter {
+    out: BufWriter<File>,
+    width: u32,
+    padding: usize,
+}
+
+impl BmpWriter {
+    pub fn create(path: &Path, width: u32, height: u32) -> Result<Self> {
+        let stride = stride(width, 24);
+        let image_size = stride * height as u64;
+        if 54 + image_size > u32::MAX as u64 || width > i32::MAX as u32 || height > i32::MAX as u32 {
+            return Err(anyhow!("Image too large for BMP."));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"BM")?;
+        out.write_all(&(54 + image_size as u32).to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?; // reserved
+        out.write_all(&54u32.to_le_bytes())?; // pixel data offset
+        out.write_all(&40u32.to_le_bytes())?; // BITMAPINFOHEADER
+        out.write_all(&(width as i32).to_le_bytes())?;
+        out.write_all(&(-(height as i32)).to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // planes
+        out.write_all(&24u16.to_le_bytes())?;
+        out.write_all(&0u32.to_le_bytes())?; // BI_RGB
+        out.write_all(&(image_size as u32).to_le_bytes())?;
+        out.write_all(&2835i32.to_le_bytes())?; // 72 DPI
+        out.write_all(&2835i32.to_le_bytes())?;
+        out.write_all(&[0u8; 8])?; // palette sizes
+        Ok(Self { out, width, padding: (stride - width as u64 * 3) as usize })
+    }
+
+    /// Writes one row of 8-bit RGB values.
+    pub fn write_row(&mut self, rgb: &[u16]) -> Result<()> {
+        let mut line = Vec::with_capacity(self.width as usize * 3 + self.padding);
+        for px in rgb.chunks_exact(3) {
+            line.extend_from_slice(&[px[2] as u8, px[1] as u8, px[0] as u8]);
+        }
+        line.resize(line.len() + self.padding, 0);
+        self.out.write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::{Result, anyhow};
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::header::{self, ChannelMode};
+use crate::plugin_loader::PluginManager;
+use crate::plugin_manifest::Capability;
+use crate::utils::WavLayout;
+use crate::pcm::{self, PcmFormat};
+use crate::audio::{self, AudioFormat, AudioOutput, AudioTarget};
+use crate::raster::{self, ImageLayout, RasterFormat};
+use crate::flac::FlacLayout;
+use crate::converter::{self, LosslessFormat};
+use crate::carrier::CarrierFormat;
+use crate::{apng, channels, gif_palette};
+
+/// Bytes sampled from the payload head when estimating the compression ratio.
+const SAMPLE_SIZE: usize = 1024 * 1024;
+
+/// Capacity of a container for the current embedding mode.
+///
+/// Encryption is a XOR stream, so it adds no overhead and is not modelled here.
+#[derive(Debug, Clone)]
+pub struct Capacity {
+    /// Total bytes (header + compressed payload) the container can carry.
+    pub raw_bytes: u64,
+    /// Bytes taken by the SPNG header.
+    pub header_bytes: u64,
+    /// Short description of the embedding mode, e.g. "PNG 16-bit RGB LSB".
+    pub mode: String,
+}
+
+impl Capacity {
+    fn new(raw_bytes: u64, mode: &str) -> Self {
+        Self {
+            raw_bytes,
+            header_bytes: header::HEADER_SIZE_BYTES as u64,
+            mode: mode.to_string(),
+        }
+    }
+
+    /// Maximum size of the compressed payload.
+    pub fn usable_bytes(&self) -> u64 {
+        self.raw_bytes.saturating_sub(self.header_bytes)
+    }
+
+    /// Fraction of the usable space a compressed payload of `payload_len` bytes would fill.
+    pub fn fill_ratio(&self, payload_len: u64) -> f32 {
+        let usable = self.usable_bytes();
+        if usable == 0 {
+            return if payload_len == 0 { 0.0 } else { f32::INFINITY };
+        }
+        payload_len as f32 / usable as f32
+    }
+
+    pub fn fits(&self, payload_len: u64) -> bool {
+        payload_len <= self.usable_bytes()
+    }
+}
+
+/// Computes the capacity of `container_path` for the given container kind.
+/// Returns `None` when the capacity cannot be known in advance (e.g. a plugin that does not report it).
+pub fn container_capacity(
+    container_path: &Path,
+    container_ext_hint: &str,
+    plugins: &Arc<Mutex<PluginManager>>,
+) -> Result<Option<Capacity>> {
+    {
+        let pm = plugins.lock().unwrap();
+        if let Some(encoder) = pm.get_encoder_by_ext(container_ext_hint) {
+            if !pm.has_capability(container_ext_hint, Capability::Capacity) {
+                return Ok(None);
+            }
+            let raw = encoder.capacity(container_path)?;
+            if raw == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(Capacity::new(raw, &encoder.metadata().name)));
+        }
+    }
+
+    if let Some(format) = CarrierFormat::from_hint(container_ext_hint) {
+        let raw = format.capacity(std::fs::metadata(container_path)?.len());
+        return Ok(Some(Capacity::new(raw, format.name())));
+    }
+
+    match container_ext_hint {
+        "png" | "apng" => {
+            let reader = png::Decoder::new(File::open(container_path)?).read_info()?;
+            let layout = if reader.info().bit_depth == png::BitDepth::Sixteen { ImageLayout::Lsb8 } else { ImageLayout::Expand8 };
+            let format = if reader.info().animation_control.is_some() { "APNG" } else { "PNG" };
+            Ok(Some(Capacity::new(get_png_capacity(container_path)?, &format!("{} {}", format, layout.describe()))))
+        }
+        "gif" => Ok(Some(Capacity::new(gif_palette::usable_pixels(container_path)? / 8, "GIF palette index LSB"))),
+        ext if RasterFormat::from_ext(ext).is_some() => {
+            let format = RasterFormat::from_ext(ext).unwrap();
+            let (width, height, depth) = raster::probe(container_path)?;
+            let layout = ImageLayout::for_container(format, depth);
+            let mode = format!("{} {}", format.name(), layout.describe());
+            Ok(Some(Capacity::new(layout.capacity(width as u64 * height as u64), &mode)))
+        }
+        "jpg" | "jpeg" => {
+            // Exact count: one bit per AC coefficient with |v| >= 2, found by an entropy-decoding pass
+            let usable = crate::jpeg::usable_coefficients(container_path)?;
+            Ok(Some(Capacity::new(usable / 8, "JPEG DCT coefficient LSB")))
+        }
+        ext if AudioFormat::from_ext(ext).is_some() => {
+            let format = AudioFormat::from_ext(ext).unwrap();
+            Ok(Some(audio_capacity(container_path, format, AudioOutput::Auto, ChannelMode::All)?))
+        }
+        "seq_dir" => Err(anyhow!("Sequence Plugin not loaded or enabled.")),
+        _ => Ok(None),
+    }
+}
+
+/// PNG capacity when the alpha channel carries payload too (see `stream_encoder::AlphaUse`):
+/// 4 bytes per pixel, except for the pixels holding the header.
+pub fn png_alpha_capacity(path: &Path) -> Result<Capacity> {
+    let reader = png::Decoder::new(File::open(path)?).read_info()?;
+    let info = reader.info();
+    let (pixels, _) = apng::pixels(path)?;
+    let layout = if info.bit_depth == png::BitDepth::Sixteen { ImageLayout::Lsb8 } else { ImageLayout::Expand8 };
+    let raw = pixels * 3 + pixels.saturating_sub(header::PNG_ALPHA_START);
+    Ok(Capacity::new(raw, &format!("PNG {} + alpha", layout.describe())))
+}
+
+/// Capacity of a PCM or FLAC container written as chosen by `output`, with the channels
+/// of `mode` carrying payload.
+pub fn audio_capacity(container_path: &Path, format: AudioFormat, output: AudioOutput, mode: ChannelMode) -> Result<Capacity> {
+    let (spec, samples) = audio::probe(container_path, format)?;
+    let layout = AudioTarget::resolve(format, &spec, output)?.layout;
+    channels::check(mode, spec.channels, layout.float())?;
+    let raw = layout.capacity(mode.carriers(samples, spec.channels));
+    let description = match mode {
+        ChannelMode::All => layout.describe(),
+        _ => format!("{}, {}", layout.describe(), mode.describe()),
+    };
+    Ok(Capacity::new(raw, &description))
+}
+
+/// True if any pixel of the PNG is not fully opaque (alpha channel or tRNS chunk).
+pub fn png_has_transparency(path: &Path) -> Result<bool> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    let (color, depth) = reader.output_color_type();
+    let channels = match color {
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgba => 4,
+        _ => return Ok(false),
+    };
+    let sample = if depth == png::BitDepth::Sixteen { 2 } else { 1 };
+    while let Some(row) = reader.next_row()? {
+        let opaque = row.data().chunks_exact(channels * sample).all(|px| px[(channels - 1) * sample..].iter().all(|&b| b == 0xFF));
+        if !opaque {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Capacity of a lossy audio container once converted to `target` (16-bit lossless) and
+/// written as chosen by `output`, with the channels of `channel_mode` carrying payload.
+pub fn lossy_capacity(container_path: &Path, target: LosslessFormat, output: AudioOutput, channel_mode: ChannelMode) -> Result<Capacity> {
+    let info = converter::probe_lossy_audio(container_path)?;
+    let (spec, samples) = (info.pcm_spec(), info.samples());
+    let format = AudioFormat::from_ext(target.extension()).unwrap();
+    let layout = AudioTarget::resolve(format, &spec, output)?.layout;
+    channels::check(channel_mode, spec.channels, false)?;
+    let raw = layout.capacity(channel_mode.carriers(samples, spec.channels));
+    let mut mode = format!("converted to {}", layout.describe());
+    if channel_mode != ChannelMode::All {
+        mode = format!("{}, {}", mode, channel_mode.describe());
+    }
+    Ok(Capacity::new(raw, &mode))
+}
+
+/// Estimates the Deflate-compressed size of a payload file by compressing its head
+/// and extrapolating the ratio. Small files are compressed completely.
+pub fn estimate_compressed_len(payload_path: &Path) -> Result<u64> {
+    let total = std::fs::metadata(payload_path)?.len();
+    let mut sample = Vec::with_capacity(SAMPLE_SIZE.min(total as usize));
+    File::open(payload_path)?.take(SAMPLE_SIZE as u64).read_to_end(&mut sample)?;
+    if sample.is_empty() {
+        return Ok(0);
+    }
+
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&sample)?;
+    let compressed = encoder.finish()?.len() as u64;
+
+    if sample.len() as u64 == total {
+        return Ok(compressed);
+    }
+    let ratio = compressed as f64 / sample.len() as f64;
+    Ok((total as f64 * ratio).ceil() as u64)
+}
+
+pub fn get_png_capacity(path: &Path) -> Result<u64> {
+    // 3 bytes per pixel (R, G, B LSBs) of every APNG frame; gray and palette images are expanded to RGB
+    let (pixels, _) = apng::pixels(path)?;
+    Ok(pixels * 3)
+}
+
+pub fn get_wav_capacity(path: &Path) -> Result<u64> {
+    let (spec, samples) = pcm::probe(path, PcmFormat::Wav)?;
+    // 2 bytes per sample for 16-bit sources, less for natively embedded 24-bit/float
+    Ok(WavLayout::for_container(&spec).capacity(samples))
+}
+
+pub fn get_flac_capacity(path: &Path) -> Result<u64> {
+    let reader = claxon::FlacReader::open(path)?;
+    let info = reader.streaminfo();
+    let samples = info.samples.ok_or_else(|| anyhow!("FLAC container does not state its length."))? * info.channels as u64;
+    Ok(FlacLayout::for_container(info.bits_per_sample)?.capacity(samples))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::flac::FlacWriter;
+    use crate::stream_decoder::decode_stream;
+    use crate::stream_encoder::encode_stream;
+    use rand::{RngCore, SeedableRng};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn deflated_len(data: &[u8]) -> u64 {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap().len() as u64
+    }
+
+    /// Random bytes whose Deflate stream (as `encode_stream` writes it) is exactly `target` bytes.
+    fn payload_compressing_to(target: u64) -> Vec<u8> {
+        let mut data = vec![0u8; target as usize];
+        rand_chacha::ChaCha8Rng::seed_from_u64(target).fill_bytes(&mut data);
+        loop {
+            let len = deflated_len(&data);
+            if len == target {
+                return data;
+            }
+            assert!(len > target && (len - target) < data.len() as u64, "no payload deflates to {} bytes", target);
+            data.truncate(data.len() - (len - target) as usize);
+        }
+    }
+
+    #[test]
+    fn test_estimate_compressed_len() -> Result<()> {
+        let dir = tempdir()?;
+        let empty = dir.path().join("empty.bin");
+        fs::write(&empty, b"")?;
+        assert_eq!(estimate_compressed_len(&empty)?, 0);
+
+        // Files up to the sample size are compressed completely
+        let small = dir.path().join("small.bin");
+        let data: Vec<u8> = (0..50_000u32).map(|i| (i % 251) as u8 ^ (i / 1000) as u8).collect();
+        fs::write(&small, &data)?;
+        assert_eq!(estimate_compressed_len(&small)?, deflated_len(&data));
+
+        // Larger files are extrapolated from their head
+        let large = dir.path().join("large.bin");
+        let data: Vec<u8> = (0..3 * SAMPLE_SIZE as u32).map(|i| (i % 97) as u8).collect();
+        fs::write(&large, &data)?;
+        let estimate = estimate_compressed_len(&large)? as f64;
+        let actual = deflated_len(&data) as f64;
+        assert!((estimate - actual).abs() / actual < 0.05, "estimate {} actual {}", estimate, actual);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill_ratio_and_fits() {
+        let capacity = Capacity::new(1000 + header::HEADER_SIZE_BYTES as u64, "test");
+        assert_eq!(capacity.usable_bytes(), 1000);
+        assert_eq!(capacity.fill_ratio(250), 0.25);
+        assert_eq!(capacity.fill_ratio(1500), 1.5);
+        assert!(capacity.fits(1000));
+        assert!(!capacity.fits(1001));
+
+        // Smaller than the header: nothing fits
+        let tiny = Capacity::new(10, "test");
+        assert_eq!(tiny.usable_bytes(), 0);
+        assert_eq!(tiny.fill_ratio(0), 0.0);
+        assert_eq!(tiny.fill_ratio(1), f32::INFINITY);
+        assert!(tiny.fits(0));
+        assert!(!tiny.fits(1));
+    }
+
+    #[test]
+    fn test_container_capacity_is_exact() -> Result<()> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = tempdir()?;
+        let png = dir.path().join("c.png");
+        image::RgbImage::from_fn(40, 30, |x, y| image::Rgb([(x * 6) as u8, (y * 8) as u8, 77])).save(&png)?;
+        let wav = dir.path().join("c.wav");
+        let spec = hound::WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&wav, spec)?;
+        for i in 0..1500i32 {
+            writer.write_sample(((i * 97) % 4000 - 2000) as i16)?;
+        }
+        writer.finalize()?;
+        let flac = dir.path().join("c.flac");
+        let mut writer = FlacWriter::create(&flac, hound::WavSpec { channels: 2, ..spec }, &[])?;
+        for i in 0..4000i32 {
+            writer.write_sample((i * 31) % 3000 - 1500)?;
+        }
+        writer.finalize()?;
+        let jpg = dir.path().join("c.jpg");
+        crate::jpeg::tests::write_jpeg(&jpg, 96, 80, &[(2, 2), (1, 1), (1, 1)], 4)?;
+        let jpg_raw = crate::jpeg::usable_coefficients(&jpg)? / 8;
+
+        for (container, ext, raw) in [(&png, "png", 40 * 30 * 3), (&wav, "wav", 1500 * 2), (&flac, "flac", 4000), (&jpg, "jpg", jpg_raw)] {
+            let capacity = container_capacity(container, ext, &plugins)?.unwrap();
+            assert_eq!(capacity.raw_bytes, raw, "{}", ext);
+
+            // A payload filling the usable space exactly embeds and comes back
+            let payload = payload_compressing_to(capacity.usable_bytes());
+            let output = dir.path().join(format!("out.{}", ext));
+            let restored = dir.path().join(format!("{}.bin", ext));
+            encode_stream(&mut &payload[..], container, None, &output, false, None, 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            decode_stream(&output, &restored, None, None, 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored)?, payload, "{}", ext);
+
+            // One more compressed byte is refused up front
+            let payload = payload_compressing_to(capacity.usable_bytes() + 1);
+            let err = encode_stream(&mut &payload[..], container, None, &output, false, None, 64, &plugins, ext.into(), &Default::default(), |_| {}).unwrap_err();
+            assert!(err.to_string().starts_with("Container too small!"), "{}: {}", ext, err);
+        }
+        Ok(())
+    }
+    /// Loads the WebAssembly copy plugin (see `wasm_plugin::tests`) from `dir`, enabled, with a
+    /// manifest declaring `capabilities`.
+    pub(crate) fn copy_plugin(dir: &Path, capabilities: &str) -> Result<Arc<Mutex<PluginManager>>> {
+        fs::create_dir_all(dir)?;
+        let path = crate::wasm_plugin::tests::write_plugin(dir)?;
+        let manifest = format!(
+            "id = \"copy\"\nversion = \"1.0.0\"\napi_version = {}\ncontainers = [\"bin\"]\ncapabilities = [{}]\n",
+            crate::wasm_plugin::ABI_VERSION, capabilities
+        );
+        fs::write(path.with_extension("toml"), manifest)?;
+        let mut pm = PluginManager::new();
+        pm.load_plugins(dir);
+        assert!(pm.get_rejected_plugins().is_empty(), "{:?}", pm.get_rejected_plugins());
+        pm.set_plugin_enabled("Copy", true);
+        Ok(Arc::new(Mutex::new(pm)))
+    }
+
+    #[test]
+    fn test_plugin_capacity() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("cover.bin");
+        fs::write(&container, vec![7u8; 500])?;
+        let plugins = copy_plugin(&dir.path().join("with"), r#""encode", "decode", "capacity""#)?;
+
+        let capacity = container_capacity(&container, "bin", &plugins)?.unwrap();
+        assert_eq!((capacity.raw_bytes, capacity.mode.as_str()), (500, "Copy"));
+        assert!(container_capacity(&dir.path().join("missing.bin"), "bin", &plugins).is_err());
+
+        // The plugin's capacity drives the pre-check like a built-in one
+        let payload = payload_compressing_to(capacity.usable_bytes());
+        let output = dir.path().join("out.bin");
+        let restored = dir.path().join("restored");
+        encode_stream(&mut &payload[..], &container, None, &output, false, None, 64, &plugins, "bin".into(), &Default::default(), |_| {})?;
+        decode_stream(&output, &restored, None, None, 64, &plugins, "bin".into(), &Default::default(), |_| {})?;
+        assert_eq!(fs::read(&restored)?, payload);
+        let payload = payload_compressing_to(capacity.usable_bytes() + 1);
+        let err = encode_stream(&mut &payload[..], &container, None, &output, false, None, 64, &plugins, "bin".into(), &Default::default(), |_| {}).unwrap_err();
+        assert!(err.to_string().starts_with("Container too small!"), "{}", err);
+
+        // Without the capability the host does not ask, and the capacity is unknown
+        let plugins = copy_plugin(&dir.path().join("without"), r#""encode", "decode""#)?;
+        assert!(container_capacity(&container, "bin", &plugins)?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,438 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use sound_png_api::ByteStream;
+
+/// Id of the private PNG chunk / RIFF chunk that holds the stream in metadata carrier mode.
+pub const CHUNK_ID: &[u8; 4] = b"spNG";
+/// Stream bytes per `spNG` chunk in PNG files.
+const PNG_CHUNK_LEN: u64 = 1 << 20;
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// Metadata carrier mode: the stream is stored in a chunk of its own, pixels and
+/// samples stay bit-exact. Selected by the container hints "png_chunk" / "wav_chunk".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CarrierFormat {
+    Png,
+    Wav,
+}
+
+impl CarrierFormat {
+    pub fn from_hint(hint: &str) -> Option<Self> {
+        match hint {
+            "png_chunk" => Some(Self::Png),
+            "wav_chunk" => Some(Self::Wav),
+            _ => None,
+        }
+    }
+
+    /// Carrier mode hint for a container extension, if the format has one.
+    pub fn hint_for(ext: &str) -> Option<&'static str> {
+        match ext.to_lowercase().as_str() {
+            "png" => Some("png_chunk"),
+            "wav" => Some("wav_chunk"),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Png => "PNG spNG chunk",
+            Self::Wav => "WAV spNG chunk",
+        }
+    }
+
+    /// Carrier format of an encoded file whose hint is the plain extension ("png" / "wav"),
+    /// or `None` if it holds no carrier chunk. Files that do not parse are left to the
+    /// regular readers, which report the actual error.
+    pub fn detect(path: &Path, hint: &str) -> Option<Self> {
+        let format = match hint {
+            "png" => Self::Png,
+            "wav" => Self::Wav,
+            _ => return None,
+        };
+        let chunks = format.chunks(&mut BufReader::new(File::open(path).ok()?)).ok()?;
+        chunks.iter().any(|c| &c.id == CHUNK_ID).then_some(format)
+    }
+
+    /// Largest stream the carrier can hold next to a container of `container_len` bytes.
+    pub fn capacity(self, container_len: u64) -> u64 {
+        match self {
+            Self::Png => u64::MAX,
+            // RIFF sizes are 32-bit
+            Self::Wav => (u32::MAX as u64).saturating_sub(container_len + 8),
+        }
+    }
+
+    /// Lists the chunks of a PNG or RIFF file.
+    fn chunks<R: Read + Seek>(self, file: &mut R) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        match self {
+            Self::Png => {
+                let mut signature = [0u8; 8];
+                file.read_exact(&mut signature)?;
+                if signature != PNG_SIGNATURE {
+                    return Err(anyhow!("Not a PNG file."));
+                }
+                loop {
+                    let start = file.stream_position()?;
+                    let mut head = [0u8; 8];
+                    file.read_exact(&mut head)?;
+                    let len = u32::from_be_bytes(head[..4].try_into().unwrap()) as u64;
+                    let id: [u8; 4] = head[4..].try_into().unwrap();
+                    chunks.push(Chunk { id, start, data_start: start + 8, data_len: len, end: start + 12 + len });
+                    if &id == b"IEND" {
+                        break;
+                    }
+                    file.seek(SeekFrom::Start(start + 12 + len))?;
+                }
+            }
+            Self::Wav => {
+                let mut head = [0u8; 12];
+                file.read_exact(&mut head)?;
+                if &head[..4] != b"RIFF" || &head[8..] != b"WAVE" {
+                    return Err(anyhow!("Not a RIFF/WAVE file."));
+                }
+                let file_len = file.seek(SeekFrom::End(0))?;
+                let mut start = 12;
+                while start + 8 <= file_len {
+                    file.seek(SeekFrom::Start(start))?;
+                    let mut head = [0u8; 8];
+                    file.read_exact(&mut head)?;
+                    let id: [u8; 4] = head[..4].try_into().unwrap();
+                    let len = u32::from_le_bytes(head[4..].try_into().unwrap()) as u64;
+                    // Chunks are padded to an even size; a truncated last chunk ends at EOF
+                    let end = (start + 8 + len + len % 2).min(file_len);
+                    chunks.push(Chunk { id, start, data_start: start + 8, data_len: len.min(end - start - 8), end });
+                    start = end;
+                }
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+/// Position of one chunk; `start..end` covers header, data, CRC / padding.
+struct Chunk {
+    id: [u8; 4],
+    start: u64,
+    data_start: u64,
+    data_len: u64,
+    end: u64,
+}
+
+/// Copies `container` to `output` without its carrier chunks; `append` writes the new
+/// ones (before IEND for PNG, at the end for WAV, whose RIFF size is then patched).
+fn rewrite(
+    format: CarrierFormat,
+    container: &Path,
+    output: &Path,
+    mut append: impl FnMut(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    let mut input = BufReader::new(File::open(container)?);
+    let chunks = format.chunks(&mut input)?;
+    let mut out = BufWriter::new(File::create(output)?);
+
+    match format {
+        CarrierFormat::Png => out.write_all(&PNG_SIGNATURE)?,
+        CarrierFormat::Wav => out.write_all(b"RIFF\0\0\0\0WAVE")?, // size patched below
+    }
+    for chunk in chunks.iter().filter(|c| &c.id != CHUNK_ID) {
+        if format == CarrierFormat::Png && &chunk.id == b"IEND" {
+            append(&mut out)?;
+        }
+        input.seek(SeekFrom::Start(chunk.start))?;
+        io::copy(&mut (&mut input).take(chunk.end - chunk.start), &mut out)?;
+        if format == CarrierFormat::Wav && chunk.end - chunk.data_start < chunk.data_len + chunk.data_len % 2 {
+            out.write_all(&[0])?; // pad a truncated odd chunk
+        }
+    }
+    if format == CarrierFormat::Wav {
+        append(&mut out)?;
+        let len = out.stream_position()?;
+        if len - 8 > u32::MAX as u64 {
+            return Err(anyhow!("WAV output exceeds the 4 GB RIFF limit."));
+        }
+        out.seek(SeekFrom::Start(4))?;
+        out.write_all(&((len - 8) as u32).to_le_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Writes `container` to `output` with `stream_len` bytes of `byte_stream` in carrier chunks.
+/// Pixels and samples are copied byte for byte.
+pub fn embed(
+    format: CarrierFormat,
+    container: &Path,
+    output: &Path,
+    byte_stream: &mut ByteStream<File>,
+    stream_len: u64,
+    on_progress: impl Fn(f32),
+) -> Result<()> {
+    let mut buf = Vec::new();
+    let mut fill = |len: u64, buf: &mut Vec<u8>| {
+        buf.clear();
+        buf.extend((0..len).map(|_| byte_stream.next_byte()));
+    };
+    rewrite(format, container, output, |out| {
+        match format {
+            CarrierFormat::Png => {
+                let mut done = 0;
+                while done < stream_len {
+                    let len = (stream_len - done).min(PNG_CHUNK_LEN);
+                    fill(len, &mut buf);
+                    let mut crc = crc32fast::Hasher::new();
+                    crc.update(CHUNK_ID);
+                    crc.update(&buf);
+                    out.write_all(&(len as u32).to_be_bytes())?;
+                    out.write_all(CHUNK_ID)?;
+                    out.write_all(&buf)?;
+                    out.write_all(&crc.finalize().to_be_bytes())?;
+                    done += len;
+                    on_progress(done as f32 / stream_len as f32);
+                }
+            }
+            CarrierFormat::Wav => {
+                if stream_len > u32::MAX as u64 {
+                    return Err(anyhow!("WAV output exceeds the 4 GB RIFF limit."));
+                }
+                out.write_all(CHUNK_ID)?;
+                out.write_all(&(stream_len as u32).to_le_bytes())?;
+                let mut done = 0;
+                while done < stream_len {
+                    let len = (stream_len - done).min(PNG_CHUNK_LEN);
+                    fill(len, &mut buf);
+                    out.write_all(&buf)?;
+                    done += len;
+                    on_progress(done as f32 / stream_len as f32);
+                }
+                if stream_len % 2 == 1 {
+                    out.write_all(&[0])?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Writes `input` back without its carrier chunks: the original container, bit-exact
+/// apart from chunk order in WAV files (the carrier is always last).
+pub fn strip(format: CarrierFormat, input: &Path, output: &Path) -> Result<()> {
+    rewrite(format, input, output, |_| Ok(()))
+}
+
+/// Reads the stream back from the carrier chunks, in file order.
+pub struct CarrierReader {
+    file: BufReader<File>,
+    /// (offset, length) of the chunk data still to read.
+    segments: std::collections::VecDeque<(u64, u64)>,
+    /// Bytes left in the current segment.
+    left: u64,
+}
+
+impl CarrierReader {
+    pub fn open(path: &Path, format: CarrierFormat) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let segments: std::collections::VecDeque<(u64, u64)> = format.chunks(&mut file)?
+            .into_iter()
+            .filter(|c| &c.id == CHUNK_ID)
+            .map(|c| (c.data_start, c.data_len))
+            .collect();
+        if segments.is_empty() {
+            return Err(anyhow!("No {} found.", format.name()));
+        }
+        Ok(Self { file, segments, left: 0 })
+    }
+}
+
+impl Read for CarrierReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.left == 0 {
+            match self.segments.pop_front() {
+                Some((offset, len)) => {
+                    self.file.seek(SeekFrom::Start(offset))?;
+                    self.left = len;
+                }
+                None => return Ok(0),
+            }
+        }
+        let want = buf.len().min(self.left as usize);
+        let n = self.file.read(&mut buf[..want])?;
+        if n == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Carrier chunk truncated"));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn stream_bytes(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i.wrapping_mul(2654435761) >> 11) as u8).collect()
+    }
+
+    /// Embeds `stream` as a carrier and checks that it reads back and strips to the original.
+    fn roundtrip(format: CarrierFormat, container: &Path, stream: &[u8]) -> Result<Vec<u8>> {
+        let dir = container.parent().unwrap();
+        let payload = dir.join("stream.bin");
+        let output = dir.join("output.bin");
+        let stripped = dir.join("stripped.bin");
+        fs::write(&payload, stream)?;
+        let mut byte_stream = ByteStream::new(Vec::new(), File::open(&payload)?, 0, None, false, 4096, stream.len() as u64);
+        embed(format, container, &output, &mut byte_stream, stream.len() as u64, |_| {})?;
+
+        let mut read = Vec::new();
+        CarrierReader::open(&output, format)?.read_to_end(&mut read)?;
+        assert_eq!(read, stream);
+        let ext = match format { CarrierFormat::Png => "png", CarrierFormat::Wav => "wav" };
+        assert_eq!(CarrierFormat::detect(&output, ext), Some(format));
+        assert_eq!(CarrierFormat::detect(container, ext), None);
+
+        strip(format, &output, &stripped)?;
+        assert_eq!(fs::read(&stripped)?, fs::read(container)?);
+        Ok(fs::read(&output)?)
+    }
+
+    /// A PNG with ancillary chunks before and after the image data.
+    fn write_png(path: &Path) -> Result<()> {
+        let mut encoder = png::Encoder::new(File::create(path)?, 17, 9);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.add_text_chunk("Comment".into(), "before IDAT".into())?;
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&stream_bytes(17 * 9 * 3))?;
+        writer.write_text_chunk(&png::text_metadata::TEXtChunk::new("After", "IDAT"))?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// A RIFF/WAVE file with the given extra chunks after the data chunk.
+    fn write_wav(path: &Path, extra: &[(&[u8; 4], &[u8])]) -> Result<()> {
+        let spec = hound::WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 8, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        // An odd sample count leaves a padded odd-length data chunk
+        for i in 0..101 {
+            writer.write_sample((i % 200 - 100) as i8)?;
+        }
+        writer.finalize()?;
+        let mut bytes = fs::read(path)?;
+        if bytes.len() % 2 == 1 {
+            bytes.push(0); // hound leaves the data chunk unpadded
+        }
+        for (id, data) in extra {
+            bytes.extend_from_slice(*id);
+            bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(data);
+            if data.len() % 2 == 1 {
+                bytes.push(0);
+            }
+        }
+        let riff = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&riff.to_le_bytes());
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_png_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.png");
+        write_png(&container)?;
+        for len in [1, 2, 1000] {
+            let output = roundtrip(CarrierFormat::Png, &container, &stream_bytes(len))?;
+            // Still a valid PNG with the same pixels
+            let mut reader = png::Decoder::new(&output[..]).read_info()?;
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            reader.next_frame(&mut buf)?;
+            assert_eq!(buf, stream_bytes(17 * 9 * 3));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_png_multi_chunk_stream() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.png");
+        write_png(&container)?;
+        let len = 2 * PNG_CHUNK_LEN as usize + 12345;
+        let output = roundtrip(CarrierFormat::Png, &container, &stream_bytes(len))?;
+
+        // Split over three chunks, all before IEND, with valid CRCs
+        let chunks = CarrierFormat::Png.chunks(&mut io::Cursor::new(&output))?;
+        let carriers: Vec<&Chunk> = chunks.iter().filter(|c| &c.id == CHUNK_ID).collect();
+        assert_eq!(carriers.iter().map(|c| c.data_len).collect::<Vec<_>>(), [PNG_CHUNK_LEN, PNG_CHUNK_LEN, 12345]);
+        assert_eq!(&chunks.last().unwrap().id, b"IEND");
+        for chunk in carriers {
+            let data = &output[chunk.start as usize + 4..chunk.end as usize - 4];
+            let crc = u32::from_be_bytes(output[chunk.end as usize - 4..chunk.end as usize].try_into().unwrap());
+            assert_eq!(crc32fast::hash(data), crc);
+        }
+        let mut reader = png::Decoder::new(&output[..]).read_info()?;
+        let mut buf = vec![0u8; reader.output_buffer_size()];
+        reader.next_frame(&mut buf)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wav_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.wav");
+        // Odd-length chunks in the container and odd-length streams
+        write_wav(&container, &[(b"LIST", b"INFOabc"), (b"junk", b"")])?;
+        for len in [0, 1, 7, 1000] {
+            let output = roundtrip(CarrierFormat::Wav, &container, &stream_bytes(len))?;
+            assert_eq!(output.len() % 2, 0);
+            assert_eq!(u32::from_le_bytes(output[4..8].try_into().unwrap()) as usize, output.len() - 8);
+            // hound still reads the samples
+            let samples: Vec<i8> = hound::WavReader::new(&output[..])?.into_samples().collect::<Result<_, _>>()?;
+            assert_eq!(samples.len(), 101);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_wav_truncated_odd_chunk() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.wav");
+        write_wav(&container, &[(b"note", b"odd")])?;
+        // Drop the pad byte of the last chunk
+        let mut bytes = fs::read(&container)?;
+        bytes.pop();
+        fs::write(&container, &bytes)?;
+
+        let chunks = CarrierFormat::Wav.chunks(&mut io::Cursor::new(&bytes))?;
+        let note = chunks.iter().find(|c| &c.id == b"note").unwrap();
+        assert_eq!((note.data_len, note.end), (3, bytes.len() as u64));
+
+        // The carrier starts on an even offset after the restored pad byte
+        let output = dir.path().join("output.wav");
+        let payload = dir.path().join("stream.bin");
+        fs::write(&payload, b"xyz")?;
+        let mut byte_stream = ByteStream::new(Vec::new(), File::open(&payload)?, 0, None, false, 4096, 3);
+        embed(CarrierFormat::Wav, &container, &output, &mut byte_stream, 3, |_| {})?;
+        let out = fs::read(&output)?;
+        assert_eq!(&out[8..bytes.len()], &bytes[8..]);
+        assert_eq!(out[bytes.len()], 0);
+        assert_eq!(&out[bytes.len() + 1..bytes.len() + 5], CHUNK_ID);
+        let mut read = Vec::new();
+        CarrierReader::open(&output, CarrierFormat::Wav)?.read_to_end(&mut read)?;
+        assert_eq!(read, b"xyz");
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_carrier() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.png");
+        write_png(&container)?;
+        assert_eq!(CarrierReader::open(&container, CarrierFormat::Png).err().unwrap().to_string(), "No PNG spNG chunk found.");
+        assert!(CarrierReader::open(&container, CarrierFormat::Wav).is_err());
+        Ok(())
+    }
+}
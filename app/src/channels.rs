@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use crate::header::ChannelMode;
+
+// Channel selection for audio containers (see `ChannelMode`). Embedding works on whole
+// frames of raw samples; extraction turns the interleaved samples back into the values
+// whose low bits carry the payload, so the layout-specific bit handling stays the same.
+
+/// Checks that a container with `channels` channels can use `mode`.
+pub fn check(mode: ChannelMode, channels: u16, float: bool) -> Result<()> {
+    if mode == ChannelMode::Side && channels < 2 {
+        return Err(anyhow!("Side channel embedding needs a container with at least two channels."));
+    }
+    if mode == ChannelMode::Side && float {
+        return Err(anyhow!("Side channel embedding needs integer samples (the container is floating point)."));
+    }
+    Ok(())
+}
+
+/// Values carrying the payload in interleaved raw samples: every sample, the first channel
+/// of each frame, or the difference of the first two channels.
+pub fn carriers(
+    samples: Box<dyn Iterator<Item = Result<u32>> + Send>,
+    channels: u16,
+    mode: ChannelMode,
+) -> Box<dyn Iterator<Item = Result<u32>> + Send> {
+    if mode == ChannelMode::All {
+        return samples;
+    }
+    let mut samples = samples;
+    Box::new(std::iter::from_fn(move || {
+        let mut frame = [0u32; 2];
+        for channel in 0..channels.max(1) as usize {
+            match samples.next()? {
+                Ok(sample) => {
+                    // Only the first two channels matter; the rest are skipped
+                    if let Some(slot) = frame.get_mut(channel) {
+                        *slot = sample;
+                    }
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        Some(Ok(match mode {
+            ChannelMode::Side => frame[0].wrapping_sub(frame[1]),
+            _ => frame[0],
+        }))
+    }))
+}
+
+/// Embeds into one frame of raw samples (a single sample with `ChannelMode::All`).
+/// `next` yields the payload bits for each carrier value, within `mask`.
+pub fn embed_frame(frame: &mut [u32], mode: ChannelMode, mask: u32, mut next: impl FnMut() -> u32) {
+    match mode {
+        ChannelMode::All => {
+            for sample in frame.iter_mut() {
+                *sample = (*sample & !mask) | next();
+            }
+        }
+        ChannelMode::Left => frame[0] = (frame[0] & !mask) | next(),
+        ChannelMode::Side => {
+            let (left, right) = side_pair(frame[0] & mask, frame[1] & mask, next(), mask);
+            frame[0] = (frame[0] & !mask) | left;
+            frame[1] = (frame[1] & !mask) | right;
+        }
+    }
+}
+
+/// New low bits for a left/right pair whose difference must end in `value`. Both signs
+/// of the difference are tried; the one keeping the sum (the mid signal) closest wins.
+fn side_pair(left: u32, right: u32, value: u32, mask: u32) -> (u32, u32) {
+    let (sum, mask) = (left as i64 + right as i64, mask as i64);
+    [value as i64, value as i64 - (mask + 1)]
+        .into_iter()
+        .filter(|&diff| diff > -(mask + 1))
+        .map(|diff| {
+            let new_left = ((sum + diff).div_euclid(2)).clamp(diff.max(0), mask + diff.min(0));
+            (new_left, new_left - diff)
+        })
+        .min_by_key(|(l, r)| (l + r - sum).abs())
+        .map(|(l, r)| (l as u32, r as u32))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    /// (payload mask, sample bits) of the integer layouts.
+    const LAYOUTS: [(u32, u32); 4] = [(0x03, 16), (0x0F, 24), (0xFF, 32), (0xFFFF, 32)];
+
+    /// Raw samples at the edges of a `bits`-bit range, sign-extended like the readers do.
+    fn edges(bits: u32, mask: u32) -> Vec<u32> {
+        let max = ((1i64 << (bits - 1)) - 1) as i32;
+        let min = (-(1i64 << (bits - 1))) as i32;
+        [0, 1, -1, mask as i32, -(mask as i32) - 1, max, min, max - mask as i32, min + mask as i32]
+            .into_iter()
+            .map(|v| v as u32)
+            .collect()
+    }
+
+    /// Payload values to try: all of them for small masks, a spread for 16 bits.
+    fn values(mask: u32) -> Vec<u32> {
+        if mask <= 0xFF { (0..=mask).collect() } else { (0..=mask).step_by(251).chain([1, mask - 1, mask]).collect() }
+    }
+
+    fn extract(frame: &[u32], mode: ChannelMode) -> Vec<u32> {
+        let samples: Vec<Result<u32>> = frame.iter().map(|&s| Ok(s)).collect();
+        carriers(Box::new(samples.into_iter()), frame.len() as u16, mode).collect::<Result<Vec<_>>>().unwrap()
+    }
+
+    /// Embeds `value` into `frame` with `mode` and checks what the extractor sees.
+    fn check_frame(frame: &[u32], mode: ChannelMode, mask: u32, value: u32) {
+        let mut embedded = frame.to_vec();
+        embed_frame(&mut embedded, mode, mask, || value);
+        let carried = extract(&embedded, mode);
+        let context = format!("{:?} mask {:#x} value {:#x} frame {:x?}", mode, mask, value, frame);
+        match mode {
+            ChannelMode::All => assert!(carried.iter().all(|&c| c & mask == value), "{}", context),
+            _ => assert_eq!(carried, [carried[0]], "{}", context),
+        }
+        assert_eq!(carried[0] & mask, value, "{}", context);
+        for (channel, (&before, &after)) in frame.iter().zip(&embedded).enumerate() {
+            if mode.carries(channel) {
+                // Only the payload bits change, so the sample stays in range
+                assert_eq!(before & !mask, after & !mask, "{}", context);
+            } else {
+                assert_eq!(before, after, "{}", context);
+            }
+        }
+    }
+
+    #[test]
+    fn test_edge_samples() {
+        for (mask, bits) in LAYOUTS {
+            let edges = edges(bits, mask);
+            for value in values(mask) {
+                for &left in &edges {
+                    for &right in &edges {
+                        for mode in [ChannelMode::All, ChannelMode::Left, ChannelMode::Side] {
+                            check_frame(&[left, right], mode, mask, value);
+                        }
+                        check_frame(&[left, right, left ^ 0x55], ChannelMode::Side, mask, value);
+                    }
+                    check_frame(&[left], ChannelMode::All, mask, value);
+                    check_frame(&[left], ChannelMode::Left, mask, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_frames() {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(41);
+        for (mask, bits) in LAYOUTS {
+            let range = -(1i64 << (bits - 1))..(1i64 << (bits - 1));
+            for _ in 0..20_000 {
+                let channels = rng.gen_range(2..=6);
+                let frame: Vec<u32> = (0..channels).map(|_| rng.gen_range(range.clone()) as i32 as u32).collect();
+                let value = rng.gen_range(0..=mask);
+                for mode in [ChannelMode::All, ChannelMode::Left, ChannelMode::Side] {
+                    check_frame(&frame, mode, mask, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_side_keeps_mid() {
+        // No other pair of low bits with the wanted difference keeps the sum closer
+        for mask in [0x03u32, 0x0F] {
+            for left in 0..=mask {
+                for right in 0..=mask {
+                    let sum = left as i64 + right as i64;
+                    for value in 0..=mask {
+                        let (l, r) = side_pair(left, right, value, mask);
+                        assert!(l <= mask && r <= mask);
+                        assert_eq!(l.wrapping_sub(r) & mask, value);
+                        let best = (0..=mask)
+                            .flat_map(|a| (0..=mask).map(move |b| (a, b)))
+                            .filter(|&(a, b)| a.wrapping_sub(b) & mask == value)
+                            .map(|(a, b)| (a as i64 + b as i64 - sum).abs())
+                            .min()
+                            .unwrap();
+                        assert_eq!((l as i64 + r as i64 - sum).abs(), best, "{:#x} {} {} {}", mask, left, right, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check(ChannelMode::Side, 1, false).is_err());
+        assert!(check(ChannelMode::Side, 2, true).is_err());
+        assert!(check(ChannelMode::Side, 2, false).is_ok());
+        assert!(check(ChannelMode::Left, 1, true).is_ok());
+        assert!(check(ChannelMode::All, 1, true).is_ok());
+    }
+}
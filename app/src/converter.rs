@@ -1,8 +1,11 @@
 use anyhow::{Context, Result};
 use std::path::Path;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufReader, Read, Seek};
 use minimp3::{Decoder, Frame, Error};
+use ogg::PacketReader;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use crate::flac::FlacWriter;
 
 use image::{DynamicImage, io::Reader as ImageReader};
 
@@ -25,9 +28,78 @@ pub fn load_image_object(path: &Path) -> Result<DynamicImage> {
     Ok(image)
 }
 
-/// 加载音频数据为 16-bit PCM 样本
-/// 支持 .wav (通过 hound) 和 .mp3 (通过 minimp3)
-pub fn load_audio_as_pcm(path: &Path) -> Result<Vec<i16>> {
+/// 源音频的编码方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceCodec {
+    /// 整数 PCM (WAV)
+    PcmInt,
+    /// 浮点 PCM (WAV)
+    PcmFloat,
+    Mp3,
+    /// Ogg Vorbis
+    Vorbis,
+    /// Ogg Opus（解码为 48 kHz）
+    Opus,
+}
+
+impl SourceCodec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PcmInt => "PCM",
+            Self::PcmFloat => "PCM float",
+            Self::Mp3 => "MP3",
+            Self::Vorbis => "Ogg Vorbis",
+            Self::Opus => "Opus",
+        }
+    }
+}
+
+/// 解码得到的音频描述：采样率、声道数、源位深、长度与源编码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// 源文件的位深（有损格式为解码输出的 16 bit）
+    pub bits_per_sample: u16,
+    /// 每声道采样数
+    pub frames: u64,
+    pub codec: SourceCodec,
+}
+
+impl AudioInfo {
+    fn new(spec: &WavSpec, frames: u64, codec: SourceCodec) -> Self {
+        Self {
+            sample_rate: spec.sample_rate,
+            channels: spec.channels,
+            bits_per_sample: spec.bits_per_sample,
+            frames,
+            codec,
+        }
+    }
+
+    /// 交错采样总数（所有声道）
+    pub fn samples(&self) -> u64 {
+        self.frames * self.channels as u64
+    }
+
+    pub fn duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs_f64(self.frames as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    /// 归一化后 16-bit 整数 PCM 的规格（采样率与声道数不变）
+    pub fn pcm_spec(&self) -> WavSpec {
+        WavSpec {
+            channels: self.channels,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+}
+
+/// 加载音频数据为 16-bit PCM 样本，并返回源音频描述
+/// 支持 .wav (通过 hound)、.mp3 (通过 minimp3) 以及 Ogg Vorbis / Opus (通过 lewton / libopus)
+pub fn load_audio_as_pcm(path: &Path) -> Result<(AudioInfo, Vec<i16>)> {
     let extension = path.extension()
         .and_then(|e| e.to_str())
         .map(|s| s.to_lowercase())
@@ -35,7 +107,7 @@ pub fn load_audio_as_pcm(path: &Path) -> Result<Vec<i16>> {
 
     match extension.as_str() {
         "wav" => load_wav(path),
-        "mp3" => load_mp3(path),
+        ext if is_lossy_audio(ext) => load_lossy(path),
         _ => Err(anyhow::anyhow!("Unsupported audio format: {}", extension)),
     }
 }
@@ -49,28 +121,610 @@ pub fn load_file_as_bytes(path: &Path) -> Result<Vec<u8>> {
     Ok(buffer)
 }
 
-fn load_wav(path: &Path) -> Result<Vec<i16>> {
+fn load_wav(path: &Path) -> Result<(AudioInfo, Vec<i16>)> {
     // Use the robust normalization logic from utils
-    // Note: We discard the spec here as the caller currently infers it or uses default.
-    // Ideally we'd return (Spec, Data) from load_audio_as_pcm too.
     let path_buf = path.to_path_buf();
-    let (_, samples) = crate::utils::read_and_normalize_wav(&path_buf)?;
-    Ok(samples)
+    let (spec, samples) = crate::utils::read_and_normalize_wav(&path_buf)?;
+    let codec = if spec.sample_format == SampleFormat::Float { SourceCodec::PcmFloat } else { SourceCodec::PcmInt };
+    let info = AudioInfo::new(&spec, samples.len() as u64 / spec.channels.max(1) as u64, codec);
+    Ok((info, samples))
 }
 
-fn load_mp3(path: &Path) -> Result<Vec<i16>> {
+fn load_lossy(path: &Path) -> Result<(AudioInfo, Vec<i16>)> {
+    let mut pcm_data = Vec::new();
+    let (spec, codec) = decode_lossy(path, |_, data| {
+        pcm_data.extend_from_slice(data);
+        Ok(())
+    })?;
+    let info = AudioInfo::new(&spec, pcm_data.len() as u64 / spec.channels as u64, codec);
+    Ok((info, pcm_data))
+}
+
+/// 按扩展名逐帧解码有损音频（MP3 或 Ogg 封装的 Vorbis / Opus），返回音频规格与源编码。
+fn decode_lossy(path: &Path, on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>) -> Result<(WavSpec, SourceCodec)> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_lowercase();
+    match extension.as_str() {
+        "mp3" => Ok((decode_mp3(path, on_frame)?, SourceCodec::Mp3)),
+        "ogg" | "oga" | "opus" => decode_ogg(path, on_frame),
+        _ => Err(anyhow::anyhow!("Unsupported audio format: {}", extension)),
+    }
+}
+
+/// 逐帧解码 MP3，`on_frame` 收到 16-bit 规格与该帧的交错采样。返回音频规格。
+/// 中途改变采样率或声道数的文件会报错。
+fn decode_mp3(path: &Path, mut on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>) -> Result<WavSpec> {
     let file = File::open(path).context("Failed to open MP3 file")?;
     let mut decoder = Decoder::new(file);
-    let mut pcm_data = Vec::new();
+    let mut spec: Option<WavSpec> = None;
 
     loop {
         match decoder.next_frame() {
-            Ok(Frame { data, .. }) => {
-                pcm_data.extend(data);
+            Ok(Frame { data, sample_rate, channels, .. }) => {
+                if data.is_empty() {
+                    continue;
+                }
+                let spec = spec.get_or_insert(WavSpec {
+                    channels: channels as u16,
+                    sample_rate: sample_rate as u32,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                });
+                if channels as u16 != spec.channels || sample_rate as u32 != spec.sample_rate {
+                    return Err(anyhow::anyhow!(
+                        "MP3 changes format mid-stream ({} Hz/{} ch -> {} Hz/{} ch), cannot convert.",
+                        spec.sample_rate, spec.channels, sample_rate, channels
+                    ));
+                }
+                on_frame(spec, &data)?;
             },
             Err(Error::Eof) => break,
             Err(e) => return Err(anyhow::anyhow!("Error decoding MP3: {:?}", e)),
         }
     }
-    Ok(pcm_data)
+    spec.ok_or_else(|| anyhow::anyhow!("MP3 contains no audio frames"))
+}
+
+/// Ogg 容器：根据第一个包（标识头）区分 Vorbis 与 Opus，扩展名不可靠（.ogg 里也常见 Opus）。
+fn decode_ogg(path: &Path, on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>) -> Result<(WavSpec, SourceCodec)> {
+    let file = BufReader::new(File::open(path).context("Failed to open Ogg file")?);
+    let mut packets = PacketReader::new(file);
+    let head = packets.read_packet()?.ok_or_else(|| anyhow::anyhow!("Ogg file contains no packets"))?;
+    if head.data.starts_with(b"\x01vorbis") {
+        // lewton 自己解析头部，从文件开头重新读取
+        let mut file = packets.into_inner();
+        file.rewind()?;
+        Ok((decode_vorbis(file, on_frame)?, SourceCodec::Vorbis))
+    } else if head.data.starts_with(b"OpusHead") {
+        Ok((decode_opus(packets, &head, on_frame)?, SourceCodec::Opus))
+    } else {
+        Err(anyhow::anyhow!("Unsupported Ogg codec (only Vorbis and Opus can be decoded)."))
+    }
+}
+
+fn decode_vorbis<R: Read + Seek>(reader: R, mut on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>) -> Result<WavSpec> {
+    let mut stream = lewton::inside_ogg::OggStreamReader::new(reader)
+        .map_err(|e| anyhow::anyhow!("Error decoding Ogg Vorbis: {:?}", e))?;
+    let spec = WavSpec {
+        channels: stream.ident_hdr.audio_channels as u16,
+        sample_rate: stream.ident_hdr.audio_sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut frames = 0u64;
+    while let Some(data) = stream.read_dec_packet_itl().map_err(|e| anyhow::anyhow!("Error decoding Ogg Vorbis: {:?}", e))? {
+        if data.is_empty() {
+            continue;
+        }
+        frames += data.len() as u64;
+        on_frame(&spec, &data)?;
+    }
+    if frames == 0 {
+        return Err(anyhow::anyhow!("Ogg Vorbis contains no audio frames"));
+    }
+    Ok(spec)
+}
+
+/// Opus 总是以 48 kHz 解码；OpusHead 中的采样率只是编码前的原始采样率
+const OPUS_RATE: u32 = 48000;
+/// 单个 Opus 包最长 120 ms
+#[cfg(feature = "opus")]
+const OPUS_MAX_FRAMES: usize = OPUS_RATE as usize * 120 / 1000;
+
+/// OpusHead 标识头中解码需要的字段
+struct OpusHead {
+    channels: u16,
+    pre_skip: u64,
+    gain_db: f32,
+}
+
+impl OpusHead {
+    /// 只接受映射族 0（单声道 / 立体声），映射族 1 / 255 的多声道流给出明确错误
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 19 || !data.starts_with(b"OpusHead") {
+            return Err(anyhow::anyhow!("Invalid OpusHead packet"));
+        }
+        let (channel_count, mapping) = (data[9], data[18]);
+        if mapping != 0 {
+            return Err(anyhow::anyhow!(
+                "Opus with {} channels (mapping family {}) is not supported, only mono and stereo (mapping family 0).",
+                channel_count, mapping
+            ));
+        }
+        if !(1..=2).contains(&channel_count) {
+            return Err(anyhow::anyhow!("Invalid OpusHead packet ({} channels in mapping family 0)", channel_count));
+        }
+        Ok(Self {
+            channels: channel_count as u16,
+            pre_skip: u16::from_le_bytes([data[10], data[11]]) as u64,
+            gain_db: i16::from_le_bytes([data[16], data[17]]) as f32 / 256.0,
+        })
+    }
+
+    fn spec(&self) -> WavSpec {
+        WavSpec {
+            channels: self.channels,
+            sample_rate: OPUS_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        }
+    }
+}
+
+/// 未启用 `opus` 特性时只校验头部，解码报错
+#[cfg(not(feature = "opus"))]
+fn decode_opus<R: Read + Seek>(
+    _packets: PacketReader<R>,
+    head: &ogg::Packet,
+    _on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>,
+) -> Result<WavSpec> {
+    OpusHead::parse(&head.data)?;
+    Err(anyhow::anyhow!("This build cannot decode Opus (build with the `opus` cargo feature)."))
+}
+
+/// 解码 Ogg Opus（RFC 7845）：丢弃 pre-skip 采样，按最后一页的 granule position 截掉末尾填充，
+/// 并应用头部的输出增益。只支持映射族 0（单声道 / 立体声）。
+#[cfg(feature = "opus")]
+fn decode_opus<R: Read + Seek>(
+    mut packets: PacketReader<R>,
+    head: &ogg::Packet,
+    mut on_frame: impl FnMut(&WavSpec, &[i16]) -> Result<()>,
+) -> Result<WavSpec> {
+    use audiopus::coder::Decoder as OpusDecoder;
+    use audiopus::packet::Packet as OpusPacket;
+    use audiopus::{Channels, MutSignals, SampleRate};
+
+    let opus_head = OpusHead::parse(&head.data)?;
+    let spec = opus_head.spec();
+    let pre_skip = opus_head.pre_skip;
+    let channels = if spec.channels == 1 { Channels::Mono } else { Channels::Stereo };
+    let serial = head.stream_serial();
+    let gain = 10f32.powf(opus_head.gain_db / 20.0);
+    let mut decoder = OpusDecoder::new(SampleRate::Hz48000, channels)?;
+    let mut pcm = vec![0i16; OPUS_MAX_FRAMES * spec.channels as usize];
+    // granule position（含 pre-skip）与已解码帧数对应
+    let mut position = 0u64;
+    let mut emitted = 0u64;
+    let mut tags_seen = false;
+
+    while let Some(packet) = packets.read_packet()? {
+        if packet.stream_serial() != serial {
+            continue;
+        }
+        if !tags_seen {
+            // 第二个包是 OpusTags 注释头
+            tags_seen = true;
+            continue;
+        }
+        if !packet.data.is_empty() {
+            let frames = decoder.decode(
+                Some(OpusPacket::try_from(&packet.data[..])?),
+                MutSignals::try_from(&mut pcm[..])?,
+                false,
+            )? as u64;
+            let start = pre_skip.saturating_sub(position).min(frames);
+            position += frames;
+            let mut end = frames;
+            if packet.last_in_stream() {
+                // 最后一页的 granule position 给出真实结尾
+                end = end.saturating_sub(position.saturating_sub(packet.absgp_page())).max(start);
+            }
+            if start < end {
+                let samples = &mut pcm[(start * spec.channels as u64) as usize..(end * spec.channels as u64) as usize];
+                if gain != 1.0 {
+                    for s in samples.iter_mut() {
+                        *s = (*s as f32 * gain).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+                    }
+                }
+                emitted += end - start;
+                on_frame(&spec, samples)?;
+            }
+        }
+        if packet.last_in_stream() {
+            break;
+        }
+    }
+    if emitted == 0 {
+        return Err(anyhow::anyhow!("Opus contains no audio frames"));
+    }
+    Ok(spec)
+}
+
+/// 有损音频作为容器前需转换成的无损格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LosslessFormat {
+    #[default]
+    Wav,
+    Flac,
+}
+
+impl LosslessFormat {
+    pub fn from_index(i: i32) -> Self {
+        if i == 1 { Self::Flac } else { Self::Wav }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+        }
+    }
+}
+
+/// 有损音频格式：LSB 嵌入无法在有损重编码后保留，只能先转换为无损容器
+pub fn is_lossy_audio(ext: &str) -> bool {
+    matches!(ext.to_lowercase().as_str(), "mp3" | "ogg" | "oga" | "opus")
+}
+
+/// 将有损音频解码并写为 16-bit 无损文件，保留采样率与声道数（流式，逐帧写出）
+pub fn convert_to_lossless(input: &Path, output: &Path, format: LosslessFormat) -> Result<AudioInfo> {
+    let mut sink: Option<LosslessWriter> = None;
+    let mut samples = 0u64;
+    let (spec, codec) = decode_lossy(input, |spec, data| {
+        let sink = match sink.as_mut() {
+            Some(sink) => sink,
+            None => sink.insert(match format {
+                LosslessFormat::Wav => LosslessWriter::Wav(WavWriter::create(output, *spec)?),
+                LosslessFormat::Flac => LosslessWriter::Flac(FlacWriter::create(output, *spec, &[])?),
+            }),
+        };
+        for &sample in data {
+            sink.write_sample(sample)?;
+        }
+        samples += data.len() as u64;
+        Ok(())
+    })?;
+    if let Some(sink) = sink {
+        sink.finalize()?;
+    }
+    Ok(AudioInfo::new(&spec, samples / spec.channels as u64, codec))
+}
+
+/// 统计有损音频的格式与长度（用于容量计算，不写文件）
+pub fn probe_lossy_audio(input: &Path) -> Result<AudioInfo> {
+    let mut samples = 0u64;
+    let (spec, codec) = decode_lossy(input, |_, data| {
+        samples += data.len() as u64;
+        Ok(())
+    })?;
+    Ok(AudioInfo::new(&spec, samples / spec.channels as u64, codec))
+}
+
+enum LosslessWriter {
+    Wav(WavWriter<std::io::BufWriter<File>>),
+    Flac(FlacWriter),
+}
+
+impl LosslessWriter {
+    fn write_sample(&mut self, sample: i16) -> Result<()> {
+        match self {
+            Self::Wav(w) => w.write_sample(sample)?,
+            Self::Flac(w) => w.write_sample(sample as i32)?,
+        }
+        Ok(())
+    }
+
+    fn finalize(self) -> Result<()> {
+        match self {
+            Self::Wav(w) => w.finalize()?,
+            Self::Flac(w) => w.finalize()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+    use tempfile::tempdir;
+
+    /// 把每个包写成单独的一页，最后一个包结束逻辑流
+    fn write_ogg(path: &Path, packets: &[(Vec<u8>, u64)]) -> Result<()> {
+        let mut writer = PacketWriter::new(File::create(path)?);
+        for (i, (data, absgp)) in packets.iter().enumerate() {
+            let end = if i + 1 == packets.len() { PacketWriteEndInfo::EndStream } else { PacketWriteEndInfo::EndPage };
+            writer.write_packet(data.clone().into_boxed_slice(), 0x5350_4e47, end, *absgp)?;
+        }
+        Ok(())
+    }
+
+    /// Vorbis 头部按 LSB 优先打包位域
+    #[derive(Default)]
+    struct Bits {
+        bytes: Vec<u8>,
+        used: u32,
+    }
+
+    impl Bits {
+        fn put(&mut self, value: u32, bits: u32) {
+            for i in 0..bits {
+                let offset = self.used % 8;
+                if offset == 0 {
+                    self.bytes.push(0);
+                }
+                *self.bytes.last_mut().unwrap() |= (((value >> i) & 1) as u8) << offset;
+                self.used += 1;
+            }
+        }
+    }
+
+    /// 最小的 Ogg Vorbis：256 采样的块、一个只有两个码字的码本、零分区的 floor 1 与空残差，
+    /// 每个音频包都把所有声道标为未使用，因此解码结果是静音。
+    fn write_vorbis(path: &Path, channels: u8, sample_rate: u32, audio_packets: usize) -> Result<()> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend_from_slice(&0u32.to_le_bytes());
+        ident.push(channels);
+        ident.extend_from_slice(&sample_rate.to_le_bytes());
+        ident.extend_from_slice(&[0; 12]);
+        ident.extend_from_slice(&[0x88, 1]);
+
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&4u32.to_le_bytes());
+        comment.extend_from_slice(b"test");
+        comment.extend_from_slice(&0u32.to_le_bytes());
+        comment.push(1);
+
+        let mut setup = Bits::default();
+        for &b in b"\x05vorbis" {
+            setup.put(b as u32, 8);
+        }
+        // 码本：1 维、2 个长度为 1 的码字、无 VQ 查找表
+        setup.put(0, 8);
+        setup.put(0x564342, 24);
+        setup.put(1, 16);
+        setup.put(2, 24);
+        setup.put(0, 2);
+        setup.put(0, 5);
+        setup.put(0, 5);
+        setup.put(0, 4);
+        // 时域变换占位
+        setup.put(0, 6);
+        setup.put(0, 16);
+        // floor 1：无分区，乘数 2，8 位范围
+        setup.put(0, 6);
+        setup.put(1, 16);
+        setup.put(0, 5);
+        setup.put(1, 2);
+        setup.put(8, 4);
+        // 残差 0：空区间
+        setup.put(0, 6);
+        setup.put(0, 16);
+        setup.put(0, 24);
+        setup.put(0, 24);
+        setup.put(0, 24);
+        setup.put(0, 6);
+        setup.put(0, 8);
+        setup.put(0, 4);
+        // 映射 0：单个子映射，无声道耦合
+        setup.put(0, 6);
+        setup.put(0, 16);
+        setup.put(0, 4);
+        setup.put(0, 24);
+        // 模式：短块，映射 0
+        setup.put(0, 6);
+        setup.put(0, 1);
+        setup.put(0, 32);
+        setup.put(0, 8);
+        setup.put(1, 1);
+
+        let mut packets = vec![(ident, 0), (comment, 0), (setup.bytes, 0)];
+        for i in 0..audio_packets {
+            packets.push((vec![0], i as u64 * 128));
+        }
+        write_ogg(path, &packets)
+    }
+
+    fn opus_head(channels: u8, pre_skip: u16, mapping: u8) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.push(1);
+        head.push(channels);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&44100u32.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(mapping);
+        if mapping != 0 {
+            // 流数、耦合流数与声道映射表
+            head.extend_from_slice(&[channels, 0]);
+            head.extend((0..channels).collect::<Vec<u8>>());
+        }
+        head
+    }
+
+    /// 每个包只有 TOC 字节（CELT 全频带 20 ms），libopus 把空帧当作丢包处理，输出 960 帧静音
+    fn write_opus(path: &Path, channels: u8, pre_skip: u16, mapping: u8, audio_packets: usize, end: u64) -> Result<()> {
+        let toc = if channels == 2 { 0xFC } else { 0xF8 };
+        let mut packets = vec![(opus_head(channels, pre_skip, mapping), 0), (b"OpusTags\0\0\0\0\0\0\0\0".to_vec(), 0)];
+        for i in 1..=audio_packets {
+            let absgp = if i == audio_packets { end } else { i as u64 * 960 };
+            packets.push((vec![toc], absgp));
+        }
+        write_ogg(path, &packets)
+    }
+
+    fn decode(path: &Path) -> Result<(WavSpec, SourceCodec, usize)> {
+        let mut samples = 0;
+        let (spec, codec) = decode_lossy(path, |_, frame| {
+            samples += frame.len();
+            Ok(())
+        })?;
+        Ok((spec, codec, samples))
+    }
+
+    #[test]
+    fn test_decode_vorbis() -> Result<()> {
+        let dir = tempdir()?;
+        for (channels, rate) in [(1u8, 22050u32), (2, 44100)] {
+            let path = dir.path().join(format!("v{}.ogg", channels));
+            write_vorbis(&path, channels, rate, 9)?;
+            let (spec, codec, samples) = decode(&path)?;
+            assert_eq!(codec, SourceCodec::Vorbis);
+            assert_eq!((spec.channels, spec.sample_rate, spec.bits_per_sample), (channels as u16, rate, 16));
+            // 首个包只用于重叠，之后每包输出半个块
+            assert_eq!(samples, 8 * 128 * channels as usize);
+
+            let (info, pcm) = load_audio_as_pcm(&path)?;
+            assert_eq!((info.channels, info.sample_rate, info.codec), (channels as u16, rate, SourceCodec::Vorbis));
+            assert!(pcm.len() == samples && pcm.iter().all(|&s| s == 0));
+        }
+        Ok(())
+    }
+
+    /// MPEG-1 Layer III，128 kbit/s、44.1 kHz：边信息与主数据全为零的帧解码为 1152 帧静音
+    fn write_mp3(path: &Path, stereo: bool, frames: usize) -> Result<()> {
+        let mut frame = vec![0u8; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, if stereo { 0x00 } else { 0xC0 }]);
+        std::fs::write(path, frame.repeat(frames))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_wav_info() -> Result<()> {
+        let dir = tempdir()?;
+        for (bits, format, codec) in [(16, SampleFormat::Int, SourceCodec::PcmInt), (24, SampleFormat::Int, SourceCodec::PcmInt), (32, SampleFormat::Float, SourceCodec::PcmFloat)] {
+            let spec = WavSpec { channels: 2, sample_rate: 22050, bits_per_sample: bits, sample_format: format };
+            let path = dir.path().join(format!("{}.wav", bits));
+            let mut writer = WavWriter::create(&path, spec)?;
+            for i in 0..300 {
+                match format {
+                    SampleFormat::Float => writer.write_sample(i as f32 / 1000.0)?,
+                    SampleFormat::Int => writer.write_sample(i << (bits - 16))?,
+                }
+            }
+            writer.finalize()?;
+
+            let (info, pcm) = load_audio_as_pcm(&path)?;
+            assert_eq!(info, AudioInfo { sample_rate: 22050, channels: 2, bits_per_sample: bits, frames: 150, codec });
+            assert_eq!(info.pcm_spec().bits_per_sample, 16);
+            assert_eq!(pcm.len(), 300);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, ignore)] // slice-deque (minimp3) trips the standard library's debug UB checks
+    fn test_load_mp3_info() -> Result<()> {
+        let dir = tempdir()?;
+        for (stereo, channels) in [(false, 1u16), (true, 2)] {
+            let path = dir.path().join(format!("{}.mp3", channels));
+            write_mp3(&path, stereo, 10)?;
+            let (info, pcm) = load_audio_as_pcm(&path)?;
+            assert_eq!((info.sample_rate, info.channels, info.bits_per_sample, info.codec), (44100, channels, 16, SourceCodec::Mp3));
+            assert_eq!(pcm.len() as u64, info.samples());
+            assert_eq!(info, probe_lossy_audio(&path)?);
+            assert_eq!(info.frames, 10 * 1152);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_lossy_audio() {
+        for ext in ["mp3", "MP3", "ogg", "oga", "opus"] {
+            assert!(is_lossy_audio(ext), "{}", ext);
+        }
+        for ext in ["wav", "flac", "aiff", "png", ""] {
+            assert!(!is_lossy_audio(ext), "{}", ext);
+        }
+    }
+
+    #[test]
+    fn test_convert_to_lossless() -> Result<()> {
+        let dir = tempdir()?;
+        let input = dir.path().join("in.ogg");
+        write_vorbis(&input, 2, 32000, 9)?;
+        let probed = probe_lossy_audio(&input)?;
+        assert_eq!(probed, AudioInfo { sample_rate: 32000, channels: 2, bits_per_sample: 16, frames: 8 * 128, codec: SourceCodec::Vorbis });
+
+        let wav = dir.path().join("out.wav");
+        assert_eq!(convert_to_lossless(&input, &wav, LosslessFormat::Wav)?, probed);
+        let reader = hound::WavReader::open(&wav)?;
+        assert_eq!(reader.spec(), probed.pcm_spec());
+        assert_eq!(reader.len() as u64, probed.samples());
+
+        let flac = dir.path().join("out.flac");
+        assert_eq!(convert_to_lossless(&input, &flac, LosslessFormat::Flac)?, probed);
+        let reader = claxon::FlacReader::open(&flac)?;
+        let info = reader.streaminfo();
+        assert_eq!((info.channels, info.sample_rate, info.bits_per_sample), (2, 32000, 16));
+        assert_eq!(info.samples, Some(probed.frames));
+
+        // 解码失败时不留下输出文件
+        let broken = dir.path().join("broken.ogg");
+        std::fs::write(&broken, b"not an ogg file")?;
+        let output = dir.path().join("broken.wav");
+        assert!(convert_to_lossless(&broken, &output, LosslessFormat::Wav).is_err());
+        assert!(probe_lossy_audio(&broken).is_err());
+        assert!(!output.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_opus_head() -> Result<()> {
+        let head = OpusHead::parse(&opus_head(2, 312, 0))?;
+        assert_eq!((head.channels, head.pre_skip, head.gain_db), (2, 312, 0.0));
+        assert_eq!(head.spec().sample_rate, OPUS_RATE);
+
+        // 映射族 1 的多声道流在解码前就被拒绝，与是否启用 `opus` 特性无关
+        let dir = tempdir()?;
+        let path = dir.path().join("surround.opus");
+        write_opus(&path, 6, 312, 1, 3, 3 * 960)?;
+        let err = decode(&path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Opus with 6 channels (mapping family 1) is not supported, only mono and stereo (mapping family 0)."
+        );
+        assert!(OpusHead::parse(&opus_head(3, 0, 0)).is_err());
+        assert!(OpusHead::parse(b"OpusHead").is_err());
+        Ok(())
+    }
+
+    #[cfg(not(feature = "opus"))]
+    #[test]
+    fn test_decode_opus_needs_feature() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("a.opus");
+        write_opus(&path, 2, 312, 0, 3, 3 * 960)?;
+        assert!(decode(&path).unwrap_err().to_string().contains("`opus` cargo feature"));
+        Ok(())
+    }
+
+    #[cfg(feature = "opus")]
+    #[test]
+    fn test_decode_opus() -> Result<()> {
+        let dir = tempdir()?;
+        for channels in [1u8, 2] {
+            // 5 个 20 ms 包共 4800 帧：去掉开头 312 帧，末页 granule 为 4000
+            let path = dir.path().join(format!("o{}.ogg", channels));
+            write_opus(&path, channels, 312, 0, 5, 4000)?;
+            let (spec, codec, samples) = decode(&path)?;
+            assert_eq!(codec, SourceCodec::Opus);
+            assert_eq!((spec.channels, spec.sample_rate), (channels as u16, OPUS_RATE));
+            assert_eq!(samples, (4000 - 312) * channels as usize);
+        }
+
+        // pre-skip 跨越多个包
+        let path = dir.path().join("skip.opus");
+        write_opus(&path, 1, 2000, 0, 4, 4 * 960)?;
+        assert_eq!(decode(&path)?.2, 4 * 960 - 2000);
+        Ok(())
+    }
 }
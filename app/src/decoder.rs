@@ -1,4 +1,4 @@
-use crate::header::{self, Header};
+use crate::header::{self, FitPolicy, Header};
 use crate::security;
 use crate::utils;
 use crate::converter;
@@ -76,6 +76,9 @@ pub fn decode_data(
         .collect();
         
     let header = Header::from_u16_chunks(&header_chunks)?;
+    if header.flags & header::FLAG_PNG_ALPHA != 0 {
+        return Err(anyhow!("This image also carries data in its alpha channel; decode it in Universal mode."));
+    }
 
     // 3. Extract Payload
     let payload_len = header.payload_len as usize;
@@ -123,9 +126,9 @@ pub fn decode_data(
     // 8. Save Restored Container (Optional)
     if let Some(cont_out) = container_out {
         if ext == "png" {
-            restore_container_png(input, cont_out)?;
+            restore_container_png(input, cont_out, &header)?;
         } else {
-            restore_container_wav(input, cont_out)?;
+            restore_container_wav(input, cont_out, &header)?;
         }
     }
     
@@ -225,16 +228,20 @@ fn extract_stream_from_png_limit(input: &PathBuf, limit_bytes: usize) -> Result<
     Ok(data)
 }
 
-fn restore_container_wav(input: &PathBuf, output: &PathBuf) -> Result<()> {
+fn restore_container_wav(input: &PathBuf, output: &PathBuf, header: &Header) -> Result<()> {
     let mut reader = WavReader::open(input)?;
     let spec = reader.spec();
     let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_,_>>()?;
     
-    let restored: Vec<i16> = samples.iter().map(|s| (s >> 16) as i16).collect();
+    let mut restored: Vec<i16> = samples.iter().map(|s| (s >> 16) as i16).collect();
+    // Strip the padding the encoder appended to fit the payload
+    if header.fit_policy.is_audio() {
+        restored.truncate(header.original_frames as usize * spec.channels as usize);
+    }
     utils::write_wav_16bit(output, spec, &restored)
 }
 
-fn restore_container_png(input: &PathBuf, output: &PathBuf) -> Result<()> {
+fn restore_container_png(input: &PathBuf, output: &PathBuf, header: &Header) -> Result<()> {
     let bytes = std::fs::read(input)?;
     let mut reader = ImageReader::new(Cursor::new(bytes));
     reader.set_format(image::ImageFormat::Png);
@@ -251,6 +258,14 @@ fn restore_container_png(input: &PathBuf, output: &PathBuf) -> Result<()> {
         let a = (pixel[3] >> 8) as u8;
         out_img.put_pixel(x, y, Rgba([r, g, b, a]));
     }
-    out_img.save(output)?;
+    
+    // Undo the enlargement the encoder applied to fit the payload
+    let (orig_w, orig_h) = (header.original_width, header.original_height);
+    let out_img = match header.fit_policy {
+        FitPolicy::Tile => image::imageops::crop_imm(&out_img, 0, 0, orig_w, orig_h).to_image(),
+        FitPolicy::Upscale => image::imageops::resize(&out_img, orig_w, orig_h, image::imageops::FilterType::Lanczos3),
+        _ => out_img,
+    };
+    out_img.save_with_format(output, image::ImageFormat::Png)?;
     Ok(())
 }
\ No newline at end of file
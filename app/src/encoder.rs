@@ -93,16 +93,21 @@ pub fn encode_data(
     
     if ext == "PNG" || ext == "JPG" || ext == "JPEG" {
         encode_as_png(container_path, output_path, &full_data_stream, embed_progress)
-    } else if ext == "WAV" {
-        let mut wav_data = converter::load_audio_as_pcm(container_path)?;
-        let (spec, _) = utils::read_and_normalize_wav(container_path)?;
-        encode_as_wav(output_path, &mut wav_data, &full_data_stream, spec, embed_progress)
+    } else if ext == "WAV" || converter::is_lossy_audio(&ext) {
+        // Lossy input is decoded to 16-bit PCM, the output is always a WAV
+        if converter::is_lossy_audio(&ext) {
+            tracing::warn!("{} container is lossy, converting to WAV", ext);
+        }
+        let (info, mut wav_data) = converter::load_audio_as_pcm(container_path)?;
+        tracing::info!("Container audio: {} {} Hz, {} ch, {}-bit, {:.1}s",
+            info.codec.name(), info.sample_rate, info.channels, info.bits_per_sample, info.duration().as_secs_f64());
+        encode_as_wav(output_path, &mut wav_data, &full_data_stream, &info, embed_progress)
     } else {
         Err(anyhow::anyhow!("Unsupported Container Format: {}", ext))
     }
 }
 
-fn encode_as_wav(output: &PathBuf, wav_container: &mut Vec<i16>, data_stream: &[u8], spec: hound::WavSpec, on_progress: impl Fn(f32)) -> Result<()> {
+fn encode_as_wav(output: &PathBuf, wav_container: &mut Vec<i16>, data_stream: &[u8], info: &converter::AudioInfo, on_progress: impl Fn(f32)) -> Result<()> {
     let total_samples_needed = (data_stream.len() + 1) / 2;
     
     if wav_container.len() < total_samples_needed {
@@ -129,7 +134,7 @@ fn encode_as_wav(output: &PathBuf, wav_container: &mut Vec<i16>, data_stream: &[
     }
     on_progress(1.0);
     
-    utils::write_wav_32bit(output, spec, &encoded_samples)
+    utils::write_wav_32bit(output, info.pcm_spec(), &encoded_samples)
 }
 
 fn encode_as_png(container_path: &PathBuf, output: &PathBuf, data_stream: &[u8], on_progress: impl Fn(f32)) -> Result<()> {
@@ -0,0 +1,567 @@
+use anyhow::{anyhow, Result};
+use hound::WavSpec;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Samples per channel in each FLAC frame.
+const BLOCK_SIZE: usize = 4096;
+/// Vorbis comment tag recording how the payload is laid out in the samples.
+pub const LAYOUT_TAG: &str = "SPNG_LAYOUT";
+
+/// How payload bits are stored in the samples of a FLAC container.
+/// FLAC frames carry at most 24 bits per sample, so unlike WAV the layout is tagged explicitly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlacLayout {
+    /// Up to 16-bit audio shifted into a 24-bit stream, 1 payload byte in the low 8 bits.
+    Expand8,
+    /// 24-bit audio kept as is, 4 payload bits in the LSBs (2 samples per byte).
+    Int24,
+    /// 16-bit audio kept as is, 2 payload bits in the LSBs (`AudioOutput::SameAsInput`).
+    Int16,
+}
+
+impl FlacLayout {
+    pub fn for_container(bits_per_sample: u32) -> Result<Self> {
+        match bits_per_sample {
+            8 | 12 | 16 => Ok(Self::Expand8),
+            20 | 24 => Ok(Self::Int24),
+            b => Err(anyhow!("Unsupported FLAC bit depth: {}", b)),
+        }
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Expand8 => "expand8",
+            Self::Int24 => "lsb4",
+            Self::Int16 => "lsb2",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "expand8" => Some(Self::Expand8),
+            "lsb4" => Some(Self::Int24),
+            "lsb2" => Some(Self::Int16),
+            _ => None,
+        }
+    }
+
+    /// Bits per sample of the encoded stream.
+    pub fn output_bits(self) -> u16 {
+        match self {
+            Self::Expand8 | Self::Int24 => 24,
+            Self::Int16 => 16,
+        }
+    }
+
+    /// Bits of each output sample that carry payload.
+    pub fn mask(self) -> u32 {
+        match self {
+            Self::Expand8 => 0xFF,
+            Self::Int24 => 0x0F,
+            Self::Int16 => 0x03,
+        }
+    }
+
+    /// Payload bytes carried by `samples` samples.
+    pub fn capacity(self, samples: u64) -> u64 {
+        match self {
+            Self::Expand8 => samples,
+            Self::Int24 => samples / 2,
+            Self::Int16 => samples / 4,
+        }
+    }
+
+    /// Samples needed to carry `bytes` payload bytes.
+    pub fn samples_for(self, bytes: u64) -> u64 {
+        match self {
+            Self::Expand8 => bytes,
+            Self::Int24 => bytes * 2,
+            Self::Int16 => bytes * 4,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Expand8 => "FLAC 24-bit, 8-bit LSB",
+            Self::Int24 => "FLAC 24-bit, 4-bit LSB",
+            Self::Int16 => "FLAC 16-bit, 2-bit LSB",
+        }
+    }
+}
+
+/// Reads the stream info and the layout tag of an encoded FLAC.
+pub fn encoded_layout(reader: &claxon::FlacReader<File>) -> Result<FlacLayout> {
+    reader
+        .get_tag(LAYOUT_TAG)
+        .next()
+        .and_then(FlacLayout::from_tag)
+        .ok_or_else(|| anyhow!("Not a Sound_PNG encoded FLAC (missing {} tag).", LAYOUT_TAG))
+}
+
+/// `WavSpec` equivalent of a FLAC stream (always integer samples).
+pub fn spec_of(reader: &claxon::FlacReader<File>) -> WavSpec {
+    let info = reader.streaminfo();
+    WavSpec {
+        channels: info.channels as u16,
+        sample_rate: info.sample_rate,
+        bits_per_sample: info.bits_per_sample as u16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+/// Owning, interleaved sample iterator over a FLAC file (claxon's `samples()` borrows the reader).
+pub struct FlacSamples {
+    reader: claxon::FlacReader<File>,
+    block: Vec<i32>,
+    channels: usize,
+    frames: usize,
+    pos: usize,
+    done: bool,
+}
+
+impl FlacSamples {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self::new(claxon::FlacReader::open(path)?))
+    }
+
+    pub fn new(reader: claxon::FlacReader<File>) -> Self {
+        let channels = reader.streaminfo().channels as usize;
+        Self { reader, block: Vec::new(), channels, frames: 0, pos: 0, done: false }
+    }
+}
+
+impl Iterator for FlacSamples {
+    type Item = Result<i32>;
+
+    fn next(&mut self) -> Option<Result<i32>> {
+        while self.pos >= self.frames * self.channels {
+            if self.done {
+                return None;
+            }
+            let buffer = std::mem::take(&mut self.block);
+            match self.reader.blocks().read_next_or_eof(buffer) {
+                Ok(Some(block)) => {
+                    self.frames = block.duration() as usize;
+                    self.pos = 0;
+                    self.block = block.into_buffer();
+                }
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        // Blocks are stored channel after channel; hand them out interleaved
+        let (frame, ch) = (self.pos / self.channels, self.pos % self.channels);
+        self.pos += 1;
+        Some(Ok(self.block[ch * self.frames + frame]))
+    }
+}
+
+/// Streaming FLAC encoder (fixed predictors, Rice-coded residuals).
+/// Samples are written interleaved, like `hound::WavWriter`.
+pub struct FlacWriter {
+    out: BufWriter<File>,
+    spec: WavSpec,
+    block: Vec<i32>,
+    frame_number: u32,
+    total_frames: u64,
+    min_frame_size: u32,
+    max_frame_size: u32,
+}
+
+impl FlacWriter {
+    pub fn create(path: &Path, spec: WavSpec, tags: &[(&str, &str)]) -> Result<Self> {
+        if !matches!(spec.bits_per_sample, 8 | 12 | 16 | 20 | 24) || spec.sample_format != hound::SampleFormat::Int {
+            return Err(anyhow!("FLAC cannot store {:?} {}-bit samples.", spec.sample_format, spec.bits_per_sample));
+        }
+        if spec.channels == 0 || spec.channels > 8 {
+            return Err(anyhow!("FLAC supports 1 to 8 channels, got {}.", spec.channels));
+        }
+        let mut writer = Self {
+            out: BufWriter::new(File::create(path)?),
+            spec,
+            block: Vec::with_capacity(BLOCK_SIZE * spec.channels as usize),
+            frame_number: 0,
+            total_frames: 0,
+            min_frame_size: 0,
+            max_frame_size: 0,
+        };
+        writer.out.write_all(b"fLaC")?;
+        writer.write_streaminfo()?;
+        writer.write_vorbis_comment(tags)?;
+        Ok(writer)
+    }
+
+    pub fn write_sample(&mut self, sample: i32) -> Result<()> {
+        self.block.push(sample);
+        if self.block.len() == BLOCK_SIZE * self.spec.channels as usize {
+            self.flush_block()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the last partial block and patches the stream info with the final counts.
+    pub fn finalize(mut self) -> Result<()> {
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+        self.out.seek(SeekFrom::Start(4))?;
+        self.write_streaminfo()?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_streaminfo(&mut self) -> Result<()> {
+        let mut bits = BitWriter::default();
+        bits.write(0, 8); // not last, STREAMINFO
+        bits.write(34, 24);
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(BLOCK_SIZE as u64, 16);
+        bits.write(self.min_frame_size as u64, 24);
+        bits.write(self.max_frame_size as u64, 24);
+        bits.write(self.spec.sample_rate as u64, 20);
+        bits.write(self.spec.channels as u64 - 1, 3);
+        bits.write(self.spec.bits_per_sample as u64 - 1, 5);
+        bits.write(self.total_frames >> 32, 4);
+        bits.write(self.total_frames & 0xFFFF_FFFF, 32);
+        for _ in 0..4 {
+            bits.write(0, 32); // MD5 unknown
+        }
+        self.out.write_all(&bits.into_bytes())?;
+        Ok(())
+    }
+
+    fn write_vorbis_comment(&mut self, tags: &[(&str, &str)]) -> Result<()> {
+        let vendor = b"sound_png";
+        let mut body = Vec::new();
+        body.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+        body.extend_from_slice(vendor);
+        body.extend_from_slice(&(tags.len() as u32).to_le_bytes());
+        for (key, value) in tags {
+            let entry = format!("{}={}", key, value);
+            body.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+            body.extend_from_slice(entry.as_bytes());
+        }
+        // Last metadata block, type 4 (VORBIS_COMMENT)
+        let len = body.len() as u32;
+        self.out.write_all(&[0x84, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+        self.out.write_all(&body)?;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        let channels = self.spec.channels as usize;
+        let frames = self.block.len() / channels;
+        let bps = self.spec.bits_per_sample as u32;
+
+        let mut bits = BitWriter::default();
+        bits.write(0b1111_1111_1111_1000, 16); // sync, fixed block size
+        bits.write(0b0111, 4); // 16-bit block size at end of header
+        bits.write(0b0000, 4); // sample rate from STREAMINFO
+        bits.write(channels as u64 - 1, 4); // independent channels
+        bits.write(bps_code(bps), 3);
+        bits.write(0, 1);
+        write_utf8_number(&mut bits, self.frame_number);
+        bits.write(frames as u64 - 1, 16);
+        let crc = crc8(bits.bytes());
+        bits.write(crc as u64, 8);
+
+        let mut channel = Vec::with_capacity(frames);
+        for ch in 0..channels {
+            channel.clear();
+            channel.extend(self.block.iter().skip(ch).step_by(channels).copied());
+            write_subframe(&mut bits, &channel, bps);
+        }
+        bits.align();
+        let crc = crc16(bits.bytes());
+        bits.write(crc as u64, 16);
+
+        let frame = bits.into_bytes();
+        let size = frame.len() as u32;
+        self.min_frame_size = if self.min_frame_size == 0 { size } else { self.min_frame_size.min(size) };
+        self.max_frame_size = self.max_frame_size.max(size);
+        self.out.write_all(&frame)?;
+
+        self.frame_number += 1;
+        self.total_frames += frames as u64;
+        self.block.clear();
+        Ok(())
+    }
+}
+
+fn bps_code(bps: u32) -> u64 {
+    match bps {
+        8 => 0b001,
+        12 => 0b010,
+        16 => 0b100,
+        20 => 0b101,
+        _ => 0b110,
+    }
+}
+
+/// Picks the cheapest of constant, fixed predictor (order 0..=4) and verbatim.
+fn write_subframe(bits: &mut BitWriter, samples: &[i32], bps: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        bits.write(0b0000_0000, 8);
+        bits.write_signed(samples[0] as i64, bps);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * bps as u64;
+    let mut best: Option<(usize, u32, u64)> = None; // order, rice parameter, size in bits
+    for order in 0..=4usize.min(samples.len() - 1) {
+        let residuals = fixed_residuals(samples, order);
+        let (param, size) = rice_cost(&residuals);
+        let size = size + (order as u64) * bps as u64 + 10;
+        if param <= 14 && !matches!(best, Some((_, _, b)) if b <= size) {
+            best = Some((order, param, size));
+        }
+    }
+
+    match best {
+        Some((order, param, size)) if size < verbatim_bits => {
+            bits.write(((0b001000 | order) << 1) as u64, 8);
+            for &s in &samples[..order] {
+                bits.write_signed(s as i64, bps);
+            }
+            bits.write(0b00, 2); // Rice, 4-bit parameters
+            bits.write(0, 4); // partition order 0
+            bits.write(param as u64, 4);
+            for r in fixed_residuals(samples, order) {
+                let u = zigzag(r);
+                bits.write_zeros(u >> param);
+                bits.write(1, 1);
+                bits.write(u & ((1 << param) - 1), param);
+            }
+        }
+        _ => {
+            bits.write(0b0000_0010, 8);
+            for &s in samples {
+                bits.write_signed(s as i64, bps);
+            }
+        }
+    }
+}
+
+fn fixed_residuals(s: &[i32], order: usize) -> Vec<i64> {
+    (order..s.len())
+        .map(|i| {
+            let x = |k: usize| s[i - k] as i64;
+            match order {
+                0 => x(0),
+                1 => x(0) - x(1),
+                2 => x(0) - 2 * x(1) + x(2),
+                3 => x(0) - 3 * x(1) + 3 * x(2) - x(3),
+                _ => x(0) - 4 * x(1) + 6 * x(2) - 4 * x(3) + x(4),
+            }
+        })
+        .collect()
+}
+
+fn zigzag(r: i64) -> u64 {
+    ((r << 1) ^ (r >> 63)) as u64
+}
+
+/// Best Rice parameter for the residuals and the resulting size in bits.
+fn rice_cost(residuals: &[i64]) -> (u32, u64) {
+    let sum: u64 = residuals.iter().map(|&r| zigzag(r)).sum();
+    let mean = sum / residuals.len().max(1) as u64;
+    let guess = if mean == 0 { 0 } else { 63 - mean.leading_zeros() };
+    let cost = |k: u32| residuals.iter().map(|&r| (zigzag(r) >> k) + 1 + k as u64).sum::<u64>();
+    // The optimum is within one of floor(log2(mean))
+    (guess.saturating_sub(1)..=guess + 1)
+        .map(|k| (k, cost(k)))
+        .min_by_key(|&(_, c)| c)
+        .unwrap()
+}
+
+fn write_utf8_number(bits: &mut BitWriter, n: u32) {
+    let n = n as u64;
+    if n < 0x80 {
+        bits.write(n, 8);
+        return;
+    }
+    let extra = match n {
+        0..=0x7FF => 1,
+        0x800..=0xFFFF => 2,
+        0x1_0000..=0x1F_FFFF => 3,
+        0x20_0000..=0x3FF_FFFF => 4,
+        _ => 5,
+    };
+    let lead_marker = (0xFF00u64 >> (extra + 1)) & 0xFF;
+    bits.write(lead_marker | (n >> (6 * extra)), 8);
+    for i in (0..extra).rev() {
+        bits.write(0x80 | ((n >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// MSB-first bit writer.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    nbits: u32,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        let mask = if bits == 64 { u64::MAX } else { (1 << bits) - 1 };
+        self.acc = (self.acc << bits) | (value & mask);
+        self.nbits += bits;
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            self.bytes.push((self.acc >> self.nbits) as u8);
+        }
+        self.acc &= (1 << self.nbits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_zeros(&mut self, mut count: u64) {
+        while count > 0 {
+            let n = count.min(32) as u32;
+            self.write(0, n);
+            count -= n as u64;
+        }
+    }
+
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.write(0, 8 - self.nbits);
+        }
+    }
+
+    /// Complete bytes written so far.
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use tempfile::tempdir;
+
+    /// Writes `samples` (interleaved) with `FlacWriter` and reads them back with claxon.
+    fn roundtrip(spec: WavSpec, samples: &[i32], tags: &[(&str, &str)]) -> Result<(claxon::FlacReader<File>, Vec<i32>)> {
+        let dir = tempdir()?;
+        let path = dir.path().join("test.flac");
+        let mut writer = FlacWriter::create(&path, spec, tags)?;
+        for &sample in samples {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+        let reader = claxon::FlacReader::open(&path)?;
+        let decoded = FlacSamples::open(&path)?.collect::<Result<Vec<_>>>()?;
+        Ok((reader, decoded))
+    }
+
+    fn spec(channels: u16, bits: u16) -> WavSpec {
+        WavSpec { channels, sample_rate: 44100, bits_per_sample: bits, sample_format: hound::SampleFormat::Int }
+    }
+
+    #[test]
+    fn test_writer_roundtrip() -> Result<()> {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(32);
+        for bits in [8u16, 12, 16, 20, 24] {
+            for channels in [1u16, 2] {
+                let max = 1i32 << (bits - 1);
+                // Two full blocks and a partial one: noise (high Rice parameters), a sine
+                // (fixed predictors pay off), full-scale extremes, then silence and a constant
+                let frames = 2 * BLOCK_SIZE + 1234;
+                let mut samples = Vec::with_capacity(frames * channels as usize);
+                for i in 0..frames {
+                    for ch in 0..channels as usize {
+                        samples.push(match i {
+                            _ if i < 1500 => rng.gen_range(-max..max),
+                            _ if i < 3000 => ((i as f64 * 0.01 * (ch + 1) as f64).sin() * (max - 1) as f64) as i32,
+                            _ if i < 3100 => if i % 2 == 0 { max - 1 } else { -max },
+                            _ if i < BLOCK_SIZE => rng.gen_range(-max..max) / 64,
+                            _ if i < 2 * BLOCK_SIZE => 0,
+                            _ => -max / 3,
+                        });
+                    }
+                }
+                let (reader, decoded) = roundtrip(spec(channels, bits), &samples, &[])?;
+                let info = reader.streaminfo();
+                assert_eq!((info.bits_per_sample, info.channels, info.samples), (bits as u32, channels as u32, Some(frames as u64)));
+                assert_eq!(decoded, samples, "{} bits, {} channels", bits, channels);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_short_streams() -> Result<()> {
+        // A single partial block, a single frame and no samples at all
+        for frames in [1usize, 17, BLOCK_SIZE - 1, 0] {
+            let samples: Vec<i32> = (0..frames * 2).map(|i| (i as i32 * 7919) % 30000 - 15000).collect();
+            let (reader, decoded) = roundtrip(spec(2, 16), &samples, &[])?;
+            // STREAMINFO stores 0 for "unknown", which claxon reports as None
+            assert_eq!(reader.streaminfo().samples.unwrap_or(0), frames as u64);
+            assert_eq!(decoded, samples);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_tag() -> Result<()> {
+        let samples = vec![5; 64];
+        for layout in [FlacLayout::Expand8, FlacLayout::Int24, FlacLayout::Int16] {
+            let (reader, _) = roundtrip(spec(1, layout.output_bits()), &samples, &[(LAYOUT_TAG, layout.tag())])?;
+            assert_eq!(encoded_layout(&reader)?, layout);
+        }
+        // A FLAC without the tag is not one of ours
+        let (reader, _) = roundtrip(spec(1, 16), &samples, &[("TITLE", "plain")])?;
+        let err = encoded_layout(&reader).unwrap_err();
+        assert_eq!(err.to_string(), format!("Not a Sound_PNG encoded FLAC (missing {} tag).", LAYOUT_TAG));
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_rejects_unsupported_specs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.flac");
+        assert!(FlacWriter::create(&path, spec(2, 32), &[]).is_err());
+        assert!(FlacWriter::create(&path, spec(9, 16), &[]).is_err());
+        let float = WavSpec { sample_format: hound::SampleFormat::Float, ..spec(2, 32) };
+        assert!(FlacWriter::create(&path, float, &[]).is_err());
+    }
+}
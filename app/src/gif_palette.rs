@@ -0,0 +1,358 @@
+use anyhow::{anyhow, Result};
+use gif::{ColorOutput, DecodeOptions, Decoder, Encoder, Frame};
+use std::fs::File;
+use std::path::Path;
+use sound_png_api::ByteStream;
+
+// Palette-index mode: the palette is rebuilt so that entries 2k and 2k+1 hold the same
+// color (or, with more than 128 colors, the two closest ones), then every pixel carries
+// one payload bit in its index LSB. Pixels in the pair of the frame's transparent index
+// are left alone, so transparency is kept and the extractor skips the same pixels.
+
+/// Palette with paired entries and the old index -> new index map.
+struct PairedPalette {
+    palette: Vec<u8>,
+    map: [u8; 256],
+}
+
+impl PairedPalette {
+    /// Pairs the colors of `palette` (RGB triples). `keep` (the transparent index) gets a
+    /// pair of its own whenever the palette leaves room for it.
+    fn new(palette: &[u8], keep: Option<u8>) -> Self {
+        let colors: Vec<[u8; 3]> = palette.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).take(256).collect();
+        let n = colors.len();
+        // 128 pairs: the colors beyond 128 have to share one with another color
+        let shared = n.saturating_sub(128);
+        let keep = keep.map(|k| k as usize).filter(|&k| k < n && n > 2 * shared);
+
+        let mut edges = Vec::new();
+        if shared > 0 {
+            for i in 0..n {
+                for j in i + 1..n {
+                    if Some(i) != keep && Some(j) != keep {
+                        let d: i32 = (0..3).map(|c| (colors[i][c] as i32 - colors[j][c] as i32).pow(2)).sum();
+                        edges.push((d, i, j));
+                    }
+                }
+            }
+            edges.sort_unstable();
+        }
+        let mut partner = vec![None; n];
+        let mut matched = 0;
+        for (_, i, j) in edges {
+            if matched == shared {
+                break;
+            }
+            if partner[i].is_none() && partner[j].is_none() {
+                partner[i] = Some(j);
+                partner[j] = Some(i);
+                matched += 1;
+            }
+        }
+
+        let mut out = Vec::with_capacity(n * 6);
+        let mut map = [0u8; 256];
+        let mut assigned = vec![false; n];
+        for i in 0..n {
+            if assigned[i] {
+                continue;
+            }
+            let base = (out.len() / 3) as u8;
+            map[i] = base;
+            assigned[i] = true;
+            out.extend_from_slice(&colors[i]);
+            match partner[i] {
+                Some(j) => {
+                    // The odd slot, so a transparent partner's pixels keep their exact index
+                    map[j] = base | 1;
+                    assigned[j] = true;
+                    out.extend_from_slice(&colors[j]);
+                }
+                None => out.extend_from_slice(&colors[i]),
+            }
+        }
+        // Out-of-range indices (broken files) fall back to entry 0
+        for i in n..256 {
+            map[i] = map[0];
+        }
+        Self { palette: out, map }
+    }
+}
+
+/// Pair of the transparent index (index >> 1), whose pixels carry no payload.
+fn skipped_pair(frame: &Frame) -> Option<u8> {
+    frame.transparent.map(|t| t >> 1)
+}
+
+fn open(path: &Path) -> Result<Decoder<File>> {
+    let mut options = DecodeOptions::new();
+    options.set_color_output(ColorOutput::Indexed);
+    Ok(options.read_info(File::open(path)?)?)
+}
+
+/// Number of frames, for progress reporting (the LZW data is skipped, not decoded).
+fn frame_count(path: &Path) -> Result<usize> {
+    let mut options = DecodeOptions::new();
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(File::open(path)?)?;
+    let mut count = 0;
+    while decoder.read_next_frame()?.is_some() {
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Moves every pixel of `frame` to its new pair (LSB cleared) and switches it to the
+/// paired palette. Pixels of the transparent pair get their exact new index.
+fn remap(frame: &mut Frame, global: Option<&PairedPalette>) -> Result<()> {
+    let local = frame.palette.as_ref().map(|p| PairedPalette::new(p, frame.transparent));
+    let paired = local.as_ref().or(global).ok_or_else(|| anyhow!("GIF frame has no color table."))?;
+    let transparent = frame.transparent.map(|t| paired.map[t as usize]);
+    let skip = transparent.map(|t| t >> 1);
+    for px in frame.buffer.to_mut().iter_mut() {
+        let new = paired.map[*px as usize];
+        *px = if Some(new >> 1) == skip { new } else { new & !1 };
+    }
+    frame.transparent = transparent;
+    if let Some(local) = local {
+        frame.palette = Some(local.palette);
+    }
+    Ok(())
+}
+
+/// Number of pixels that carry a payload bit (all frames, transparent pairs excluded).
+pub fn usable_pixels(path: &Path) -> Result<u64> {
+    let mut decoder = open(path)?;
+    let global = decoder.global_palette().map(|p| PairedPalette::new(p, None));
+    let mut usable = 0;
+    while let Some(frame) = decoder.read_next_frame()? {
+        let mut frame = frame.clone();
+        remap(&mut frame, global.as_ref())?;
+        let skip = skipped_pair(&frame);
+        usable += frame.buffer.iter().filter(|&&px| Some(px >> 1) != skip).count() as u64;
+    }
+    Ok(usable)
+}
+
+/// Embeds into an animated (or still) GIF, frame by frame. Timing, disposal, frame
+/// regions and the loop count are kept; interlaced frames are written progressive.
+pub fn embed(container: &Path, output: &Path, byte_stream: &mut ByteStream<File>, on_progress: impl Fn(f32)) -> Result<()> {
+    let mut decoder = open(container)?;
+    let global = decoder.global_palette().map(|p| PairedPalette::new(p, None));
+    let empty = Vec::new();
+    let global_palette = global.as_ref().map_or(&empty, |g| &g.palette);
+    let mut encoder = Encoder::new(File::create(output)?, decoder.width(), decoder.height(), global_palette)?;
+    let frames = frame_count(container)?;
+
+    // Byte being spread over index LSBs, and bits left in it
+    let mut pending = (0u8, 0u8);
+    let mut index = 0;
+    while let Some(frame) = decoder.read_next_frame()? {
+        let mut frame = frame.clone();
+        if index == 0 {
+            // The loop count is only known once the extensions before the first frame are read
+            encoder.set_repeat(decoder.repeat())?;
+        }
+        remap(&mut frame, global.as_ref())?;
+        let skip = skipped_pair(&frame);
+        for px in frame.buffer.to_mut().iter_mut() {
+            if Some(*px >> 1) == skip {
+                continue;
+            }
+            if pending.1 == 0 {
+                pending = (byte_stream.next_byte(), 8);
+            }
+            *px |= pending.0 & 1;
+            pending = (pending.0 >> 1, pending.1 - 1);
+        }
+        encoder.write_frame(&frame)?;
+        index += 1;
+        on_progress(index as f32 / frames as f32);
+    }
+    Ok(())
+}
+
+/// Payload bits of an encoded GIF, one `Vec` per frame.
+pub struct GifBits {
+    decoder: Decoder<File>,
+}
+
+impl GifBits {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self { decoder: open(path)? })
+    }
+}
+
+impl Iterator for GifBits {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.decoder.read_next_frame() {
+            Ok(Some(frame)) => {
+                let skip = skipped_pair(frame);
+                Some(Ok(frame.buffer.iter().filter(|&&px| Some(px >> 1) != skip).map(|px| px & 1).collect()))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// Writes the GIF back with every payload LSB cleared. Pixels in pairs of identical colors
+/// look exactly as before; with more than 128 colors some pixels show their pair partner.
+pub fn restore(input: &Path, output: &Path, on_progress: impl Fn(f32)) -> Result<()> {
+    let mut decoder = open(input)?;
+    let global_palette = decoder.global_palette().map(|p| p.to_vec()).unwrap_or_default();
+    let mut encoder = Encoder::new(File::create(output)?, decoder.width(), decoder.height(), &global_palette)?;
+    let frames = frame_count(input)?;
+    let mut index = 0;
+    while let Some(frame) = decoder.read_next_frame()? {
+        let mut frame = frame.clone();
+        if index == 0 {
+            encoder.set_repeat(decoder.repeat())?;
+        }
+        let skip = skipped_pair(&frame);
+        for px in frame.buffer.to_mut().iter_mut() {
+            if Some(*px >> 1) != skip {
+                *px &= !1;
+            }
+        }
+        encoder.write_frame(&frame)?;
+        index += 1;
+        on_progress(index as f32 / frames as f32);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gif::{DisposalMethod, Repeat};
+    use std::borrow::Cow;
+    use tempfile::tempdir;
+
+    /// A full 256-color table (more than 128 colors, so entries have to share pairs):
+    /// 128 twins one blue step apart, far from all other colors.
+    fn global_palette() -> Vec<u8> {
+        let mut colors = Vec::new();
+        for i in 0..128u8 {
+            let c = [i * 2, 255 - i * 2, i.wrapping_mul(37) & 0xFE];
+            colors.extend_from_slice(&c);
+            colors.extend_from_slice(&[c[0], c[1], c[2] + 1]);
+        }
+        colors
+    }
+
+    fn write_gif(path: &Path) -> Result<()> {
+        let global = global_palette();
+        let mut encoder = Encoder::new(File::create(path)?, 24, 18, &global)?;
+        encoder.set_repeat(Repeat::Finite(3))?;
+
+        // Full canvas, global palette with a transparent index
+        let buffer: Vec<u8> = (0..24 * 18).map(|i| (i * 7 % 256) as u8).collect();
+        encoder.write_frame(&Frame {
+            width: 24, height: 18, buffer: Cow::Owned(buffer), transparent: Some(150),
+            delay: 7, dispose: DisposalMethod::Keep, ..Default::default()
+        })?;
+        // A smaller region with a local palette of 50 colors and its own transparent index
+        let local: Vec<u8> = (0..50u8).flat_map(|i| [i * 5, 3, 250 - i * 5]).collect();
+        let buffer: Vec<u8> = (0..11 * 6).map(|i| (i * 3 % 50) as u8).collect();
+        encoder.write_frame(&Frame {
+            width: 11, height: 6, left: 5, top: 9, buffer: Cow::Owned(buffer), palette: Some(local), transparent: Some(4),
+            delay: 20, dispose: DisposalMethod::Background, ..Default::default()
+        })?;
+        // No transparency, restore-to-previous
+        let buffer: Vec<u8> = (0..8 * 8).map(|i| (255 - i * 3) as u8).collect();
+        encoder.write_frame(&Frame {
+            width: 8, height: 8, left: 16, top: 0, buffer: Cow::Owned(buffer),
+            delay: 3, dispose: DisposalMethod::Previous, ..Default::default()
+        })?;
+        Ok(())
+    }
+
+    /// Frames decoded to RGBA (each over its own region), with their metadata.
+    fn frames(path: &Path) -> Result<(Repeat, Vec<Frame<'static>>)> {
+        let mut options = DecodeOptions::new();
+        options.set_color_output(ColorOutput::RGBA);
+        let mut decoder = options.read_info(File::open(path)?)?;
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame()? {
+            frames.push(frame.clone());
+        }
+        Ok((decoder.repeat(), frames))
+    }
+
+    #[test]
+    fn test_paired_palette() {
+        let global = global_palette();
+        let paired = PairedPalette::new(&global, None);
+        assert_eq!(paired.palette.len(), 256 * 3);
+        for (i, color) in global.chunks_exact(3).enumerate() {
+            let at = paired.map[i] as usize * 3;
+            assert_eq!(&paired.palette[at..at + 3], color);
+            // Each color shares its pair with itself or its near twin
+            let partner = (paired.map[i] as usize ^ 1) * 3;
+            let partner = &paired.palette[partner..partner + 3];
+            assert!(color.iter().zip(partner).all(|(a, b)| a.abs_diff(*b) <= 1), "{}", i);
+        }
+
+        // Small palettes: every color gets a pair of its own, the transparent one included
+        let small: Vec<u8> = (0..50u8).flat_map(|i| [i, i, i]).collect();
+        let paired = PairedPalette::new(&small, Some(4));
+        assert!((0..50).all(|i| paired.map[i] == i as u8 * 2));
+        assert_eq!(paired.palette.len(), 100 * 3);
+        assert!(paired.palette.chunks_exact(6).zip(small.chunks_exact(3)).all(|(pair, color)| pair[..3] == *color && pair[3..] == *color));
+    }
+
+    #[test]
+    fn test_embed_extract_restore() -> Result<()> {
+        let dir = tempdir()?;
+        let container = dir.path().join("container.gif");
+        let output = dir.path().join("output.gif");
+        let restored = dir.path().join("restored.gif");
+        write_gif(&container)?;
+
+        let usable = usable_pixels(&container)?;
+        // All pixels except those of the two transparent indices' pairs
+        let (_, original) = frames(&container)?;
+        assert!(usable < (24 * 18 + 11 * 6 + 8 * 8) as u64 && usable > 400);
+        let stream: Vec<u8> = (0..usable / 8).map(|i| (i * 37 + 11) as u8).collect();
+        let payload = dir.path().join("stream.bin");
+        std::fs::write(&payload, &stream)?;
+        let mut byte_stream = ByteStream::new(Vec::new(), File::open(&payload)?, 0, None, false, 4096, stream.len() as u64);
+        embed(&container, &output, &mut byte_stream, |_| {})?;
+
+        // Every usable pixel carries one bit, low bit first
+        let bits: Vec<u8> = GifBits::open(&output)?.collect::<Result<Vec<_>>>()?.concat();
+        assert_eq!(bits.len() as u64, usable);
+        let bytes: Vec<u8> = bits.chunks_exact(8).map(|b| b.iter().rev().fold(0, |acc, &bit| (acc << 1) | bit)).collect();
+        assert_eq!(bytes, stream);
+        assert_eq!(usable_pixels(&output)?, usable);
+
+        // Loop count, regions, timing, disposal and transparency are kept; colors move at most
+        // to their near twin
+        let (repeat, embedded) = frames(&output)?;
+        assert_eq!(repeat, Repeat::Finite(3));
+        assert_eq!(embedded.len(), original.len());
+        for (a, b) in original.iter().zip(&embedded) {
+            assert_eq!((a.left, a.top, a.width, a.height, a.delay, a.dispose), (b.left, b.top, b.width, b.height, b.delay, b.dispose));
+            assert_eq!(a.transparent.is_some(), b.transparent.is_some());
+            for (pa, pb) in a.buffer.chunks_exact(4).zip(b.buffer.chunks_exact(4)) {
+                assert_eq!(pa[3], pb[3]);
+                assert!(pa.iter().zip(pb).all(|(x, y)| x.abs_diff(*y) <= 1));
+            }
+        }
+        // The local palette has room for a pair per color, so that frame is exact
+        assert_eq!(original[1].buffer, embedded[1].buffer);
+
+        restore(&output, &restored, |_| {})?;
+        assert!(GifBits::open(&restored)?.collect::<Result<Vec<_>>>()?.concat().iter().all(|&b| b == 0));
+        let (repeat, restored_frames) = frames(&restored)?;
+        assert_eq!(repeat, Repeat::Finite(3));
+        for (a, b) in original.iter().zip(&restored_frames) {
+            assert_eq!((a.left, a.top, a.width, a.height, a.delay, a.dispose), (b.left, b.top, b.width, b.height, b.delay, b.dispose));
+            assert!(a.buffer.iter().zip(b.buffer.iter()).all(|(x, y)| x.abs_diff(*y) <= 1));
+        }
+        Ok(())
+    }
+}
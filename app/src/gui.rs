@@ -5,9 +5,19 @@ use std::{path::PathBuf, thread, fs};
 use slint::Model;
 use serde::Deserialize;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use crate::plugin_loader::PluginManager;
 use tracing_subscriber::fmt::format::FmtSpan;
 use crate::gui_logging_snippet::ChannelWriter;
+use crate::header::{ChannelMode, FitPolicy};
+use crate::stream_encoder::{AlphaUse, EncodeOptions};
+use crate::stream_decoder::DecodeOptions;
+use crate::converter::{self, LosslessFormat};
+use crate::pcm::PcmFormat;
+use crate::audio::{AudioFormat, AudioOutput};
+use crate::raster::RasterFormat;
+use crate::carrier::CarrierFormat;
+use crate::spectrogram::SpectrogramOptions;
 
 slint::include_modules!();
 
@@ -28,10 +38,13 @@ enum WorkerMessage {
         buffer_size_kb: usize,
         is_std_mode: bool, 
         is_sequence_mode: bool,
+        is_carrier_mode: bool,
+        options: EncodeOptions,
     },
     DecodeStream {
         input_path: PathBuf,
         output_path: PathBuf,
+        container_out: Option<PathBuf>,
         key_path: Option<PathBuf>,
         buffer_size_kb: usize,
         preset_ext: Option<String>,
@@ -50,6 +63,12 @@ enum WorkerMessage {
         encrypt: bool,
         buffer_size_kb: usize,
     },
+    Spectrogram {
+        image: PathBuf,
+        audio: PathBuf,
+        output: PathBuf,
+        options: SpectrogramOptions,
+    },
     BatchDecode {
         inputs: Vec<PathBuf>,
         key: Option<PathBuf>,
@@ -187,6 +206,17 @@ pub fn run() -> Result<(), PlatformError> {
             name: meta.name.into(),
             enabled,
             description: meta.description.into(),
+            compatible: true,
+            reason: "".into(),
+        });
+    }
+    for rejected in pm.get_rejected_plugins() {
+        ui_plugin_list.push(PluginItem {
+            name: rejected.name.clone().into(),
+            enabled: false,
+            description: "".into(),
+            compatible: false,
+            reason: rejected.reason.clone().into(),
         });
     }
     let settings = ui.global::<Settings>();
@@ -308,7 +338,7 @@ pub fn run() -> Result<(), PlatformError> {
     let ui_handle_clone = ui_handle.clone();
     logic.on_browse_input_voice(move || {
         let ui = ui_handle_clone.unwrap();
-        if let Some(path) = FileDialog::new().add_filter("Audio", &["wav", "mp3"]).pick_file() {
+        if let Some(path) = FileDialog::new().add_filter("Audio", &["wav", "mp3", "ogg", "oga", "opus"]).pick_file() {
             ui.global::<State>().set_input_voice_path(path.to_string_lossy().to_string().into());
             check_std_encode(&ui);
         }
@@ -374,6 +404,8 @@ pub fn run() -> Result<(), PlatformError> {
             buffer_size_kb: buffer_size,
             is_std_mode: true,
             is_sequence_mode: false,
+            is_carrier_mode: false,
+            options: EncodeOptions::default(),
         }).unwrap();
     });
 
@@ -427,10 +459,13 @@ pub fn run() -> Result<(), PlatformError> {
         let key_in = if key_str.is_empty() { None } else { Some(PathBuf::from(key_str)) };
         
         let ext = input.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase();
-        let output_path = if ext == "png" {
-            PathBuf::from(state.get_decode_output_voice_path().to_string())
+        let voice_out = PathBuf::from(state.get_decode_output_voice_path().to_string());
+        let picture_out = PathBuf::from(state.get_decode_output_picture_path().to_string());
+        // Payload goes to one output, the restored container to the other
+        let (output_path, container_out) = if ext == "png" {
+            (voice_out, picture_out)
         } else {
-            PathBuf::from(state.get_decode_output_picture_path().to_string())
+            (picture_out, voice_out)
         };
         
         let settings = ui.global::<Settings>();
@@ -441,6 +476,7 @@ pub fn run() -> Result<(), PlatformError> {
         worker_tx_dec.send(WorkerMessage::DecodeStream {
             input_path: input,
             output_path,
+            container_out: Some(container_out),
             key_path: key_in,
             buffer_size_kb: buffer_size,
             preset_ext: None,
@@ -451,27 +487,41 @@ pub fn run() -> Result<(), PlatformError> {
 
     // ... Uni Encode ...
     let ui_handle_clone = ui_handle.clone();
+    let pm_cap = pm.clone();
     logic.on_browse_uni_payload(move || {
         let ui = ui_handle_clone.unwrap();
         if let Some(path) = FileDialog::new().set_title("Select Payload").pick_file() {
             ui.global::<State>().set_uni_payload_path(path.to_string_lossy().to_string().into());
             check_uni_encode(&ui);
+            refresh_uni_capacity(&ui, &pm_cap);
         }
     });
 
     let ui_handle_clone = ui_handle.clone();
+    let pm_cap = pm.clone();
     logic.on_browse_uni_container(move || {
         let ui = ui_handle_clone.unwrap();
         let state = ui.global::<State>();
         if state.get_uni_enc_sequence_mode() {
              if let Some(path) = FileDialog::new().set_title("Select Container Folder").pick_folder() {
+                 state.set_uni_container_lossy(false);
+                 state.set_uni_container_png(false);
+                 state.set_uni_container_audio(false);
+                 state.set_uni_container_carrier(false);
                  state.set_uni_container_path(path.to_string_lossy().to_string().into());
                  check_uni_encode(&ui);
+                 refresh_uni_capacity(&ui, &pm_cap);
              }
         } else {
-            if let Some(path) = FileDialog::new().add_filter("Container", &["png", "wav", "jpg", "jpeg", "mp3"]).pick_file() {
+            if let Some(path) = FileDialog::new().add_filter("Container", &["png", "apng", "gif", "bmp", "tif", "tiff", "webp", "wav", "flac", "aif", "aiff", "aifc", "au", "snd", "jpg", "jpeg", "mp3", "ogg", "oga", "opus"]).pick_file() {
+                let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("");
+                state.set_uni_container_lossy(converter::is_lossy_audio(ext));
+                state.set_uni_container_png(matches!(ext.to_lowercase().as_str(), "png" | "apng"));
+                state.set_uni_container_audio(PcmFormat::from_ext(ext).is_some() || ext.eq_ignore_ascii_case("flac") || converter::is_lossy_audio(ext));
+                state.set_uni_container_carrier(CarrierFormat::hint_for(ext).is_some());
                 state.set_uni_container_path(path.to_string_lossy().to_string().into());
                 check_uni_encode(&ui);
+                refresh_uni_capacity(&ui, &pm_cap);
             }
         }
     });
@@ -496,7 +546,19 @@ pub fn run() -> Result<(), PlatformError> {
                  check_uni_encode(&ui);
              }
         } else {
-            let ext = if container.to_lowercase().ends_with("wav") || container.to_lowercase().ends_with("mp3") { "wav" } else { "png" };
+            let container_ext = std::path::Path::new(&container).extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase();
+            // Audio, BMP/TIFF/WebP, JPEG, GIF and APNG containers keep their own format
+            let keeps_format = matches!(container_ext.as_str(), "flac" | "jpg" | "jpeg" | "gif" | "apng")
+                || PcmFormat::from_ext(&container_ext).is_some()
+                || RasterFormat::from_ext(&container_ext).is_some();
+            let ext = if state.get_uni_container_lossy() {
+                LosslessFormat::from_index(state.get_uni_lossless_index()).extension()
+            } else if keeps_format { container_ext.as_str() } else { "png" };
+            // Audio may be written as WAV or FLAC instead
+            let ext = match AudioFormat::from_ext(ext) {
+                Some(format) if !state.get_uni_carrier_mode() => AudioOutput::from_index(state.get_uni_audio_output_index()).format(format).extension(),
+                _ => ext,
+            };
             if let Some(path) = FileDialog::new().add_filter(ext, &[ext]).save_file() {
                 state.set_uni_output_path(path.to_string_lossy().to_string().into());
                 check_uni_encode(&ui);
@@ -504,6 +566,13 @@ pub fn run() -> Result<(), PlatformError> {
         }
     });
 
+    let ui_handle_clone = ui_handle.clone();
+    let pm_cap = pm.clone();
+    logic.on_refresh_uni_capacity(move || {
+        let ui = ui_handle_clone.unwrap();
+        refresh_uni_capacity(&ui, &pm_cap);
+    });
+
     let ui_handle_clone = ui_handle.clone();
     let worker_tx_uni_enc = worker_tx.clone();
     logic.on_request_uni_encode(move || {
@@ -522,9 +591,19 @@ pub fn run() -> Result<(), PlatformError> {
         let output: PathBuf = state.get_uni_output_path().to_string().into();
         let encrypt = state.get_uni_use_encryption();
         let is_seq = state.get_uni_enc_sequence_mode();
+        let is_carrier = state.get_uni_container_carrier() && state.get_uni_carrier_mode();
         let buffer_size = settings.get_stream_buffer_size() as usize;
         
-        tracing::info!("Requesting Uni Encode: Payload={:?}, Container={:?}, Seq={}", payload, container, is_seq);
+        // Fit Index: 0=Fail, 1=Pad Silence, 2=Pad Loop, 3=Upscale, 4=Tile
+        let options = EncodeOptions {
+            fit: FitPolicy::from_u8(state.get_uni_fit_index() as u8),
+            lossless: LosslessFormat::from_index(state.get_uni_lossless_index()),
+            alpha: AlphaUse::from_index(state.get_uni_alpha_index()),
+            channels: ChannelMode::from_u8(state.get_uni_channel_index() as u8),
+            output: AudioOutput::from_index(state.get_uni_audio_output_index()),
+        };
+        
+        tracing::info!("Requesting Uni Encode: Payload={:?}, Container={:?}, Seq={}, Fit={:?}", payload, container, is_seq, options.fit);
 
         worker_tx_uni_enc.send(WorkerMessage::EncodeStream {
             payload_path: payload,
@@ -535,6 +614,57 @@ pub fn run() -> Result<(), PlatformError> {
             buffer_size_kb: buffer_size,
             is_std_mode: false,
             is_sequence_mode: is_seq,
+            is_carrier_mode: is_carrier,
+            options,
+        }).unwrap();
+    });
+
+    // === SPECTROGRAM ART ===
+    let ui_handle_clone = ui_handle.clone();
+    logic.on_browse_spec_image(move || {
+        let ui = ui_handle_clone.unwrap();
+        if let Some(path) = FileDialog::new().add_filter("Image", &["png", "jpg", "jpeg", "bmp", "gif", "webp", "tif", "tiff"]).pick_file() {
+            ui.global::<State>().set_spec_image_path(path.to_string_lossy().to_string().into());
+            check_spec_render(&ui);
+        }
+    });
+
+    let ui_handle_clone = ui_handle.clone();
+    logic.on_browse_spec_audio(move || {
+        let ui = ui_handle_clone.unwrap();
+        if let Some(path) = FileDialog::new().add_filter("Audio", &["wav", "mp3", "ogg", "oga", "opus"]).pick_file() {
+            ui.global::<State>().set_spec_audio_path(path.to_string_lossy().to_string().into());
+            check_spec_render(&ui);
+        }
+    });
+
+    let ui_handle_clone = ui_handle.clone();
+    logic.on_browse_spec_output(move || {
+        let ui = ui_handle_clone.unwrap();
+        if let Some(path) = FileDialog::new().add_filter("wav", &["wav"]).save_file() {
+            ui.global::<State>().set_spec_output_path(path.with_extension("wav").to_string_lossy().to_string().into());
+            check_spec_render(&ui);
+        }
+    });
+
+    let ui_handle_clone = ui_handle.clone();
+    let worker_tx_spec = worker_tx.clone();
+    logic.on_request_spec_render(move || {
+        let ui = ui_handle_clone.unwrap();
+        let state = ui.global::<State>();
+        let options = match spectrogram_options(&state) {
+            Ok(options) => options,
+            Err(e) => {
+                state.set_status_text(format!("Error: {}", e).into());
+                return;
+            }
+        };
+        tracing::info!("Requesting Spectrogram: {:?}", options);
+        worker_tx_spec.send(WorkerMessage::Spectrogram {
+            image: state.get_spec_image_path().to_string().into(),
+            audio: state.get_spec_audio_path().to_string().into(),
+            output: state.get_spec_output_path().to_string().into(),
+            options,
         }).unwrap();
     });
 
@@ -547,11 +677,12 @@ pub fn run() -> Result<(), PlatformError> {
         if state.get_uni_dec_sequence_mode() {
              if let Some(path) = FileDialog::new().set_title("Select Sequence Folder").pick_folder() {
                 state.set_uni_decode_input_path(path.to_string_lossy().to_string().into());
-                state.set_uni_decode_analyzed(true);
+                state.set_uni_decode_analyzed(false);
                 check_uni_decode(&ui);
+                worker_tx_analyze_uni.send(WorkerMessage::Analyze { input: path, mode: "Universal".into() }).unwrap();
              }
         } else {
-            if let Some(path) = FileDialog::new().add_filter("Encoded", &["wav", "png"]).pick_file() {
+            if let Some(path) = FileDialog::new().add_filter("Encoded", &["wav", "png", "apng", "gif", "bmp", "tif", "tiff", "webp", "jpg", "jpeg", "flac", "aif", "aiff", "aifc", "au", "snd"]).pick_file() {
                 state.set_uni_decode_input_path(path.to_string_lossy().to_string().into());
                 state.set_uni_decode_analyzed(false);
                 check_uni_decode(&ui);
@@ -596,7 +727,12 @@ pub fn run() -> Result<(), PlatformError> {
     let ui_handle_clone = ui_handle.clone();
     logic.on_browse_uni_decode_container_out(move || {
         let ui = ui_handle_clone.unwrap();
-        if let Some(path) = FileDialog::new().set_title("Save Container As...").save_file() {
+        let dialog = if ui.global::<State>().get_uni_dec_sequence_mode() {
+            FileDialog::new().set_title("Select Container Output Folder").pick_folder()
+        } else {
+            FileDialog::new().set_title("Save Container As...").save_file()
+        };
+        if let Some(path) = dialog {
             ui.global::<State>().set_uni_decode_container_out(path.to_string_lossy().to_string().into());
         }
     });
@@ -616,6 +752,8 @@ pub fn run() -> Result<(), PlatformError> {
         let key_str = state.get_uni_decode_key_path().to_string();
         let key = if key_str.is_empty() { None } else { Some(PathBuf::from(key_str)) };
         let payload_out: PathBuf = state.get_uni_decode_payload_out().to_string().into();
+        let container_out_str = state.get_uni_decode_container_out().to_string();
+        let container_out = if container_out_str.is_empty() { None } else { Some(PathBuf::from(container_out_str)) };
         let is_seq = state.get_uni_dec_sequence_mode();
         
         let preset_idx = state.get_uni_decode_preset_index();
@@ -644,6 +782,7 @@ pub fn run() -> Result<(), PlatformError> {
         worker_tx_uni_dec.send(WorkerMessage::DecodeStream {
             input_path: input,
             output_path: payload_out,
+            container_out,
             key_path: key,
             buffer_size_kb: buffer_size,
             preset_ext: force_ext,
@@ -839,7 +978,7 @@ fn worker_thread_main(worker_rx: Receiver<WorkerMessage>, ui_tx: Sender<UIMessag
         };
 
         match message {
-            WorkerMessage::EncodeStream { payload_path, container_path, key_path, output_path, encrypt, buffer_size_kb, is_std_mode, is_sequence_mode } => {
+            WorkerMessage::EncodeStream { payload_path, container_path, key_path, output_path, encrypt, buffer_size_kb, is_std_mode, is_sequence_mode, is_carrier_mode, options } => {
                 let mode_str = if is_std_mode { "Std" } else { "Uni" };
                 ui_tx.send(UIMessage::Status(format!("Encoding ({} Stream)...", mode_str).into())).unwrap();
                 
@@ -848,6 +987,10 @@ fn worker_thread_main(worker_rx: Receiver<WorkerMessage>, ui_tx: Sender<UIMessag
                 let container_ext = if is_sequence_mode { "seq_dir".to_string() } else {
                     container_path.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase()
                 };
+                let container_ext = match CarrierFormat::hint_for(&container_ext) {
+                    Some(hint) if is_carrier_mode => hint.to_string(),
+                    _ => container_ext,
+                };
 
                 match fs::File::open(&payload_path) {
                     Ok(mut payload_file) => {
@@ -860,9 +1003,13 @@ fn worker_thread_main(worker_rx: Receiver<WorkerMessage>, ui_tx: Sender<UIMessag
                             payload_ext.as_deref(),
                             buffer_size_kb,
                             &plugins,
-                            container_ext,
+                            container_ext.clone(),
+                            &options,
                             on_progress
                         ) {
+                            Ok(_) if converter::is_lossy_audio(&container_ext) => ui_tx.send(UIMessage::Status(format!(
+                                "{} Encoding Complete! The {} container was converted to lossless {}.",
+                                mode_str, container_ext.to_uppercase(), options.lossless.extension().to_uppercase()).into())).unwrap(),
                             Ok(_) => ui_tx.send(UIMessage::Status(format!("{} Encoding Complete!", mode_str).into())).unwrap(),
                             Err(e) => {
                                 tracing::error!("Encode error: {}", e);
@@ -873,6 +1020,71 @@ fn worker_thread_main(worker_rx: Receiver<WorkerMessage>, ui_tx: Sender<UIMessag
                     Err(e) => ui_tx.send(UIMessage::Status(format!("Error opening payload: {}", e).into())).unwrap(),
                 }
             },
+            WorkerMessage::DecodeStream { input_path, output_path, container_out, key_path, buffer_size_kb, preset_ext, resize_factor, is_sequence_mode } => {
+                ui_tx.send(UIMessage::Status("Decoding (Stream)...".into())).unwrap();
+                
+                let input_ext = if is_sequence_mode { "seq_dir".to_string() } else {
+                    input_path.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase()
+                };
+
+                match crate::stream_decoder::decode_stream(
+                    &input_path,
+                    &output_path,
+                    container_out.as_ref(),
+                    key_path.as_ref(),
+                    buffer_size_kb,
+                    &plugins,
+                    input_ext,
+                    &DecodeOptions { preset_ext, resize_factor },
+                    on_progress
+                ) {
+                    Ok(info) => {
+                        tracing::info!("Decoded payload ({}, stored ext: {:?}) to {:?}", info.detected.mime(), info.stored_ext, info.path);
+                        let current_ext = info.path.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase();
+                        let suggested = info.suggested_ext();
+                        let status = if let Some(m) = info.mismatch() {
+                            tracing::warn!("Payload type mismatch: {}", m);
+                            format!("Decoding Complete! Warning: {}", m)
+                        } else if !suggested.is_empty() && suggested != current_ext {
+                            format!("Decoding Complete! Suggested extension: .{}", suggested)
+                        } else {
+                            "Decoding Complete!".to_string()
+                        };
+                        ui_tx.send(UIMessage::Status(status.into())).unwrap();
+                    },
+                    Err(e) => {
+                        tracing::error!("Decode error: {}", e);
+                        ui_tx.send(UIMessage::Status(format!("Error: {}", e).into())).unwrap();
+                    },
+                }
+            },
+            WorkerMessage::Spectrogram { image, audio, output, options } => {
+                ui_tx.send(UIMessage::Status("Drawing spectrogram...".into())).unwrap();
+                match crate::spectrogram::render(&image, &audio, &output, &options, on_progress) {
+                    Ok(()) => ui_tx.send(UIMessage::Status("Spectrogram Complete!".into())).unwrap(),
+                    Err(e) => {
+                        tracing::error!("Spectrogram error: {}", e);
+                        ui_tx.send(UIMessage::Status(format!("Error: {}", e).into())).unwrap();
+                    },
+                }
+            },
+            WorkerMessage::Analyze { input, mode } => {
+                // Sequence folders are the only directory inputs
+                let input_ext = if input.is_dir() { "seq_dir".to_string() } else {
+                    input.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase()
+                };
+                match crate::stream_decoder::analyze(&input, &plugins, &input_ext) {
+                    Ok(header) => {
+                        let encrypted = (header.flags & 0x01) != 0;
+                        tracing::info!("Analyzed {:?}: {} bytes, encrypted: {}", input, header.payload_len, encrypted);
+                        ui_tx.send(UIMessage::AnalysisResult { encrypted, mode }).unwrap();
+                    },
+                    Err(e) => {
+                        tracing::error!("Analyze error: {}", e);
+                        ui_tx.send(UIMessage::Status(format!("Check Error: {}", e).into())).unwrap();
+                    },
+                }
+            },
             // ... (Other cases remain similar, just add tracing::error! on Err) ...
             // Truncated for brevity as requested logic is implemented
             _ => {}
@@ -900,6 +1112,130 @@ fn check_uni_encode(ui: &AppWindow) {
     state.set_uni_encode_enabled(enabled);
 }
 
+fn check_spec_render(ui: &AppWindow) {
+    let state = ui.global::<State>();
+    let enabled = !state.get_spec_image_path().is_empty() && !state.get_spec_audio_path().is_empty() && !state.get_spec_output_path().is_empty();
+    state.set_spec_enabled(enabled);
+}
+
+/// Reads the Spectrogram Art fields; the text boxes are parsed here.
+fn spectrogram_options(state: &State) -> Result<SpectrogramOptions, String> {
+    let number = |text: SharedString, name: &str| text.trim().parse::<f32>().map_err(|_| format!("{} must be a number.", name));
+    Ok(SpectrogramOptions {
+        low_hz: number(state.get_spec_low_hz(), "Low frequency")?,
+        high_hz: number(state.get_spec_high_hz(), "High frequency")?,
+        start: number(state.get_spec_start(), "Start")?,
+        duration: number(state.get_spec_duration(), "Length")?,
+        intensity: state.get_spec_intensity() / 100.0,
+    })
+}
+
+/// Bumped by every capacity refresh; a probe only applies its result if no newer one started.
+static CAPACITY_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Recomputes the Encode tab capacity readout off the UI thread.
+fn refresh_uni_capacity(ui: &AppWindow, plugins: &Arc<Mutex<PluginManager>>) {
+    let generation = CAPACITY_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
+    let state = ui.global::<State>();
+    let container = PathBuf::from(state.get_uni_container_path().to_string());
+    let payload_str = state.get_uni_payload_path().to_string();
+    let payload = if payload_str.is_empty() { None } else { Some(PathBuf::from(payload_str)) };
+    let container_ext = if state.get_uni_enc_sequence_mode() { "seq_dir".to_string() } else {
+        container.extension().and_then(|s| s.to_str()).unwrap_or("").to_lowercase()
+    };
+    let container_ext = match CarrierFormat::hint_for(&container_ext) {
+        Some(hint) if state.get_uni_carrier_mode() => hint.to_string(),
+        _ => container_ext,
+    };
+
+    state.set_uni_capacity_known(false);
+    state.set_uni_capacity_over(false);
+    if container.as_os_str().is_empty() {
+        check_uni_encode(ui);
+        return;
+    }
+
+    let lossless = state.get_uni_container_lossy().then(|| LosslessFormat::from_index(state.get_uni_lossless_index()));
+    let alpha = if matches!(container_ext.as_str(), "png" | "apng") { AlphaUse::from_index(state.get_uni_alpha_index()) } else { AlphaUse::Off };
+    let (channel_mode, output) = if state.get_uni_container_audio() && !state.get_uni_carrier_mode() {
+        (ChannelMode::from_u8(state.get_uni_channel_index() as u8), AudioOutput::from_index(state.get_uni_audio_output_index()))
+    } else {
+        (ChannelMode::All, AudioOutput::Auto)
+    };
+
+    let ui_handle = ui.as_weak();
+    let plugins = plugins.clone();
+    thread::spawn(move || {
+        let capacity = match lossless {
+            Some(target) => crate::capacity::lossy_capacity(&container, target, output, channel_mode).map(Some),
+            None if (channel_mode != ChannelMode::All || output != AudioOutput::Auto)
+                && plugins.lock().unwrap().get_encoder_by_ext(&container_ext).is_none() => {
+                match AudioFormat::from_ext(&container_ext) {
+                    Some(format) => crate::capacity::audio_capacity(&container, format, output, channel_mode).map(Some),
+                    None => crate::capacity::container_capacity(&container, &container_ext, &plugins),
+                }
+            }
+            None => match alpha.applies(&container) {
+                Ok(true) => crate::capacity::png_alpha_capacity(&container).map(Some),
+                Ok(false) => crate::capacity::container_capacity(&container, &container_ext, &plugins),
+                Err(e) => Err(e),
+            },
+        };
+        let capacity = match capacity {
+            Ok(Some(c)) => c,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Capacity check failed: {}", e);
+                return;
+            }
+        };
+        let estimate = payload.as_deref().and_then(|p| crate::capacity::estimate_compressed_len(p).ok());
+
+        let mut text = if capacity.raw_bytes == u64::MAX {
+            // Carrier chunks grow with the payload
+            format!("Unlimited ({}, header {} B)", capacity.mode, capacity.header_bytes)
+        } else {
+            format!("{} usable / {} raw ({}, header {} B)",
+                format_bytes(capacity.usable_bytes()), format_bytes(capacity.raw_bytes), capacity.mode, capacity.header_bytes)
+        };
+        let (fill, over) = match estimate {
+            Some(len) => {
+                let fill = capacity.fill_ratio(len);
+                text.push_str(&format!(" | Payload ~{} ({:.1}%)", format_bytes(len), fill * 100.0));
+                (fill, !capacity.fits(len))
+            }
+            None => (0.0, false),
+        };
+        tracing::info!("Capacity: {}", text);
+
+        let _ = slint::invoke_from_event_loop(move || {
+            // Probes finish in any order (a JPEG or MP3 pass is slow); stale ones are dropped
+            if CAPACITY_GENERATION.load(Ordering::SeqCst) != generation {
+                return;
+            }
+            if let Some(ui) = ui_handle.upgrade() {
+                let state = ui.global::<State>();
+                state.set_uni_capacity_text(text.into());
+                state.set_uni_capacity_fill(fill);
+                state.set_uni_capacity_over(over);
+                state.set_uni_capacity_known(true);
+                check_uni_encode(&ui);
+            }
+        });
+    });
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} {}", bytes, UNITS[0]) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
+
 fn check_uni_decode(ui: &AppWindow) {
     let state = ui.global::<State>();
     let ready = if state.get_uni_dec_sequence_mode() { 
@@ -1,20 +1,129 @@
 use anyhow::Result;
 use std::convert::TryInto;
+use crate::sniff::ContentType;
 
 pub const HEADER_SIZE_SAMPLES: usize = 64; // 64 samples = 128 bytes (LSB 16-bit)
 pub const HEADER_SIZE_BYTES: usize = HEADER_SIZE_SAMPLES * 2;
 pub const MAGIC: &[u8; 4] = b"SPNG";
-pub const VERSION: u8 = 1;
+// v2: container fit info in the previously zero-padded area (v1 files read as "no fit")
+// v3: sniffed payload content type (older files read as unknown)
+// v4: `FLAG_PNG_ALPHA`
+// v5: audio channel mode (older files read as all channels)
+pub const VERSION: u8 = 5;
+
+/// Flag bit: the PNG alpha channel carries payload too, from `PNG_ALPHA_START` on.
+pub const FLAG_PNG_ALPHA: u8 = 0x04;
+/// First pixel whose alpha carries payload. The header itself always sits in the RGB
+/// channels, so the decoder knows the flag before it reaches an alpha byte.
+pub const PNG_ALPHA_START: u64 = HEADER_SIZE_BYTES.div_ceil(3) as u64;
+
+/// How the encoder made a too-small container fit the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FitPolicy {
+    /// Refuse to encode (original behaviour).
+    #[default]
+    Fail = 0,
+    /// Append digital silence to the audio.
+    PadSilence = 1,
+    /// Append the audio again from the start until it is long enough.
+    PadLoop = 2,
+    /// Resize the image up (Lanczos3).
+    Upscale = 3,
+    /// Repeat the image to the right and below.
+    Tile = 4,
+}
+
+impl FitPolicy {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Self::PadSilence,
+            2 => Self::PadLoop,
+            3 => Self::Upscale,
+            4 => Self::Tile,
+            _ => Self::Fail,
+        }
+    }
+
+    pub fn is_audio(self) -> bool {
+        matches!(self, Self::PadSilence | Self::PadLoop)
+    }
+
+    pub fn is_image(self) -> bool {
+        matches!(self, Self::Upscale | Self::Tile)
+    }
+}
+
+/// Which channels of an audio container carry payload. The header is embedded the same
+/// way as the payload, so channels left out stay untouched from the first sample on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelMode {
+    /// Every sample, interleaved (original behaviour).
+    #[default]
+    All = 0,
+    /// Only the first (left) channel.
+    Left = 1,
+    /// The side signal (left - right) of the first two channels; the mid signal is kept.
+    Side = 2,
+}
+
+impl ChannelMode {
+    pub fn from_u8(v: u8) -> Self {
+        match v {
+            1 => Self::Left,
+            2 => Self::Side,
+            _ => Self::All,
+        }
+    }
+
+    /// Whether samples of `channel` are changed by the embedding.
+    pub fn carries(self, channel: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::Left => channel == 0,
+            Self::Side => channel < 2,
+        }
+    }
+
+    /// Payload-carrying values in `samples` interleaved samples of `channels` channels.
+    pub fn carriers(self, samples: u64, channels: u16) -> u64 {
+        match self {
+            Self::All => samples,
+            Self::Left | Self::Side => samples / channels.max(1) as u64,
+        }
+    }
+
+    /// Frames needed for `carriers` payload-carrying values.
+    pub fn frames_for(self, carriers: u64, channels: u16) -> u64 {
+        match self {
+            Self::All => carriers.div_ceil(channels.max(1) as u64),
+            Self::Left | Self::Side => carriers,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::All => "all channels",
+            Self::Left => "left channel",
+            Self::Side => "side channel",
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Header {
     pub magic: [u8; 4],
     pub version: u8,
-    pub flags: u8, // Bit 0: Encrypted, Bit 1: Compressed (Always 1 for now)
+    pub flags: u8, // Bit 0: Encrypted, Bit 1: Compressed (Always 1 for now), Bit 2: PNG alpha
     pub payload_len: u64,
     pub timestamp: u64,
     pub hash: [u8; 32],
     pub extension: [u8; 8], // New field for file extension
+    pub fit_policy: FitPolicy,
+    pub original_width: u32,  // Image containers enlarged by `fit_policy`
+    pub original_height: u32,
+    pub original_frames: u64, // Audio containers padded by `fit_policy`
+    pub content_type: ContentType, // Detected from the payload's magic bytes
+    pub channel_mode: ChannelMode, // Audio containers only
 }
 
 impl Header {
@@ -38,6 +147,12 @@ impl Header {
             timestamp,
             hash,
             extension,
+            fit_policy: FitPolicy::Fail,
+            original_width: 0,
+            original_height: 0,
+            original_frames: 0,
+            content_type: ContentType::Unknown,
+            channel_mode: ChannelMode::All,
         }
     }
 
@@ -52,6 +167,12 @@ impl Header {
         bytes[14..22].copy_from_slice(&self.timestamp.to_le_bytes());
         bytes[22..54].copy_from_slice(&self.hash);
         bytes[54..62].copy_from_slice(&self.extension);
+        bytes[62] = self.fit_policy as u8;
+        bytes[63..67].copy_from_slice(&self.original_width.to_le_bytes());
+        bytes[67..71].copy_from_slice(&self.original_height.to_le_bytes());
+        bytes[71..79].copy_from_slice(&self.original_frames.to_le_bytes());
+        bytes[79] = self.content_type as u8;
+        bytes[80] = self.channel_mode as u8;
         // Remaining bytes are zero-padded by default
 
         // Convert bytes to u16 chunks (Little Endian)
@@ -83,6 +204,12 @@ impl Header {
         let timestamp = u64::from_le_bytes(bytes[14..22].try_into().unwrap());
         let hash: [u8; 32] = bytes[22..54].try_into().unwrap();
         let extension: [u8; 8] = bytes[54..62].try_into().unwrap();
+        let fit_policy = FitPolicy::from_u8(bytes[62]);
+        let original_width = u32::from_le_bytes(bytes[63..67].try_into().unwrap());
+        let original_height = u32::from_le_bytes(bytes[67..71].try_into().unwrap());
+        let original_frames = u64::from_le_bytes(bytes[71..79].try_into().unwrap());
+        let content_type = ContentType::from_u8(bytes[79]);
+        let channel_mode = ChannelMode::from_u8(bytes[80]);
 
         Ok(Self {
             magic,
@@ -92,6 +219,12 @@ impl Header {
             timestamp,
             hash,
             extension,
+            fit_policy,
+            original_width,
+            original_height,
+            original_frames,
+            content_type,
+            channel_mode,
         })
     }
 
@@ -0,0 +1,880 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+/// Quantized coefficients of one 8x8 block in zigzag order (index 0 is DC).
+pub type Block = [i16; 64];
+
+/// True for coefficients that carry a payload bit: AC values with |v| >= 2.
+/// Changing the LSB of such a value keeps it non-zero and in the same magnitude
+/// category, so the Huffman symbols, the quantization and the set of usable
+/// coefficients all stay the same. Zeros, ±1 and DC are never touched.
+pub fn is_usable(index: usize, value: i16) -> bool {
+    index > 0 && value.unsigned_abs() >= 2
+}
+
+/// Replaces the LSB of the magnitude, keeping the sign.
+pub fn set_lsb(value: i16, bit: u8) -> i16 {
+    let magnitude = ((value.unsigned_abs() & !1) | bit as u16) as i16;
+    if value < 0 { -magnitude } else { magnitude }
+}
+
+/// Canonical Huffman table from a DHT segment, usable for decoding and encoding.
+struct Huffman {
+    maxcode: [i32; 17],
+    mincode: [i32; 17],
+    valptr: [usize; 17],
+    values: Vec<u8>,
+    /// (code, length) per symbol; length 0 = not in the table.
+    codes: [(u16, u8); 256],
+}
+
+impl Huffman {
+    fn new(counts: &[u8], values: Vec<u8>) -> Self {
+        let mut table = Self { maxcode: [-1; 17], mincode: [0; 17], valptr: [0; 17], values, codes: [(0, 0); 256] };
+        let mut code = 0i32;
+        let mut k = 0;
+        for len in 1..=16 {
+            let n = counts[len - 1] as usize;
+            table.valptr[len] = k;
+            table.mincode[len] = code;
+            for _ in 0..n {
+                if let Some(&symbol) = table.values.get(k) {
+                    table.codes[symbol as usize] = (code as u16, len as u8);
+                }
+                code += 1;
+                k += 1;
+            }
+            if n > 0 {
+                table.maxcode[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+
+    fn decode<R: Read>(&self, bits: &mut BitReader<R>) -> Result<u8> {
+        let mut code = 0i32;
+        for len in 1..=16 {
+            code = (code << 1) | bits.bit()? as i32;
+            if code <= self.maxcode[len] {
+                let index = self.valptr[len] + (code - self.mincode[len]) as usize;
+                return self.values.get(index).copied().ok_or_else(|| anyhow!("Corrupt Huffman table."));
+            }
+        }
+        Err(anyhow!("Invalid Huffman code in JPEG data."))
+    }
+
+    fn encode<W: Write>(&self, out: &mut BitWriter<W>, symbol: u8) -> Result<()> {
+        match self.codes[symbol as usize] {
+            (_, 0) => Err(anyhow!("JPEG Huffman table has no code for symbol {:#04x}.", symbol)),
+            (code, len) => out.bits(code as u32, len as u32),
+        }
+    }
+}
+
+/// Entropy-coded data reader: removes byte stuffing and stops at markers.
+struct BitReader<R: Read> {
+    inner: R,
+    acc: u32,
+    nbits: u32,
+    /// Marker met inside the entropy-coded data; zeros are returned from then on.
+    marker: Option<u8>,
+    consumed: u64,
+}
+
+impl<R: Read> BitReader<R> {
+    fn raw(&mut self) -> Result<u8> {
+        let mut b = [0u8];
+        self.inner.read_exact(&mut b)?;
+        self.consumed += 1;
+        Ok(b[0])
+    }
+
+    fn raw_vec(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut v = vec![0u8; len];
+        self.inner.read_exact(&mut v)?;
+        self.consumed += len as u64;
+        Ok(v)
+    }
+
+    fn bit(&mut self) -> Result<u32> {
+        if self.nbits == 0 {
+            let mut byte = 0;
+            if self.marker.is_none() {
+                byte = self.raw()?;
+                if byte == 0xFF {
+                    let mut next = self.raw()?;
+                    while next == 0xFF {
+                        next = self.raw()?;
+                    }
+                    if next == 0 {
+                        byte = 0xFF;
+                    } else {
+                        self.marker = Some(next);
+                        byte = 0;
+                    }
+                }
+            }
+            self.acc = byte as u32;
+            self.nbits = 8;
+        }
+        self.nbits -= 1;
+        Ok((self.acc >> self.nbits) & 1)
+    }
+
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut v = 0;
+        for _ in 0..count {
+            v = (v << 1) | self.bit()?;
+        }
+        Ok(v)
+    }
+
+    /// Drops the remaining bits of the current byte and returns the next marker code.
+    fn next_marker(&mut self) -> Result<u8> {
+        self.nbits = 0;
+        if let Some(m) = self.marker.take() {
+            return Ok(m);
+        }
+        loop {
+            if self.raw()? != 0xFF {
+                continue;
+            }
+            let mut code = self.raw()?;
+            while code == 0xFF {
+                code = self.raw()?;
+            }
+            if code != 0 {
+                return Ok(code);
+            }
+        }
+    }
+}
+
+/// Entropy-coded data writer with byte stuffing.
+struct BitWriter<W: Write> {
+    inner: W,
+    acc: u32,
+    nbits: u32,
+}
+
+impl<W: Write> BitWriter<W> {
+    fn bits(&mut self, value: u32, count: u32) -> Result<()> {
+        for i in (0..count).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.nbits += 1;
+            if self.nbits == 8 {
+                let byte = self.acc as u8;
+                self.inner.write_all(if byte == 0xFF { &[0xFF, 0x00] } else { std::slice::from_ref(&byte) })?;
+                self.acc = 0;
+                self.nbits = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pads the last byte with 1-bits.
+    fn flush_bits(&mut self) -> Result<()> {
+        if self.nbits > 0 {
+            self.bits(0x7F, 8 - self.nbits)?;
+        }
+        Ok(())
+    }
+
+    fn raw(&mut self, bytes: &[u8]) -> Result<()> {
+        self.inner.write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// Magnitude category and appended bits of a coefficient (or DC difference).
+fn magnitude(value: i32) -> (u32, u32) {
+    let size = 32 - value.unsigned_abs().leading_zeros();
+    let bits = if value < 0 { value + (1 << size) - 1 } else { value };
+    (size, bits as u32 & ((1u32 << size) - 1))
+}
+
+fn extend(bits: u32, size: u32) -> i32 {
+    if size == 0 {
+        0
+    } else if bits < 1 << (size - 1) {
+        bits as i32 - (1 << size) + 1
+    } else {
+        bits as i32
+    }
+}
+
+struct Component {
+    id: u8,
+    h: u32,
+    v: u32,
+}
+
+struct Frame {
+    width: u32,
+    height: u32,
+    components: Vec<Component>,
+}
+
+/// Component of the current scan: frame component index and DC / AC table ids.
+#[derive(Clone, Copy)]
+struct ScanComponent {
+    component: usize,
+    dc: usize,
+    ac: usize,
+}
+
+struct Scan {
+    components: Vec<ScanComponent>,
+    /// Scan component of each block of an MCU, in coding order.
+    mcu_blocks: Vec<usize>,
+    total_mcus: u64,
+    done_mcus: u64,
+    position: usize,
+}
+
+fn tables<'a>(dc: &'a [Option<Huffman>; 4], ac: &'a [Option<Huffman>; 4], sc: ScanComponent) -> Result<(&'a Huffman, &'a Huffman)> {
+    let dc = dc[sc.dc].as_ref().ok_or_else(|| anyhow!("Missing JPEG DC table {}.", sc.dc))?;
+    let ac = ac[sc.ac].as_ref().ok_or_else(|| anyhow!("Missing JPEG AC table {}.", sc.ac))?;
+    Ok((dc, ac))
+}
+
+/// Item of the JPEG stream as seen by `JpegReader`.
+enum Event {
+    /// Marker segment, copied verbatim.
+    Segment(Vec<u8>),
+    Block { scan_component: ScanComponent, coefficients: Block },
+    Restart(u8),
+    ScanEnd,
+}
+
+/// Streaming reader for baseline and extended sequential Huffman JPEGs.
+struct JpegReader {
+    bits: BitReader<BufReader<File>>,
+    frame: Option<Frame>,
+    dc_tables: [Option<Huffman>; 4],
+    ac_tables: [Option<Huffman>; 4],
+    restart_interval: u64,
+    scan: Option<Scan>,
+    predictions: Vec<i32>,
+    done: bool,
+}
+
+impl JpegReader {
+    fn open(path: &Path) -> Result<Self> {
+        let mut inner = BufReader::new(File::open(path)?);
+        let mut soi = [0u8; 2];
+        inner.read_exact(&mut soi)?;
+        if soi != [0xFF, 0xD8] {
+            return Err(anyhow!("Not a JPEG file."));
+        }
+        Ok(Self {
+            bits: BitReader { inner, acc: 0, nbits: 0, marker: None, consumed: 2 },
+            frame: None,
+            dc_tables: Default::default(),
+            ac_tables: Default::default(),
+            restart_interval: 0,
+            scan: None,
+            predictions: Vec::new(),
+            done: false,
+        })
+    }
+
+    fn tables(&self, sc: ScanComponent) -> Result<(&Huffman, &Huffman)> {
+        tables(&self.dc_tables, &self.ac_tables, sc)
+    }
+
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        if self.done {
+            return Ok(None);
+        }
+        if self.scan.is_some() {
+            return self.next_scan_event().map(Some);
+        }
+
+        let marker = self.bits.next_marker()?;
+        match marker {
+            0xD9 => {
+                self.done = true;
+                Ok(Some(Event::Segment(vec![0xFF, 0xD9])))
+            }
+            0x01 | 0xD0..=0xD8 => Ok(Some(Event::Segment(vec![0xFF, marker]))),
+            _ => {
+                let len_bytes = self.bits.raw_vec(2)?;
+                let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+                if len < 2 {
+                    return Err(anyhow!("Corrupt JPEG segment length."));
+                }
+                let payload = self.bits.raw_vec(len - 2)?;
+                self.parse_segment(marker, &payload)?;
+                let mut segment = vec![0xFF, marker];
+                segment.extend_from_slice(&len_bytes);
+                segment.extend_from_slice(&payload);
+                Ok(Some(Event::Segment(segment)))
+            }
+        }
+    }
+
+    fn parse_segment(&mut self, marker: u8, p: &[u8]) -> Result<()> {
+        let short = || anyhow!("Truncated JPEG segment {:#04x}.", marker);
+        match marker {
+            0xC0 | 0xC1 => {
+                if p.len() < 6 || p[0] != 8 {
+                    return Err(anyhow!("Only 8-bit JPEGs are supported."));
+                }
+                let height = u16::from_be_bytes([p[1], p[2]]) as u32;
+                let width = u16::from_be_bytes([p[3], p[4]]) as u32;
+                let count = p[5] as usize;
+                if p.len() < 6 + count * 3 || height == 0 {
+                    return Err(short());
+                }
+                let components = (0..count)
+                    .map(|i| {
+                        let c = &p[6 + i * 3..9 + i * 3];
+                        Component { id: c[0], h: (c[1] >> 4).max(1) as u32, v: (c[1] & 0x0F).max(1) as u32 }
+                    })
+                    .collect();
+                self.predictions = vec![0; count];
+                self.frame = Some(Frame { width, height, components });
+            }
+            0xC2 | 0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => {
+                return Err(anyhow!("Progressive, lossless and arithmetic-coded JPEGs are not supported."));
+            }
+            0xC4 => {
+                let mut i = 0;
+                while i < p.len() {
+                    if i + 17 > p.len() {
+                        return Err(short());
+                    }
+                    let (class, id) = (p[i] >> 4, (p[i] & 0x0F) as usize);
+                    let counts = &p[i + 1..i + 17];
+                    let total: usize = counts.iter().map(|&c| c as usize).sum();
+                    if i + 17 + total > p.len() || id > 3 {
+                        return Err(short());
+                    }
+                    let table = Huffman::new(counts, p[i + 17..i + 17 + total].to_vec());
+                    if class == 0 { self.dc_tables[id] = Some(table) } else { self.ac_tables[id] = Some(table) }
+                    i += 17 + total;
+                }
+            }
+            0xDD => {
+                if p.len() < 2 {
+                    return Err(short());
+                }
+                self.restart_interval = u16::from_be_bytes([p[0], p[1]]) as u64;
+            }
+            0xDA => self.start_scan(p)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_scan(&mut self, p: &[u8]) -> Result<()> {
+        let frame = self.frame.as_ref().ok_or_else(|| anyhow!("JPEG scan before frame header."))?;
+        let count = *p.first().ok_or_else(|| anyhow!("Truncated JPEG scan header."))? as usize;
+        if p.len() < 1 + count * 2 + 3 || count == 0 {
+            return Err(anyhow!("Truncated JPEG scan header."));
+        }
+        let mut components = Vec::with_capacity(count);
+        for i in 0..count {
+            let (id, tables) = (p[1 + i * 2], p[2 + i * 2]);
+            let component = frame.components.iter().position(|c| c.id == id)
+                .ok_or_else(|| anyhow!("JPEG scan references unknown component {}.", id))?;
+            components.push(ScanComponent { component, dc: (tables >> 4) as usize & 3, ac: (tables & 0x0F) as usize & 3 });
+        }
+        let (ss, se, a) = (p[1 + count * 2], p[2 + count * 2], p[3 + count * 2]);
+        if ss != 0 || se != 63 || a != 0 {
+            return Err(anyhow!("Only sequential JPEG scans are supported."));
+        }
+
+        let hmax = frame.components.iter().map(|c| c.h).max().unwrap_or(1);
+        let vmax = frame.components.iter().map(|c| c.v).max().unwrap_or(1);
+        let (mcu_blocks, total_mcus) = if count == 1 {
+            // Non-interleaved: one block per MCU over the component's own size
+            let c = &frame.components[components[0].component];
+            let cols = (frame.width * c.h).div_ceil(hmax).div_ceil(8);
+            let rows = (frame.height * c.v).div_ceil(vmax).div_ceil(8);
+            (vec![0], cols as u64 * rows as u64)
+        } else {
+            let blocks = components.iter().enumerate()
+                .flat_map(|(i, sc)| {
+                    let c = &frame.components[sc.component];
+                    std::iter::repeat_n(i, (c.h * c.v) as usize)
+                })
+                .collect();
+            let mcus = frame.width.div_ceil(8 * hmax) as u64 * frame.height.div_ceil(8 * vmax) as u64;
+            (blocks, mcus)
+        };
+        self.predictions.iter_mut().for_each(|p| *p = 0);
+        self.scan = Some(Scan { components, mcu_blocks, total_mcus, done_mcus: 0, position: 0 });
+        Ok(())
+    }
+
+    fn next_scan_event(&mut self) -> Result<Event> {
+        let scan = self.scan.as_mut().unwrap();
+        if scan.position == scan.mcu_blocks.len() {
+            scan.position = 0;
+            scan.done_mcus += 1;
+            if scan.done_mcus == scan.total_mcus {
+                self.scan = None;
+                self.bits.nbits = 0;
+                return Ok(Event::ScanEnd);
+            }
+            if self.restart_interval > 0 && scan.done_mcus.is_multiple_of(self.restart_interval) {
+                let marker = self.bits.next_marker()?;
+                if !(0xD0..=0xD7).contains(&marker) {
+                    return Err(anyhow!("Expected a JPEG restart marker, found {:#04x}.", marker));
+                }
+                self.predictions.iter_mut().for_each(|p| *p = 0);
+                return Ok(Event::Restart(marker - 0xD0));
+            }
+        }
+
+        let sc = scan.components[scan.mcu_blocks[scan.position]];
+        scan.position += 1;
+        let mut coefficients = [0i16; 64];
+        let (dc, ac) = tables(&self.dc_tables, &self.ac_tables, sc)?;
+
+        let size = dc.decode(&mut self.bits)? as u32;
+        let diff = extend(self.bits.bits(size)?, size);
+        self.predictions[sc.component] += diff;
+        coefficients[0] = self.predictions[sc.component] as i16;
+        let mut k = 1;
+        while k < 64 {
+            let rs = ac.decode(&mut self.bits)?;
+            let (run, size) = ((rs >> 4) as usize, (rs & 0x0F) as u32);
+            if size == 0 {
+                if run == 15 {
+                    k += 16;
+                    continue;
+                }
+                break; // EOB
+            }
+            k += run;
+            if k > 63 {
+                return Err(anyhow!("Corrupt JPEG block."));
+            }
+            coefficients[k] = extend(self.bits.bits(size)?, size) as i16;
+            k += 1;
+        }
+        Ok(Event::Block { scan_component: sc, coefficients })
+    }
+}
+
+/// Iterates over the coefficient blocks of a JPEG in coding order.
+pub struct JpegBlocks {
+    reader: JpegReader,
+}
+
+impl JpegBlocks {
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self { reader: JpegReader::open(path)? })
+    }
+}
+
+impl Iterator for JpegBlocks {
+    type Item = Result<Block>;
+
+    fn next(&mut self) -> Option<Result<Block>> {
+        loop {
+            match self.reader.next_event() {
+                Ok(Some(Event::Block { coefficients, .. })) => return Some(Ok(coefficients)),
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                Err(e) => {
+                    self.reader.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// Number of coefficients that can carry a payload bit (see `is_usable`).
+pub fn usable_coefficients(path: &Path) -> Result<u64> {
+    let mut count = 0;
+    for block in JpegBlocks::open(path)? {
+        count += block?.iter().enumerate().filter(|(k, v)| is_usable(*k, **v)).count() as u64;
+    }
+    Ok(count)
+}
+
+/// Re-encodes `input` into `output` block by block after `modify` has seen each block.
+/// Markers, quantization and Huffman tables are copied, so the result has the same quality.
+pub fn transcode(input: &Path, output: &Path, mut modify: impl FnMut(&mut Block), on_progress: impl Fn(f32)) -> Result<()> {
+    let total_bytes = std::fs::metadata(input)?.len().max(1);
+    let mut reader = JpegReader::open(input)?;
+    let mut out = BitWriter { inner: BufWriter::new(File::create(output)?), acc: 0, nbits: 0 };
+    let mut predictions: Vec<i32> = Vec::new();
+    let mut blocks = 0u64;
+
+    out.raw(&[0xFF, 0xD8])?; // consumed by `JpegReader::open`
+    while let Some(event) = reader.next_event()? {
+        match event {
+            Event::Segment(bytes) => {
+                if bytes[1] == 0xDA || predictions.is_empty() {
+                    predictions = vec![0; reader.predictions.len()];
+                }
+                out.raw(&bytes)?;
+            }
+            Event::Block { scan_component, mut coefficients } => {
+                modify(&mut coefficients);
+                let (dc, ac) = reader.tables(scan_component)?;
+                let prediction = &mut predictions[scan_component.component];
+                let (size, bits) = magnitude(coefficients[0] as i32 - *prediction);
+                *prediction = coefficients[0] as i32;
+                dc.encode(&mut out, size as u8)?;
+                out.bits(bits, size)?;
+
+                let mut run = 0;
+                for &c in &coefficients[1..] {
+                    if c == 0 {
+                        run += 1;
+                        continue;
+                    }
+                    while run > 15 {
+                        ac.encode(&mut out, 0xF0)?;
+                        run -= 16;
+                    }
+                    let (size, bits) = magnitude(c as i32);
+                    ac.encode(&mut out, ((run << 4) | size) as u8)?;
+                    out.bits(bits, size)?;
+                    run = 0;
+                }
+                if run > 0 {
+                    ac.encode(&mut out, 0x00)?; // EOB
+                }
+
+                blocks += 1;
+                if blocks.is_multiple_of(4096) { on_progress(reader.bits.consumed as f32 / total_bytes as f32); }
+            }
+            Event::Restart(n) => {
+                out.flush_bits()?;
+                out.raw(&[0xFF, 0xD0 + n])?;
+                predictions.iter_mut().for_each(|p| *p = 0);
+            }
+            Event::ScanEnd => out.flush_bits()?,
+        }
+    }
+    out.inner.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Natural (row-major) index of each zigzag position.
+    const ZIGZAG: [usize; 64] = [
+        0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28,
+        35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+    ];
+
+    /// Test image: smooth gradients with some texture, so blocks have a mix of small and large coefficients.
+    pub(crate) fn test_pixel(x: u32, y: u32) -> [u8; 3] {
+        let t = ((x * 7 + y * 13) % 23) as f64;
+        [
+            (x * 255 / 64 % 256) as u8,
+            (128.0 + 100.0 * ((x + y) as f64 * 0.15).sin() + t) as u8,
+            (y * 4 % 200) as u8 + t as u8,
+        ]
+    }
+
+    /// Entropy-coded data writer, independent of `BitWriter`.
+    struct Bits {
+        out: Vec<u8>,
+        acc: u32,
+        nbits: u32,
+    }
+
+    impl Bits {
+        fn put(&mut self, value: u32, count: u32) {
+            for i in (0..count).rev() {
+                self.acc = (self.acc << 1) | ((value >> i) & 1);
+                self.nbits += 1;
+                if self.nbits == 8 {
+                    self.out.push(self.acc as u8);
+                    if self.acc == 0xFF {
+                        self.out.push(0);
+                    }
+                    (self.acc, self.nbits) = (0, 0);
+                }
+            }
+        }
+
+        fn pad(&mut self) {
+            if self.nbits > 0 {
+                self.put(0x7F, 8 - self.nbits);
+            }
+        }
+    }
+
+    /// Writes a baseline JPEG of `test_pixel` with the given (h, v) sampling per component
+    /// (one component: grayscale) and restart interval, through a forward DCT and quantization.
+    pub(crate) fn write_jpeg(path: &Path, width: u32, height: u32, sampling: &[(u32, u32)], restart_interval: u16) -> Result<()> {
+        let quant: Vec<f64> = (0..64).map(|k| 2.0 + (k / 6) as f64).collect();
+        let hmax = sampling.iter().map(|s| s.0).max().unwrap();
+        let vmax = sampling.iter().map(|s| s.1).max().unwrap();
+        let (mcus_x, mcus_y) = if sampling.len() == 1 { (width.div_ceil(8), height.div_ceil(8)) } else { (width.div_ceil(8 * hmax), height.div_ceil(8 * vmax)) };
+
+        // Component planes at their own resolution, averaged over the subsampled pixels
+        let ycc = |x: u32, y: u32| {
+            let [r, g, b] = test_pixel(x.min(width - 1), y.min(height - 1)).map(f64::from);
+            [0.299 * r + 0.587 * g + 0.114 * b, 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b, 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b]
+        };
+        let block = |c: usize, bx: u32, by: u32| -> [i32; 64] {
+            let (sx, sy) = (hmax / sampling[c].0, vmax / sampling[c].1);
+            let mut samples = [0f64; 64];
+            for (i, s) in samples.iter_mut().enumerate() {
+                let (x, y) = ((bx * 8 + i as u32 % 8) * sx, (by * 8 + i as u32 / 8) * sy);
+                let sum: f64 = (0..sy).flat_map(|dy| (0..sx).map(move |dx| (dx, dy))).map(|(dx, dy)| ycc(x + dx, y + dy)[c]).sum();
+                *s = sum / (sx * sy) as f64 - 128.0;
+            }
+            let mut out = [0i32; 64];
+            for (k, o) in out.iter_mut().enumerate() {
+                let (u, v) = (ZIGZAG[k] % 8, ZIGZAG[k] / 8);
+                let mut sum = 0.0;
+                for (i, s) in samples.iter().enumerate() {
+                    let (x, y) = (i % 8, i / 8);
+                    sum += s * ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / 16.0).cos() * ((2 * y + 1) as f64 * v as f64 * std::f64::consts::PI / 16.0).cos();
+                }
+                let cu = if u == 0 { std::f64::consts::FRAC_1_SQRT_2 } else { 1.0 };
+                let cv = if v == 0 { std::f64::consts::FRAC_1_SQRT_2 } else { 1.0 };
+                *o = (0.25 * cu * cv * sum / quant[k]).round() as i32;
+            }
+            out
+        };
+
+        // DC: categories 0-11 with 4-bit codes; AC: every symbol, 8-bit codes and one 9-bit code
+        let (dc_counts, ac_counts) = {
+            let (mut dc, mut ac) = ([0u8; 16], [0u8; 16]);
+            dc[3] = 12;
+            (ac[7], ac[8]) = (255, 1);
+            (dc, ac)
+        };
+        let code = |symbol: u32, dc: bool| if dc { (symbol, 4) } else if symbol < 255 { (symbol, 8) } else { (0x1FE, 9) };
+        let size = |v: i32| 32 - v.unsigned_abs().leading_zeros();
+
+        let mut bits = Bits { out: Vec::new(), acc: 0, nbits: 0 };
+        let mut predictions = vec![0i32; sampling.len()];
+        let total = mcus_x * mcus_y;
+        for mcu in 0..total {
+            if restart_interval > 0 && mcu > 0 && mcu % restart_interval as u32 == 0 {
+                bits.pad();
+                bits.out.extend_from_slice(&[0xFF, 0xD0 + ((mcu / restart_interval as u32 - 1) % 8) as u8]);
+                predictions.iter_mut().for_each(|p| *p = 0);
+            }
+            let (mx, my) = (mcu % mcus_x, mcu / mcus_x);
+            for (c, &(h, v)) in sampling.iter().enumerate() {
+                let (h, v) = if sampling.len() == 1 { (1, 1) } else { (h, v) };
+                for by in 0..v {
+                    for bx in 0..h {
+                        let coefficients = block(c, mx * h + bx, my * v + by);
+                        let diff = coefficients[0] - predictions[c];
+                        predictions[c] = coefficients[0];
+                        let (c_, l) = code(size(diff), true);
+                        bits.put(c_, l);
+                        bits.put((if diff < 0 { diff - 1 } else { diff }) as u32 & ((1 << size(diff)) - 1), size(diff));
+                        let mut run = 0;
+                        for &value in &coefficients[1..] {
+                            if value == 0 {
+                                run += 1;
+                                continue;
+                            }
+                            while run > 15 {
+                                let (c_, l) = code(0xF0, false);
+                                bits.put(c_, l);
+                                run -= 16;
+                            }
+                            let (c_, l) = code((run << 4) | size(value), false);
+                            bits.put(c_, l);
+                            bits.put((if value < 0 { value - 1 } else { value }) as u32 & ((1 << size(value)) - 1), size(value));
+                            run = 0;
+                        }
+                        if run > 0 {
+                            bits.put(0, 8); // EOB
+                        }
+                    }
+                }
+            }
+        }
+        bits.pad();
+
+        let segment = |out: &mut Vec<u8>, marker: u8, body: &[u8]| {
+            out.extend_from_slice(&[0xFF, marker]);
+            out.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            out.extend_from_slice(body);
+        };
+        let mut file = vec![0xFF, 0xD8];
+        segment(&mut file, 0xDB, &[[0u8].as_slice(), &quant.iter().map(|&q| q as u8).collect::<Vec<_>>()].concat());
+        let mut sof = vec![8];
+        sof.extend_from_slice(&(height as u16).to_be_bytes());
+        sof.extend_from_slice(&(width as u16).to_be_bytes());
+        sof.push(sampling.len() as u8);
+        for (i, &(h, v)) in sampling.iter().enumerate() {
+            sof.extend_from_slice(&[i as u8 + 1, ((h << 4) | v) as u8, 0]);
+        }
+        segment(&mut file, 0xC0, &sof);
+        segment(&mut file, 0xC4, &[[0x00].as_slice(), &dc_counts, &(0..12).collect::<Vec<u8>>()].concat());
+        segment(&mut file, 0xC4, &[[0x10].as_slice(), &ac_counts, &(0..=255).collect::<Vec<u8>>()].concat());
+        if restart_interval > 0 {
+            segment(&mut file, 0xDD, &restart_interval.to_be_bytes());
+        }
+        let mut sos = vec![sampling.len() as u8];
+        for i in 0..sampling.len() {
+            sos.extend_from_slice(&[i as u8 + 1, 0x00]);
+        }
+        sos.extend_from_slice(&[0, 63, 0]);
+        segment(&mut file, 0xDA, &sos);
+        file.extend_from_slice(&bits.out);
+        file.extend_from_slice(&[0xFF, 0xD9]);
+        std::fs::write(path, file)?;
+        Ok(())
+    }
+
+    /// Mean absolute difference between a decoded JPEG and `test_pixel` (its luma if gray).
+    fn mean_error(path: &Path) -> Result<f64> {
+        let img = image::open(path)?;
+        let gray = img.color() == image::ColorType::L8;
+        let img = img.to_rgb8();
+        let total: f64 = img.enumerate_pixels()
+            .flat_map(|(x, y, p)| {
+                let [r, g, b] = test_pixel(x, y).map(f64::from);
+                let expected = if gray { [0.299 * r + 0.587 * g + 0.114 * b; 3] } else { [r, g, b] };
+                (0..3).map(move |c| (p[c] as f64 - expected[c]).abs())
+            })
+            .sum();
+        Ok(total / (img.width() * img.height() * 3) as f64)
+    }
+
+    fn blocks(path: &Path) -> Result<Vec<Block>> {
+        JpegBlocks::open(path)?.collect()
+    }
+
+    #[test]
+    fn test_identity_transcode() -> Result<()> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpg");
+        let output = dir.path().join("output.jpg");
+        // (sampling, restart interval): 4:2:0, 4:2:0 with DRI, 4:2:2 with a restart after every MCU, gray with DRI
+        let cases: [(&[(u32, u32)], u16); 5] = [
+            (&[(2, 2), (1, 1), (1, 1)], 0),
+            (&[(2, 2), (1, 1), (1, 1)], 3),
+            (&[(2, 1), (1, 1), (1, 1)], 1),
+            (&[(1, 1), (1, 1), (1, 1)], 0),
+            (&[(1, 1)], 5),
+        ];
+        for (sampling, restart_interval) in cases {
+            // Sizes that are not a multiple of the MCU
+            write_jpeg(&input, 37, 21, sampling, restart_interval)?;
+            assert!(mean_error(&input)? < 4.0, "{:?}", sampling);
+
+            transcode(&input, &output, |_| {}, |_| {})?;
+            assert_eq!(std::fs::read(&output)?, std::fs::read(&input)?, "{:?} DRI {}", sampling, restart_interval);
+        }
+
+        // The image crate's encoder (4:4:4, standard tables)
+        image::RgbImage::from_fn(45, 19, |x, y| image::Rgb(test_pixel(x, y))).save(&input)?;
+        transcode(&input, &output, |_| {}, |_| {})?;
+        assert_eq!(std::fs::read(&output)?, std::fs::read(&input)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_modified_blocks_decode() -> Result<()> {
+        let dir = tempdir()?;
+        let input = dir.path().join("input.jpg");
+        let output = dir.path().join("output.jpg");
+        write_jpeg(&input, 50, 34, &[(2, 2), (1, 1), (1, 1)], 2)?;
+        let mut flip = 0u8;
+        transcode(&input, &output, |block| {
+            for (k, value) in block.iter_mut().enumerate().skip(1) {
+                if is_usable(k, *value) {
+                    *value = set_lsb(*value, flip);
+                    flip ^= 1;
+                }
+            }
+        }, |_| {})?;
+
+        // Only the LSBs of usable coefficients change, and they stay usable
+        let (before, after) = (blocks(&input)?, blocks(&output)?);
+        assert_eq!(before.len(), after.len());
+        let mut changed = 0;
+        for (a, b) in before.iter().zip(&after) {
+            for k in 0..64 {
+                assert_eq!(is_usable(k, a[k]), is_usable(k, b[k]));
+                if is_usable(k, a[k]) {
+                    assert_eq!(a[k].signum(), b[k].signum());
+                    assert_eq!(a[k].unsigned_abs() | 1, b[k].unsigned_abs() | 1);
+                    changed += (a[k] != b[k]) as u64;
+                } else {
+                    assert_eq!(a[k], b[k]);
+                }
+            }
+        }
+        assert!(changed > 0);
+        assert_eq!(usable_coefficients(&output)?, usable_coefficients(&input)?);
+        // Still a valid JPEG of about the same image
+        assert!(mean_error(&output)? < 5.0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_usable_coefficients() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("input.jpg");
+        write_jpeg(&path, 40, 24, &[(2, 2), (1, 1), (1, 1)], 0)?;
+        let expected: u64 = blocks(&path)?.iter()
+            .map(|b| b[1..].iter().filter(|v| v.unsigned_abs() >= 2).count() as u64)
+            .sum();
+        assert!(expected > 0);
+        assert_eq!(usable_coefficients(&path)?, expected);
+        // 4:2:0 at 40x24: 3x2 MCUs of 4 luma and 2 chroma blocks
+        assert_eq!(blocks(&path)?.len(), 3 * 2 * 6);
+
+        let mut written = 0;
+        transcode(&path, &dir.path().join("output.jpg"), |block| {
+            written += (1..64).filter(|&k| is_usable(k, block[k])).count() as u64;
+        }, |_| {})?;
+        assert_eq!(written, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_jpegs_are_errors() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("input.jpg");
+        let output = dir.path().join("output.jpg");
+        write_jpeg(&path, 16, 16, &[(1, 1), (1, 1), (1, 1)], 0)?;
+        let baseline = std::fs::read(&path)?;
+        let sof = baseline.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+
+        // Progressive and arithmetic-coded frames
+        for marker in [0xC2, 0xC9] {
+            let mut bytes = baseline.clone();
+            bytes[sof + 1] = marker;
+            std::fs::write(&path, &bytes)?;
+            let err = transcode(&path, &output, |_| {}, |_| {}).unwrap_err();
+            assert!(err.to_string().contains("not supported"), "{}", err);
+            assert!(usable_coefficients(&path).is_err());
+            assert!(JpegBlocks::open(&path)?.any(|b| b.is_err()));
+        }
+
+        // A progressive-style scan (spectral selection) under a baseline frame
+        let mut bytes = baseline.clone();
+        let sos = bytes.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        bytes[sos + 2 + 2 + 1 + 3 * 2 + 1] = 5; // Se
+        std::fs::write(&path, &bytes)?;
+        assert!(transcode(&path, &output, |_| {}, |_| {}).is_err());
+
+        // Truncated entropy data and a non-JPEG
+        std::fs::write(&path, &baseline[..baseline.len() - 40])?;
+        assert!(usable_coefficients(&path).is_err());
+        std::fs::write(&path, b"GIF89a")?;
+        assert_eq!(usable_coefficients(&path).unwrap_err().to_string(), "Not a JPEG file.");
+        Ok(())
+    }
+}
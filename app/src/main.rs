@@ -11,7 +11,37 @@ mod converter;
 mod stream_encoder;
 mod stream_decoder;
 mod plugin_loader;
+mod plugin_manifest;
+mod plugin_host;
+mod wasm_plugin;
+mod capacity;
+mod postprocess;
+mod sniff;
+mod flac;
+mod audio;
+mod aiff;
+mod au;
+mod pcm;
+mod bmp;
+mod tif;
+mod webp;
+mod raster;
+mod jpeg;
+mod carrier;
+mod apng;
+mod gif_palette;
+mod channels;
+mod spectrogram;
 
 fn main() -> Result<(), slint::PlatformError> {
+    // The same executable serves out-of-process plugins
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    if args.len() == 3 && args[1] == plugin_host::HOST_FLAG {
+        if let Err(e) = plugin_host::serve(std::path::Path::new(&args[2])) {
+            eprintln!("Plugin host for {:?} failed: {:#}", args[2], e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     gui::run()
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Context, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use crate::aiff::{self, AiffSamples, AiffWriter};
+use crate::au::{self, AuSamples, AuWriter};
+
+/// Uncompressed PCM containers. They share the `WavLayout` embedding and differ only in framing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PcmFormat {
+    Wav,
+    Aiff,
+    Au,
+}
+
+impl PcmFormat {
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "wav" => Some(Self::Wav),
+            "aif" | "aiff" | "aifc" => Some(Self::Aiff),
+            "au" | "snd" => Some(Self::Au),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Wav => "WAV",
+            Self::Aiff => "AIFF",
+            Self::Au => "AU",
+        }
+    }
+}
+
+/// An opened PCM file. Samples are interleaved raw bits: integers as i32, floats as f32 bits.
+pub struct PcmSource {
+    pub spec: WavSpec,
+    /// Interleaved samples (all channels).
+    pub samples: u64,
+    pub iter: Box<dyn Iterator<Item = Result<u32>> + Send>,
+    /// The AIFF container was AIFF-C; written back the same way.
+    pub aifc: bool,
+}
+
+pub fn open(path: &Path, format: PcmFormat) -> Result<PcmSource> {
+    match format {
+        PcmFormat::Wav => {
+            let reader = hound::WavReader::open(path).context("Failed to open WAV file.")?;
+            let spec = reader.spec();
+            let samples = reader.len() as u64;
+            let iter: Box<dyn Iterator<Item = Result<u32>> + Send> = if spec.sample_format == SampleFormat::Float {
+                Box::new(reader.into_samples::<f32>().map(|s| s.map(f32::to_bits).context("Read error")))
+            } else {
+                Box::new(reader.into_samples::<i32>().map(|s| s.map(|v| v as u32).context("Read error")))
+            };
+            Ok(PcmSource { spec, samples, iter, aifc: false })
+        }
+        PcmFormat::Aiff => {
+            let info = aiff::read_info(path)?;
+            let iter = Box::new(AiffSamples::open(path, &info)?);
+            Ok(PcmSource { spec: info.spec, samples: info.frames * info.spec.channels as u64, iter, aifc: info.aifc })
+        }
+        PcmFormat::Au => {
+            let info = au::read_info(path)?;
+            let iter = Box::new(AuSamples::open(path, &info)?);
+            Ok(PcmSource { spec: info.spec, samples: info.samples, iter, aifc: false })
+        }
+    }
+}
+
+/// Spec and interleaved sample count without reading the samples.
+pub fn probe(path: &Path, format: PcmFormat) -> Result<(WavSpec, u64)> {
+    let source = open(path, format)?;
+    Ok((source.spec, source.samples))
+}
+
+/// A raw sample in `spec` scaled to 16 bits (the `WavLayout::Expand16` audio part).
+pub fn to_i16(raw: u32, spec: &WavSpec) -> Result<i16> {
+    let bits = spec.bits_per_sample as u32;
+    match spec.sample_format {
+        SampleFormat::Float => Ok((f32::from_bits(raw) * i16::MAX as f32) as i16),
+        SampleFormat::Int if (1..=16).contains(&bits) => Ok(((raw as i32) << (16 - bits)) as i16),
+        SampleFormat::Int if bits <= 32 => Ok(((raw as i32) >> (bits - 16)) as i16),
+        SampleFormat::Int => Err(anyhow!("Unsupported format: Int {} bits", bits)),
+    }
+}
+
+/// Streaming writer for any `PcmFormat`.
+pub enum PcmWriter {
+    Wav(WavWriter<BufWriter<File>>, SampleFormat),
+    Aiff(AiffWriter),
+    Au(AuWriter),
+}
+
+impl PcmWriter {
+    /// `aifc` keeps an AIFF-C container as AIFF-C (float output is always AIFF-C).
+    pub fn create(path: &Path, format: PcmFormat, spec: WavSpec, aifc: bool) -> Result<Self> {
+        Ok(match format {
+            PcmFormat::Wav => Self::Wav(WavWriter::create(path, spec)?, spec.sample_format),
+            PcmFormat::Aiff => Self::Aiff(AiffWriter::create(path, spec, aifc)?),
+            PcmFormat::Au => Self::Au(AuWriter::create(path, spec)?),
+        })
+    }
+
+    /// Writes one sample given as raw bits (i32 value or f32 bits).
+    pub fn write_raw(&mut self, bits: u32) -> Result<()> {
+        match self {
+            Self::Wav(writer, SampleFormat::Float) => writer.write_sample(f32::from_bits(bits))?,
+            Self::Wav(writer, SampleFormat::Int) => writer.write_sample(bits as i32)?,
+            Self::Aiff(writer) => writer.write_raw(bits)?,
+            Self::Au(writer) => writer.write_raw(bits)?,
+        }
+        Ok(())
+    }
+
+    pub fn finalize(self) -> Result<()> {
+        match self {
+            Self::Wav(writer, _) => writer.finalize()?,
+            Self::Aiff(writer) => writer.finalize()?,
+            Self::Au(writer) => writer.finalize()?,
+        }
+        Ok(())
+    }
+}
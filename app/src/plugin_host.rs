@@ -0,0 +1,701 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sound_png_api::{ByteSource, ContainerDecoder, ContainerEncoder, PluginMetadata};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::plugin_loader;
+
+// Out-of-process plugins. The executable started with `--plugin-host <library>` loads one
+// plugin library and serves it over stdin/stdout; the application talks to it through
+// `HostedEncoder` / `HostedDecoder`, which implement the plugin traits again. If the host
+// process dies (abort, segfault, stack overflow) or stops sending frames for `HOST_TIMEOUT`,
+// it is killed, the current operation fails with an error and the process is started again
+// on the next call.
+//
+// Every message is a frame: tag (u8), body length (u32 LE), body. The application sends a
+// REQUEST (JSON) and serves PULL frames with stream bytes until the host answers DONE or
+// ERROR. A decode leaves the plugin's reader open in the host; READ frames then fetch it
+// chunk by chunk until any other request closes it. Plugins must not write to stdout in
+// this mode; stderr is passed through.
+
+/// Command-line flag that turns the executable into the host of the library that follows it.
+pub const HOST_FLAG: &str = "--plugin-host";
+/// Overrides the host executable, which defaults to the running executable.
+const HOST_PROGRAM_ENV: &str = "SPNG_PLUGIN_HOST";
+/// Stream bytes moved per PULL / READ.
+const CHUNK: usize = 64 * 1024;
+/// Larger frames mean the pipe is out of sync (e.g. a plugin printed to stdout).
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+/// A host that sends no frame (progress, PULL, answer) for this long is considered hung.
+const HOST_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Application -> host: a `Request` as JSON.
+const REQUEST: u8 = 1;
+/// Application -> host: read up to n (u32) bytes from the open decode reader.
+const READ: u8 = 2;
+/// Stream bytes: answer to PULL (application -> host) and READ (host -> application). Empty at end.
+const DATA: u8 = 3;
+/// Host -> application: the plugin wants up to n (u32) more bytes of the byte stream.
+const PULL: u8 = 4;
+/// Host -> application: progress (f32).
+const PROGRESS: u8 = 5;
+/// Host -> application: request finished; the body is the result, if any.
+const DONE: u8 = 6;
+/// Host -> application: request failed; the body is the message.
+const ERROR: u8 = 7;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Info,
+    Encode { container: PathBuf, output: PathBuf, total_len: u64 },
+    Capacity { container: PathBuf },
+    Decode { input: PathBuf },
+    Restore { input: PathBuf, output: PathBuf },
+}
+
+/// Metadata and container kinds of an encoder or decoder; also the JSON WebAssembly plugins return.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Side {
+    name: String,
+    description: String,
+    version: String,
+    author: String,
+    pub(crate) extensions: Vec<String>,
+}
+
+impl Side {
+    fn new(metadata: PluginMetadata, extensions: Vec<String>) -> Self {
+        Self { name: metadata.name, description: metadata.description, version: metadata.version, author: metadata.author, extensions }
+    }
+
+    pub(crate) fn metadata(&self) -> PluginMetadata {
+        PluginMetadata { name: self.name.clone(), description: self.description.clone(), version: self.version.clone(), author: self.author.clone() }
+    }
+}
+
+/// Answer to `Request::Info`.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Info {
+    pub(crate) encoder: Option<Side>,
+    pub(crate) decoder: Option<Side>,
+}
+
+fn write_frame(w: &mut impl Write, tag: u8, body: &[u8]) -> io::Result<()> {
+    w.write_all(&[tag])?;
+    w.write_all(&(body.len() as u32).to_le_bytes())?;
+    w.write_all(body)?;
+    w.flush()
+}
+
+fn read_frame(r: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut head = [0u8; 5];
+    r.read_exact(&mut head)?;
+    let len = u32::from_le_bytes([head[1], head[2], head[3], head[4]]) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body)?;
+    Ok((head[0], body))
+}
+
+fn read_u32(body: &[u8]) -> io::Result<u32> {
+    body.try_into().map(u32::from_le_bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad frame body"))
+}
+
+// ---------------------------------------------------------------------------------------------
+// Application side
+
+struct Connection {
+    child: Child,
+    stdin: BufWriter<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl Connection {
+    /// Sends one frame and handles the host's frames until it finishes the request.
+    /// The outer error means the pipe broke or the host went quiet for `timeout` (`TimedOut`;
+    /// the host is killed); the inner one is the plugin's error message.
+    fn exchange(
+        &mut self,
+        tag: u8,
+        body: &[u8],
+        timeout: Duration,
+        progress: Option<&(dyn Fn(f32) + Send + Sync)>,
+        source: Option<&mut dyn ByteSource>,
+    ) -> io::Result<Result<Vec<u8>, String>> {
+        let Connection { child, stdin, stdout } = self;
+        let (alive, watched) = mpsc::channel();
+        thread::scope(|scope| {
+            // Killing the host unblocks the pipe reads and writes below
+            let watchdog = scope.spawn(move || loop {
+                match watched.recv_timeout(timeout) {
+                    Ok(()) => {}
+                    Err(RecvTimeoutError::Disconnected) => return false,
+                    Err(RecvTimeoutError::Timeout) => {
+                        let _ = child.kill();
+                        return true;
+                    }
+                }
+            });
+            let result = Self::frames(stdin, stdout, alive, tag, body, progress, source);
+            if watchdog.join().unwrap_or(false) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "plugin host stopped responding"));
+            }
+            result
+        })
+    }
+
+    /// The frame loop of `exchange`; every frame from the host is reported to `alive`.
+    fn frames(
+        stdin: &mut BufWriter<ChildStdin>,
+        stdout: &mut BufReader<ChildStdout>,
+        alive: mpsc::Sender<()>,
+        tag: u8,
+        body: &[u8],
+        progress: Option<&(dyn Fn(f32) + Send + Sync)>,
+        mut source: Option<&mut dyn ByteSource>,
+    ) -> io::Result<Result<Vec<u8>, String>> {
+        let mut remaining = source.as_ref().map_or(0, |s| s.total_len());
+        write_frame(stdin, tag, body)?;
+        loop {
+            let (tag, body) = read_frame(stdout)?;
+            let _ = alive.send(());
+            match tag {
+                PROGRESS => {
+                    if let (Some(progress), Ok(bytes)) = (progress, body.try_into()) {
+                        progress(f32::from_le_bytes(bytes));
+                    }
+                }
+                PULL => {
+                    let source = source.as_deref_mut().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unexpected PULL"))?;
+                    let n = (read_u32(&body)? as u64).min(CHUNK as u64).min(remaining);
+                    let data: Vec<u8> = (0..n).map(|_| source.next_byte()).collect();
+                    remaining -= n;
+                    write_frame(stdin, DATA, &data)?;
+                }
+                DONE | DATA => return Ok(Ok(body)),
+                ERROR => return Ok(Err(String::from_utf8_lossy(&body).into_owned())),
+                other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected frame {}", other))),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    connection: Option<Connection>,
+    /// Bumped by every request, so a decode reader notices that its stream was closed.
+    generation: u64,
+}
+
+/// One plugin library served by its own host process.
+struct PluginProcess {
+    library: PathBuf,
+    /// See `HOST_TIMEOUT`.
+    timeout: Duration,
+    state: Mutex<State>,
+}
+
+impl PluginProcess {
+    fn spawn(&self) -> Result<Connection> {
+        let program = match std::env::var_os(HOST_PROGRAM_ENV) {
+            Some(program) => PathBuf::from(program),
+            None => std::env::current_exe()?,
+        };
+        let mut command = Command::new(program);
+        command.arg(HOST_FLAG).arg(&self.library).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::inherit());
+        #[cfg(target_os = "windows")]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+            command.creation_flags(CREATE_NO_WINDOW);
+        }
+        let mut child = command.spawn().context("Failed to start the plugin host process")?;
+        let stdin = BufWriter::new(child.stdin.take().unwrap());
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Ok(Connection { child, stdin, stdout })
+    }
+
+    /// Runs one exchange on the host process, starting it if needed.
+    /// Returns the result body and the generation the request ran in.
+    fn call(
+        &self,
+        tag: u8,
+        body: &[u8],
+        generation: Option<u64>,
+        progress: Option<&(dyn Fn(f32) + Send + Sync)>,
+        source: Option<&mut dyn ByteSource>,
+    ) -> Result<(Vec<u8>, u64)> {
+        let mut state = self.state.lock().unwrap();
+        match generation {
+            // READ continues the stream opened by the decode request of that generation
+            Some(generation) if generation != state.generation || state.connection.is_none() => {
+                return Err(anyhow!("The plugin stream was closed by another request."));
+            }
+            Some(_) => {}
+            None => {
+                state.generation += 1;
+                if state.connection.is_none() {
+                    state.connection = Some(self.spawn()?);
+                }
+            }
+        }
+
+        let connection = state.connection.as_mut().unwrap();
+        match connection.exchange(tag, body, self.timeout, progress, source) {
+            Ok(Ok(body)) => Ok((body, state.generation)),
+            Ok(Err(message)) => Err(anyhow!(message)),
+            Err(e) => {
+                let mut connection = state.connection.take().unwrap();
+                let _ = connection.child.kill();
+                let status = connection.child.wait().map(|s| s.to_string()).unwrap_or_else(|_| e.to_string());
+                let name = self.library.file_name().unwrap_or_default();
+                if e.kind() == io::ErrorKind::TimedOut {
+                    Err(anyhow!(
+                        "Plugin process for {:?} did not respond for {} s and was stopped; it will be restarted on the next call.",
+                        name, self.timeout.as_secs()
+                    ))
+                } else {
+                    Err(anyhow!("Plugin process for {:?} crashed ({}); it will be restarted on the next call.", name, status))
+                }
+            }
+        }
+    }
+
+    fn request(
+        &self,
+        request: &Request,
+        progress: Option<&(dyn Fn(f32) + Send + Sync)>,
+        source: Option<&mut dyn ByteSource>,
+    ) -> Result<(Vec<u8>, u64)> {
+        self.call(REQUEST, &serde_json::to_vec(request)?, None, progress, source)
+    }
+}
+
+impl Drop for PluginProcess {
+    fn drop(&mut self) {
+        if let Some(mut connection) = self.state.get_mut().unwrap().connection.take() {
+            let _ = connection.child.kill();
+            let _ = connection.child.wait();
+        }
+    }
+}
+
+/// Starts the host process for `library` and returns its encoder and decoder.
+pub fn launch(library: &Path) -> Result<(Option<HostedEncoder>, Option<HostedDecoder>)> {
+    connect(PluginProcess { library: library.to_path_buf(), timeout: HOST_TIMEOUT, state: Mutex::default() })
+}
+
+fn connect(process: PluginProcess) -> Result<(Option<HostedEncoder>, Option<HostedDecoder>)> {
+    let process = Arc::new(process);
+    let (body, _) = process.request(&Request::Info, None, None)?;
+    let info: Info = serde_json::from_slice(&body)?;
+    let encoder = info.encoder.map(|side| HostedEncoder { process: process.clone(), side });
+    let decoder = info.decoder.map(|side| HostedDecoder { process: process.clone(), side });
+    Ok((encoder, decoder))
+}
+
+pub struct HostedEncoder {
+    process: Arc<PluginProcess>,
+    side: Side,
+}
+
+impl ContainerEncoder for HostedEncoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.side.metadata()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.side.extensions.clone()
+    }
+
+    fn encode(
+        &self,
+        container_path: &Path,
+        output_path: &Path,
+        byte_stream: &mut dyn ByteSource,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>,
+    ) -> Result<()> {
+        let request = Request::Encode {
+            container: container_path.to_path_buf(),
+            output: output_path.to_path_buf(),
+            total_len: byte_stream.total_len(),
+        };
+        self.process.request(&request, Some(&*on_progress), Some(byte_stream))?;
+        Ok(())
+    }
+
+    fn capacity(&self, container_path: &Path) -> Result<u64> {
+        let (body, _) = self.process.request(&Request::Capacity { container: container_path.to_path_buf() }, None, None)?;
+        body.try_into().map(u64::from_le_bytes).map_err(|_| anyhow!("Plugin host sent a malformed capacity."))
+    }
+}
+
+pub struct HostedDecoder {
+    process: Arc<PluginProcess>,
+    side: Side,
+}
+
+impl ContainerDecoder for HostedDecoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.side.metadata()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.side.extensions.clone()
+    }
+
+    fn decode(&self, input_path: &Path, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<Box<dyn Read + Send>> {
+        let progress: Arc<dyn Fn(f32) + Send + Sync> = Arc::from(on_progress);
+        let (_, generation) = self.process.request(&Request::Decode { input: input_path.to_path_buf() }, Some(&*progress), None)?;
+        Ok(Box::new(HostedReader { process: self.process.clone(), generation, progress, finished: false }))
+    }
+
+    fn restore_container(&self, input_path: &Path, output_path: &Path, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<()> {
+        let request = Request::Restore { input: input_path.to_path_buf(), output: output_path.to_path_buf() };
+        self.process.request(&request, Some(&*on_progress), None)?;
+        Ok(())
+    }
+}
+
+/// The plugin's decode reader, living in the host process.
+struct HostedReader {
+    process: Arc<PluginProcess>,
+    generation: u64,
+    progress: Arc<dyn Fn(f32) + Send + Sync>,
+    finished: bool,
+}
+
+impl Read for HostedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = buf.len().min(CHUNK) as u32;
+        let (data, _) = self.process
+            .call(READ, &want.to_le_bytes(), Some(self.generation), Some(&*self.progress), None)
+            .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        if data.len() > buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "plugin host sent more than requested"));
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        self.finished = data.is_empty();
+        Ok(data.len())
+    }
+}
+
+// ---------------------------------------------------------------------------------------------
+// Host side
+
+type Output = Arc<Mutex<BufWriter<io::Stdout>>>;
+
+fn send(out: &Output, tag: u8, body: &[u8]) -> io::Result<()> {
+    write_frame(&mut *out.lock().unwrap(), tag, body)
+}
+
+fn progress_frames(out: &Output) -> Box<dyn Fn(f32) + Send + Sync> {
+    let out = out.clone();
+    Box::new(move |p: f32| {
+        let _ = send(&out, PROGRESS, &p.to_le_bytes());
+    })
+}
+
+/// The byte stream of an encode request, pulled from the application in chunks.
+struct PipeSource<'a, R: Read> {
+    input: &'a mut R,
+    out: &'a Output,
+    total_len: u64,
+    chunk: Vec<u8>,
+    pos: usize,
+    exhausted: bool,
+}
+
+impl<R: Read> ByteSource for PipeSource<'_, R> {
+    fn next_byte(&mut self) -> u8 {
+        if self.pos == self.chunk.len() {
+            if self.exhausted {
+                return 0;
+            }
+            match send(self.out, PULL, &(CHUNK as u32).to_le_bytes()).and_then(|_| read_frame(self.input)) {
+                Ok((DATA, data)) => {
+                    self.exhausted = data.is_empty();
+                    self.chunk = data;
+                    self.pos = 0;
+                    if self.exhausted {
+                        return 0;
+                    }
+                }
+                // The application is gone or out of sync; nothing left to report to
+                _ => std::process::exit(1),
+            }
+        }
+        self.pos += 1;
+        self.chunk[self.pos - 1]
+    }
+
+    fn total_len(&self) -> u64 {
+        self.total_len
+    }
+}
+
+/// Entry point of the host process: serves the plugin in `library` until stdin closes.
+pub fn serve(library: &Path) -> Result<()> {
+    // A library that fails to open is reported as the answer to every request
+    let opened = plugin_loader::open_library(library).map_err(|e| format!("{:#}", e));
+    match &opened {
+        Ok((encoder, decoder, _)) => serve_plugin(Ok((encoder.as_deref(), decoder.as_deref()))),
+        Err(message) => serve_plugin(Err(message)),
+    }
+}
+
+type Plugin<'a> = (Option<&'a dyn ContainerEncoder>, Option<&'a dyn ContainerDecoder>);
+
+/// Serves an opened plugin, or the error that kept it from opening, over stdin/stdout.
+fn serve_plugin(opened: Result<Plugin, &str>) -> Result<()> {
+    let (encoder, decoder) = opened.unwrap_or((None, None));
+    let out: Output = Arc::new(Mutex::new(BufWriter::new(io::stdout())));
+    let mut input = BufReader::new(io::stdin());
+    let mut reader: Option<Box<dyn Read + Send>> = None;
+
+    loop {
+        let (tag, body) = match read_frame(&mut input) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        if tag == READ {
+            let mut buf = vec![0u8; (read_u32(&body)? as usize).min(CHUNK)];
+            match reader.as_mut().map(|r| r.read(&mut buf)) {
+                Some(Ok(n)) => send(&out, DATA, &buf[..n])?,
+                Some(Err(e)) => send(&out, ERROR, e.to_string().as_bytes())?,
+                None => send(&out, ERROR, b"No decode stream is open.")?,
+            }
+            continue;
+        }
+        // Any other request closes the open decode stream
+        reader = None;
+        if tag != REQUEST {
+            return Err(anyhow!("Unexpected frame {} from the application.", tag));
+        }
+
+        let result = match (&opened, serde_json::from_slice::<Request>(&body)) {
+            (Err(message), _) => Err(anyhow!(message.to_string())),
+            (_, Err(e)) => Err(e.into()),
+            (Ok(_), Ok(request)) => handle(request, encoder, decoder, &mut input, &out, &mut reader),
+        };
+        match result {
+            Ok(body) => send(&out, DONE, &body)?,
+            Err(e) => send(&out, ERROR, format!("{:#}", e).as_bytes())?,
+        }
+    }
+}
+
+fn handle(
+    request: Request,
+    encoder: Option<&dyn ContainerEncoder>,
+    decoder: Option<&dyn ContainerDecoder>,
+    input: &mut impl Read,
+    out: &Output,
+    reader: &mut Option<Box<dyn Read + Send>>,
+) -> Result<Vec<u8>> {
+    let encoder = || encoder.ok_or_else(|| anyhow!("Plugin has no encoder."));
+    let decoder = || decoder.ok_or_else(|| anyhow!("Plugin has no decoder."));
+    match request {
+        Request::Info => {
+            let info = Info {
+                encoder: encoder().ok().map(|e| Side::new(e.metadata(), e.supported_extensions())),
+                decoder: decoder().ok().map(|d| Side::new(d.metadata(), d.supported_extensions())),
+            };
+            Ok(serde_json::to_vec(&info)?)
+        }
+        Request::Encode { container, output, total_len } => {
+            let mut source = PipeSource { input, out, total_len, chunk: Vec::new(), pos: 0, exhausted: false };
+            encoder()?.encode(&container, &output, &mut source, progress_frames(out))?;
+            Ok(Vec::new())
+        }
+        Request::Capacity { container } => Ok(encoder()?.capacity(&container)?.to_le_bytes().to_vec()),
+        Request::Decode { input } => {
+            *reader = Some(decoder()?.decode(&input, progress_frames(out))?);
+            Ok(Vec::new())
+        }
+        Request::Restore { input, output } => {
+            decoder()?.restore_container(&input, &output, progress_frames(out))?;
+            Ok(Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    /// Started with `HOST_FLAG`, the test binary serves `TestEncoder` instead of a library.
+    #[ctor::ctor]
+    fn host_entry() {
+        let args: Vec<_> = std::env::args_os().collect();
+        if args.len() == 3 && args[1] == HOST_FLAG {
+            std::process::exit(if serve_plugin(Ok((Some(&TestEncoder), None))).is_ok() { 0 } else { 1 });
+        }
+    }
+
+    /// Copies the byte stream to the output; panics or hangs on containers named so.
+    /// Its capacity is the host's process id, which tells a restarted host apart.
+    struct TestEncoder;
+
+    impl ContainerEncoder for TestEncoder {
+        fn metadata(&self) -> PluginMetadata {
+            PluginMetadata { name: "Test".into(), description: String::new(), version: "1.0.0".into(), author: String::new() }
+        }
+
+        fn supported_extensions(&self) -> Vec<String> {
+            vec!["test".into()]
+        }
+
+        fn encode(
+            &self,
+            container_path: &Path,
+            output_path: &Path,
+            byte_stream: &mut dyn ByteSource,
+            on_progress: Box<dyn Fn(f32) + Send + Sync>,
+        ) -> Result<()> {
+            match container_path.to_str() {
+                Some("panic") => panic!("test plugin panicked"),
+                Some("hang") => loop {
+                    thread::sleep(Duration::from_secs(60));
+                },
+                _ => {
+                    let data: Vec<u8> = (0..byte_stream.total_len()).map(|_| byte_stream.next_byte()).collect();
+                    on_progress(1.0);
+                    Ok(std::fs::write(output_path, data)?)
+                }
+            }
+        }
+
+        fn capacity(&self, _container_path: &Path) -> Result<u64> {
+            Ok(std::process::id() as u64)
+        }
+    }
+
+    struct Bytes(Vec<u8>, usize);
+
+    impl ByteSource for Bytes {
+        fn next_byte(&mut self) -> u8 {
+            self.1 += 1;
+            self.0.get(self.1 - 1).copied().unwrap_or(0)
+        }
+
+        fn total_len(&self) -> u64 {
+            self.0.len() as u64
+        }
+    }
+
+    fn test_encoder(timeout: Duration) -> Result<HostedEncoder> {
+        let (encoder, decoder) = connect(PluginProcess { library: "test.sn".into(), timeout, state: Mutex::default() })?;
+        assert!(decoder.is_none());
+        Ok(encoder.unwrap())
+    }
+
+    #[test]
+    fn test_host_restarts_after_panic() -> Result<()> {
+        let dir = tempdir()?;
+        let output = dir.path().join("out.test");
+        let encoder = test_encoder(HOST_TIMEOUT)?;
+        assert_eq!(encoder.metadata().name, "Test");
+        assert_eq!(encoder.supported_extensions(), ["test"]);
+
+        let host = encoder.capacity(Path::new("a.test"))?;
+        assert_ne!(host, std::process::id() as u64);
+        let payload = vec![7u8; CHUNK * 2 + 3];
+        encoder.encode(Path::new("a.test"), &output, &mut Bytes(payload.clone(), 0), Box::new(|_| {}))?;
+        assert_eq!(std::fs::read(&output)?, payload);
+        assert_eq!(encoder.capacity(Path::new("a.test"))?, host);
+
+        let e = encoder.encode(Path::new("panic"), &output, &mut Bytes(payload, 0), Box::new(|_| {})).unwrap_err();
+        assert!(e.to_string().starts_with("Plugin process for \"test.sn\" crashed"), "{}", e);
+        let restarted = encoder.capacity(Path::new("a.test"))?;
+        assert_ne!(restarted, host);
+        assert_eq!(encoder.capacity(Path::new("a.test"))?, restarted);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hung_host_is_stopped() -> Result<()> {
+        let dir = tempdir()?;
+        let encoder = test_encoder(Duration::from_secs(1))?;
+        let host = encoder.capacity(Path::new("a.test"))?;
+
+        let start = Instant::now();
+        let e = encoder.encode(Path::new("hang"), &dir.path().join("out.test"), &mut Bytes(vec![1], 0), Box::new(|_| {})).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(
+            e.to_string(),
+            "Plugin process for \"test.sn\" did not respond for 1 s and was stopped; it will be restarted on the next call."
+        );
+        assert_ne!(encoder.capacity(Path::new("a.test"))?, host);
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames_roundtrip() -> Result<()> {
+        let large = vec![0xA5u8; MAX_FRAME];
+        let frames: [(u8, &[u8]); 4] = [(REQUEST, br#"{"op":"info"}"#), (DATA, &[]), (PROGRESS, &0.25f32.to_le_bytes()), (DATA, &large)];
+        let mut pipe = Vec::new();
+        for (tag, body) in frames {
+            write_frame(&mut pipe, tag, body)?;
+        }
+        assert_eq!(&pipe[..5], &[REQUEST, 13, 0, 0, 0]);
+        assert_eq!(pipe.len(), frames.iter().map(|(_, body)| 5 + body.len()).sum::<usize>());
+
+        let mut r = Cursor::new(pipe);
+        for (tag, body) in frames {
+            let (read_tag, read_body) = read_frame(&mut r)?;
+            assert_eq!((read_tag, read_body.as_slice()), (tag, body));
+        }
+        // A closed pipe between frames is a clean end of stream
+        assert_eq!(read_frame(&mut r).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_frame_is_rejected() {
+        let mut pipe = vec![DATA];
+        pipe.extend_from_slice(&(MAX_FRAME as u32 + 1).to_le_bytes());
+        let e = read_frame(&mut Cursor::new(&pipe)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), format!("frame of {} bytes", MAX_FRAME + 1));
+
+        // Stray stdout text reads as a huge length
+        let e = read_frame(&mut Cursor::new(b"Hello from the plugin\n")).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_truncated_frame() -> Result<()> {
+        let mut pipe = Vec::new();
+        write_frame(&mut pipe, DONE, b"result")?;
+        for len in [1, 4, 5, pipe.len() - 1] {
+            let e = read_frame(&mut Cursor::new(&pipe[..len])).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_u32() {
+        assert_eq!(read_u32(&CHUNK.to_le_bytes()[..4]).unwrap(), CHUNK as u32);
+        for body in [&[][..], &[1, 2, 3], &[1, 2, 3, 4, 5]] {
+            assert_eq!(read_u32(body).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,5 +1,9 @@
 use libloading::{Library, Symbol};
 use sound_png_api::{ContainerEncoder, ContainerDecoder, PluginMetadata};
+use sound_png_api::ffi::{self, ForeignDecoder, ForeignEncoder};
+use crate::plugin_manifest::{Capability, Isolation, PluginManifest};
+use crate::{plugin_host, wasm_plugin};
+use anyhow::{anyhow, Result};
 use std::path::Path;
 use std::fs;
 use std::sync::{Arc, Mutex};
@@ -9,63 +13,102 @@ pub struct LoadedPlugin {
     pub encoder: Option<Box<dyn ContainerEncoder>>,
     pub decoder: Option<Box<dyn ContainerDecoder>>,
     pub metadata: PluginMetadata,
+    pub manifest: PluginManifest,
     pub enabled: bool,
+    // Only set for in-process plugins; isolated ones live in their host process
     #[allow(dead_code)]
-    lib: Arc<Library>,
+    lib: Option<Arc<Library>>,
+}
+
+/// A `.sn` file that was not loaded, with the reason shown in the Settings plugin list.
+#[derive(Debug, Clone)]
+pub struct RejectedPlugin {
+    pub name: String,
+    pub reason: String,
 }
 
 pub struct PluginManager {
     plugins: HashMap<String, LoadedPlugin>, // Keyed by name
+    rejected: Vec<RejectedPlugin>,
 }
 
 impl PluginManager {
     pub fn new() -> Self {
-        Self { plugins: HashMap::new() }
+        Self { plugins: HashMap::new(), rejected: Vec::new() }
     }
 
     pub fn load_plugins(&mut self, dir: &Path) {
         if let Ok(entries) = fs::read_dir(dir) {
             for entry in entries.flatten() {
                 let path = entry.path();
-                if path.extension().and_then(|s| s.to_str()) == Some("sn") {
-                    unsafe {
-                        if let Ok(lib) = Library::new(&path) {
-                            let lib = Arc::new(lib);
-                            
-                            // Try load encoder
-                            let enc_func: Result<Symbol<fn() -> Box<dyn ContainerEncoder>>, _> = lib.get(b"_create_encoder");
-                            let encoder = if let Ok(f) = enc_func { Some(f()) } else { None };
-                            
-                            // Try load decoder
-                            let dec_func: Result<Symbol<fn() -> Box<dyn ContainerDecoder>>, _> = lib.get(b"_create_decoder");
-                            let decoder = if let Ok(f) = dec_func { Some(f()) } else { None };
-
-                            if let Some(enc) = &encoder {
-                                let meta = enc.metadata();
-                                self.plugins.insert(meta.name.clone(), LoadedPlugin {
-                                    encoder,
-                                    decoder, // Might be None if only Encoder
-                                    metadata: meta,
-                                    enabled: false, // Default disabled
-                                    lib: lib.clone(),
-                                });
-                                println!("Loaded Plugin: {:?}", path);
-                            } else if let Some(dec) = &decoder {
-                                let meta = dec.metadata();
-                                self.plugins.insert(meta.name.clone(), LoadedPlugin {
-                                    encoder: None,
-                                    decoder,
-                                    metadata: meta,
-                                    enabled: false,
-                                    lib: lib.clone(),
-                                });
-                            }
-                        }
+                if matches!(path.extension().and_then(|s| s.to_str()), Some("sn" | "wasm")) {
+                    if let Err(e) = self.load_plugin(&path) {
+                        tracing::warn!("Skipped Plugin {:?}: {:#}", path, e);
+                        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+                        self.rejected.push(RejectedPlugin { name, reason: format!("{:#}", e) });
                     }
                 }
             }
         }
     }
+
+    fn load_plugin(&mut self, path: &Path) -> Result<()> {
+        // The manifest is checked before the library is opened, so an incompatible plugin never runs
+        let manifest = PluginManifest::load(path)?;
+        if let Some(other) = self.plugins.values().find(|p| p.manifest.id == manifest.id) {
+            return Err(anyhow!("Plugin id \"{}\" is already used by {}.", manifest.id, other.metadata.name));
+        }
+
+        // `lib` is bound first so that on an early return it is dropped after the plugin objects
+        let wasm = path.extension().and_then(|s| s.to_str()) == Some("wasm");
+        let (lib, encoder, decoder) = match manifest.isolation {
+            // WebAssembly plugins are sandboxed by the runtime wherever they run
+            _ if wasm => {
+                let (encoder, decoder) = wasm_plugin::load(path)?;
+                (
+                    None,
+                    encoder.map(|e| Box::new(e) as Box<dyn ContainerEncoder>),
+                    decoder.map(|d| Box::new(d) as Box<dyn ContainerDecoder>),
+                )
+            }
+            Isolation::Process => {
+                let (encoder, decoder) = plugin_host::launch(path)?;
+                (
+                    None,
+                    encoder.map(|e| Box::new(e) as Box<dyn ContainerEncoder>),
+                    decoder.map(|d| Box::new(d) as Box<dyn ContainerDecoder>),
+                )
+            }
+            Isolation::InProcess => {
+                let (encoder, decoder, lib) = open_library(path)?;
+                (Some(lib), encoder, decoder)
+            }
+        };
+
+        manifest.check_exports(
+            encoder.as_ref().map(|e| e.supported_extensions()),
+            decoder.as_ref().map(|d| d.supported_extensions()),
+        )?;
+        let metadata = match (&encoder, &decoder) {
+            (Some(enc), _) => enc.metadata(),
+            (None, Some(dec)) => dec.metadata(),
+            (None, None) => unreachable!("manifest declares encode or decode"),
+        };
+        if self.plugins.contains_key(&metadata.name) {
+            return Err(anyhow!("A plugin named \"{}\" is already loaded.", metadata.name));
+        }
+
+        self.plugins.insert(metadata.name.clone(), LoadedPlugin {
+            encoder,
+            decoder, // Might be None if only Encoder
+            metadata,
+            manifest,
+            enabled: false, // Default disabled
+            lib,
+        });
+        tracing::info!("Loaded Plugin: {:?}", path);
+        Ok(())
+    }
     
     pub fn set_plugin_enabled(&mut self, name: &str, enabled: bool) {
         if let Some(plugin) = self.plugins.get_mut(name) {
@@ -86,6 +129,17 @@ impl PluginManager {
             .collect()
     }
     
+    pub fn get_rejected_plugins(&self) -> &[RejectedPlugin] {
+        &self.rejected
+    }
+
+    /// True if the enabled plugin handling `ext` declares `capability` in its manifest.
+    pub fn has_capability(&self, ext: &str, capability: Capability) -> bool {
+        self.plugins.values().any(|p| {
+            p.enabled && p.manifest.has(capability) && p.manifest.containers.iter().any(|c| c == ext)
+        })
+    }
+
     pub fn get_encoder(&self, name: &str) -> Option<&dyn ContainerEncoder> {
         if let Some(plugin) = self.plugins.get(name) {
             if plugin.enabled {
@@ -124,6 +178,68 @@ impl PluginManager {
     }
 }
 
+/// Encoder, decoder and the library they live in. The library comes last so that the tuple
+/// drops it after the plugin objects.
+pub type OpenedLibrary = (Option<Box<dyn ContainerEncoder>>, Option<Box<dyn ContainerDecoder>>, Arc<Library>);
+
+/// Opens a plugin library in this process and wraps its C ABI tables.
+/// Used for in-process plugins and by the plugin host process.
+pub fn open_library(path: &Path) -> Result<OpenedLibrary> {
+    unsafe {
+        let lib = Arc::new(Library::new(path)?);
+
+        // Nothing else is called until the plugin speaks our C ABI version
+        let version: Result<Symbol<ffi::AbiVersionFn>, _> = lib.get(ffi::ABI_VERSION_SYMBOL);
+        match version.map(|f| f()) {
+            Ok(ffi::ABI_VERSION) => {}
+            Ok(v) => return Err(anyhow!("ABI version {} (expected {}).", v, ffi::ABI_VERSION)),
+            Err(_) => return Err(anyhow!("No ABI version symbol (built for the old Rust ABI).")),
+        }
+
+        // Try load encoder
+        let enc_func: Result<Symbol<ffi::EncoderFn>, _> = lib.get(ffi::ENCODER_SYMBOL);
+        let encoder = enc_func.ok().map(|f| Box::new(ForeignEncoder::from_raw(f())) as Box<dyn ContainerEncoder>);
+
+        // Try load decoder
+        let dec_func: Result<Symbol<ffi::DecoderFn>, _> = lib.get(ffi::DECODER_SYMBOL);
+        let decoder = dec_func.ok().map(|f| Box::new(ForeignDecoder::from_raw(f())) as Box<dyn ContainerDecoder>);
+
+        Ok((encoder, decoder, lib))
+    }
+}
+
 // Make it thread-safe
 unsafe impl Send for PluginManager {}
-unsafe impl Sync for PluginManager {}
\ No newline at end of file
+unsafe impl Sync for PluginManager {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rejected_plugins() -> Result<()> {
+        let dir = tempdir()?;
+        let first = crate::wasm_plugin::tests::write_plugin(dir.path())?;
+        let manifest = format!(
+            "id = \"copy\"\nversion = \"1.0.0\"\napi_version = {}\ncontainers = [\"bin\"]\ncapabilities = [\"encode\", \"decode\"]\n",
+            crate::wasm_plugin::ABI_VERSION
+        );
+        fs::write(first.with_extension("toml"), &manifest)?;
+        // Same id under another file name, and a plugin without a manifest
+        let second = dir.path().join("copy_again.wasm");
+        fs::copy(&first, &second)?;
+        fs::write(second.with_extension("toml"), &manifest)?;
+        fs::copy(&first, dir.path().join("bare.wasm"))?;
+
+        let mut pm = PluginManager::new();
+        pm.load_plugins(dir.path());
+        assert_eq!(pm.get_all_plugins_meta().len(), 1);
+        let mut rejected: Vec<_> = pm.get_rejected_plugins().iter().map(|r| (r.name.as_str(), r.reason.as_str())).collect();
+        rejected.sort();
+        // Directory order decides which of the two copies is loaded first
+        assert_eq!(rejected.len(), 2);
+        assert_eq!(rejected[0], ("bare.wasm", "No manifest (bare.toml not found)."));
+        assert!(rejected[1].1.starts_with("Plugin id \"copy\" is already used by Copy"), "{:?}", rejected[1]);
+        Ok(())
+    }
+}
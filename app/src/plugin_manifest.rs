@@ -0,0 +1,251 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use sound_png_api::ffi;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::wasm_plugin;
+
+// Sidecar manifest read before a plugin library is opened. `Library::new` already runs the
+// plugin's initializers, so everything that can be decided from a text file is decided here.
+//
+//     # Plugins/sequence_frame_plugin.toml
+//     id = "sequence_frame"
+//     version = "0.1.0"
+//     api_version = 2
+//     containers = ["seq_dir"]
+//     capabilities = ["encode", "decode", "capacity", "restore"]
+//     isolation = "process"          # optional, or "in-process"
+
+/// What a plugin declares it can do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Capability {
+    /// Exports an encoder.
+    Encode,
+    /// Exports a decoder.
+    Decode,
+    /// The encoder reports container capacity.
+    Capacity,
+    /// The decoder restores the original container.
+    Restore,
+}
+
+/// Where a plugin runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Isolation {
+    /// Loaded into the application itself. Fastest, but a crash takes the application down.
+    /// WebAssembly plugins always run sandboxed and ignore this setting.
+    #[default]
+    InProcess,
+    /// In its own host process (see `plugin_host`); a crash or hang fails only the current operation.
+    Process,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginManifest {
+    /// Stable identifier, lowercase letters, digits, `_` and `-`.
+    pub id: String,
+    /// Plugin version, `MAJOR.MINOR.PATCH`.
+    pub version: String,
+    /// ABI version the plugin was built against (`ffi::ABI_VERSION`, or
+    /// `wasm_plugin::ABI_VERSION` for WebAssembly plugins).
+    pub api_version: u32,
+    /// Container kinds (extension hints such as `seq_dir`) the plugin handles.
+    pub containers: Vec<String>,
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub isolation: Isolation,
+}
+
+impl PluginManifest {
+    /// Manifest path of a plugin library: same file stem, `.toml` extension.
+    pub fn path_for(plugin_path: &Path) -> PathBuf {
+        plugin_path.with_extension("toml")
+    }
+
+    /// Reads and validates the manifest next to `plugin_path`.
+    pub fn load(plugin_path: &Path) -> Result<Self> {
+        let path = Self::path_for(plugin_path);
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        if !path.is_file() {
+            return Err(anyhow!("No manifest ({} not found).", name));
+        }
+        let manifest: Self = toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid manifest {}", name))?;
+        manifest.validate(Self::api_version_for(plugin_path))?;
+        Ok(manifest)
+    }
+
+    /// API version this build provides for the kind of plugin at `plugin_path`.
+    pub fn api_version_for(plugin_path: &Path) -> u32 {
+        match plugin_path.extension().and_then(|e| e.to_str()) {
+            Some("wasm") => wasm_plugin::ABI_VERSION,
+            _ => ffi::ABI_VERSION,
+        }
+    }
+
+    pub fn validate(&self, api_version: u32) -> Result<()> {
+        if self.id.is_empty() || !self.id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-') {
+            return Err(anyhow!("Invalid plugin id \"{}\" (use lowercase letters, digits, '_' and '-').", self.id));
+        }
+        let parts: Vec<&str> = self.version.split('.').collect();
+        if parts.len() != 3 || parts.iter().any(|p| p.is_empty() || !p.chars().all(|c| c.is_ascii_digit())) {
+            return Err(anyhow!("Invalid version \"{}\" (expected MAJOR.MINOR.PATCH).", self.version));
+        }
+        if self.api_version != api_version {
+            return Err(anyhow!(
+                "Requires plugin API v{}, this build provides v{}.",
+                self.api_version, api_version
+            ));
+        }
+        if self.containers.is_empty() || self.containers.iter().any(|c| c.is_empty()) {
+            return Err(anyhow!("Manifest declares no container kinds."));
+        }
+        let has = |c| self.capabilities.contains(&c);
+        if !has(Capability::Encode) && !has(Capability::Decode) {
+            return Err(anyhow!("Manifest declares neither encode nor decode."));
+        }
+        if has(Capability::Capacity) && !has(Capability::Encode) {
+            return Err(anyhow!("Capability \"capacity\" requires \"encode\"."));
+        }
+        if has(Capability::Restore) && !has(Capability::Decode) {
+            return Err(anyhow!("Capability \"restore\" requires \"decode\"."));
+        }
+        Ok(())
+    }
+
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+
+    /// Checks the loaded library against its manifest: the exported encoder/decoder must match the
+    /// declared capabilities, and together they must handle exactly the declared container kinds.
+    pub fn check_exports(&self, encoder: Option<Vec<String>>, decoder: Option<Vec<String>>) -> Result<()> {
+        let declared: BTreeSet<&str> = self.containers.iter().map(String::as_str).collect();
+        let mut handled = BTreeSet::new();
+        for (side, capability, exported) in [("encoder", Capability::Encode, &encoder), ("decoder", Capability::Decode, &decoder)] {
+            match (self.has(capability), exported) {
+                (true, None) => return Err(anyhow!("Manifest declares a {} but the library does not export one.", side)),
+                (false, Some(_)) => return Err(anyhow!("Library exports a {} the manifest does not declare.", side)),
+                (true, Some(exts)) => {
+                    if let Some(ext) = exts.iter().find(|e| !declared.contains(e.as_str())) {
+                        return Err(anyhow!("The {} handles \"{}\", which the manifest does not declare.", side, ext));
+                    }
+                    handled.extend(exts.iter().map(String::as_str));
+                }
+                (false, None) => {}
+            }
+        }
+        if let Some(ext) = declared.iter().find(|e| !handled.contains(*e)) {
+            return Err(anyhow!("Manifest declares \"{}\" but no exported encoder or decoder handles it.", ext));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn manifest(capabilities: &[Capability]) -> PluginManifest {
+        PluginManifest {
+            id: "seq".into(),
+            version: "1.0.0".into(),
+            api_version: ffi::ABI_VERSION,
+            containers: vec!["seq_dir".into()],
+            capabilities: capabilities.to_vec(),
+            isolation: Isolation::default(),
+        }
+    }
+
+    #[test]
+    fn test_load() -> Result<()> {
+        let dir = tempdir()?;
+        let plugin = dir.path().join("seq.sn");
+        let err = PluginManifest::load(&plugin).unwrap_err();
+        assert_eq!(err.to_string(), "No manifest (seq.toml not found).");
+
+        let write = |body: &str| fs::write(PluginManifest::path_for(&plugin), body);
+        let valid = format!(
+            "id = \"seq\"\nversion = \"0.1.0\"\napi_version = {}\ncontainers = [\"seq_dir\"]\ncapabilities = [\"encode\", \"decode\"]\n",
+            ffi::ABI_VERSION
+        );
+        write(&valid)?;
+        let loaded = PluginManifest::load(&plugin)?;
+        assert_eq!((loaded.id.as_str(), loaded.isolation), ("seq", Isolation::InProcess));
+        assert!(loaded.has(Capability::Decode) && !loaded.has(Capability::Restore));
+
+        write(&format!("{}isolation = \"process\"\n", valid))?;
+        assert_eq!(PluginManifest::load(&plugin)?.isolation, Isolation::Process);
+
+        // Unknown capabilities and fields fail parsing
+        write(&valid.replace("\"decode\"", "\"export\""))?;
+        let err = PluginManifest::load(&plugin).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid manifest seq.toml"));
+        assert!(format!("{:#}", err).contains("export"));
+        write(&format!("{}name = \"Seq\"\n", valid))?;
+        assert!(format!("{:#}", PluginManifest::load(&plugin).unwrap_err()).contains("name"));
+
+        write(&valid.replace(&format!("api_version = {}", ffi::ABI_VERSION), "api_version = 99"))?;
+        let err = PluginManifest::load(&plugin).unwrap_err();
+        assert_eq!(err.to_string(), format!("Requires plugin API v99, this build provides v{}.", ffi::ABI_VERSION));
+
+        // WebAssembly plugins are checked against their own ABI version
+        let wasm = dir.path().join("seq.wasm");
+        assert_eq!(PluginManifest::api_version_for(&wasm), wasm_plugin::ABI_VERSION);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate() {
+        use Capability::*;
+        let api = ffi::ABI_VERSION;
+        assert!(manifest(&[Encode, Decode, Capacity, Restore]).validate(api).is_ok());
+        assert!(manifest(&[Encode]).validate(api + 1).is_err());
+
+        let invalid = |change: &dyn Fn(&mut PluginManifest), message: &str| {
+            let mut m = manifest(&[Encode, Decode]);
+            change(&mut m);
+            assert_eq!(m.validate(api).unwrap_err().to_string(), message);
+        };
+        invalid(&|m| m.id = "Seq Frames".into(), "Invalid plugin id \"Seq Frames\" (use lowercase letters, digits, '_' and '-').");
+        invalid(&|m| m.id.clear(), "Invalid plugin id \"\" (use lowercase letters, digits, '_' and '-').");
+        invalid(&|m| m.version = "1.0".into(), "Invalid version \"1.0\" (expected MAJOR.MINOR.PATCH).");
+        invalid(&|m| m.version = "1.x.0".into(), "Invalid version \"1.x.0\" (expected MAJOR.MINOR.PATCH).");
+        invalid(&|m| m.containers.clear(), "Manifest declares no container kinds.");
+        invalid(&|m| m.containers.push(String::new()), "Manifest declares no container kinds.");
+        invalid(&|m| m.capabilities = vec![Capacity, Restore], "Manifest declares neither encode nor decode.");
+        invalid(&|m| m.capabilities = vec![Decode, Capacity], "Capability \"capacity\" requires \"encode\".");
+        invalid(&|m| m.capabilities = vec![Encode, Restore], "Capability \"restore\" requires \"decode\".");
+    }
+
+    #[test]
+    fn test_check_exports() {
+        use Capability::*;
+        let exts = |list: &[&str]| Some(list.iter().map(|e| e.to_string()).collect::<Vec<_>>());
+        let both = manifest(&[Encode, Decode]);
+        assert!(both.check_exports(exts(&["seq_dir"]), exts(&["seq_dir"])).is_ok());
+
+        let message = |m: &PluginManifest, encoder, decoder| m.check_exports(encoder, decoder).unwrap_err().to_string();
+        assert_eq!(message(&both, exts(&["seq_dir"]), None), "Manifest declares a decoder but the library does not export one.");
+        assert_eq!(
+            message(&manifest(&[Encode]), exts(&["seq_dir"]), exts(&["seq_dir"])),
+            "Library exports a decoder the manifest does not declare."
+        );
+        assert_eq!(
+            message(&both, exts(&["seq_dir", "gif"]), exts(&["seq_dir"])),
+            "The encoder handles \"gif\", which the manifest does not declare."
+        );
+        let mut two = manifest(&[Encode, Decode]);
+        two.containers.push("seq_zip".into());
+        assert!(two.check_exports(exts(&["seq_dir"]), exts(&["seq_zip"])).is_ok());
+        assert_eq!(
+            message(&two, exts(&["seq_dir"]), exts(&["seq_dir"])),
+            "Manifest declares \"seq_zip\" but no exported encoder or decoder handles it."
+        );
+    }
+}
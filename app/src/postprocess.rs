@@ -0,0 +1,184 @@
+use anyhow::Result;
+use image::io::Reader as ImageReader;
+use image::imageops::FilterType;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::stream_decoder::DecodeOptions;
+use hound::SampleFormat;
+
+/// Applies the decode options to a freshly decompressed payload file.
+/// Returns the final path, which differs from `path` when the extension was forced.
+pub fn apply(path: &Path, options: &DecodeOptions) -> Result<PathBuf> {
+    let target = match &options.preset_ext {
+        Some(ext) if !path.extension().is_some_and(|e| e.eq_ignore_ascii_case(ext)) => path.with_extension(ext),
+        _ => path.to_path_buf(),
+    };
+    let factor = options.resize_factor.filter(|f| *f > 0.0 && (*f - 1.0).abs() > f32::EPSILON);
+
+    if let Some((img, source_format)) = load_image(path) {
+        // Convert when the preset names another image format, resize when asked
+        let target_format = image::ImageFormat::from_path(&target).ok();
+        let convert = target != path && target_format.is_some_and(|f| f != source_format);
+        if factor.is_some() || convert {
+            let img = match factor {
+                Some(f) => {
+                    let width = ((img.width() as f32 * f).round() as u32).max(1);
+                    let height = ((img.height() as f32 * f).round() as u32).max(1);
+                    img.resize_exact(width, height, FilterType::Lanczos3)
+                }
+                None => img,
+            };
+            img.save_with_format(&target, target_format.unwrap_or(source_format))?;
+            if target != path {
+                fs::remove_file(path)?;
+            }
+            return Ok(target);
+        }
+    } else if let Some(f) = factor {
+        if hound::WavReader::open(path).is_ok() {
+            resample_wav(path, f)?;
+        }
+    }
+
+    if target != path {
+        fs::rename(path, &target)?;
+    }
+    Ok(target)
+}
+
+/// Decodes `path` as an image if its magic bytes identify a supported format.
+fn load_image(path: &Path) -> Option<(image::DynamicImage, image::ImageFormat)> {
+    let reader = ImageReader::open(path).ok()?.with_guessed_format().ok()?;
+    let format = reader.format()?;
+    let img = reader.decode().ok()?;
+    Some((img, format))
+}
+
+/// Resamples a WAV payload in place to `factor` times its sample rate (same duration), keeping
+/// its sample format and depth. Downsampling averages the covered source frames to limit aliasing.
+fn resample_wav(path: &Path, factor: f32) -> Result<()> {
+    let mut reader = hound::WavReader::open(path)?;
+    let spec = reader.spec();
+    let samples: Vec<f64> = match spec.sample_format {
+        SampleFormat::Int => reader.samples::<i32>().map(|s| s.map(f64::from)).collect::<Result<_, _>>()?,
+        SampleFormat::Float => reader.samples::<f32>().map(|s| s.map(f64::from)).collect::<Result<_, _>>()?,
+    };
+    drop(reader);
+    let channels = spec.channels as usize;
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        // Nothing to resample; an empty payload stays as it is
+        return Ok(());
+    }
+    let new_frames = ((frames as f64 * factor as f64).round() as usize).max(1);
+    let step = frames as f64 / new_frames as f64;
+
+    let mut out = Vec::with_capacity(new_frames * channels);
+    for i in 0..new_frames {
+        let start = i as f64 * step;
+        for ch in 0..channels {
+            let value = if step > 1.0 {
+                let first = start.floor() as usize;
+                let last = (((i + 1) as f64 * step).ceil() as usize).min(frames).max(first + 1);
+                let sum: f64 = (first..last).map(|f| samples[f * channels + ch]).sum();
+                sum / (last - first) as f64
+            } else {
+                // Upsampling: linear interpolation
+                let idx = start.floor() as usize;
+                let frac = start - idx as f64;
+                let a = samples[idx.min(frames - 1) * channels + ch];
+                let b = samples[(idx + 1).min(frames - 1) * channels + ch];
+                a + (b - a) * frac
+            };
+            out.push(value);
+        }
+    }
+
+    let new_spec = hound::WavSpec {
+        sample_rate: ((spec.sample_rate as f32 * factor).round() as u32).max(1),
+        ..spec
+    };
+    let temp = path.with_extension("resample.tmp");
+    let mut writer = hound::WavWriter::create(&temp, new_spec)?;
+    match spec.sample_format {
+        SampleFormat::Int => {
+            let max = (1i64 << (spec.bits_per_sample - 1)) as f64;
+            for value in out {
+                writer.write_sample(value.round().clamp(-max, max - 1.0) as i32)?;
+            }
+        }
+        SampleFormat::Float => {
+            for value in out {
+                writer.write_sample(value as f32)?;
+            }
+        }
+    }
+    writer.finalize()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hound::{WavReader, WavSpec, WavWriter};
+    use tempfile::tempdir;
+
+    fn options(factor: f32) -> DecodeOptions {
+        DecodeOptions { preset_ext: None, resize_factor: Some(factor) }
+    }
+
+    #[test]
+    fn test_resample_keeps_sample_format() -> Result<()> {
+        let dir = tempdir()?;
+        for (bits, format) in [(16, SampleFormat::Int), (24, SampleFormat::Int), (32, SampleFormat::Int), (32, SampleFormat::Float)] {
+            let path = dir.path().join(format!("{}_{:?}.wav", bits, format));
+            let spec = WavSpec { channels: 2, sample_rate: 48000, bits_per_sample: bits, sample_format: format };
+            let mut writer = WavWriter::create(&path, spec)?;
+            // A constant level below 16-bit resolution must survive in the deeper formats
+            for _ in 0..1000 {
+                match format {
+                    SampleFormat::Int => {
+                        writer.write_sample((1i32 << (bits - 1)) / 3)?;
+                        writer.write_sample(-((1i32 << (bits - 1)) / 5))?;
+                    }
+                    SampleFormat::Float => {
+                        writer.write_sample(0.123_456_79f32)?;
+                        writer.write_sample(-0.5f32)?;
+                    }
+                }
+            }
+            writer.finalize()?;
+
+            for factor in [0.5, 1.5] {
+                apply(&path, &options(factor))?;
+            }
+            let mut reader = WavReader::open(&path)?;
+            assert_eq!(reader.spec(), WavSpec { sample_rate: 36000, ..spec });
+            assert_eq!(reader.duration(), 750);
+            match format {
+                SampleFormat::Int => {
+                    let samples: Vec<i32> = reader.samples::<i32>().collect::<Result<_, _>>()?;
+                    assert!(samples.chunks(2).all(|f| f == [(1i32 << (bits - 1)) / 3, -((1i32 << (bits - 1)) / 5)]), "{} bits", bits);
+                }
+                SampleFormat::Float => {
+                    let samples: Vec<f32> = reader.samples::<f32>().collect::<Result<_, _>>()?;
+                    assert!(samples.chunks(2).all(|f| f == [0.123_456_79, -0.5]));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resample_empty_wav() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("empty.wav");
+        let spec = WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        WavWriter::create(&path, spec)?.finalize()?;
+        let before = fs::read(&path)?;
+        assert_eq!(apply(&path, &options(0.5))?, path);
+        assert_eq!(fs::read(&path)?, before);
+        Ok(())
+    }
+}
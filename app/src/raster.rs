@@ -0,0 +1,349 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use crate::bmp::{BmpRows, BmpWriter};
+use crate::tif::{TiffRows, TiffWriter};
+use crate::webp::WebpWriter;
+
+/// Prefix of the TIFF ImageDescription that records the embedding layout.
+pub const LAYOUT_PREFIX: &str = "SPNG_LAYOUT=";
+
+/// Image containers besides PNG. Each is written back in its own format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RasterFormat {
+    Bmp,
+    Tiff,
+    Webp,
+}
+
+impl RasterFormat {
+    pub fn from_ext(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "bmp" | "dib" => Some(Self::Bmp),
+            "tif" | "tiff" => Some(Self::Tiff),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Bmp => "BMP",
+            Self::Tiff => "TIFF",
+            Self::Webp => "WebP",
+        }
+    }
+}
+
+/// Keyword of the PNG tEXt chunk that records the embedding layout (absent for `Expand8`).
+pub const PNG_LAYOUT_KEYWORD: &str = "SPNG_LAYOUT";
+
+/// How payload bits are stored in the RGB channels of an image container.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageLayout {
+    /// 8-bit image widened to 16 bits: original in the high byte, 1 payload byte in the low byte.
+    Expand8,
+    /// 16-bit image kept as is: 1 payload byte replaces the low byte.
+    Lsb8,
+    /// 8-bit image kept as is: 2 payload bits per channel (4 channels per byte).
+    Lsb2,
+}
+
+impl ImageLayout {
+    /// Layout used when embedding into `format` with `depth` bits per channel.
+    /// BMP and WebP cannot hold 16-bit channels, so they embed into the LSBs.
+    pub fn for_container(format: RasterFormat, depth: u8) -> Self {
+        match format {
+            RasterFormat::Tiff if depth == 16 => Self::Lsb8,
+            RasterFormat::Tiff => Self::Expand8,
+            RasterFormat::Bmp | RasterFormat::Webp => Self::Lsb2,
+        }
+    }
+
+    /// Layout of an already encoded container.
+    pub fn for_encoded(format: RasterFormat, reader: &RasterReader) -> Result<Self> {
+        match format {
+            RasterFormat::Tiff => {
+                if reader.depth != 16 {
+                    return Err(anyhow!("Not a Sound_PNG encoded TIFF (expected 16-bit RGB)."));
+                }
+                let tag = reader.description.as_deref().and_then(|d| d.strip_prefix(LAYOUT_PREFIX));
+                Ok(match tag {
+                    Some(tag) => Self::from_tag(tag).ok_or_else(|| anyhow!("Unknown TIFF layout: {}", tag))?,
+                    None => Self::Expand8,
+                })
+            }
+            RasterFormat::Bmp | RasterFormat::Webp if reader.depth == 8 => Ok(Self::Lsb2),
+            _ => Err(anyhow!("Not a Sound_PNG encoded {} image.", format.name())),
+        }
+    }
+
+    /// Bits per channel of the encoded output.
+    pub fn output_depth(self) -> u8 {
+        match self {
+            Self::Expand8 | Self::Lsb8 => 16,
+            Self::Lsb2 => 8,
+        }
+    }
+
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Expand8 => "expand8",
+            Self::Lsb8 => "lsb8",
+            Self::Lsb2 => "lsb2",
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        [Self::Expand8, Self::Lsb8, Self::Lsb2].into_iter().find(|l| l.tag() == tag)
+    }
+
+    /// Payload bytes carried by `pixels` pixels.
+    pub fn capacity(self, pixels: u64) -> u64 {
+        match self {
+            Self::Expand8 | Self::Lsb8 => pixels * 3,
+            Self::Lsb2 => pixels * 3 / 4,
+        }
+    }
+
+    /// Pixels needed to carry `bytes` payload bytes.
+    pub fn pixels_for(self, bytes: u64) -> u64 {
+        match self {
+            Self::Expand8 | Self::Lsb8 => bytes.div_ceil(3),
+            Self::Lsb2 => (bytes * 4).div_ceil(3),
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Expand8 => "16-bit RGB LSB",
+            Self::Lsb8 => "16-bit RGB, 8-bit LSB",
+            Self::Lsb2 => "8-bit RGB, 2-bit LSB",
+        }
+    }
+}
+
+/// Row source for any image container: RGB rows at `depth` bits per channel.
+/// BMP (24/32-bit) and TIFF strips are streamed; other files are decoded in memory.
+pub struct RasterReader {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u8,
+    /// TIFF ImageDescription, if any.
+    pub description: Option<String>,
+    rows: Box<dyn Iterator<Item = Result<Vec<u16>>> + Send>,
+}
+
+impl RasterReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        let read = File::open(path)?.read(&mut magic)?;
+        let magic = &magic[..read];
+
+        if magic.starts_with(b"BM") {
+            if let Some(rows) = BmpRows::open(path)? {
+                return Ok(Self { width: rows.width, height: rows.height, depth: 8, description: None, rows: Box::new(rows) });
+            }
+        } else if magic == b"II*\0" || magic == b"MM\0*" {
+            if let Some(rows) = TiffRows::open(path)? {
+                return Ok(Self {
+                    width: rows.width,
+                    height: rows.height,
+                    depth: rows.depth,
+                    description: rows.description.clone(),
+                    rows: Box::new(rows),
+                });
+            }
+        }
+        Self::decode_whole(path)
+    }
+
+    /// Fallback through the `image` crate (WebP, PNG, palette / RLE BMPs, unusual TIFFs).
+    fn decode_whole(path: &Path) -> Result<Self> {
+        use image::ColorType;
+
+        let img = crate::converter::load_image_object(path)?;
+        let (width, height) = (img.width(), img.height());
+        let wide = matches!(img.color(), ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16);
+        let (depth, data): (u8, Vec<u16>) = if wide {
+            (16, img.to_rgb16().into_raw())
+        } else {
+            (8, img.to_rgb8().into_raw().into_iter().map(u16::from).collect())
+        };
+        let row_len = width as usize * 3;
+        let mut row = 0;
+        let rows = std::iter::from_fn(move || {
+            let start = row * row_len;
+            row += 1;
+            (start < data.len()).then(|| Ok(data[start..start + row_len].to_vec()))
+        });
+        Ok(Self { width, height, depth, description: None, rows: Box::new(rows) })
+    }
+}
+
+impl Iterator for RasterReader {
+    type Item = Result<Vec<u16>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u16>>> {
+        self.rows.next()
+    }
+}
+
+/// Width, height and depth without reading the pixels (where the format allows it).
+pub fn probe(path: &Path) -> Result<(u32, u32, u8)> {
+    let mut magic = [0u8; 4];
+    let read = File::open(path)?.read(&mut magic)?;
+    if magic[..read].starts_with(b"BM") || magic == *b"II*\0" || magic == *b"MM\0*" {
+        let reader = RasterReader::open(path)?;
+        return Ok((reader.width, reader.height, reader.depth));
+    }
+    // WebP and the rest only come in 8 bits per channel here
+    let (width, height) = image::image_dimensions(path)?;
+    Ok((width, height, 8))
+}
+
+/// Streaming writer for any `RasterFormat`.
+pub enum RasterWriter {
+    Bmp(BmpWriter),
+    Tiff(TiffWriter),
+    Webp(WebpWriter),
+}
+
+impl RasterWriter {
+    /// `depth` must be 8 for BMP and WebP. `layout` is recorded where the format has room for it.
+    pub fn create(path: &Path, format: RasterFormat, width: u32, height: u32, depth: u8, layout: Option<ImageLayout>) -> Result<Self> {
+        if depth != 8 && format != RasterFormat::Tiff {
+            return Err(anyhow!("{} supports 8-bit channels only.", format.name()));
+        }
+        Ok(match format {
+            RasterFormat::Bmp => Self::Bmp(BmpWriter::create(path, width, height)?),
+            RasterFormat::Tiff => {
+                let description = layout.map(|l| format!("{}{}", LAYOUT_PREFIX, l.tag()));
+                Self::Tiff(TiffWriter::create(path, width, height, depth, description.as_deref())?)
+            }
+            RasterFormat::Webp => Self::Webp(WebpWriter::create(path, width, height)?),
+        })
+    }
+
+    /// Writes one row of RGB values at the writer's depth.
+    pub fn write_row(&mut self, rgb: &[u16]) -> Result<()> {
+        match self {
+            Self::Bmp(writer) => writer.write_row(rgb),
+            Self::Tiff(writer) => writer.write_row(rgb),
+            Self::Webp(writer) => writer.write_row(rgb),
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            Self::Bmp(writer) => writer.finish(),
+            Self::Tiff(writer) => writer.finish(),
+            Self::Webp(writer) => writer.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Writes a `width`x`height` test image with `format` and returns its RGB samples.
+    fn write(path: &Path, format: RasterFormat, width: u32, height: u32, depth: u8) -> Result<Vec<u16>> {
+        let max = if depth == 16 { 0xFFFF } else { 0xFF };
+        let pixels: Vec<u16> = (0..width as usize * height as usize * 3)
+            .map(|i| match i % 97 {
+                0 => 0,
+                1 => max,
+                _ => ((i * 2654435761) >> 9) as u16 & max,
+            })
+            .collect();
+        let mut writer = RasterWriter::create(path, format, width, height, depth, Some(ImageLayout::Lsb8))?;
+        for row in pixels.chunks(width as usize * 3) {
+            writer.write_row(row)?;
+        }
+        writer.finish()?;
+        Ok(pixels)
+    }
+
+    #[test]
+    fn test_writers_decode_with_image() -> Result<()> {
+        let dir = tempdir()?;
+        let cases = [(RasterFormat::Bmp, 8), (RasterFormat::Tiff, 8), (RasterFormat::Tiff, 16), (RasterFormat::Webp, 8)];
+        // Odd widths exercise BMP row padding and unaligned VP8L bit positions
+        for (format, depth) in cases {
+            for (width, height) in [(1, 1), (7, 5), (33, 9), (256, 3)] {
+                let path = dir.path().join(format!("test.{}", format.name().to_lowercase()));
+                let expected = write(&path, format, width, height, depth)?;
+                let img = image::open(&path)?;
+                assert_eq!((img.width(), img.height()), (width, height));
+                let decoded: Vec<u16> = if depth == 16 {
+                    assert_eq!(img.color(), image::ColorType::Rgb16);
+                    img.to_rgb16().into_raw()
+                } else if format == RasterFormat::Webp {
+                    // VP8L always decodes with alpha; ours is opaque
+                    let rgba = img.to_rgba8();
+                    assert!(rgba.pixels().all(|p| p[3] == 255));
+                    rgba.pixels().flat_map(|p| [p[0], p[1], p[2]].map(u16::from)).collect()
+                } else {
+                    assert_eq!(img.color(), image::ColorType::Rgb8, "{:?}", format);
+                    img.to_rgb8().into_raw().into_iter().map(u16::from).collect()
+                };
+                assert_eq!(decoded, expected, "{:?} {} bits {}x{}", format, depth, width, height);
+
+                // Our own reader sees the same rows
+                let reader = RasterReader::open(&path)?;
+                assert_eq!((reader.width, reader.height, reader.depth), (width, height, depth));
+                let description = reader.description.clone();
+                let read: Vec<u16> = reader.collect::<Result<Vec<_>>>()?.concat();
+                assert_eq!(read, expected, "{:?} {} bits {}x{}", format, depth, width, height);
+                if format == RasterFormat::Tiff {
+                    assert_eq!(description.as_deref(), Some("SPNG_LAYOUT=lsb8"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer_limits() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("test.webp");
+        assert!(RasterWriter::create(&path, RasterFormat::Webp, crate::webp::MAX_DIMENSION + 1, 1, 8, None).is_err());
+        assert!(RasterWriter::create(&path, RasterFormat::Webp, 0, 1, 8, None).is_err());
+        assert!(RasterWriter::create(&path, RasterFormat::Bmp, 4, 4, 16, None).is_err());
+        assert!(RasterWriter::create(&path, RasterFormat::Tiff, 4, 4, 12, None).is_err());
+    }
+
+    #[test]
+    fn test_probe_matches_open() -> Result<()> {
+        let dir = tempdir()?;
+        let mut paths = Vec::new();
+        for (format, depth) in [(RasterFormat::Bmp, 8), (RasterFormat::Tiff, 8), (RasterFormat::Tiff, 16), (RasterFormat::Webp, 8)] {
+            let path = dir.path().join(format!("written{}.{}", depth, format.name().to_lowercase()));
+            write(&path, format, 13, 6, depth)?;
+            paths.push(path);
+        }
+        // Files from other encoders: a palette BMP (decoded whole), gray and RGBA TIFFs
+        let gray = image::GrayImage::from_fn(9, 4, |x, y| image::Luma([(x * 20 + y) as u8]));
+        let palette_bmp = dir.path().join("palette.bmp");
+        gray.save(&palette_bmp)?;
+        let gray_tif = dir.path().join("gray.tif");
+        gray.save(&gray_tif)?;
+        let rgba16 = image::ImageBuffer::<image::Rgba<u16>, _>::from_fn(5, 3, |x, y| image::Rgba([x as u16 * 999, y as u16 * 777, 3, 40000]));
+        let rgba_tif = dir.path().join("rgba16.tif");
+        rgba16.save(&rgba_tif)?;
+        paths.extend([palette_bmp, gray_tif, rgba_tif]);
+
+        for path in paths {
+            let reader = RasterReader::open(&path)?;
+            assert_eq!(probe(&path)?, (reader.width, reader.height, reader.depth), "{:?}", path);
+            let rows = reader.collect::<Result<Vec<_>>>()?;
+            let (width, height, _) = probe(&path)?;
+            assert_eq!(rows.len(), height as usize);
+            assert!(rows.iter().all(|row| row.len() == width as usize * 3));
+        }
+        Ok(())
+    }
+}
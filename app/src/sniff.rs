@@ -0,0 +1,337 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Bytes inspected when sniffing a payload.
+pub const SNIFF_LEN: usize = 512;
+
+/// Payload type detected from magic bytes. Stored as one byte in the header (0 = unknown).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentType {
+    #[default]
+    Unknown = 0,
+    Png = 1,
+    Jpeg = 2,
+    Gif = 3,
+    Bmp = 4,
+    Webp = 5,
+    Tiff = 6,
+    Wav = 7,
+    Flac = 8,
+    Mp3 = 9,
+    Ogg = 10,
+    Aiff = 11,
+    Mp4 = 12,
+    Zip = 13,
+    Gzip = 14,
+    SevenZip = 15,
+    Rar = 16,
+    Pdf = 17,
+    Exe = 18,
+    Elf = 19,
+    Text = 20,
+    Au = 21,
+}
+
+const ALL: [ContentType; 21] = [
+    ContentType::Png, ContentType::Jpeg, ContentType::Gif, ContentType::Bmp, ContentType::Webp,
+    ContentType::Tiff, ContentType::Wav, ContentType::Flac, ContentType::Mp3, ContentType::Ogg,
+    ContentType::Aiff, ContentType::Mp4, ContentType::Zip, ContentType::Gzip, ContentType::SevenZip,
+    ContentType::Rar, ContentType::Pdf, ContentType::Exe, ContentType::Elf, ContentType::Text,
+    ContentType::Au,
+];
+
+impl ContentType {
+    pub fn from_u8(v: u8) -> Self {
+        ALL.iter().copied().find(|t| *t as u8 == v).unwrap_or(Self::Unknown)
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Unknown => "application/octet-stream",
+            Self::Png => "image/png",
+            Self::Jpeg => "image/jpeg",
+            Self::Gif => "image/gif",
+            Self::Bmp => "image/bmp",
+            Self::Webp => "image/webp",
+            Self::Tiff => "image/tiff",
+            Self::Wav => "audio/wav",
+            Self::Flac => "audio/flac",
+            Self::Mp3 => "audio/mpeg",
+            Self::Ogg => "audio/ogg",
+            Self::Aiff => "audio/aiff",
+            Self::Mp4 => "video/mp4",
+            Self::Zip => "application/zip",
+            Self::Gzip => "application/gzip",
+            Self::SevenZip => "application/x-7z-compressed",
+            Self::Rar => "application/vnd.rar",
+            Self::Pdf => "application/pdf",
+            Self::Exe => "application/vnd.microsoft.portable-executable",
+            Self::Elf => "application/x-elf",
+            Self::Text => "text/plain",
+            Self::Au => "audio/basic",
+        }
+    }
+
+    /// Extensions that are consistent with this type; the first one is the suggested one.
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Self::Unknown => &[],
+            Self::Png => &["png", "apng"],
+            Self::Jpeg => &["jpg", "jpeg", "jfif"],
+            Self::Gif => &["gif"],
+            Self::Bmp => &["bmp", "dib"],
+            Self::Webp => &["webp"],
+            Self::Tiff => &["tif", "tiff"],
+            Self::Wav => &["wav", "wave"],
+            Self::Flac => &["flac"],
+            Self::Mp3 => &["mp3"],
+            Self::Ogg => &["ogg", "oga", "opus"],
+            Self::Aiff => &["aiff", "aif", "aifc"],
+            Self::Mp4 => &["mp4", "m4a", "m4v", "mov", "3gp"],
+            Self::Zip => &["zip", "apk", "jar", "docx", "xlsx", "pptx", "odt", "epub"],
+            Self::Gzip => &["gz", "tgz"],
+            Self::SevenZip => &["7z"],
+            Self::Rar => &["rar"],
+            Self::Pdf => &["pdf"],
+            Self::Exe => &["exe", "dll"],
+            Self::Elf => &["elf", "so"],
+            Self::Text => &["txt", "md", "csv", "json", "xml", "html", "htm", "toml", "yaml", "yml", "log"],
+            Self::Au => &["au", "snd"],
+        }
+    }
+
+    pub fn suggested_extension(self) -> Option<&'static str> {
+        self.extensions().first().copied()
+    }
+
+    /// The type an extension usually denotes, `Unknown` if the extension is not in the table.
+    pub fn from_extension(ext: &str) -> Self {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        ALL.iter().copied().find(|t| t.extensions().contains(&ext.as_str())).unwrap_or(Self::Unknown)
+    }
+
+    /// True when `ext` names a different known type than `self`.
+    pub fn conflicts_with_extension(self, ext: &str) -> bool {
+        let claimed = Self::from_extension(ext);
+        self != Self::Unknown && claimed != Self::Unknown && claimed != self
+    }
+}
+
+/// Detects the content type from the first bytes of a file.
+pub fn sniff(head: &[u8]) -> ContentType {
+    let starts = |magic: &[u8]| head.starts_with(magic);
+    let riff = |form: &[u8]| head.len() >= 12 && &head[0..4] == b"RIFF" && &head[8..12] == form;
+    let iff = |form: &[u8]| head.len() >= 12 && &head[0..4] == b"FORM" && &head[8..12] == form;
+
+    if starts(b"\x89PNG\r\n\x1a\n") {
+        ContentType::Png
+    } else if starts(&[0xFF, 0xD8, 0xFF]) {
+        ContentType::Jpeg
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        ContentType::Gif
+    } else if riff(b"WEBP") {
+        ContentType::Webp
+    } else if riff(b"WAVE") {
+        ContentType::Wav
+    } else if iff(b"AIFF") || iff(b"AIFC") {
+        ContentType::Aiff
+    } else if starts(b".snd") {
+        ContentType::Au
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        ContentType::Tiff
+    } else if starts(b"fLaC") {
+        ContentType::Flac
+    } else if starts(b"OggS") {
+        ContentType::Ogg
+    } else if starts(b"ID3") || (head.len() >= 2 && head[0] == 0xFF && matches!(head[1], 0xFB | 0xFA | 0xF3 | 0xF2)) {
+        ContentType::Mp3
+    } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+        ContentType::Mp4
+    } else if starts(b"PK\x03\x04") || starts(b"PK\x05\x06") {
+        ContentType::Zip
+    } else if starts(&[0x1F, 0x8B]) {
+        ContentType::Gzip
+    } else if starts(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C]) {
+        ContentType::SevenZip
+    } else if starts(b"Rar!\x1a\x07") {
+        ContentType::Rar
+    } else if starts(b"%PDF-") {
+        ContentType::Pdf
+    } else if starts(b"\x7fELF") {
+        ContentType::Elf
+    } else if starts(b"MZ") && is_pe(head) {
+        ContentType::Exe
+    } else if starts(b"BM") && is_bmp(head) {
+        ContentType::Bmp
+    } else if looks_like_text(head) {
+        ContentType::Text
+    } else {
+        ContentType::Unknown
+    }
+}
+
+fn u16_at(head: &[u8], pos: usize) -> Option<u16> {
+    head.get(pos..pos + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(head: &[u8], pos: usize) -> Option<u32> {
+    head.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// `MZ` alone is common at the start of text. `e_lfanew` must point at `PE\0\0`, or, when that
+/// lies past the sniffed head, the DOS header must be sane (at least 4 header paragraphs and a
+/// small, aligned `e_lfanew` behind it).
+fn is_pe(head: &[u8]) -> bool {
+    let (Some(paragraphs), Some(e_lfanew)) = (u16_at(head, 0x08), u32_at(head, 0x3C)) else {
+        return false;
+    };
+    let e_lfanew = e_lfanew as usize;
+    match head.get(e_lfanew..e_lfanew + 4) {
+        Some(signature) => signature == b"PE\0\0",
+        None => head.len() >= SNIFF_LEN && paragraphs >= 4 && (64..0x10000).contains(&e_lfanew) && e_lfanew.is_multiple_of(4),
+    }
+}
+
+/// `BM` plus a consistent file header: zero reserved bytes, a known DIB header size, and a
+/// file size and pixel data offset that leave room for the headers.
+fn is_bmp(head: &[u8]) -> bool {
+    let (Some(size), Some(offset), Some(dib)) = (u32_at(head, 2), u32_at(head, 10), u32_at(head, 14)) else {
+        return false;
+    };
+    let (size, offset, dib) = (size as usize, offset as usize, dib as usize);
+    head[6..10] == [0; 4]
+        && matches!(dib, 12 | 40 | 56 | 108 | 124)
+        && offset >= 14 + dib
+        && size >= offset.max(head.len())
+}
+
+/// Reads the head of a file and sniffs it.
+pub fn sniff_file(path: &Path) -> std::io::Result<ContentType> {
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?.take(SNIFF_LEN as u64).read_to_end(&mut head)?;
+    Ok(sniff(&head))
+}
+
+/// UTF-8 without control characters other than whitespace. The sample may end mid-character.
+fn looks_like_text(head: &[u8]) -> bool {
+    if head.is_empty() {
+        return false;
+    }
+    let valid = match std::str::from_utf8(head) {
+        Ok(s) => s,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap(),
+        Err(_) => return false,
+    };
+    valid.chars().all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t' | '\x0C'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn image_bytes(format: image::ImageOutputFormat) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(20, 20, |x, y| image::Rgb([x as u8 * 12, y as u8 * 12, 99]));
+        let mut out = Cursor::new(Vec::new());
+        img.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    fn riff(form: &[u8]) -> Vec<u8> {
+        [b"RIFF".as_slice(), &[100, 0, 0, 0], form, &[0; 20]].concat()
+    }
+
+    /// A minimal PE image: DOS header with `e_lfanew` = 0x80, then the PE signature.
+    fn pe() -> Vec<u8> {
+        let mut exe = vec![0u8; SNIFF_LEN];
+        exe[..2].copy_from_slice(b"MZ");
+        exe[0x08] = 4;
+        exe[0x3C] = 0x80;
+        exe[0x80..0x84].copy_from_slice(b"PE\0\0");
+        exe
+    }
+
+    #[test]
+    fn test_sniff_every_type() {
+        let mut far_pe = pe();
+        far_pe[0x3C..0x40].copy_from_slice(&0x400u32.to_le_bytes());
+        let cases: Vec<(Vec<u8>, ContentType)> = vec![
+            (image_bytes(image::ImageOutputFormat::Png), ContentType::Png),
+            (image_bytes(image::ImageOutputFormat::Jpeg(90)), ContentType::Jpeg),
+            (image_bytes(image::ImageOutputFormat::Gif), ContentType::Gif),
+            (image_bytes(image::ImageOutputFormat::Bmp), ContentType::Bmp),
+            (image_bytes(image::ImageOutputFormat::Tiff), ContentType::Tiff),
+            (b"MM\0*\0\0\0\x08".to_vec(), ContentType::Tiff),
+            (riff(b"WEBP"), ContentType::Webp),
+            (riff(b"WAVE"), ContentType::Wav),
+            ([b"FORM".as_slice(), &[0, 0, 0, 50], b"AIFF"].concat(), ContentType::Aiff),
+            ([b"FORM".as_slice(), &[0, 0, 0, 50], b"AIFC"].concat(), ContentType::Aiff),
+            (b".snd\0\0\0\x18".to_vec(), ContentType::Au),
+            (b"fLaC\0\0\0\x22".to_vec(), ContentType::Flac),
+            (b"OggS\0\x02".to_vec(), ContentType::Ogg),
+            (b"ID3\x04\0".to_vec(), ContentType::Mp3),
+            (vec![0xFF, 0xFB, 0x90, 0x64], ContentType::Mp3),
+            (b"\0\0\0\x20ftypisom".to_vec(), ContentType::Mp4),
+            (b"PK\x03\x04\x14\0".to_vec(), ContentType::Zip),
+            (b"PK\x05\x06\0\0".to_vec(), ContentType::Zip),
+            (vec![0x1F, 0x8B, 0x08, 0x00], ContentType::Gzip),
+            (vec![0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C, 0x00, 0x04], ContentType::SevenZip),
+            (b"Rar!\x1a\x07\x01\0".to_vec(), ContentType::Rar),
+            (b"%PDF-1.7\n".to_vec(), ContentType::Pdf),
+            (b"\x7fELF\x02\x01\x01".to_vec(), ContentType::Elf),
+            (pe(), ContentType::Exe),
+            (far_pe, ContentType::Exe),
+            ("Grüße, world\r\n\tindented\n".as_bytes().to_vec(), ContentType::Text),
+            (vec![0x00, 0x01, 0x02, 0x03], ContentType::Unknown),
+            (Vec::new(), ContentType::Unknown),
+        ];
+        for (head, expected) in cases {
+            assert_eq!(sniff(&head), expected, "{:?}", &head[..head.len().min(16)]);
+            assert_eq!(ContentType::from_u8(expected as u8), expected);
+        }
+        // Text cut off in the middle of a character is still text
+        let text = "ü".repeat(SNIFF_LEN);
+        assert_eq!(sniff(&text.as_bytes()[..SNIFF_LEN - 1]), ContentType::Text);
+    }
+
+    #[test]
+    fn test_sniff_magic_prefixed_text() {
+        let long = |prefix: &str| format!("{} {}", prefix, "lorem ipsum dolor sit amet ".repeat(30)).into_bytes();
+        for head in [long("MZ"), long("MZ-2 route notes:"), long("BM"), long("BMW service log"), b"BM short".to_vec(), b"MZ".to_vec()] {
+            assert_eq!(sniff(&head), ContentType::Text, "{}", String::from_utf8_lossy(&head[..16.min(head.len())]));
+        }
+    }
+
+    #[test]
+    fn test_sniff_rejects_malformed_headers() {
+        // MZ with e_lfanew pointing at something other than the PE signature
+        let mut exe = pe();
+        exe[0x80..0x84].copy_from_slice(b"NE\0\0");
+        assert_eq!(sniff(&exe), ContentType::Unknown);
+        // PE signature past the head, but a DOS header that cannot be right
+        exe[0x3C..0x40].copy_from_slice(&0x400u32.to_le_bytes());
+        exe[0x08] = 1;
+        assert_eq!(sniff(&exe), ContentType::Unknown);
+
+        let bmp = image_bytes(image::ImageOutputFormat::Bmp);
+        for (pos, value) in [(6, 1u8), (14, 41), (2, 20)] {
+            let mut broken = bmp.clone();
+            broken[pos] = value;
+            if pos == 2 {
+                broken[3..6].fill(0);
+            }
+            assert_eq!(sniff(&broken), ContentType::Unknown, "byte {}", pos);
+        }
+    }
+
+    #[test]
+    fn test_extension_conflicts() {
+        assert_eq!(ContentType::from_extension(".JPG"), ContentType::Jpeg);
+        assert_eq!(ContentType::Png.suggested_extension(), Some("png"));
+        assert!(ContentType::Png.conflicts_with_extension("jpg"));
+        assert!(!ContentType::Png.conflicts_with_extension("apng"));
+        assert!(!ContentType::Png.conflicts_with_extension("bin"));
+        assert!(!ContentType::Unknown.conflicts_with_extension("jpg"));
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Result};
+use hound::WavWriter;
+use image::imageops::{self, FilterType};
+use std::f64::consts::PI;
+use std::path::Path;
+use crate::converter;
+
+// Visible spectrogram drawing: unlike the LSB modes nothing is hidden, the image is added to
+// the audio as a bank of sines (one per image row) so it shows up in a spectrum analyzer.
+
+/// Image rows are scaled down to at most this many frequency lines.
+const MAX_ROWS: u32 = 256;
+/// Image columns are scaled down to at most this many time steps.
+const MAX_COLUMNS: u32 = 1024;
+/// Brightness maps to level on a dB scale, as spectrum analyzers display it; black is silent.
+const DYNAMIC_RANGE_DB: f32 = 60.0;
+/// Fade in/out of the drawing, in seconds, so its edges do not click.
+const FADE_SECONDS: f64 = 0.005;
+/// Longest drawing, in seconds; it is synthesized in memory.
+const MAX_DURATION_SECONDS: f32 = 600.0;
+
+/// Where and how loud the image is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpectrogramOptions {
+    /// Frequency of the bottom row, in Hz.
+    pub low_hz: f32,
+    /// Frequency of the top row, in Hz; at most half the sample rate.
+    pub high_hz: f32,
+    /// Start of the image in the audio, in seconds; at most the length of the audio.
+    pub start: f32,
+    /// Length of the image, in seconds, up to `MAX_DURATION_SECONDS`. The audio is padded
+    /// with silence if it is shorter.
+    pub duration: f32,
+    /// Peak level of the drawing relative to full scale, in (0, 1].
+    pub intensity: f32,
+}
+
+impl Default for SpectrogramOptions {
+    fn default() -> Self {
+        Self { low_hz: 10000.0, high_hz: 18000.0, start: 0.0, duration: 5.0, intensity: 0.25 }
+    }
+}
+
+impl SpectrogramOptions {
+    /// Checks the options against a container of `sample_rate` Hz lasting `seconds`.
+    fn check(&self, sample_rate: u32, seconds: f64) -> Result<()> {
+        let nyquist = sample_rate as f32 / 2.0;
+        if !(self.low_hz >= 0.0 && self.low_hz < self.high_hz && self.high_hz <= nyquist) {
+            return Err(anyhow!(
+                "Frequency band {}-{} Hz is invalid; it must lie within 0-{} Hz for a {} Hz container.",
+                self.low_hz, self.high_hz, nyquist, sample_rate
+            ));
+        }
+        if !(self.start >= 0.0 && self.start as f64 <= seconds) {
+            return Err(anyhow!("Start must lie within the audio (0-{:.2} s).", seconds));
+        }
+        if !(self.duration > 0.0 && self.duration <= MAX_DURATION_SECONDS) {
+            return Err(anyhow!("Duration must be more than 0 s and at most {} s.", MAX_DURATION_SECONDS));
+        }
+        if !(self.intensity > 0.0 && self.intensity <= 1.0) {
+            return Err(anyhow!("Intensity must be between 0 and 1."));
+        }
+        Ok(())
+    }
+}
+
+/// Draws `image_path` into the spectrogram of `audio_path` and writes the result as a 16-bit WAV.
+/// Any audio the converter reads (WAV, MP3, Ogg) can be the source; all channels get the drawing.
+pub fn render(
+    image_path: &Path,
+    audio_path: &Path,
+    output_path: &Path,
+    options: &SpectrogramOptions,
+    mut on_progress: impl FnMut(f32),
+) -> Result<()> {
+    let (info, mut samples) = converter::load_audio_as_pcm(audio_path)?;
+    let rate = info.sample_rate as f64;
+    let channels = info.channels as usize;
+    options.check(info.sample_rate, (samples.len() / channels.max(1)) as f64 / rate)?;
+    let levels = Levels::load(image_path)?;
+
+    let start = (options.start as f64 * rate).round() as usize;
+    let length = ((options.duration as f64 * rate).round() as usize).max(1);
+    let end_frame = start + length;
+    if samples.len() < end_frame * channels {
+        samples.resize(end_frame * channels, 0);
+    }
+
+    let overlay = levels.synthesize(options, rate, length, &mut on_progress);
+    let peak = overlay.iter().fold(0f32, |m, v| m.max(v.abs()));
+    let scale = if peak > 0.0 { options.intensity * i16::MAX as f32 / peak } else { 0.0 };
+    for (i, value) in overlay.iter().enumerate() {
+        let frame = (start + i) * channels;
+        for sample in &mut samples[frame..frame + channels] {
+            *sample = (*sample as f32 + value * scale).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    let mut writer = WavWriter::create(output_path, info.pcm_spec())?;
+    for sample in samples {
+        writer.write_sample(sample)?;
+    }
+    writer.finalize()?;
+    on_progress(1.0);
+    Ok(())
+}
+
+/// Linear amplitude of each image cell, column-major (`columns` x `rows`, row 0 at the top).
+struct Levels {
+    columns: usize,
+    rows: usize,
+    amplitude: Vec<f32>,
+}
+
+impl Levels {
+    fn load(path: &Path) -> Result<Self> {
+        let image = converter::load_image_object(path)?.to_luma_alpha8();
+        let (width, height) = image.dimensions();
+        let (columns, rows) = (width.min(MAX_COLUMNS), height.min(MAX_ROWS));
+        let image = imageops::resize(&image, columns, rows, FilterType::Triangle);
+
+        let mut amplitude = Vec::with_capacity((columns * rows) as usize);
+        for x in 0..columns {
+            for y in 0..rows {
+                let [luma, alpha] = image.get_pixel(x, y).0;
+                // Transparent pixels count as black
+                let value = luma as f32 / 255.0 * alpha as f32 / 255.0;
+                amplitude.push(if value > 0.0 { 10f32.powf(-DYNAMIC_RANGE_DB * (1.0 - value) / 20.0) } else { 0.0 });
+            }
+        }
+        Ok(Self { columns: columns as usize, rows: rows as usize, amplitude })
+    }
+
+    /// Sum of one sine per row over `length` frames, the amplitudes interpolated between columns.
+    fn synthesize(&self, options: &SpectrogramOptions, rate: f64, length: usize, on_progress: &mut impl FnMut(f32)) -> Vec<f32> {
+        let (low, high) = (options.low_hz as f64, options.high_hz as f64);
+        let rows = self.rows;
+        let omega: Vec<f64> = (0..rows)
+            .map(|r| {
+                let f = if rows == 1 { (low + high) / 2.0 } else { high - (high - low) * r as f64 / (rows - 1) as f64 };
+                2.0 * PI * f / rate
+            })
+            .collect();
+        // Schroeder phases keep the peak of the sum low, so less headroom is needed
+        let phase: Vec<f64> = (0..rows).map(|r| PI * (r * r) as f64 / rows as f64).collect();
+        let fade = (FADE_SECONDS * rate).max(1.0);
+
+        let mut out = Vec::with_capacity(length);
+        for n in 0..length {
+            let x = (n as f64 + 0.5) / length as f64 * self.columns as f64 - 0.5;
+            let c0 = x.floor().clamp(0.0, (self.columns - 1) as f64) as usize;
+            let c1 = (c0 + 1).min(self.columns - 1);
+            let t = (x - c0 as f64).clamp(0.0, 1.0) as f32;
+            let (a0, a1) = (&self.amplitude[c0 * rows..][..rows], &self.amplitude[c1 * rows..][..rows]);
+
+            let mut sum = 0f32;
+            for r in 0..rows {
+                let amp = a0[r] + (a1[r] - a0[r]) * t;
+                if amp > 0.0 {
+                    sum += amp * (omega[r] * n as f64 + phase[r]).sin() as f32;
+                }
+            }
+            let edge = (n.min(length - 1 - n) as f64 / fade).min(1.0) as f32;
+            out.push(sum * edge);
+
+            if n % 8192 == 0 {
+                on_progress(n as f32 / length as f32 * 0.95);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Power of `samples` at `freq` Hz (Goertzel).
+    fn power(samples: &[i16], freq: f64, rate: f64) -> f64 {
+        let coeff = 2.0 * (2.0 * PI * freq / rate).cos();
+        let (mut s1, mut s2) = (0.0, 0.0);
+        for &x in samples {
+            let s0 = x as f64 + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s0;
+        }
+        (s1 * s1 + s2 * s2 - coeff * s1 * s2) / samples.len() as f64
+    }
+
+    fn write_silence(path: &Path, rate: u32, frames: usize) -> Result<()> {
+        let spec = hound::WavSpec { channels: 2, sample_rate: rate, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = WavWriter::create(path, spec)?;
+        for _ in 0..frames * 2 {
+            writer.write_sample(0i16)?;
+        }
+        Ok(writer.finalize()?)
+    }
+
+    #[test]
+    fn test_render_draws_into_band() -> Result<()> {
+        let dir = tempdir()?;
+        let (audio, image, output) = (dir.path().join("in.wav"), dir.path().join("logo.png"), dir.path().join("out.wav"));
+        write_silence(&audio, 16000, 16000)?;
+        image::GrayImage::from_pixel(16, 8, image::Luma([255])).save(&image)?;
+        let options = SpectrogramOptions { low_hz: 2000.0, high_hz: 3000.0, start: 0.25, duration: 0.5, intensity: 0.5 };
+        render(&image, &audio, &output, &options, |_| {})?;
+
+        let mut reader = hound::WavReader::open(&output)?;
+        assert_eq!((reader.spec().channels, reader.spec().sample_rate, reader.spec().bits_per_sample), (2, 16000, 16));
+        let samples: Vec<i16> = reader.samples::<i16>().collect::<Result<_, _>>()?;
+        assert_eq!(samples.len(), 32000);
+        // Both channels carry the same drawing
+        assert!(samples.chunks_exact(2).all(|f| f[0] == f[1]));
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+
+        // Silent outside the drawing, which peaks at the intensity
+        assert!(left[..4000].iter().chain(&left[12000..]).all(|&s| s == 0));
+        let peak = left.iter().map(|s| s.unsigned_abs()).max().unwrap() as f32;
+        assert!((peak / i16::MAX as f32 - 0.5).abs() < 0.01, "peak {}", peak);
+        let drawn = &left[4000..12000];
+        let inside = power(drawn, 2500.0, 16000.0);
+        for outside in [500.0, 1500.0, 4000.0, 6000.0] {
+            assert!(inside > 100.0 * power(drawn, outside, 16000.0), "{} Hz", outside);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_pads_short_audio() -> Result<()> {
+        let dir = tempdir()?;
+        let (audio, image, output) = (dir.path().join("in.wav"), dir.path().join("logo.png"), dir.path().join("out.wav"));
+        write_silence(&audio, 8000, 800)?;
+        image::GrayImage::from_pixel(4, 4, image::Luma([200])).save(&image)?;
+        let options = SpectrogramOptions { low_hz: 1000.0, high_hz: 2000.0, start: 0.05, duration: 1.0, intensity: 0.1 };
+        render(&image, &audio, &output, &options, |_| {})?;
+        assert_eq!(hound::WavReader::open(&output)?.duration(), 8400);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let valid = SpectrogramOptions::default();
+        assert!(valid.check(44100, 10.0).is_ok());
+        let invalid = [
+            (SpectrogramOptions { high_hz: 22051.0, ..valid }, "Frequency band"),
+            (SpectrogramOptions { low_hz: 18000.0, ..valid }, "Frequency band"),
+            (SpectrogramOptions { low_hz: f32::NAN, ..valid }, "Frequency band"),
+            (SpectrogramOptions { start: -1.0, ..valid }, "Start"),
+            (SpectrogramOptions { start: 10.5, ..valid }, "Start"),
+            (SpectrogramOptions { start: f32::INFINITY, ..valid }, "Start"),
+            (SpectrogramOptions { duration: 0.0, ..valid }, "Duration"),
+            (SpectrogramOptions { duration: MAX_DURATION_SECONDS + 1.0, ..valid }, "Duration"),
+            (SpectrogramOptions { duration: f32::INFINITY, ..valid }, "Duration"),
+            (SpectrogramOptions { intensity: 0.0, ..valid }, "Intensity"),
+            (SpectrogramOptions { intensity: 1.5, ..valid }, "Intensity"),
+        ];
+        for (options, message) in invalid {
+            let e = options.check(44100, 10.0).unwrap_err();
+            assert!(e.to_string().starts_with(message), "{:?}: {}", options, e);
+        }
+        assert_eq!(
+            SpectrogramOptions { start: 10.5, ..valid }.check(44100, 10.0).unwrap_err().to_string(),
+            "Start must lie within the audio (0-10.00 s)."
+        );
+        // The drawing may start right at the end and extend the audio
+        assert!(SpectrogramOptions { start: 10.0, ..valid }.check(44100, 10.0).is_ok());
+    }
+}
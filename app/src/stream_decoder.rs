@@ -1,15 +1,34 @@
 use anyhow::{Result, anyhow, Context};
 use std::io::{self, Read, Write};
 use flate2::read::DeflateDecoder;
-use crate::header::{self, Header};
+use crate::header::{self, ChannelMode, FitPolicy, Header};
 use crate::security;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use rand::SeedableRng;
 use rand_chacha::ChaCha8Rng;
 use rand::RngCore;
 use crate::plugin_loader::PluginManager;
+use crate::plugin_manifest::Capability;
 use std::sync::{Arc, Mutex};
+use crate::postprocess;
+use crate::sniff::{self, ContentType};
+use crate::utils::WavLayout;
+use crate::pcm::{self, PcmFormat, PcmWriter};
+use crate::jpeg::{self, JpegBlocks};
+use crate::{apng, channels, gif_palette};
+use crate::carrier::{self, CarrierFormat, CarrierReader};
+use crate::raster::{self, ImageLayout, RasterFormat, RasterReader, RasterWriter};
+use crate::flac::{self, FlacLayout, FlacSamples, FlacWriter};
+
+/// User-selectable knobs for `decode_stream`, applied after decompression.
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    /// Forces the payload extension (and image format) instead of the one stored in the header.
+    pub preset_ext: Option<String>,
+    /// Scale factor for image payloads; WAV payloads are resampled by the same factor.
+    pub resize_factor: Option<f32>,
+}
 
 /// Reads extracted payload bytes (encrypted/compressed) from a container stream.
 /// This reader yields the raw byte stream hidden in the container (Header + Payload).
@@ -17,12 +36,58 @@ enum ContainerReader {
     Png {
         reader: png::Reader<File>,
         bpp: usize,
+        /// Current row, extracted one pixel at a time so `FLAG_PNG_ALPHA` can take
+        /// effect right after the header.
+        row: Vec<u8>,
+        pos: usize,
+        pixel: u64,
+        alpha: bool,
+        /// APNG frames after the current one.
+        frames_left: u32,
         extracted_buf: std::collections::VecDeque<u8>, 
     },
-    Wav {
-        iter: hound::WavIntoSamples<io::BufReader<File>, i32>,
+    /// BMP, TIFF and WebP (see `ImageLayout`).
+    Raster {
+        reader: RasterReader,
+        layout: ImageLayout,
+        /// Byte being assembled from 2-bit slots, and slots filled so far (`ImageLayout::Lsb2`).
+        pending: (u8, u8),
+        extracted_buf: std::collections::VecDeque<u8>,
+    },
+    /// WAV, AIFF and AU share the `WavLayout` embedding.
+    Pcm {
+        /// Raw bits of each carrier value (i32 for int layouts, f32 bits for float), see
+        /// `channels::carriers`.
+        iter: Box<dyn Iterator<Item = Result<u32>> + Send>,
+        layout: WavLayout,
+        /// Byte being assembled from LSB fields, and bits filled so far (`Int24`, `Int16`).
+        pending: (u8, u32),
+        extracted_buf: std::collections::VecDeque<u8>,
+    },
+    Flac {
+        /// Carrier values as raw bits, see `channels::carriers`.
+        iter: Box<dyn Iterator<Item = Result<u32>> + Send>,
+        layout: FlacLayout,
+        /// Byte being assembled from LSB fields, and bits filled so far (`Int24`, `Int16`).
+        pending: (u8, u32),
+        extracted_buf: std::collections::VecDeque<u8>,
+    },
+    /// One payload bit per usable AC coefficient (see `jpeg::is_usable`).
+    Jpeg {
+        blocks: Box<JpegBlocks>,
+        /// Byte being assembled from coefficient LSBs, and bits filled so far.
+        pending: (u8, u8),
         extracted_buf: std::collections::VecDeque<u8>,
     },
+    /// One payload bit per palette index (see `gif_palette`), frame by frame.
+    Gif {
+        frames: gif_palette::GifBits,
+        /// Byte being assembled from index LSBs, and bits filled so far.
+        pending: (u8, u8),
+        extracted_buf: std::collections::VecDeque<u8>,
+    },
+    /// Metadata carrier mode: the stream is stored as-is in `spNG` chunks.
+    Carrier(CarrierReader),
     Plugin(Box<dyn Read + Send>), // Added for plugins
 }
 
@@ -36,14 +101,70 @@ impl ContainerReader {
         Ok(Self::Png {
             reader,
             bpp,
+            row: Vec::new(),
+            pos: 0,
+            pixel: 0,
+            alpha: false,
+            frames_left: apng::images(&info) - 1,
             extracted_buf: std::collections::VecDeque::new(),
         })
     }
+
+    /// Layout the header was found in, needed again to restore a PCM container.
+    fn wav_layout(&self) -> Option<WavLayout> {
+        match self {
+            Self::Pcm { layout, .. } => Some(*layout),
+            _ => None,
+        }
+    }
+
+    /// Applies the header flags that change how the rest of the container is read.
+    fn apply_header(&mut self, header: &Header) {
+        if let Self::Png { alpha, .. } = self {
+            *alpha = header.flags & header::FLAG_PNG_ALPHA != 0;
+        }
+    }
     
-    fn new_wav(file: File) -> Result<Self> {
-        let reader = hound::WavReader::new(io::BufReader::new(file))?;
-        Ok(Self::Wav {
-            iter: reader.into_samples::<i32>(),
+    fn new_raster(path: &Path, format: RasterFormat) -> Result<Self> {
+        let reader = RasterReader::open(path)?;
+        let layout = ImageLayout::for_encoded(format, &reader)?;
+        Ok(Self::Raster {
+            reader,
+            layout,
+            pending: (0, 0),
+            extracted_buf: std::collections::VecDeque::new(),
+        })
+    }
+
+    fn new_jpeg(path: &Path) -> Result<Self> {
+        Ok(Self::Jpeg {
+            blocks: Box::new(JpegBlocks::open(path)?),
+            pending: (0, 0),
+            extracted_buf: std::collections::VecDeque::new(),
+        })
+    }
+
+    fn new_pcm(path: &Path, format: PcmFormat, layout: WavLayout, mode: ChannelMode) -> Result<Self> {
+        let source = pcm::open(path, format)?;
+        channels::check(mode, source.spec.channels, source.spec.sample_format == hound::SampleFormat::Float)?;
+        Ok(Self::Pcm {
+            iter: channels::carriers(source.iter, source.spec.channels, mode),
+            layout,
+            pending: (0, 0),
+            extracted_buf: std::collections::VecDeque::new(),
+        })
+    }
+
+    fn new_flac(path: &PathBuf, mode: ChannelMode) -> Result<Self> {
+        let reader = claxon::FlacReader::open(path)?;
+        let layout = flac::encoded_layout(&reader)?;
+        let channels = reader.streaminfo().channels as u16;
+        channels::check(mode, channels, false)?;
+        let samples = Box::new(FlacSamples::new(reader).map(|s| s.map(|v| v as u32)));
+        Ok(Self::Flac {
+            iter: channels::carriers(samples, channels, mode),
+            layout,
+            pending: (0, 0),
             extracted_buf: std::collections::VecDeque::new(),
         })
     }
@@ -53,7 +174,42 @@ impl Read for ContainerReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match self {
             Self::Plugin(r) => r.read(buf),
-            Self::Png { extracted_buf, reader, bpp } => {
+            Self::Carrier(r) => r.read(buf),
+            Self::Png { extracted_buf, reader, bpp, row, pos, pixel, alpha, frames_left } => {
+                let mut total_read = 0;
+                while total_read < buf.len() {
+                    if let Some(b) = extracted_buf.pop_front() {
+                        buf[total_read] = b;
+                        total_read += 1;
+                        continue;
+                    }
+                    if *pos >= row.len() {
+                        match reader.next_row() {
+                            Ok(Some(next)) => {
+                                row.clear();
+                                row.extend_from_slice(next.data());
+                                *pos = 0;
+                            },
+                            Ok(None) if *frames_left > 0 => {
+                                *frames_left -= 1;
+                                reader.next_frame_info().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                                continue;
+                            },
+                            Ok(None) => break,
+                            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        }
+                    }
+                    let data = &row[*pos..(*pos + *bpp).min(row.len())];
+                    if data.len() > 1 { extracted_buf.push_back(data[1]); }
+                    if data.len() > 3 { extracted_buf.push_back(data[3]); }
+                    if data.len() > 5 { extracted_buf.push_back(data[5]); }
+                    if *alpha && *pixel >= header::PNG_ALPHA_START && data.len() > 7 { extracted_buf.push_back(data[7]); }
+                    *pos += *bpp;
+                    *pixel += 1;
+                }
+                Ok(total_read)
+            },
+            Self::Raster { extracted_buf, reader, layout, pending } => {
                 let mut total_read = 0;
                 while total_read < buf.len() {
                     if let Some(b) = extracted_buf.pop_front() {
@@ -61,24 +217,81 @@ impl Read for ContainerReader {
                         total_read += 1;
                         continue;
                     }
-                    match reader.next_row() {
-                        Ok(Some(row)) => {
-                            let data = row.data();
-                            let mut i = 0;
-                            while i < data.len() {
-                                if i+1 < data.len() { extracted_buf.push_back(data[i+1]); }
-                                if i+3 < data.len() { extracted_buf.push_back(data[i+3]); }
-                                if i+5 < data.len() { extracted_buf.push_back(data[i+5]); }
-                                i += *bpp;
+                    match reader.next() {
+                        Some(Ok(row)) => {
+                            for value in row {
+                                match layout {
+                                    ImageLayout::Expand8 | ImageLayout::Lsb8 => extracted_buf.push_back(value as u8),
+                                    ImageLayout::Lsb2 => {
+                                        pending.0 |= ((value & 0x03) as u8) << (2 * pending.1);
+                                        pending.1 += 1;
+                                        if pending.1 == 4 {
+                                            extracted_buf.push_back(pending.0);
+                                            *pending = (0, 0);
+                                        }
+                                    }
+                                }
                             }
-                        },
-                        Ok(None) => break,
-                        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        }
+                        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        None => break,
+                    }
+                }
+                Ok(total_read)
+            },
+            Self::Jpeg { extracted_buf, blocks, pending } => {
+                let mut total_read = 0;
+                while total_read < buf.len() {
+                    if let Some(b) = extracted_buf.pop_front() {
+                        buf[total_read] = b;
+                        total_read += 1;
+                        continue;
+                    }
+                    match blocks.next() {
+                        Some(Ok(block)) => {
+                            for (k, &value) in block.iter().enumerate() {
+                                if jpeg::is_usable(k, value) {
+                                    pending.0 |= ((value.unsigned_abs() & 1) as u8) << pending.1;
+                                    pending.1 += 1;
+                                    if pending.1 == 8 {
+                                        extracted_buf.push_back(pending.0);
+                                        *pending = (0, 0);
+                                    }
+                                }
+                            }
+                        }
+                        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        None => break,
                     }
                 }
                 Ok(total_read)
             },
-            Self::Wav { extracted_buf, iter } => {
+            Self::Gif { extracted_buf, frames, pending } => {
+                let mut total_read = 0;
+                while total_read < buf.len() {
+                    if let Some(b) = extracted_buf.pop_front() {
+                        buf[total_read] = b;
+                        total_read += 1;
+                        continue;
+                    }
+                    match frames.next() {
+                        Some(Ok(indices)) => {
+                            for bit in indices {
+                                pending.0 |= bit << pending.1;
+                                pending.1 += 1;
+                                if pending.1 == 8 {
+                                    extracted_buf.push_back(pending.0);
+                                    *pending = (0, 0);
+                                }
+                            }
+                        }
+                        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        None => break,
+                    }
+                }
+                Ok(total_read)
+            },
+            Self::Pcm { extracted_buf, iter, layout, pending } => {
                 let mut total_read = 0;
                 while total_read < buf.len() {
                     if let Some(b) = extracted_buf.pop_front() {
@@ -88,11 +301,18 @@ impl Read for ContainerReader {
                     }
                     if let Some(sample_res) = iter.next() {
                         match sample_res {
-                            Ok(sample) => {
-                                let chunk = (sample & 0xFFFF) as u16;
-                                let bytes = chunk.to_le_bytes();
-                                extracted_buf.push_back(bytes[0]);
-                                extracted_buf.push_back(bytes[1]);
+                            Ok(sample) => match layout {
+                                WavLayout::Expand16 => {
+                                    let bytes = ((sample & 0xFFFF) as u16).to_le_bytes();
+                                    extracted_buf.push_back(bytes[0]);
+                                    extracted_buf.push_back(bytes[1]);
+                                },
+                                WavLayout::Int24 | WavLayout::Int16 => {
+                                    if let Some(b) = push_bits(pending, sample, layout.mask()) {
+                                        extracted_buf.push_back(b);
+                                    }
+                                },
+                                WavLayout::Float32 | WavLayout::Int32 => extracted_buf.push_back((sample & 0xFF) as u8),
                             },
                             Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
                         }
@@ -101,11 +321,47 @@ impl Read for ContainerReader {
                     }
                 }
                 Ok(total_read)
+            },
+            Self::Flac { extracted_buf, iter, layout, pending } => {
+                let mut total_read = 0;
+                while total_read < buf.len() {
+                    if let Some(b) = extracted_buf.pop_front() {
+                        buf[total_read] = b;
+                        total_read += 1;
+                        continue;
+                    }
+                    match iter.next() {
+                        Some(Ok(sample)) => match layout {
+                            FlacLayout::Expand8 => extracted_buf.push_back((sample & 0xFF) as u8),
+                            FlacLayout::Int24 | FlacLayout::Int16 => {
+                                if let Some(b) = push_bits(pending, sample, layout.mask()) {
+                                    extracted_buf.push_back(b);
+                                }
+                            },
+                        },
+                        Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+                        None => break,
+                    }
+                }
+                Ok(total_read)
             }
         }
     }
 }
 
+/// Adds the `mask` bits of `sample` above the bits already in `pending` (low bits first)
+/// and returns the byte once it is complete.
+fn push_bits(pending: &mut (u8, u32), sample: u32, mask: u32) -> Option<u8> {
+    pending.0 |= ((sample & mask) << pending.1) as u8;
+    pending.1 += mask.count_ones();
+    if pending.1 < 8 {
+        return None;
+    }
+    let byte = pending.0;
+    *pending = (0, 0);
+    Some(byte)
+}
+
 struct DecryptReader<R: Read> {
     inner: R,
     rng: Option<ChaCha8Rng>,
@@ -157,42 +413,180 @@ impl<R: Read> Read for DecryptReader<R> {
     }
 }
 
+/// Candidate layouts of an encoded PCM container, most common first.
+fn pcm_layouts(path: &Path, format: PcmFormat) -> Result<&'static [WavLayout]> {
+    WavLayout::for_encoded(&pcm::probe(path, format)?.0)
+}
+
+fn read_header(reader: &mut ContainerReader) -> Result<Header> {
+    let mut header_bytes = vec![0u8; header::HEADER_SIZE_BYTES];
+    reader.read_exact(&mut header_bytes).context("Failed to read header")?;
+    let chunks: Vec<u16> = header_bytes.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+    Header::from_u16_chunks(&chunks)
+}
+
+/// Opens the raw extractor for `input_ext_hint` and reads the header from it.
+fn open_container(
+    input_path: &PathBuf,
+    input_ext_hint: &str,
+    carrier_format: Option<CarrierFormat>,
+    plugins: &Arc<Mutex<PluginManager>>,
+    on_progress: Box<dyn Fn(f32) + Send + Sync>
+) -> Result<(ContainerReader, Header)> {
+    let mut raw_extractor = {
+        let pm = plugins.lock().unwrap();
+        if let Some(decoder) = pm.get_decoder_by_ext(input_ext_hint) {
+             let reader = decoder.decode(input_path, on_progress)?;
+             ContainerReader::Plugin(reader)
+        } else if let Some(format) = carrier_format {
+             ContainerReader::Carrier(CarrierReader::open(input_path, format)?)
+        } else if input_ext_hint == "png" {
+             ContainerReader::new_png(File::open(input_path)?)?
+        } else if let Some(format) = RasterFormat::from_ext(input_ext_hint) {
+             ContainerReader::new_raster(input_path, format)?
+        } else if matches!(input_ext_hint, "jpg" | "jpeg") {
+             ContainerReader::new_jpeg(input_path)?
+        } else if input_ext_hint == "gif" {
+             ContainerReader::Gif { frames: gif_palette::GifBits::open(input_path)?, pending: (0, 0), extracted_buf: std::collections::VecDeque::new() }
+        } else if let Some(format) = PcmFormat::from_ext(input_ext_hint) {
+             ContainerReader::new_pcm(input_path, format, pcm_layouts(input_path, format)?[0], ChannelMode::All)?
+        } else if input_ext_hint == "flac" {
+             ContainerReader::new_flac(input_path, ChannelMode::All)?
+        } else {
+             return Err(anyhow!("Unsupported container: {}", input_ext_hint));
+        }
+    };
+
+    let header = match read_header(&mut raw_extractor) {
+        Ok(header) => header,
+        Err(e) if matches!(raw_extractor, ContainerReader::Pcm { .. } | ContainerReader::Flac { .. }) => {
+            // Audio embedded in selected channels keeps its header there too, and 32-bit PCM
+            // can be in either of two layouts
+            let layouts: Vec<Option<WavLayout>> = match PcmFormat::from_ext(input_ext_hint) {
+                Some(format) => pcm_layouts(input_path, format)?.iter().copied().map(Some).collect(),
+                None => vec![None],
+            };
+            let mut found = None;
+            'search: for (i, layout) in layouts.into_iter().enumerate() {
+                for mode in [ChannelMode::All, ChannelMode::Left, ChannelMode::Side] {
+                    if i == 0 && mode == ChannelMode::All {
+                        continue; // The attempt above
+                    }
+                    let reopened = match (PcmFormat::from_ext(input_ext_hint), layout) {
+                        (Some(format), Some(layout)) => ContainerReader::new_pcm(input_path, format, layout, mode),
+                        _ => ContainerReader::new_flac(input_path, mode),
+                    };
+                    let Ok(mut reader) = reopened else { continue };
+                    if let Ok(header) = read_header(&mut reader) {
+                        if header.channel_mode == mode {
+                            found = Some((reader, header));
+                            break 'search;
+                        }
+                    }
+                }
+            }
+            let (reader, header) = found.ok_or(e)?;
+            raw_extractor = reader;
+            header
+        }
+        Err(e) => return Err(e),
+    };
+    raw_extractor.apply_header(&header);
+    Ok((raw_extractor, header))
+}
+
+/// Reads only the header of `input_path`, so the decode tabs can tell whether a key is needed.
+/// Plugin containers are asked through `ContainerDecoder::peek_header`.
+pub fn analyze(input_path: &PathBuf, plugins: &Arc<Mutex<PluginManager>>, input_ext_hint: &str) -> Result<Header> {
+    let input_ext_hint = if input_ext_hint == "apng" { "png" } else { input_ext_hint };
+    if let Some(decoder) = plugins.lock().unwrap().get_decoder_by_ext(input_ext_hint) {
+        let bytes = decoder.peek_header(input_path, header::HEADER_SIZE_BYTES)?;
+        return Header::read_from_stream(&mut &bytes[..]).context("Failed to read header");
+    }
+    let carrier_format = CarrierFormat::from_hint(input_ext_hint).or_else(|| CarrierFormat::detect(input_path, input_ext_hint));
+    open_container(input_path, input_ext_hint, carrier_format, plugins, Box::new(|_| {})).map(|(_, header)| header)
+}
+
+/// What `decode_stream` learned about the recovered payload.
+#[derive(Debug, Clone)]
+pub struct PayloadInfo {
+    /// Path the payload was finally written to (after post-processing).
+    pub path: PathBuf,
+    /// Extension stored in the header; empty for raw streams, cut off after 8 bytes.
+    pub stored_ext: String,
+    /// Content type the encoder sniffed (unknown for pre-v3 files).
+    pub recorded: ContentType,
+    /// Content type sniffed from the final payload file, after post-processing.
+    pub detected: ContentType,
+    /// The decode preset rewrote the payload as another format, so `recorded` and `stored_ext`
+    /// describe the payload as it was embedded rather than the file written.
+    pub converted: bool,
+}
+
+impl PayloadInfo {
+    /// The stored extension, or the sniffed one when the stored one is empty,
+    /// possibly truncated, or names a different type than the content.
+    pub fn suggested_ext(&self) -> String {
+        let truncated = self.stored_ext.len() == 8 && ContentType::from_extension(&self.stored_ext) == ContentType::Unknown;
+        let unusable = self.stored_ext.is_empty() || truncated || self.detected.conflicts_with_extension(&self.stored_ext);
+        match self.detected.suggested_extension() {
+            Some(ext) if unusable => ext.to_string(),
+            _ => self.stored_ext.clone(),
+        }
+    }
+
+    /// Describes a disagreement between the header and the decoded content, if any.
+    pub fn mismatch(&self) -> Option<String> {
+        if self.converted {
+            // The user asked for the new format
+            None
+        } else if self.recorded != ContentType::Unknown && self.detected != self.recorded {
+            Some(format!("header records {} but the payload looks like {}", self.recorded.mime(), self.detected.mime()))
+        } else if self.detected.conflicts_with_extension(&self.stored_ext) {
+            Some(format!("stored extension .{} does not match the payload content ({})", self.stored_ext, self.detected.mime()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Decodes the payload hidden in `input_path` into `output_path` (streaming).
+/// If `container_out` is given, the clean cover is restored there as a second output.
+/// Returns where the payload ended up and what its content looks like.
+#[allow(clippy::too_many_arguments)]
 pub fn decode_stream(
     input_path: &PathBuf,
     output_path: &PathBuf,
+    container_out: Option<&PathBuf>,
     key_path: Option<&PathBuf>,
     buffer_size_kb: usize,
     plugins: &Arc<Mutex<PluginManager>>,
     input_ext_hint: String,
+    options: &DecodeOptions,
     on_progress: impl Fn(f32) + Send + Sync + 'static
-) -> Result<String> {
+) -> Result<PayloadInfo> {
     on_progress(0.0);
     let buffer_size = buffer_size_kb * 1024;
-    
-    // Plugin Check
-    let on_progress = Arc::new(on_progress);
+    // APNG files are PNGs to every reader
+    let input_ext_hint = if input_ext_hint == "apng" { "png".to_string() } else { input_ext_hint };
 
-    let mut raw_extractor = {
+    // A restoration the plugin cannot do fails before anything is written
+    if container_out.is_some() {
         let pm = plugins.lock().unwrap();
         if let Some(decoder) = pm.get_decoder_by_ext(&input_ext_hint) {
-             let cb = on_progress.clone();
-             let reader = decoder.decode(input_path, Box::new(move |p| cb(p)))?;
-             ContainerReader::Plugin(reader)
-        } else if input_ext_hint == "png" {
-             ContainerReader::new_png(File::open(input_path)?)?
-        } else if input_ext_hint == "wav" {
-             ContainerReader::new_wav(File::open(input_path)?)?
-        } else {
-             return Err(anyhow!("Unsupported container: {}", input_ext_hint));
+            if !pm.has_capability(&input_ext_hint, Capability::Restore) {
+                return Err(anyhow!("Plugin {} does not support container restoration.", decoder.metadata().name));
+            }
         }
-    };
-
-    // Header parsing...
-    let mut header_bytes = vec![0u8; header::HEADER_SIZE_BYTES];
-    raw_extractor.read_exact(&mut header_bytes).context("Failed to read header")?;
+    }
     
-    let chunks: Vec<u16> = header_bytes.chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
-    let header = Header::from_u16_chunks(&chunks)?;
+    // Plugin Check
+    let on_progress = Arc::new(on_progress);
+    // Carrier chunks are found by hint or, for plain PNG / WAV files, by looking for them
+    let carrier_format = CarrierFormat::from_hint(&input_ext_hint).or_else(|| CarrierFormat::detect(input_path, &input_ext_hint));
+
+    let cb = on_progress.clone();
+    let (raw_extractor, header) = open_container(input_path, &input_ext_hint, carrier_format, plugins, Box::new(move |p| cb(p)))?;
     
     on_progress(0.05);
 
@@ -204,6 +598,7 @@ pub fn decode_stream(
         None
     };
     
+    let wav_layout = raw_extractor.wav_layout();
     let decryptor = DecryptReader::new(raw_extractor, header.timestamp, key_stream, effective_encrypt, buffer_size);
     let limited_reader = decryptor.take(header.payload_len);
     let mut decompressor = DeflateDecoder::new(limited_reader);
@@ -212,10 +607,15 @@ pub fn decode_stream(
     let mut file_out = File::create(output_path)?;
     let mut buf = vec![0u8; buffer_size];
     let mut total_written = 0;
+    let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
     
     loop {
         let n = decompressor.read(&mut buf)?;
         if n == 0 { break; }
+        if head.len() < sniff::SNIFF_LEN {
+            let take = n.min(sniff::SNIFF_LEN - head.len());
+            head.extend_from_slice(&buf[..take]);
+        }
         file_out.write_all(&buf[..n])?;
         total_written += n as u64;
         
@@ -223,11 +623,378 @@ pub fn decode_stream(
              on_progress(0.1); 
         }
     }
+    drop(file_out);
+
+    // 4. Post-process payload (preset extension, resize/resample)
+    let final_path = postprocess::apply(output_path, options)?;
+    // Sniffed again after post-processing, so a preset conversion (e.g. JPEG to PNG) is what gets reported
+    let written = sniff::sniff(&head);
+    let detected = sniff::sniff_file(&final_path)?;
+    if &final_path != output_path {
+        tracing::info!("Payload written to {:?}", final_path);
+    }
+    
+    // 5. Restore Container (Optional)
+    if let Some(cont_out) = container_out {
+        on_progress(0.9);
+        let cb = on_progress.clone();
+        let restore_progress = move |p: f32| cb(0.9 + 0.1 * p);
+        let handled = {
+            let pm = plugins.lock().unwrap();
+            if let Some(decoder) = pm.get_decoder_by_ext(&input_ext_hint) {
+                decoder.restore_container(input_path, cont_out, Box::new(restore_progress.clone()))?;
+                true
+            } else {
+                false
+            }
+        };
+        if !handled {
+            if let Some(format) = carrier_format {
+                carrier::strip(format, input_path, cont_out)?;
+            } else if input_ext_hint == "png" {
+                restore_png(input_path, cont_out, &header, restore_progress)?;
+            } else if let Some(format) = RasterFormat::from_ext(&input_ext_hint) {
+                restore_raster(format, input_path, cont_out, &header, restore_progress)?;
+            } else if matches!(input_ext_hint.as_str(), "jpg" | "jpeg") {
+                jpeg::transcode(input_path, cont_out, |block| {
+                    // The original LSBs are gone; clearing them keeps every usable coefficient usable
+                    for (k, coefficient) in block.iter_mut().enumerate().skip(1) {
+                        if jpeg::is_usable(k, *coefficient) {
+                            *coefficient = jpeg::set_lsb(*coefficient, 0);
+                        }
+                    }
+                }, restore_progress)?;
+            } else if input_ext_hint == "gif" {
+                gif_palette::restore(input_path, cont_out, restore_progress)?;
+            } else if let (Some(format), Some(layout)) = (PcmFormat::from_ext(&input_ext_hint), wav_layout) {
+                restore_pcm(format, layout, input_path, cont_out, &header, restore_progress)?;
+            } else if input_ext_hint == "flac" {
+                restore_flac(input_path, cont_out, &header, restore_progress)?;
+            }
+        }
+    }
     
     on_progress(1.0);
     
     let ext_str = String::from_utf8_lossy(&header.extension).to_string();
     let clean_ext = ext_str.trim_matches(char::from(0)).to_string();
     
-    Ok(clean_ext)
-}
\ No newline at end of file
+    Ok(PayloadInfo {
+        path: final_path,
+        stored_ext: clean_ext,
+        recorded: header.content_type,
+        detected,
+        converted: detected != written,
+    })
+}
+
+/// Writes the cover image back as RGBA, row by row, alpha included. `Expand8` images
+/// return to 8 bits (the original values are the high bytes); `Lsb8` images came from
+/// 16-bit PNGs and keep 16 bits with the payload bytes (alpha too, with `FLAG_PNG_ALPHA`) cleared.
+fn restore_png(input: &PathBuf, output: &PathBuf, header: &Header, on_progress: impl Fn(f32)) -> Result<()> {
+    use png::{BitDepth, ColorType, Decoder, Encoder};
+
+    let mut reader = Decoder::new(File::open(input)?).read_info()?;
+    let info = reader.info().clone();
+    if info.bit_depth != BitDepth::Sixteen || info.color_type != ColorType::Rgba {
+        return Err(anyhow!("Not a Sound_PNG encoded image (expected 16-bit RGBA)."));
+    }
+    let (width, height) = (info.width, info.height);
+    let layout = png_layout(&info)?;
+
+    if header.fit_policy == FitPolicy::Upscale {
+        // Scaling back needs the whole image; it was small enough to need upscaling anyway
+        let mut img = image::RgbaImage::new(width, height);
+        let mut y = 0;
+        while let Some(row) = reader.next_row()? {
+            for (x, px) in row.data().chunks_exact(8).enumerate() {
+                img.put_pixel(x as u32, y, image::Rgba([px[0], px[2], px[4], px[6]]));
+            }
+            y += 1;
+        }
+        let restored = image::imageops::resize(&img, header.original_width, header.original_height, image::imageops::FilterType::Lanczos3);
+        restored.save_with_format(output, image::ImageFormat::Png)?;
+        on_progress(1.0);
+        return Ok(());
+    }
+
+    // Tiled covers are cropped back to the first tile
+    let (out_w, out_h) = if header.fit_policy == FitPolicy::Tile {
+        (header.original_width, header.original_height)
+    } else {
+        (width, height)
+    };
+
+    let wide = layout == ImageLayout::Lsb8;
+    let alpha_payload = header.flags & header::FLAG_PNG_ALPHA != 0;
+    let mut encoder = Encoder::new(File::create(output)?, out_w, out_h);
+    encoder.set_color(ColorType::Rgba);
+    encoder.set_depth(if wide { BitDepth::Sixteen } else { BitDepth::Eight });
+    // APNG frames keep their region and timing (fit policies never apply to them)
+    apng::start(&mut encoder, &info)?;
+    let mut writer = encoder.write_header()?;
+    let mut sink = apng::Output::new(&mut writer, info.animation_control.is_some())?;
+
+    let images = apng::images(&info);
+    let mut out_row = Vec::new();
+    for image in 0..images {
+        let (frame_w, frame_h) = if image == 0 { (out_w, out_h) } else { sink.next_frame(&mut reader)? };
+        out_row.resize(frame_w as usize * if wide { 8 } else { 4 }, 0);
+        for row_num in 0..frame_h {
+            let row = reader.next_row()?.ok_or_else(|| anyhow!("Image data truncated"))?;
+            if wide {
+                for (dst, px) in out_row.chunks_exact_mut(8).zip(row.data().chunks_exact(8)) {
+                    dst.copy_from_slice(&[px[0], 0, px[2], 0, px[4], 0, px[6], if alpha_payload { 0 } else { px[7] }]);
+                }
+            } else {
+                for (dst, px) in out_row.chunks_exact_mut(4).zip(row.data().chunks_exact(8)) {
+                    dst.copy_from_slice(&[px[0], px[2], px[4], px[6]]);
+                }
+            }
+            sink.write_row(&out_row)?;
+            if row_num % 50 == 0 { on_progress((image as f32 + row_num as f32 / frame_h as f32) / images as f32); }
+        }
+    }
+    sink.finish()
+}
+
+/// Embedding layout of an encoded PNG: `Expand8` unless a tEXt chunk says otherwise.
+fn png_layout(info: &png::Info) -> Result<ImageLayout> {
+    match info.uncompressed_latin1_text.iter().find(|t| t.keyword == raster::PNG_LAYOUT_KEYWORD) {
+        Some(t) => ImageLayout::from_tag(&t.text).ok_or_else(|| anyhow!("Unknown PNG layout: {}", t.text)),
+        None => Ok(ImageLayout::Expand8),
+    }
+}
+
+/// Writes the cover image back in its own format without the payload bits: `Expand8`
+/// returns to 8 bits, the LSB layouts keep their depth with the payload bits cleared.
+/// Fit padding is undone as in `restore_png`.
+fn restore_raster(format: RasterFormat, input: &Path, output: &Path, header: &Header, on_progress: impl Fn(f32)) -> Result<()> {
+    let reader = RasterReader::open(input)?;
+    let layout = ImageLayout::for_encoded(format, &reader)?;
+    let (width, height) = (reader.width, reader.height);
+    let depth = if layout == ImageLayout::Lsb8 { 16 } else { 8 };
+    let restore = move |value: u16| match layout {
+        ImageLayout::Expand8 => value >> 8,
+        ImageLayout::Lsb8 => value & 0xFF00,
+        ImageLayout::Lsb2 => value & !0x03,
+    };
+
+    if header.fit_policy == FitPolicy::Upscale {
+        // Scaling back needs the whole image; it was small enough to need upscaling anyway
+        let mut data = Vec::with_capacity(width as usize * height as usize * 3);
+        for row in reader {
+            data.extend(row?.into_iter().map(restore));
+        }
+        let img: image::ImageBuffer<image::Rgb<u16>, Vec<u16>> = image::ImageBuffer::from_raw(width, height, data)
+            .ok_or_else(|| anyhow!("Image data truncated"))?;
+        let restored = image::imageops::resize(&img, header.original_width, header.original_height, image::imageops::FilterType::Lanczos3);
+        let mut writer = RasterWriter::create(output, format, header.original_width, header.original_height, depth, None)?;
+        for row in restored.into_raw().chunks_exact(header.original_width as usize * 3) {
+            writer.write_row(row)?;
+        }
+        writer.finish()?;
+        on_progress(1.0);
+        return Ok(());
+    }
+
+    // Tiled covers are cropped back to the first tile
+    let (out_w, out_h) = if header.fit_policy == FitPolicy::Tile {
+        (header.original_width, header.original_height)
+    } else {
+        (width, height)
+    };
+
+    let mut writer = RasterWriter::create(output, format, out_w, out_h, depth, None)?;
+    for (row_num, row) in reader.take(out_h as usize).enumerate() {
+        let row: Vec<u16> = row?.into_iter().take(out_w as usize * 3).map(restore).collect();
+        writer.write_row(&row)?;
+        if row_num % 50 == 0 { on_progress(row_num as f32 / out_h as f32); }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the cover audio back in its own format, sample by sample, dropping any fit padding.
+/// `Expand16` goes back to 16-bit; layouts at the input depth keep their spec with the payload LSBs
+/// cleared, since the originals are gone. Channels outside the channel mode are copied as is.
+fn restore_pcm(format: PcmFormat, layout: WavLayout, input: &Path, output: &Path, header: &Header, on_progress: impl Fn(f32)) -> Result<()> {
+    let source = pcm::open(input, format)?;
+    let spec = source.spec;
+    let mut total_samples = source.samples;
+    if header.fit_policy.is_audio() {
+        total_samples = total_samples.min(header.original_frames * spec.channels as u64);
+    }
+
+    let out_spec = match layout {
+        WavLayout::Expand16 => hound::WavSpec { bits_per_sample: 16, sample_format: hound::SampleFormat::Int, ..spec },
+        WavLayout::Int24 | WavLayout::Float32 | WavLayout::Int32 | WavLayout::Int16 => spec,
+    };
+    let mut writer = PcmWriter::create(output, format, out_spec, source.aifc)?;
+    for (count, raw) in source.iter.take(total_samples as usize).enumerate() {
+        let raw = raw?;
+        let carries = header.channel_mode.carries(count % spec.channels as usize);
+        writer.write_raw(match layout {
+            WavLayout::Expand16 => ((raw as i32) >> 16) as u32,
+            _ if carries => raw & !layout.mask(),
+            _ => raw,
+        })?;
+        if count % 10000 == 0 { on_progress(count as f32 / total_samples.max(1) as f32); }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Re-encodes the cover FLAC without the payload bits: 16-bit again for `Expand8`,
+/// cleared LSBs (in the channels of the channel mode) at the same depth otherwise.
+fn restore_flac(input: &Path, output: &Path, header: &Header, on_progress: impl Fn(f32)) -> Result<()> {
+    let reader = claxon::FlacReader::open(input)?;
+    let layout = flac::encoded_layout(&reader)?;
+    let spec = flac::spec_of(&reader);
+    let mut total_samples = reader.streaminfo().samples.unwrap_or(0) * spec.channels as u64;
+    if header.fit_policy.is_audio() {
+        total_samples = total_samples.min(header.original_frames * spec.channels as u64);
+    }
+
+    let out_bits = if layout == FlacLayout::Expand8 { 16 } else { layout.output_bits() };
+    let mut writer = FlacWriter::create(output, hound::WavSpec { bits_per_sample: out_bits, ..spec }, &[])?;
+    for (count, sample) in FlacSamples::new(reader).take(total_samples as usize).enumerate() {
+        let sample = sample?;
+        let carries = header.channel_mode.carries(count % spec.channels as usize);
+        writer.write_sample(match layout {
+            FlacLayout::Expand8 => sample >> 8,
+            _ if carries => sample & !(layout.mask() as i32),
+            _ => sample,
+        })?;
+        if count % 10000 == 0 { on_progress(count as f32 / total_samples.max(1) as f32); }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_encoder::{encode_stream, EncodeOptions};
+    use rand::Rng;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        ChaCha8Rng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    /// Writes `frames` frames of random stereo audio and returns the samples.
+    fn write_wav(path: &Path, bits: u16, frames: usize) -> Result<(hound::WavSpec, Vec<i32>)> {
+        let spec = hound::WavSpec { channels: 2, sample_rate: 8000, bits_per_sample: bits, sample_format: hound::SampleFormat::Int };
+        let mut rng = ChaCha8Rng::seed_from_u64(frames as u64);
+        let samples: Vec<i32> = (0..frames * 2).map(|_| rng.gen_range(-(1 << (bits - 2))..(1 << (bits - 2)))).collect();
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for &s in &samples {
+            writer.write_sample(s)?;
+        }
+        writer.finalize()?;
+        Ok((spec, samples))
+    }
+
+    fn read_wav(path: &Path) -> Result<(hound::WavSpec, Vec<i32>)> {
+        let mut reader = hound::WavReader::open(path)?;
+        let samples = reader.samples::<i32>().collect::<Result<_, _>>()?;
+        Ok((reader.spec(), samples))
+    }
+
+    /// Encodes `payload` into `container`, decodes it with container restoration and returns the restored cover.
+    fn roundtrip(container: &PathBuf, ext: &str, payload: &[u8], options: &EncodeOptions) -> Result<PathBuf> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = container.parent().unwrap();
+        let output = dir.join(format!("output.{}", ext));
+        let restored_payload = dir.join("payload.bin");
+        let restored = dir.join(format!("restored.{}", ext));
+        encode_stream(&mut &payload[..], container, None, &output, false, Some("bin"), 64, &plugins, ext.into(), options, |_| {})?;
+        decode_stream(&output, &restored_payload, Some(&restored), None, 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+        assert_eq!(fs::read(&restored_payload)?, payload, "{}", ext);
+        Ok(restored)
+    }
+
+    #[test]
+    fn test_restore_drops_audio_padding() -> Result<()> {
+        let payload = random_bytes(3000, 1);
+        for policy in [FitPolicy::PadSilence, FitPolicy::PadLoop] {
+            let dir = tempdir()?;
+            let container = dir.path().join("cover.wav");
+            let original = write_wav(&container, 16, 200)?;
+            let options = EncodeOptions { fit: policy, ..Default::default() };
+            // 16-bit covers are widened (`Expand16`), so the restored cover is the original
+            let restored = roundtrip(&container, "wav", &payload, &options)?;
+            assert_eq!(read_wav(&restored)?, original, "{:?}", policy);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_crops_image_padding() -> Result<()> {
+        let payload = random_bytes(2000, 2);
+        // BMP keeps 8 bits and loses its 2 payload LSBs (`Lsb2`); PNG restores them (`Expand8`)
+        for (ext, policy, mask) in [("png", FitPolicy::Tile, 0xFF), ("bmp", FitPolicy::Tile, 0xFC), ("png", FitPolicy::Upscale, 0xFF)] {
+            let dir = tempdir()?;
+            let container = dir.path().join(format!("cover.{}", ext));
+            let original = image::RgbImage::from_fn(9, 7, |x, y| image::Rgb([(x * 25) as u8, (y * 30) as u8, ((x + y) * 12) as u8]));
+            original.save(&container)?;
+            let options = EncodeOptions { fit: policy, ..Default::default() };
+            let restored = image::open(roundtrip(&container, ext, &payload, &options)?)?.to_rgb8();
+            assert_eq!(restored.dimensions(), original.dimensions(), "{} {:?}", ext, policy);
+            if policy == FitPolicy::Tile {
+                assert!(restored.as_raw().iter().zip(original.as_raw()).all(|(&a, &b)| a == b & mask), "{}", ext);
+            } else {
+                // Scaled up and down again: close to, but not exactly, the original
+                let diff: u64 = restored.as_raw().iter().zip(original.as_raw()).map(|(&a, &b)| a.abs_diff(b) as u64).sum();
+                assert!(diff / (original.as_raw().len() as u64) < 4, "mean difference {}", diff as f64 / original.as_raw().len() as f64);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_channel_modes() -> Result<()> {
+        let payload = random_bytes(400, 3);
+        for mode in [ChannelMode::Left, ChannelMode::Side] {
+            let dir = tempdir()?;
+            let container = dir.path().join("cover.wav");
+            let (spec, original) = write_wav(&container, 24, 4000)?;
+            let options = EncodeOptions { channels: mode, ..Default::default() };
+            let (restored_spec, restored) = read_wav(&roundtrip(&container, "wav", &payload, &options)?)?;
+            assert_eq!(restored_spec, spec);
+            assert_eq!(restored.len(), original.len());
+            // Carrying channels lose their payload bits (`Int24` uses the low 4), the others are untouched
+            for (i, (&a, &b)) in original.iter().zip(&restored).enumerate() {
+                let expected = if mode.carries(i % 2) { a & !0x0F } else { a };
+                assert_eq!(b, expected, "{:?} sample {}", mode, i);
+            }
+            assert!(original.iter().zip(&restored).any(|(a, b)| a != b), "{:?}", mode);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_needs_plugin_capability() -> Result<()> {
+        let dir = tempdir()?;
+        let plugins = crate::capacity::tests::copy_plugin(&dir.path().join("plugins"), r#""encode", "decode""#)?;
+        let container = dir.path().join("cover.bin");
+        fs::write(&container, vec![0u8; 2000])?;
+        let output = dir.path().join("output.bin");
+        encode_stream(&mut &b"payload"[..], &container, None, &output, false, None, 64, &plugins, "bin".into(), &Default::default(), |_| {})?;
+
+        let restored_payload = dir.path().join("payload");
+        let restored = dir.path().join("restored.bin");
+        let e = decode_stream(&output, &restored_payload, Some(&restored), None, 64, &plugins, "bin".into(), &Default::default(), |_| {}).unwrap_err();
+        assert_eq!(e.to_string(), "Plugin Copy does not support container restoration.");
+        assert!(!restored_payload.exists() && !restored.exists());
+
+        // Without restoration the payload still comes out
+        decode_stream(&output, &restored_payload, None, None, 64, &plugins, "bin".into(), &Default::default(), |_| {})?;
+        assert_eq!(fs::read(&restored_payload)?, b"payload");
+        Ok(())
+    }
+}
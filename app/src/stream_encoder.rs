@@ -3,14 +3,100 @@ use anyhow::{Result, Context, anyhow};
 use std::io::{self, Read, Write, Seek};
 use flate2::write::DeflateEncoder;
 use flate2::Compression;
-use crate::header::{self, Header};
+use crate::header::{self, ChannelMode, FitPolicy, Header};
 use std::time::{SystemTime, UNIX_EPOCH};
 use sha2::{Digest, Sha256};
 use std::fs::File;
-use std::path::PathBuf;
-use hound::{WavWriter, WavSpec, SampleFormat};
+use std::path::{Path, PathBuf};
 use sound_png_api::ByteStream;
 use crate::plugin_loader::PluginManager;
+use crate::sniff;
+use crate::raster::{self, ImageLayout, RasterFormat, RasterReader, RasterWriter};
+use crate::jpeg;
+use crate::{apng, channels, gif_palette};
+use crate::carrier::{self, CarrierFormat};
+use crate::audio::{self, AudioFormat, AudioOutput, AudioTarget, AudioWriter};
+use crate::converter::{self, LosslessFormat};
+
+/// User-selectable knobs for `encode_stream`.
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// What to do when the container is too small for the payload.
+    pub fit: FitPolicy,
+    /// Format lossy audio containers (MP3, Ogg Vorbis, Opus) are converted to before embedding.
+    pub lossless: LosslessFormat,
+    /// Whether PNG containers also carry payload in the alpha channel.
+    pub alpha: AlphaUse,
+    /// Which channels of audio containers carry payload.
+    pub channels: ChannelMode,
+    /// Encoding of the output for audio containers.
+    pub output: AudioOutput,
+}
+
+/// Opt-in use of the PNG alpha channel (4 bytes per pixel instead of 3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AlphaUse {
+    #[default]
+    Off,
+    /// Only when the cover already has transparency, so an opaque cover stays opaque.
+    IfTransparent,
+    /// Always; opaque covers come out with alpha just below 100% (0xFF00..=0xFFFF).
+    Always,
+}
+
+impl AlphaUse {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => Self::IfTransparent,
+            2 => Self::Always,
+            _ => Self::Off,
+        }
+    }
+
+    /// Whether the alpha channel of the PNG `container` would carry payload.
+    pub fn applies(self, container: &Path) -> Result<bool> {
+        match self {
+            Self::Off => Ok(false),
+            Self::IfTransparent => crate::capacity::png_has_transparency(container),
+            Self::Always => Ok(true),
+        }
+    }
+}
+
+/// Container adjustments decided by the capacity check.
+enum FitPlan {
+    /// Container is used as-is.
+    AsIs,
+    /// Embed into this enlarged temporary copy of the image instead.
+    Image(PathBuf),
+    /// Append this many frames after the original audio.
+    Audio { pad_frames: u64 },
+}
+
+/// Temporary files of one `encode_stream` call, removed when it returns, on success and on every error.
+#[derive(Default)]
+struct TempFiles(Vec<PathBuf>);
+
+impl TempFiles {
+    /// Registers `path` for removal and returns it.
+    fn add(&mut self, path: PathBuf) -> PathBuf {
+        self.0.push(path.clone());
+        path
+    }
+}
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Where `plan_fit` writes the enlarged copy of an image container.
+fn enlarged_path(timestamp: u64) -> PathBuf {
+    std::env::temp_dir().join(format!("spng_fit_{}.png", timestamp))
+}
 
 /// Encodes data from a Reader source into a Container (streaming).
 pub fn encode_stream(
@@ -23,16 +109,22 @@ pub fn encode_stream(
     buffer_size_kb: usize,
     plugins: &Arc<Mutex<PluginManager>>,
     container_ext_hint: String,
+    options: &EncodeOptions,
     on_progress: impl Fn(f32) + Send + Sync + 'static
 ) -> Result<()> {
     on_progress(0.0);
     let buffer_size = buffer_size_kb * 1024;
+    // APNG files are PNGs to every reader
+    let container_ext_hint = if container_ext_hint == "apng" { "png".to_string() } else { container_ext_hint };
     
     let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
     let temp_dir = std::env::temp_dir();
-    let temp_compressed = temp_dir.join(format!("spng_enc_{}.tmp", timestamp));
+    // Declared before every open handle on these files so it is dropped after them
+    let mut temps = TempFiles::default();
+    let temp_compressed = temps.add(temp_dir.join(format!("spng_enc_{}.tmp", timestamp)));
     
     // Step 1: Compress & Hash to Temp File
+    let mut head = Vec::with_capacity(sniff::SNIFF_LEN);
     {
         let f_out = File::create(&temp_compressed)?;
         let mut encoder = DeflateEncoder::new(f_out, Compression::default());
@@ -41,11 +133,26 @@ pub fn encode_stream(
         loop {
             let n = payload.read(&mut buf)?;
             if n == 0 { break; }
+            if head.len() < sniff::SNIFF_LEN {
+                let take = n.min(sniff::SNIFF_LEN - head.len());
+                head.extend_from_slice(&buf[..take]);
+            }
             encoder.write_all(&buf[..n])?;
         }
         encoder.finish()?;
     }
     
+    // Step 1.5: Sniff payload type, fall back to it when no extension is given
+    let content_type = sniff::sniff(&head);
+    let payload_ext = payload_ext.filter(|e| !e.is_empty());
+    match payload_ext {
+        Some(ext) if content_type.conflicts_with_extension(ext) => {
+            tracing::warn!("Payload extension .{} does not match its content ({})", ext, content_type.mime());
+        }
+        _ => {}
+    }
+    let payload_ext = payload_ext.or(content_type.suggested_extension());
+    
     // Step 2: Calculate Hash & Size
     let mut compressed_file = File::open(&temp_compressed)?;
     let payload_len = compressed_file.metadata()?.len();
@@ -62,30 +169,53 @@ pub fn encode_stream(
     
     // Step 3: Prepare Header
     let effective_encrypt = encrypt || key_path.is_some();
-    let header = Header::new(payload_len, effective_encrypt, timestamp, hash, payload_ext.unwrap_or(""));
-    let header_bytes = header.to_u16_chunks().iter().flat_map(|u| u.to_le_bytes()).collect::<Vec<u8>>();
+    let mut header = Header::new(payload_len, effective_encrypt, timestamp, hash, payload_ext.unwrap_or(""));
+    header.content_type = content_type;
     
-    // Step 3.5: Capacity Check
-    let total_required = (header_bytes.len() as u64) + payload_len;
-    let capacity = if container_ext_hint == "png" {
-        get_png_capacity(container_path)?
-    } else if container_ext_hint == "wav" {
-        get_wav_capacity(container_path)?
-    } else if container_ext_hint == "seq_dir" {
-        u64::MAX // Sequence mode handled by plugin, assume infinite or plugin checks
+    // Step 3.4: Lossy audio cannot carry LSB data, embed into a lossless copy instead
+    let converted = if converter::is_lossy_audio(&container_ext_hint) && plugins.lock().unwrap().get_encoder_by_ext(&container_ext_hint).is_none() {
+        let target = options.lossless;
+        let temp = temps.add(temp_dir.join(format!("spng_conv_{}.{}", timestamp, target.extension())));
+        tracing::warn!("{} container is lossy: converting to lossless {}, the output cannot be an {}.",
+            container_ext_hint.to_uppercase(), target.extension().to_uppercase(), container_ext_hint.to_uppercase());
+        converter::convert_to_lossless(container_path, &temp, target)?;
+        Some(temp)
     } else {
-        // Plugins might handle other types, skip check or ask plugin? 
-        // For now, if plugin handles it, we assume it checks or we skip.
-        // But `encode_stream` falls back to internal if plugin doesn't handle.
-        // Since we don't know if plugin handles it yet (logic below), we might be premature.
-        // However, standard modes (png/wav) are handled internally below.
-        0 // Unknown
+        None
     };
-
-    if capacity > 0 && capacity < total_required {
-         let _ = std::fs::remove_file(temp_compressed);
-         return Err(anyhow!("Container too small! Required: {} bytes, Available: {} bytes.", total_required, capacity));
-    }
+    let container_path = converted.as_ref().unwrap_or(container_path);
+    let container_ext_hint = if converted.is_some() { options.lossless.extension().to_string() } else { container_ext_hint };
+    let converted_output;
+    let output_path = if converted.is_some() && output_path.extension().is_some_and(|e| converter::is_lossy_audio(&e.to_string_lossy())) {
+        converted_output = output_path.with_extension(&container_ext_hint);
+        tracing::warn!("Output renamed to {:?}", converted_output);
+        &converted_output
+    } else {
+        output_path
+    };
+    
+    // Step 3.45: Audio containers may be written in another format (see `AudioOutput`)
+    let audio_output;
+    let output_path = match AudioFormat::from_ext(&container_ext_hint) {
+        Some(format) if plugins.lock().unwrap().get_encoder_by_ext(&container_ext_hint).is_none() => {
+            let target = options.output.format(format);
+            let output_format = output_path.extension().and_then(|e| AudioFormat::from_ext(&e.to_string_lossy()));
+            if target != format && output_format != Some(target) {
+                audio_output = output_path.with_extension(target.extension());
+                tracing::warn!("Output renamed to {:?}", audio_output);
+                &audio_output
+            } else {
+                output_path
+            }
+        }
+        _ => output_path,
+    };
+    
+    // Step 3.5: Capacity Check (enlarges the container if the fit policy allows it)
+    let total_required = header::HEADER_SIZE_BYTES as u64 + payload_len;
+    temps.add(enlarged_path(timestamp));
+    let fit = plan_fit(container_path, &container_ext_hint, plugins, options, total_required, &mut header, timestamp)?;
+    let header_bytes = header.to_u16_chunks().iter().flat_map(|u| u.to_le_bytes()).collect::<Vec<u8>>();
     
     // Step 4: Embed
     compressed_file.seek(std::io::SeekFrom::Start(0))?;
@@ -111,7 +241,7 @@ pub fn encode_stream(
 
     let handled = {
         let pm = plugins.lock().unwrap();
-        if let Some(encoder) = pm.get_encoder(&container_ext_hint) {
+        if let Some(encoder) = pm.get_encoder_by_ext(&container_ext_hint) {
              let cb = embed_progress.clone();
              encoder.encode(container_path, output_path, &mut byte_stream, Box::new(move |p| cb(p)))?;
              true
@@ -121,135 +251,903 @@ pub fn encode_stream(
     };
 
     if handled {
-        let _ = std::fs::remove_file(temp_compressed);
         return Ok(());
     }
 
     let cb = embed_progress.clone();
-    if container_ext_hint == "png" || container_ext_hint == "seq_dir" {
-        if container_ext_hint == "seq_dir" {
-             return Err(anyhow!("Sequence Plugin not loaded or enabled."));
+    if container_ext_hint == "seq_dir" {
+        Err(anyhow!("Sequence Plugin not loaded or enabled."))
+    } else if let Some(format) = CarrierFormat::from_hint(&container_ext_hint) {
+        carrier::embed(format, container_path, output_path, &mut byte_stream, total_required, move |p| cb(p))
+    } else if container_ext_hint == "png" {
+        let source = if let FitPlan::Image(enlarged) = &fit { enlarged } else { container_path };
+        let alpha = header.flags & header::FLAG_PNG_ALPHA != 0;
+        embed_png(source, output_path, &mut byte_stream, alpha, move |p| cb(p))
+    } else if let Some(format) = RasterFormat::from_ext(&container_ext_hint) {
+        let source = if let FitPlan::Image(enlarged) = &fit { enlarged } else { container_path };
+        embed_raster(format, source, output_path, &mut byte_stream, move |p| cb(p))
+    } else if matches!(container_ext_hint.as_str(), "jpg" | "jpeg") {
+        embed_jpeg(container_path, output_path, &mut byte_stream, move |p| cb(p))
+    } else if container_ext_hint == "gif" {
+        gif_palette::embed(container_path, output_path, &mut byte_stream, move |p| cb(p))
+    } else if let Some(format) = AudioFormat::from_ext(&container_ext_hint) {
+        let pad_frames = if let FitPlan::Audio { pad_frames } = fit { pad_frames } else { 0 };
+        audio::probe(container_path, format)
+            .and_then(|(spec, _)| AudioTarget::resolve(format, &spec, options.output))
+            .and_then(|target| embed_audio(target, container_path, output_path, &mut byte_stream, &header, pad_frames, move |p| cb(p)))
+    } else {
+        Err(anyhow!("Unsupported container: {}", container_ext_hint))
+    }
+}
+
+/// Checks the container against the required size and, if it is too small,
+/// applies the fit policy and records it in the header.
+fn plan_fit(
+    container: &Path,
+    container_ext_hint: &str,
+    plugins: &Arc<Mutex<PluginManager>>,
+    options: &EncodeOptions,
+    total_required: u64,
+    header: &mut Header,
+    timestamp: u64,
+) -> Result<FitPlan> {
+    let policy = options.fit;
+    let png_alpha = container_ext_hint == "png"
+        && plugins.lock().unwrap().get_encoder_by_ext("png").is_none()
+        && options.alpha.applies(container)?;
+    let audio = AudioFormat::from_ext(container_ext_hint)
+        .filter(|_| plugins.lock().unwrap().get_encoder_by_ext(container_ext_hint).is_none());
+    let channel_mode = if audio.is_some() { options.channels } else { ChannelMode::All };
+    let capacity = if png_alpha {
+        header.flags |= header::FLAG_PNG_ALPHA;
+        crate::capacity::png_alpha_capacity(container)?
+    } else if let Some(format) = audio {
+        header.channel_mode = channel_mode;
+        crate::capacity::audio_capacity(container, format, options.output, channel_mode)?
+    } else {
+        match crate::capacity::container_capacity(container, container_ext_hint, plugins)? {
+            Some(c) => c,
+            None => return Ok(FitPlan::AsIs), // Unknown (plugin checks itself)
         }
-        embed_png(container_path, output_path, &mut byte_stream, move |p| cb(p))?;
-    } else if container_ext_hint == "wav" {
-        embed_wav(container_path, output_path, &mut byte_stream, move |p| cb(p))?;
+    };
+    if capacity.raw_bytes >= total_required {
+        return Ok(FitPlan::AsIs);
+    }
+
+    let too_small = format!("Container too small! Required: {} bytes, Available: {} bytes.", total_required, capacity.raw_bytes);
+    // Enlarging works on a single still image
+    let animated = container_ext_hint == "png" && apng::is_animated(container)?;
+    match container_ext_hint {
+        ext if policy.is_image() && ((ext == "png" && !animated) || RasterFormat::from_ext(ext).is_some()) => {
+            let enlarged = enlarged_path(timestamp);
+            // 3 bytes per pixel for PNG and TIFF (4 with PNG alpha), fewer for 8-bit formats
+            let needed_pixels = match RasterFormat::from_ext(ext) {
+                Some(format) => ImageLayout::for_container(format, 8).pixels_for(total_required),
+                None if png_alpha => (total_required + header::PNG_ALPHA_START).div_ceil(4),
+                None => total_required.div_ceil(3),
+            };
+            let (width, height) = enlarge_png(container, &enlarged, policy, needed_pixels)?;
+            header.fit_policy = policy;
+            header.original_width = width;
+            header.original_height = height;
+            Ok(FitPlan::Image(enlarged))
+        }
+        _ if policy.is_audio() && audio.is_some() => {
+            let format = audio.unwrap();
+            let (spec, samples) = audio::probe(container, format)?;
+            let layout = AudioTarget::resolve(format, &spec, options.output)?.layout;
+            let frames = samples / spec.channels as u64;
+            let frames_needed = channel_mode.frames_for(layout.samples_for(total_required), spec.channels);
+            header.fit_policy = policy;
+            header.original_frames = frames;
+            Ok(FitPlan::Audio { pad_frames: frames_needed.saturating_sub(frames) })
+        }
+        _ if policy == FitPolicy::Fail => Err(anyhow!(too_small)),
+        _ => Err(anyhow!("{} Fit policy {:?} does not apply to '{}' containers.", too_small, policy, container_ext_hint)),
+    }
+}
+
+/// Writes an enlarged RGBA copy of `container` with at least `needed_pixels` pixels, at 16 bits
+/// for covers with more than 8 bits per channel so they keep the `ImageLayout::Lsb8` layout.
+/// Returns the original dimensions.
+fn enlarge_png(container: &Path, output: &Path, policy: FitPolicy, needed_pixels: u64) -> Result<(u32, u32)> {
+    use image::{imageops::FilterType, DynamicImage, GenericImageView};
+
+    let img = crate::converter::load_image_object(container)?;
+    let (width, height) = img.dimensions();
+    let current_pixels = width as u64 * height as u64;
+    let scale = (needed_pixels as f64 / current_pixels as f64).sqrt();
+    let wide = img.color().bytes_per_pixel() > img.color().channel_count();
+
+    let enlarged = if policy == FitPolicy::Upscale {
+        let new_width = (width as f64 * scale).ceil() as u32;
+        let new_height = (height as f64 * scale).ceil() as u32;
+        img.resize_exact(new_width, new_height, FilterType::Lanczos3)
+    } else {
+        let tiles_x = scale.ceil() as u32;
+        let tiles_y = needed_pixels.div_ceil(current_pixels * tiles_x as u64) as u32;
+        if wide {
+            DynamicImage::ImageRgba16(tile(&img.to_rgba16(), tiles_x, tiles_y))
+        } else {
+            DynamicImage::ImageRgba8(tile(&img.to_rgba8(), tiles_x, tiles_y))
+        }
+    };
+
+    if wide {
+        DynamicImage::ImageRgba16(enlarged.to_rgba16()).save_with_format(output, image::ImageFormat::Png)?;
     } else {
-        return Err(anyhow!("Unsupported container: {}", container_ext_hint));
+        enlarged.to_rgba8().save_with_format(output, image::ImageFormat::Png)?;
     }
-    
-    // Cleanup
-    let _ = std::fs::remove_file(temp_compressed);
-    Ok(())
+    Ok((width, height))
+}
+
+/// Repeats `image` `tiles_x` times to the right and `tiles_y` times below.
+fn tile<P: image::Pixel>(image: &image::ImageBuffer<P, Vec<P::Subpixel>>, tiles_x: u32, tiles_y: u32) -> image::ImageBuffer<P, Vec<P::Subpixel>> {
+    let (width, height) = image.dimensions();
+    image::ImageBuffer::from_fn(width * tiles_x, height * tiles_y, |x, y| *image.get_pixel(x % width, y % height))
 }
 
+/// Embeds into a PNG of any color type and writes 16-bit RGBA with the original alpha.
+/// 8-bit sources (and palette / low-bit gray, expanded to 8 bits) keep their values in the
+/// high bytes (`ImageLayout::Expand8`); 16-bit sources keep their depth and the payload
+/// replaces the low bytes (`ImageLayout::Lsb8`, recorded in a tEXt chunk).
+/// With `alpha`, the alpha low bytes carry payload too from `header::PNG_ALPHA_START` on.
+/// APNG frames are embedded one after the other and keep their region and timing.
 fn embed_png(
     container: &PathBuf,
     output: &PathBuf,
     byte_stream: &mut ByteStream<File>,
+    alpha: bool,
     on_progress: impl Fn(f32)
 ) -> Result<()> {
-    use png::{Decoder, Encoder, ColorType, BitDepth};
+    use png::{Decoder, Encoder, ColorType, BitDepth, Transformations};
     
     let file_in = File::open(container)?;
-    let decoder = Decoder::new(file_in);
+    let mut decoder = Decoder::new(file_in);
+    decoder.set_transformations(Transformations::EXPAND);
     let mut reader = decoder.read_info()?;
     let info = reader.info().clone();
+    let (color, depth) = reader.output_color_type();
+    let layout = if depth == BitDepth::Sixteen { ImageLayout::Lsb8 } else { ImageLayout::Expand8 };
     
     let file_out = File::create(output)?;
     let mut encoder = Encoder::new(file_out, info.width, info.height);
     encoder.set_color(ColorType::Rgba);
     encoder.set_depth(BitDepth::Sixteen);
+    if layout != ImageLayout::Expand8 {
+        encoder.add_text_chunk(raster::PNG_LAYOUT_KEYWORD.to_string(), layout.tag().to_string())?;
+    }
+    apng::start(&mut encoder, &info)?;
     let mut writer = encoder.write_header()?;
+    // Row-by-row output for still images (`write_image_data` only accepts a whole image)
+    let mut sink = apng::Output::new(&mut writer, info.animation_control.is_some())?;
     
-    let bpp = info.bytes_per_pixel();
+    let images = apng::images(&info);
     let mut out_row = vec![0u8; info.width as usize * 8]; // RGBA16 output
-    let total_rows = info.height as usize;
+    let mut pixel = 0u64;
+    for image in 0..images {
+        let (width, height) = if image == 0 { (info.width, info.height) } else { sink.next_frame(&mut reader)? };
+        out_row.resize(width as usize * 8, 0);
+        let mut row_num = 0;
+        while let Some(row) = reader.next_row()? {
+            for (dst, px) in out_row.chunks_exact_mut(8).zip(png_rgba(row.data(), color, depth)) {
+                let channels = if alpha && pixel >= header::PNG_ALPHA_START { 4 } else { 3 };
+                pixel += 1;
+                // 8-bit alpha without payload is widened exactly (0xAB -> 0xABAB), so the high byte restores it
+                let mut out = [0, 0, 0, if layout == ImageLayout::Lsb8 { px[3] } else { px[3] * 257 }];
+                for c in 0..channels {
+                    let embed_b = byte_stream.next_byte() as u16;
+                    out[c] = match layout {
+                        ImageLayout::Lsb8 => (px[c] & 0xFF00) | embed_b,
+                        _ => (px[c] << 8) | embed_b,
+                    };
+                }
+                for (d, v) in dst.chunks_exact_mut(2).zip(out) {
+                    d.copy_from_slice(&v.to_be_bytes());
+                }
+            }
+            sink.write_row(&out_row)?;
 
-    let mut row_num = 0;
-    while let Ok(Some(row)) = reader.next_row() {
-        let src_data = row.data();
-        let mut out_idx = 0;
-        let mut src_idx = 0;
-        
-        while src_idx < src_data.len() {
-            let r = src_data[src_idx];
-            let g = src_data[src_idx+1];
-            let b = src_data[src_idx+2];
-            let _ = if bpp == 4 { src_data[src_idx+3] } else { 255 };
-            src_idx += bpp;
-            
-            let embed_b = byte_stream.next_byte();
-            let r16 = ((r as u16) << 8) | (embed_b as u16);
-            
-            let embed_b = byte_stream.next_byte();
-            let g16 = ((g as u16) << 8) | (embed_b as u16);
-            
-            let embed_b = byte_stream.next_byte();
-            let b16 = ((b as u16) << 8) | (embed_b as u16);
-            
-            let a16: u16 = 0xFFFF;
-            
-            out_row[out_idx..out_idx+2].copy_from_slice(&r16.to_be_bytes());
-            out_row[out_idx+2..out_idx+4].copy_from_slice(&g16.to_be_bytes());
-            out_row[out_idx+4..out_idx+6].copy_from_slice(&b16.to_be_bytes());
-            out_row[out_idx+6..out_idx+8].copy_from_slice(&a16.to_be_bytes());
-            out_idx += 8;
-        }
-        writer.write_image_data(&out_row)?;
-        
-        if row_num % 50 == 0 { on_progress(row_num as f32 / total_rows as f32); }
-        row_num += 1;
+            if row_num % 50 == 0 { on_progress((image as f32 + row_num as f32 / height as f32) / images as f32); }
+            row_num += 1;
+        }
     }
-    writer.finish()?;
-    Ok(())
+    sink.finish()
 }
 
-fn embed_wav(
+/// RGBA pixels of a PNG row decoded with `Transformations::EXPAND` (gray, gray + alpha,
+/// RGB or RGBA at 8 or 16 bits), at the row's own depth. Missing alpha is opaque.
+fn png_rgba(data: &[u8], color: png::ColorType, depth: png::BitDepth) -> Vec<[u16; 4]> {
+    use png::ColorType;
+
+    let (samples, opaque): (Vec<u16>, u16) = if depth == png::BitDepth::Sixteen {
+        (data.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect(), 0xFFFF)
+    } else {
+        (data.iter().map(|&v| v as u16).collect(), 0xFF)
+    };
+    match color {
+        ColorType::Grayscale => samples.iter().map(|&v| [v, v, v, opaque]).collect(),
+        ColorType::GrayscaleAlpha => samples.chunks_exact(2).map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        ColorType::Rgb => samples.chunks_exact(3).map(|p| [p[0], p[1], p[2], opaque]).collect(),
+        _ => samples.chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+    }
+}
+
+/// Embeds into a BMP, TIFF or WebP container and writes the same format, row by row
+/// (see `ImageLayout`). Alpha is dropped.
+fn embed_raster(
+    format: RasterFormat,
+    container: &Path,
+    output: &Path,
+    byte_stream: &mut ByteStream<File>,
+    on_progress: impl Fn(f32)
+) -> Result<()> {
+    let reader = RasterReader::open(container)?;
+    let layout = ImageLayout::for_container(format, reader.depth);
+    let (width, height) = (reader.width, reader.height);
+    let mut writer = RasterWriter::create(output, format, width, height, layout.output_depth(), Some(layout))?;
+
+    // Byte being spread over 2-bit slots, and slots left in it (`ImageLayout::Lsb2`)
+    let mut pending = (0u8, 0u8);
+    let mut out_row = vec![0u16; width as usize * 3];
+    for (row_num, row) in reader.enumerate() {
+        for (dst, &value) in out_row.iter_mut().zip(row?.iter()) {
+            *dst = match layout {
+                ImageLayout::Expand8 => (value << 8) | byte_stream.next_byte() as u16,
+                ImageLayout::Lsb8 => (value & 0xFF00) | byte_stream.next_byte() as u16,
+                ImageLayout::Lsb2 => {
+                    if pending.1 == 0 {
+                        pending = (byte_stream.next_byte(), 4);
+                    }
+                    let bits = pending.0 & 0x03;
+                    pending = (pending.0 >> 2, pending.1 - 1);
+                    (value & !0x03) | bits as u16
+                }
+            };
+        }
+        writer.write_row(&out_row)?;
+        if row_num % 50 == 0 { on_progress(row_num as f32 / height as f32); }
+    }
+    writer.finish()
+}
+
+/// Embeds into the quantized DCT coefficients of a JPEG (JSteg-style): one payload bit,
+/// low bit first, in the LSB of each AC coefficient with |v| >= 2 (see `jpeg::is_usable`).
+/// Tables and markers are copied, so the output keeps the quality and roughly the size.
+fn embed_jpeg(
+    container: &Path,
+    output: &Path,
+    byte_stream: &mut ByteStream<File>,
+    on_progress: impl Fn(f32)
+) -> Result<()> {
+    // Byte being spread over coefficients, and bits left in it
+    let mut pending = (0u8, 0u8);
+    jpeg::transcode(container, output, |block| {
+        for (k, coefficient) in block.iter_mut().enumerate().skip(1) {
+            if jpeg::is_usable(k, *coefficient) {
+                if pending.1 == 0 {
+                    pending = (byte_stream.next_byte(), 8);
+                }
+                *coefficient = jpeg::set_lsb(*coefficient, pending.0 & 1);
+                pending = (pending.0 >> 1, pending.1 - 1);
+            }
+        }
+    }, on_progress)
+}
+
+/// Embeds into a PCM (WAV, AIFF, AU) or FLAC container and writes the format and layout
+/// of `target` (see `AudioTarget`): widened 16-bit audio carries whole payload bytes,
+/// samples kept at their depth carry payload bits in the LSBs. Only the channels of
+/// `header.channel_mode` are changed.
+fn embed_audio(
+    target: AudioTarget,
     container: &PathBuf,
     output: &PathBuf,
     byte_stream: &mut ByteStream<File>,
+    header: &Header,
+    pad_frames: u64,
     on_progress: impl Fn(f32)
 ) -> Result<()> {
-    use crate::utils::WavIterator;
-    let iter = WavIterator::new(container)?;
-    let spec = iter.spec();
-    let total_samples = iter.len();
-    
-    let mut writer = WavWriter::create(output, WavSpec {
-        bits_per_sample: 32,
-        sample_format: SampleFormat::Int,
-        ..spec
-    })?;
-
-    let mut count = 0;
-    for sample_res in iter.into_iter() {
-        let sample = sample_res?;
-        
-        let b1 = byte_stream.next_byte();
-        let b2 = byte_stream.next_byte();
-        
-        let chunk = u16::from_le_bytes([b1, b2]);
-        let out = ((sample as i32) << 16) | (chunk as i32);
-        writer.write_sample(out)?;
-        
-        if count % 10000 == 0 { on_progress(count as f32 / total_samples as f32); }
-        count += 1;
+    let source = audio::open(container, target.source)?;
+    let spec = source.spec;
+    let mode = header.channel_mode;
+    channels::check(mode, spec.channels, target.layout.float())?;
+    let pad_samples = pad_frames * spec.channels as u64;
+    let total_samples = source.samples + pad_samples;
+
+    // Raw bits 0 are silence for both int and float samples
+    let padding: Box<dyn Iterator<Item = Result<u32>> + Send> = if header.fit_policy == FitPolicy::PadLoop && source.samples > 0 {
+        let path = container.clone();
+        Box::new(looped(move || Ok(audio::open(&path, target.source)?.iter)))
+    } else {
+        Box::new(std::iter::repeat_with(|| Ok(0)))
+    };
+
+    let mut writer = AudioWriter::create(output, target.layout, spec, source.aifc)?;
+    let mask = target.layout.mask();
+    let width = mask.count_ones();
+    // Byte being spread over sub-byte fields (low bits first), and bits left in it
+    let mut pending = (0u8, 0u32);
+    let mut next = || {
+        if width >= 8 {
+            // Whole bytes, little-endian
+            (0..width / 8).fold(0u32, |value, k| value | (byte_stream.next_byte() as u32) << (8 * k))
+        } else {
+            if pending.1 == 0 {
+                pending = (byte_stream.next_byte(), 8);
+            }
+            let bits = pending.0 as u32 & mask;
+            pending = (pending.0 >> width, pending.1 - width);
+            bits
+        }
+    };
+    let frame_len = if mode == ChannelMode::All { 1 } else { spec.channels as usize };
+    let mut frame = Vec::with_capacity(frame_len);
+    for (count, raw) in source.iter.chain(padding.take(pad_samples as usize)).enumerate() {
+        frame.push(target.base(raw?, &spec)?);
+        if frame.len() == frame_len {
+            channels::embed_frame(&mut frame, mode, mask, &mut next);
+            for sample in frame.drain(..) {
+                writer.write_raw(sample)?;
+            }
+        }
+        if count % 10000 == 0 { on_progress(count as f32 / total_samples.max(1) as f32); }
+    }
+    // A truncated last frame is written unchanged
+    for sample in frame {
+        writer.write_raw(sample)?;
     }
-    writer.finalize()?;
-    Ok(())
+    writer.finalize()
 }
 
-fn get_png_capacity(path: &PathBuf) -> Result<u64> {
-    let file = File::open(path)?;
-    let decoder = png::Decoder::new(file);
-    let reader = decoder.read_info()?;
-    let info = reader.info();
-    // 3 bytes per pixel (R, G, B LSBs)
-    Ok((info.width as u64) * (info.height as u64) * 3)
+/// Endless sample iterator that starts the audio over (via `open`) each time it runs out.
+fn looped<T>(open: impl Fn() -> Result<Box<dyn Iterator<Item = Result<T>> + Send>> + Send) -> impl Iterator<Item = Result<T>> + Send {
+    let mut current: Option<Box<dyn Iterator<Item = Result<T>> + Send>> = None;
+    std::iter::from_fn(move || loop {
+        if let Some(samples) = current.as_mut() {
+            if let Some(sample) = samples.next() {
+                return Some(sample);
+            }
+        }
+        match open() {
+            Ok(iter) => current = Some(iter),
+            Err(e) => return Some(Err(e)),
+        }
+    })
 }
 
-fn get_wav_capacity(path: &PathBuf) -> Result<u64> {
-    let reader = hound::WavReader::open(path)?;
-    // 2 bytes per sample (we embed 2 bytes per 32-bit sample expansion)
-    Ok(reader.len() as u64 * 2)
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream_decoder;
+    use png::{BitDepth, ColorType};
+    use std::fs;
+    use tempfile::tempdir;
+
+    const W: u32 = 23;
+    const H: u32 = 17;
+
+    /// Writes a test PNG of the given type and returns its pixels as RGBA at 8 or 16 bits.
+    fn write_png(path: &std::path::Path, color: ColorType, depth: BitDepth) -> anyhow::Result<Vec<[u16; 4]>> {
+        let n = (W * H) as usize;
+        let mut encoder = png::Encoder::new(File::create(path)?, W, H);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        let (data, expected): (Vec<u8>, Vec<[u16; 4]>) = match (color, depth) {
+            (ColorType::Indexed, _) | (ColorType::Grayscale, BitDepth::One | BitDepth::Two | BitDepth::Four) => {
+                // Indices or gray levels, packed MSB first with each row starting on a byte
+                let bits = depth as usize;
+                let levels = 1usize << bits.min(4);
+                let values: Vec<u8> = (0..n).map(|i| ((i * 7 + i / W as usize) % levels) as u8).collect();
+                let mut data = Vec::new();
+                for row in values.chunks(W as usize) {
+                    for byte in row.chunks(8 / bits) {
+                        data.push(byte.iter().enumerate().fold(0u8, |acc, (k, &v)| acc | (v << (8 - bits * (k + 1)))));
+                    }
+                }
+                let expected = if color == ColorType::Indexed {
+                    let palette: Vec<[u8; 4]> = (0..levels as u8).map(|i| [i * 16, 255 - i * 9, i * 5 + 3, 255 - i * 15]).collect();
+                    encoder.set_palette(palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<_>>());
+                    encoder.set_trns(palette.iter().map(|c| c[3]).collect::<Vec<_>>());
+                    values.iter().map(|&i| palette[i as usize].map(u16::from)).collect()
+                } else {
+                    let max = (1u16 << bits) - 1;
+                    values.iter().map(|&v| { let v = v as u16 * 255 / max; [v, v, v, 255] }).collect()
+                };
+                (data, expected)
+            }
+            _ => {
+                let wide = depth == BitDepth::Sixteen;
+                let max = if wide { 0xFFFF } else { 0xFF };
+                let channels = color.samples();
+                let samples: Vec<u16> = (0..n * channels).map(|i| ((i * 2654435761) >> 7) as u16 & max).collect();
+                let data = if wide { samples.iter().flat_map(|v| v.to_be_bytes()).collect() } else { samples.iter().map(|&v| v as u8).collect() };
+                let expected = samples.chunks_exact(channels).map(|p| match channels {
+                    1 => [p[0], p[0], p[0], max],
+                    2 => [p[0], p[0], p[0], p[1]],
+                    3 => [p[0], p[1], p[2], max],
+                    _ => [p[0], p[1], p[2], p[3]],
+                }).collect();
+                (data, expected)
+            }
+        };
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(expected)
+    }
+
+    #[test]
+    fn test_png_color_types_roundtrip() -> anyhow::Result<()> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let payload: Vec<u8> = (0..600u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+        let cases = [
+            (ColorType::Indexed, BitDepth::One),
+            (ColorType::Indexed, BitDepth::Two),
+            (ColorType::Indexed, BitDepth::Four),
+            (ColorType::Indexed, BitDepth::Eight),
+            (ColorType::Grayscale, BitDepth::One),
+            (ColorType::Grayscale, BitDepth::Two),
+            (ColorType::Grayscale, BitDepth::Four),
+            (ColorType::Grayscale, BitDepth::Eight),
+            (ColorType::Grayscale, BitDepth::Sixteen),
+            (ColorType::GrayscaleAlpha, BitDepth::Eight),
+            (ColorType::GrayscaleAlpha, BitDepth::Sixteen),
+            (ColorType::Rgb, BitDepth::Eight),
+            (ColorType::Rgb, BitDepth::Sixteen),
+            (ColorType::Rgba, BitDepth::Eight),
+            (ColorType::Rgba, BitDepth::Sixteen),
+        ];
+        for (color, depth) in cases {
+            let dir = tempdir()?;
+            let container = dir.path().join("container.png");
+            let output = dir.path().join("output.png");
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join("restored.png");
+            let expected = write_png(&container, color, depth)?;
+
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "png".into(), &EncodeOptions::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{:?} {:?}", color, depth);
+
+            // Only the non-default layout is recorded
+            let reader = png::Decoder::new(File::open(&output)?).read_info()?;
+            let layout = reader.info().uncompressed_latin1_text.iter().find(|t| t.keyword == raster::PNG_LAYOUT_KEYWORD).map(|t| t.text.clone());
+            let expected_layout = (depth == BitDepth::Sixteen).then(|| ImageLayout::Lsb8.tag().to_string());
+            assert_eq!(layout, expected_layout, "{:?} {:?}", color, depth);
+
+            // The restored cover has the original colors and alpha (16-bit: payload bytes cleared)
+            let mut reader = png::Decoder::new(File::open(&restored_container)?).read_info()?;
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            let frame = reader.next_frame(&mut buf)?;
+            let restored = png_rgba(&buf[..frame.buffer_size()], frame.color_type, frame.bit_depth);
+            let wide = depth == BitDepth::Sixteen;
+            assert_eq!(frame.bit_depth, if wide { BitDepth::Sixteen } else { BitDepth::Eight });
+            let expected: Vec<[u16; 4]> = if wide {
+                expected.iter().map(|p| [p[0] & 0xFF00, p[1] & 0xFF00, p[2] & 0xFF00, p[3]]).collect()
+            } else {
+                expected
+            };
+            assert_eq!(restored, expected, "{:?} {:?}", color, depth);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_sniffed_after_preset_conversion() -> anyhow::Result<()> {
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = tempdir()?;
+        let container = dir.path().join("container.png");
+        write_png(&container, ColorType::Rgb, BitDepth::Eight)?;
+        let mut jpeg = std::io::Cursor::new(Vec::new());
+        image::RgbImage::from_fn(8, 8, |x, y| image::Rgb([x as u8 * 30, y as u8 * 30, 0])).write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(90))?;
+        let output = dir.path().join("output.png");
+        encode_stream(&mut &jpeg.get_ref()[..], &container, None, &output, false, Some("jpg"), 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+
+        let options = stream_decoder::DecodeOptions { preset_ext: Some("png".into()), resize_factor: None };
+        let info = stream_decoder::decode_stream(&output, &dir.path().join("payload.jpg"), None, None, 64, &plugins, "png".into(), &options, |_| {})?;
+        assert_eq!(info.path, dir.path().join("payload.png"));
+        assert_eq!((info.recorded, info.detected, info.converted), (sniff::ContentType::Jpeg, sniff::ContentType::Png, true));
+        assert_eq!(info.suggested_ext(), "png");
+        assert_eq!(info.mismatch(), None);
+        Ok(())
+    }
+
+    /// Raw sample bits of a WAV file (floats as their bit patterns).
+    fn wav_bits(path: &std::path::Path) -> anyhow::Result<(hound::WavSpec, Vec<u32>)> {
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let bits = match spec.sample_format {
+            hound::SampleFormat::Int => reader.samples::<i32>().map(|s| s.map(|v| v as u32)).collect::<Result<_, _>>()?,
+            hound::SampleFormat::Float => reader.samples::<f32>().map(|s| s.map(f32::to_bits)).collect::<Result<_, _>>()?,
+        };
+        Ok((spec, bits))
+    }
+
+    #[test]
+    fn test_native_wav_layouts_keep_audio_bits() -> anyhow::Result<()> {
+        use rand::{Rng, RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(31);
+        for (bits, format, mask) in [(24, hound::SampleFormat::Int, 0x0Fu32), (32, hound::SampleFormat::Int, 0xFF), (32, hound::SampleFormat::Float, 0xFF)] {
+            let dir = tempdir()?;
+            let container = dir.path().join("container.wav");
+            let output = dir.path().join("output.wav");
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join("restored.wav");
+            let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: bits, sample_format: format };
+            let mut writer = hound::WavWriter::create(&container, spec)?;
+            for _ in 0..6000 {
+                match format {
+                    // Full scale, including the extremes
+                    hound::SampleFormat::Int => writer.write_sample(rng.gen_range(-(1i64 << (bits - 1))..(1i64 << (bits - 1))) as i32)?,
+                    hound::SampleFormat::Float => writer.write_sample(rng.gen_range(-1.0f32..1.0))?,
+                }
+            }
+            writer.finalize()?;
+            let (_, original) = wav_bits(&container)?;
+
+            let mut payload = vec![0u8; 1500];
+            rng.fill_bytes(&mut payload);
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "wav".into(), &Default::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "wav".into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{:?} {}", format, bits);
+
+            // Only the payload LSBs differ; the output and the restored cover keep the input spec
+            let (out_spec, embedded) = wav_bits(&output)?;
+            let (restored_spec, restored) = wav_bits(&restored_container)?;
+            assert_eq!((out_spec, restored_spec), (spec, spec));
+            assert!(original.iter().zip(&embedded).all(|(a, b)| a & !mask == b & !mask), "{:?} {}", format, bits);
+            assert!(original.iter().zip(&restored).all(|(a, b)| a & !mask == *b), "{:?} {}", format, bits);
+        }
+
+        // 32-bit output of a 16-bit cover (`Expand16`) is told apart from a native 32-bit one
+        let dir = tempdir()?;
+        let container = dir.path().join("container.wav");
+        let output = dir.path().join("output.wav");
+        let restored = dir.path().join("payload.bin");
+        let spec = hound::WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&container, spec)?;
+        for i in 0..2000i32 {
+            writer.write_sample((i * 37 % 20000) as i16)?;
+        }
+        writer.finalize()?;
+        let options = EncodeOptions { output: AudioOutput::Wav32, ..Default::default() };
+        encode_stream(&mut &b"expanded"[..], &container, None, &output, false, None, 64, &plugins, "wav".into(), &options, |_| {})?;
+        stream_decoder::decode_stream(&output, &restored, None, None, 64, &plugins, "wav".into(), &Default::default(), |_| {})?;
+        assert_eq!(fs::read(&restored)?, b"expanded");
+        Ok(())
+    }
+
+    #[test]
+    fn test_aiff_au_roundtrip() -> anyhow::Result<()> {
+        use crate::pcm::{self, PcmFormat};
+        use crate::utils::WavLayout;
+        use rand::{RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(34);
+        // (extension, bits, AIFF-C sowt)
+        for (ext, bits, sowt) in [("aiff", 16u16, false), ("aiff", 24, false), ("aifc", 16, true), ("aifc", 24, true), ("au", 16, false), ("au", 24, false)] {
+            let format = PcmFormat::from_ext(ext).unwrap();
+            let dir = tempdir()?;
+            let container = dir.path().join(format!("container.{}", ext));
+            let output = dir.path().join(format!("output.{}", ext));
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join(format!("restored.{}", ext));
+            let spec = hound::WavSpec { channels: 2, sample_rate: 44100, bits_per_sample: bits, sample_format: hound::SampleFormat::Int };
+            let max = 1i64 << (bits - 1);
+            let original: Vec<u32> = (0..8000i64).map(|i| ((i * 2654435761) % (2 * max) - max) as i32 as u32).collect();
+            if sowt {
+                std::fs::write(&container, crate::aiff::tests::sowt_file(bits, 2, &original))?;
+            } else {
+                let mut writer = pcm::PcmWriter::create(&container, format, spec, false)?;
+                for &s in &original {
+                    writer.write_raw(s)?;
+                }
+                writer.finalize()?;
+            }
+
+            let mut payload = vec![0u8; 1500];
+            rng.fill_bytes(&mut payload);
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{} {}", ext, bits);
+
+            // The output keeps the container type; the restored cover has the original audio bits
+            let layout = WavLayout::for_container(&spec);
+            let embedded = pcm::open(&output, format)?;
+            assert_eq!(embedded.spec, layout.output_spec(if sowt { hound::WavSpec { sample_rate: 48000, ..spec } } else { spec }));
+            assert_eq!(embedded.aifc, sowt);
+            let restored = pcm::open(&restored_container, format)?;
+            let audio_mask = if layout == WavLayout::Expand16 { u32::MAX } else { !layout.mask() };
+            let restored = restored.iter.collect::<anyhow::Result<Vec<_>>>()?;
+            assert!(original.iter().zip(&restored).all(|(a, b)| a & audio_mask == *b), "{} {}", ext, bits);
+            assert_eq!(restored.len(), original.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_raster_roundtrip() -> anyhow::Result<()> {
+        use rand::{RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(35);
+        for (ext, depth) in [("bmp", 8u8), ("tif", 8), ("tif", 16), ("webp", 8)] {
+            let format = RasterFormat::from_ext(ext).unwrap();
+            let dir = tempdir()?;
+            let container = dir.path().join(format!("container.{}", ext));
+            let output = dir.path().join(format!("output.{}", ext));
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join(format!("restored.{}", ext));
+            let (width, height) = (61u32, 29u32);
+            let max = if depth == 16 { 0xFFFF } else { 0xFF };
+            let original: Vec<u16> = (0..(width * height * 3) as usize).map(|i| ((i * 2654435761) >> 11) as u16 & max).collect();
+            let mut writer = RasterWriter::create(&container, format, width, height, depth, None)?;
+            for row in original.chunks(width as usize * 3) {
+                writer.write_row(row)?;
+            }
+            writer.finish()?;
+
+            let mut payload = vec![0u8; 800];
+            rng.fill_bytes(&mut payload);
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, ext.into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{} {}", ext, depth);
+
+            // The output stays in the container format; the restored cover loses only the payload bits
+            let layout = ImageLayout::for_container(format, depth);
+            let embedded = RasterReader::open(&output)?;
+            assert_eq!((embedded.width, embedded.height, embedded.depth), (width, height, layout.output_depth()));
+            assert_eq!(ImageLayout::for_encoded(format, &embedded)?, layout);
+            let restored = RasterReader::open(&restored_container)?;
+            assert_eq!(restored.depth, depth);
+            let restored: Vec<u16> = restored.collect::<anyhow::Result<Vec<_>>>()?.concat();
+            let kept = match layout {
+                ImageLayout::Expand8 => 0xFF,
+                ImageLayout::Lsb8 => 0xFF00,
+                ImageLayout::Lsb2 => 0xFC,
+            };
+            let expected: Vec<u16> = original.iter().map(|v| v & kept).collect();
+            assert_eq!(restored, expected, "{} {}", ext, depth);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_jpeg_roundtrip() -> anyhow::Result<()> {
+        use rand::{RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(36);
+        // 4:2:0 without and with restart intervals, and 4:4:4 from the image crate
+        let dir = tempdir()?;
+        let sources = [dir.path().join("a.jpg"), dir.path().join("b.jpg"), dir.path().join("c.jpg")];
+        jpeg::tests::write_jpeg(&sources[0], 201, 117, &[(2, 2), (1, 1), (1, 1)], 0)?;
+        jpeg::tests::write_jpeg(&sources[1], 201, 117, &[(2, 2), (1, 1), (1, 1)], 7)?;
+        image::RgbImage::from_fn(201, 117, |x, y| image::Rgb(jpeg::tests::test_pixel(x, y))).save(&sources[2])?;
+        for container in &sources {
+            let output = dir.path().join("output.jpg");
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join("restored.jpg");
+            let mut payload = vec![0u8; 300];
+            rng.fill_bytes(&mut payload);
+            encode_stream(&mut &payload[..], container, None, &output, false, Some("bin"), 64, &plugins, "jpg".into(), &Default::default(), |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "jpg".into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{:?}", container);
+
+            // The restored cover is the original with the usable LSBs cleared
+            let cleared = dir.path().join("cleared.jpg");
+            jpeg::transcode(container, &cleared, |block| {
+                for (k, value) in block.iter_mut().enumerate().skip(1) {
+                    if jpeg::is_usable(k, *value) {
+                        *value = jpeg::set_lsb(*value, 0);
+                    }
+                }
+            }, |_| {})?;
+            assert_eq!(fs::read(&restored_container)?, fs::read(&cleared)?, "{:?}", container);
+            assert_eq!(jpeg::usable_coefficients(&output)?, jpeg::usable_coefficients(container)?);
+            image::open(&output)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_png_alpha_capacity() -> anyhow::Result<()> {
+        use rand::{RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        for (color, depth) in [(ColorType::Rgba, BitDepth::Eight), (ColorType::Rgba, BitDepth::Sixteen), (ColorType::Rgb, BitDepth::Eight)] {
+            let dir = tempdir()?;
+            let container = dir.path().join("container.png");
+            let output = dir.path().join("output.png");
+            let restored_payload = dir.path().join("payload.bin");
+            let restored_container = dir.path().join("restored.png");
+            let expected = write_png(&container, color, depth)?;
+
+            let rgb = crate::capacity::container_capacity(&container, "png", &plugins)?.unwrap();
+            let rgba = crate::capacity::png_alpha_capacity(&container)?;
+            assert_eq!(rgba.raw_bytes, (W * H) as u64 * 4 - header::PNG_ALPHA_START);
+            // Random bytes do not deflate, so this only fits with alpha
+            let mut payload = vec![0u8; rgb.usable_bytes() as usize + 100];
+            rand_chacha::ChaCha8Rng::seed_from_u64(7).fill_bytes(&mut payload);
+            let mut options = EncodeOptions { alpha: AlphaUse::IfTransparent, ..Default::default() };
+            assert_eq!(options.alpha.applies(&container)?, color == ColorType::Rgba);
+            if color == ColorType::Rgb {
+                assert!(encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "png".into(), &options, |_| {}).is_err());
+                options.alpha = AlphaUse::Always;
+            }
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "png".into(), &options, |_| {})?;
+            stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored_payload)?, payload, "{:?} {:?}", color, depth);
+
+            let mut reader = png::Decoder::new(File::open(&restored_container)?).read_info()?;
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            let frame = reader.next_frame(&mut buf)?;
+            let restored = png_rgba(&buf[..frame.buffer_size()], frame.color_type, frame.bit_depth);
+            let mask = if depth == BitDepth::Sixteen { 0xFF00 } else { 0xFFFF };
+            let expected: Vec<[u16; 4]> = expected.iter().map(|p| p.map(|v| v & mask)).collect();
+            assert_eq!(restored, expected, "{:?} {:?}", color, depth);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_temp_files_removed_on_drop() -> anyhow::Result<()> {
+        let dir = tempdir()?;
+        let written = dir.path().join("written.tmp");
+        let open = dir.path().join("open.tmp");
+        {
+            let mut temps = TempFiles::default();
+            fs::write(temps.add(written.clone()), b"data")?;
+            let _handle = File::create(temps.add(open.clone()))?;
+            // Never created, e.g. the enlarged copy when the container fits
+            temps.add(dir.path().join("missing.tmp"));
+            assert!(written.exists() && open.exists());
+        }
+        assert!(!written.exists() && !open.exists());
+        Ok(())
+    }
+
+    /// Writes `samples` as 16-bit mono audio.
+    fn write_wav16(path: &std::path::Path, samples: &[i16]) -> anyhow::Result<()> {
+        let spec = hound::WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec)?;
+        for &s in samples {
+            writer.write_sample(s)?;
+        }
+        Ok(writer.finalize()?)
+    }
+
+    /// Pixels of a PNG as RGBA at its own depth.
+    fn read_png(path: &std::path::Path) -> anyhow::Result<(u32, u32, Vec<[u16; 4]>)> {
+        let mut reader = png::Decoder::new(File::open(path)?).read_info()?;
+        let mut buf = vec![0u8; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf)?;
+        Ok((frame.width, frame.height, png_rgba(&buf[..frame.buffer_size()], frame.color_type, frame.bit_depth)))
+    }
+
+    #[test]
+    fn test_plan_fit() -> anyhow::Result<()> {
+        use FitPolicy::*;
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let dir = tempdir()?;
+        let png = dir.path().join("cover.png");
+        write_png(&png, ColorType::Rgb, BitDepth::Eight)?;
+        let wav = dir.path().join("cover.wav");
+        write_wav16(&wav, &(0..200).map(|i| (i * 37 % 2000) as i16).collect::<Vec<_>>())?;
+        let fit_plan = |container: &PathBuf, ext: &str, fit: FitPolicy, required: u64| {
+            let mut header = Header::new(0, false, 0, [0; 32], "");
+            let options = EncodeOptions { fit, ..Default::default() };
+            plan_fit(container, ext, &plugins, &options, required, &mut header, 27).map(|plan| (plan, header))
+        };
+        // 3 bytes per pixel; 2 bytes per sample of a widened 16-bit WAV (`Expand16`)
+        let (png_raw, wav_raw) = ((W * H * 3) as u64, 400);
+
+        // Covers that are large enough are used as they are, whatever the policy
+        for fit in [Fail, PadSilence, PadLoop, Upscale, Tile] {
+            for (container, ext, raw) in [(&png, "png", png_raw), (&wav, "wav", wav_raw)] {
+                let (plan, header) = fit_plan(container, ext, fit, raw)?;
+                assert!(matches!(plan, FitPlan::AsIs) && header.fit_policy == Fail, "{} {:?}", ext, fit);
+            }
+        }
+        let e = fit_plan(&png, "png", Fail, png_raw + 1).err().unwrap();
+        assert_eq!(e.to_string(), format!("Container too small! Required: {} bytes, Available: {} bytes.", png_raw + 1, png_raw));
+        let e = fit_plan(&png, "png", PadLoop, png_raw + 1).err().unwrap();
+        assert!(e.to_string().ends_with("Fit policy PadLoop does not apply to 'png' containers."), "{}", e);
+        let e = fit_plan(&wav, "wav", Tile, wav_raw + 1).err().unwrap();
+        assert!(e.to_string().ends_with("Fit policy Tile does not apply to 'wav' containers."), "{}", e);
+
+        // Images grow to at least the required size and remember their own
+        for fit in [Upscale, Tile] {
+            let (FitPlan::Image(enlarged), header) = fit_plan(&png, "png", fit, png_raw * 5)? else { panic!("{:?} did not enlarge", fit) };
+            assert_eq!((header.fit_policy, header.original_width, header.original_height), (fit, W, H));
+            let (width, height) = image::image_dimensions(&enlarged)?;
+            fs::remove_file(&enlarged)?;
+            assert!((width * height * 3) as u64 >= png_raw * 5, "{:?} {}x{}", fit, width, height);
+            if fit == Tile {
+                assert_eq!((width % W, height % H), (0, 0));
+            }
+        }
+
+        // Audio is padded by the missing frames
+        for fit in [PadSilence, PadLoop] {
+            let (FitPlan::Audio { pad_frames }, header) = fit_plan(&wav, "wav", fit, wav_raw * 3)? else { panic!("{:?} did not pad", fit) };
+            assert_eq!((header.fit_policy, header.original_frames, pad_frames), (fit, 200, 400));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fit_roundtrips() -> anyhow::Result<()> {
+        use rand::{RngCore, SeedableRng};
+
+        let plugins = Arc::new(Mutex::new(PluginManager::new()));
+        let mut payload = vec![0u8; 3000];
+        rand_chacha::ChaCha8Rng::seed_from_u64(27).fill_bytes(&mut payload);
+
+        // Audio: the padding follows the original samples (widened, so in the high 16 bits)
+        for fit in [FitPolicy::PadSilence, FitPolicy::PadLoop] {
+            let dir = tempdir()?;
+            let container = dir.path().join("cover.wav");
+            let output = dir.path().join("output.wav");
+            let restored = dir.path().join("payload.bin");
+            let original: Vec<i16> = (0..300).map(|i| (i * 37 % 2000 - 1000) as i16).collect();
+            write_wav16(&container, &original)?;
+            let options = EncodeOptions { fit, ..Default::default() };
+            encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "wav".into(), &options, |_| {})?;
+            stream_decoder::decode_stream(&output, &restored, None, None, 64, &plugins, "wav".into(), &Default::default(), |_| {})?;
+            assert_eq!(fs::read(&restored)?, payload, "{:?}", fit);
+
+            let (_, bits) = wav_bits(&output)?;
+            assert!(bits.len() * 2 >= payload.len() + header::HEADER_SIZE_BYTES, "{:?}", fit);
+            for (i, &b) in bits.iter().enumerate() {
+                let expected = match (i < original.len(), fit) {
+                    (true, _) | (false, FitPolicy::PadLoop) => original[i % original.len()],
+                    _ => 0,
+                };
+                assert_eq!((b as i32 >> 16) as i16, expected, "{:?} sample {}", fit, i);
+            }
+        }
+
+        // Images: tiles repeat the cover, upscaling keeps its aspect; 16-bit covers stay 16-bit
+        for (color, depth) in [(ColorType::Rgb, BitDepth::Eight), (ColorType::Rgba, BitDepth::Sixteen)] {
+            for fit in [FitPolicy::Upscale, FitPolicy::Tile] {
+                let dir = tempdir()?;
+                let container = dir.path().join("cover.png");
+                let output = dir.path().join("output.png");
+                let restored_payload = dir.path().join("payload.bin");
+                let restored_container = dir.path().join("restored.png");
+                let expected = write_png(&container, color, depth)?;
+                let options = EncodeOptions { fit, ..Default::default() };
+                encode_stream(&mut &payload[..], &container, None, &output, false, Some("bin"), 64, &plugins, "png".into(), &options, |_| {})?;
+                stream_decoder::decode_stream(&output, &restored_payload, Some(&restored_container), None, 64, &plugins, "png".into(), &Default::default(), |_| {})?;
+                assert_eq!(fs::read(&restored_payload)?, payload, "{:?} {:?}", depth, fit);
+
+                // 8-bit covers sit in the high bytes (`Expand8`), 16-bit ones keep them (`Lsb8`)
+                let (width, height, pixels) = read_png(&output)?;
+                assert!(width > W && height > H && (width * height * 3) as usize >= payload.len(), "{:?} {:?}", depth, fit);
+                assert!((width as f64 / height as f64 - W as f64 / H as f64).abs() < 0.1, "{:?} {:?}", depth, fit);
+                let high = |v: u16| if depth == BitDepth::Sixteen { v >> 8 } else { v };
+                if fit == FitPolicy::Tile {
+                    for (i, px) in pixels.iter().enumerate() {
+                        let (x, y) = (i as u32 % width, i as u32 / width);
+                        let original = expected[((y % H) * W + x % W) as usize];
+                        assert_eq!(px[..3].iter().map(|v| v >> 8).collect::<Vec<_>>(), original[..3].iter().map(|&v| high(v)).collect::<Vec<_>>());
+                    }
+                }
+
+                let (width, height, restored) = read_png(&restored_container)?;
+                assert_eq!((width, height), (W, H), "{:?} {:?}", depth, fit);
+                if fit == FitPolicy::Tile {
+                    // Payload bits are cleared from the color channels; alpha carried none
+                    let mask = if depth == BitDepth::Sixteen { 0xFF00 } else { 0xFFFF };
+                    let expected: Vec<[u16; 4]> = expected.iter().map(|p| [p[0] & mask, p[1] & mask, p[2] & mask, p[3]]).collect();
+                    assert_eq!(restored, expected, "{:?}", depth);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use tiff::decoder::{ChunkType, Decoder, DecodingResult};
+use tiff::tags::Tag;
+use tiff::ColorType;
+
+/// Strip-by-strip TIFF reader yielding RGB rows at the file's own depth (8 or 16 bits).
+/// Gray images are expanded to RGB and alpha is dropped.
+pub struct TiffRows {
+    decoder: Decoder<BufReader<File>>,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u8,
+    /// ImageDescription tag, where the embedding layout is recorded.
+    pub description: Option<String>,
+    channels: usize,
+    tiled: bool,
+    next_chunk: u32,
+    chunk: Vec<u16>,
+    chunk_pos: usize,
+    row: u32,
+}
+
+impl TiffRows {
+    /// Returns `None` for color types other than gray / RGB (with or without alpha).
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let (width, height) = decoder.dimensions()?;
+        let (channels, depth) = match decoder.colortype()? {
+            ColorType::Gray(bits @ (8 | 16)) => (1, bits),
+            ColorType::GrayA(bits @ (8 | 16)) => (2, bits),
+            ColorType::RGB(bits @ (8 | 16)) => (3, bits),
+            ColorType::RGBA(bits @ (8 | 16)) => (4, bits),
+            _ => return Ok(None),
+        };
+        let description = decoder.get_tag_ascii_string(Tag::ImageDescription).ok();
+        let tiled = decoder.get_chunk_type() == ChunkType::Tile;
+        Ok(Some(Self {
+            decoder,
+            width,
+            height,
+            depth,
+            description,
+            channels,
+            tiled,
+            next_chunk: 0,
+            chunk: Vec::new(),
+            chunk_pos: 0,
+            row: 0,
+        }))
+    }
+
+    /// Loads the next strip, or the whole image at once when it is tiled.
+    fn load_chunk(&mut self) -> Result<()> {
+        let data = if self.tiled {
+            self.decoder.read_image()?
+        } else {
+            self.decoder.read_chunk(self.next_chunk)?
+        };
+        self.next_chunk += 1;
+        self.chunk = match data {
+            DecodingResult::U8(v) => v.into_iter().map(u16::from).collect(),
+            DecodingResult::U16(v) => v,
+            _ => return Err(anyhow!("Unsupported TIFF sample type.")),
+        };
+        self.chunk_pos = 0;
+        Ok(())
+    }
+}
+
+impl Iterator for TiffRows {
+    type Item = Result<Vec<u16>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u16>>> {
+        if self.row == self.height {
+            return None;
+        }
+        self.row += 1;
+        let row_len = self.width as usize * self.channels;
+        if self.chunk_pos + row_len > self.chunk.len() {
+            if let Err(e) = self.load_chunk() {
+                self.row = self.height;
+                return Some(Err(e));
+            }
+            if row_len > self.chunk.len() {
+                self.row = self.height;
+                return Some(Err(anyhow!("TIFF image data truncated.")));
+            }
+        }
+        let src = &self.chunk[self.chunk_pos..self.chunk_pos + row_len];
+        self.chunk_pos += row_len;
+        let rgb = match self.channels {
+            1 | 2 => src.chunks_exact(self.channels).flat_map(|px| [px[0]; 3]).collect(),
+            _ => src.chunks_exact(self.channels).flat_map(|px| [px[0], px[1], px[2]]).collect(),
+        };
+        Some(Ok(rgb))
+    }
+}
+
+/// Streaming baseline TIFF writer: uncompressed RGB (8 or 16 bits) in a single strip,
+/// little-endian, with the IFD appended on `finish`.
+pub struct TiffWriter {
+    out: BufWriter<File>,
+    width: u32,
+    height: u32,
+    depth: u8,
+    description: Option<String>,
+    data_bytes: u64,
+}
+
+impl TiffWriter {
+    pub fn create(path: &Path, width: u32, height: u32, depth: u8, description: Option<&str>) -> Result<Self> {
+        if !matches!(depth, 8 | 16) {
+            return Err(anyhow!("Unsupported TIFF depth: {}", depth));
+        }
+        if width as u64 * height as u64 * 3 * (depth as u64 / 8) + 4096 > u32::MAX as u64 {
+            return Err(anyhow!("Image too large for TIFF."));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"II*\0")?;
+        out.write_all(&0u32.to_le_bytes())?; // IFD offset, patched on finish
+        Ok(Self { out, width, height, depth, description: description.map(str::to_string), data_bytes: 0 })
+    }
+
+    /// Writes one row of RGB values at the writer's depth.
+    pub fn write_row(&mut self, rgb: &[u16]) -> Result<()> {
+        let line: Vec<u8> = if self.depth == 16 {
+            rgb.iter().flat_map(|v| v.to_le_bytes()).collect()
+        } else {
+            rgb.iter().map(|v| *v as u8).collect()
+        };
+        self.out.write_all(&line)?;
+        self.data_bytes += line.len() as u64;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let mut extra = Vec::new(); // Values that do not fit into an IFD entry
+        if self.data_bytes % 2 == 1 {
+            self.out.write_all(&[0])?;
+        }
+        let extra_start = 8 + self.data_bytes + self.data_bytes % 2;
+
+        let bits_offset = extra_start as u32;
+        for _ in 0..3 {
+            extra.extend_from_slice(&(self.depth as u16).to_le_bytes());
+        }
+        let description = self.description.take().map(|text| {
+            let mut bytes = text.into_bytes();
+            bytes.push(0);
+            let count = bytes.len() as u32;
+            if bytes.len() <= 4 {
+                // Short strings live in the entry itself
+                bytes.resize(4, 0);
+                return (count, u32::from_le_bytes(bytes[..4].try_into().unwrap()));
+            }
+            let offset = extra_start as u32 + extra.len() as u32;
+            extra.extend_from_slice(&bytes);
+            if extra.len() % 2 == 1 {
+                extra.push(0);
+            }
+            (count, offset)
+        });
+        self.out.write_all(&extra)?;
+        let ifd_offset = extra_start + extra.len() as u64;
+
+        // (tag, type, count, value); type 3 = SHORT, 4 = LONG, 2 = ASCII
+        let mut entries: Vec<(u16, u16, u32, u32)> = vec![
+            (256, 4, 1, self.width),
+            (257, 4, 1, self.height),
+            (258, 3, 3, bits_offset),
+            (259, 3, 1, 1),  // no compression
+            (262, 3, 1, 2),  // RGB
+            (273, 4, 1, 8),  // strip offset
+            (277, 3, 1, 3),  // samples per pixel
+            (278, 4, 1, self.height),
+            (279, 4, 1, self.data_bytes as u32),
+            (284, 3, 1, 1),  // chunky
+        ];
+        if let Some((count, offset)) = description {
+            entries.push((270, 2, count, offset));
+        }
+        entries.sort_by_key(|e| e.0);
+
+        self.out.write_all(&(entries.len() as u16).to_le_bytes())?;
+        for (tag, kind, count, value) in entries {
+            self.out.write_all(&tag.to_le_bytes())?;
+            self.out.write_all(&kind.to_le_bytes())?;
+            self.out.write_all(&count.to_le_bytes())?;
+            if kind == 3 && count == 1 {
+                // SHORT values are left-aligned in the 4-byte field
+                self.out.write_all(&(value as u16).to_le_bytes())?;
+                self.out.write_all(&[0, 0])?;
+            } else {
+                self.out.write_all(&value.to_le_bytes())?;
+            }
+        }
+        self.out.write_all(&0u32.to_le_bytes())?; // no next IFD
+
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(ifd_offset as u32).to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
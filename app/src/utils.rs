@@ -51,6 +51,101 @@ impl WavIterator {
     }
 }
 
+/// How payload bits are stored in the samples of a PCM container (WAV, AIFF, AU).
+/// The layout follows from the container's own `WavSpec` (for 32-bit int, from which candidate
+/// yields a valid header), so the decoder needs nothing else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavLayout {
+    /// 32-bit int output: the 16-bit audio in the high half, 2 payload bytes in the low half.
+    Expand16,
+    /// 24-bit int kept as is: 4 payload bits in the LSBs (2 samples per byte).
+    Int24,
+    /// 32-bit float kept as is: 1 payload byte in the low mantissa bits.
+    Float32,
+    /// 32-bit int kept as is: 1 payload byte in the low 8 bits.
+    Int32,
+    /// 16-bit int kept as is: 2 payload bits in the LSBs (4 samples per byte).
+    /// Only written when the output keeps the input depth (`AudioOutput::SameAsInput`).
+    Int16,
+}
+
+impl WavLayout {
+    /// Layout used when embedding into a container with `spec`.
+    pub fn for_container(spec: &WavSpec) -> Self {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 24) => Self::Int24,
+            (SampleFormat::Int, 32) => Self::Int32,
+            (SampleFormat::Float, 32) => Self::Float32,
+            _ => Self::Expand16,
+        }
+    }
+
+    /// Layouts an already encoded PCM container can be in. 32-bit int output is either a
+    /// 16-bit cover expanded to 32 bits or a native 32-bit one; the decoder keeps whichever
+    /// yields a valid header.
+    pub fn for_encoded(spec: &WavSpec) -> Result<&'static [Self]> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 32) => Ok(&[Self::Expand16, Self::Int32]),
+            (SampleFormat::Int, 24) => Ok(&[Self::Int24]),
+            (SampleFormat::Float, 32) => Ok(&[Self::Float32]),
+            (SampleFormat::Int, 16) => Ok(&[Self::Int16]),
+            _ => Err(anyhow!(
+                "Not a Sound_PNG encoded audio file ({:?} {} bits).",
+                spec.sample_format,
+                spec.bits_per_sample
+            )),
+        }
+    }
+
+    /// Spec of the encoded output for a container with `spec`.
+    pub fn output_spec(self, spec: WavSpec) -> WavSpec {
+        match self {
+            Self::Expand16 => WavSpec { bits_per_sample: 32, sample_format: SampleFormat::Int, ..spec },
+            Self::Int24 | Self::Float32 | Self::Int32 | Self::Int16 => spec,
+        }
+    }
+
+    /// Bits of each output sample (raw bits) that carry payload.
+    pub fn mask(self) -> u32 {
+        match self {
+            Self::Expand16 => 0xFFFF,
+            Self::Int24 => 0x0F,
+            Self::Float32 | Self::Int32 => 0xFF,
+            Self::Int16 => 0x03,
+        }
+    }
+
+    /// Payload bytes carried by `samples` samples.
+    pub fn capacity(self, samples: u64) -> u64 {
+        match self {
+            Self::Expand16 => samples * 2,
+            Self::Int24 => samples / 2,
+            Self::Float32 | Self::Int32 => samples,
+            Self::Int16 => samples / 4,
+        }
+    }
+
+    /// Samples needed to carry `bytes` payload bytes.
+    pub fn samples_for(self, bytes: u64) -> u64 {
+        match self {
+            Self::Expand16 => bytes.div_ceil(2),
+            Self::Int24 => bytes * 2,
+            Self::Float32 | Self::Int32 => bytes,
+            Self::Int16 => bytes * 4,
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Self::Expand16 => "32-bit, 16-bit LSB",
+            Self::Int24 => "24-bit, 4-bit LSB",
+            Self::Float32 => "32-bit float, 8-bit mantissa LSB",
+            Self::Int32 => "32-bit, 8-bit LSB",
+            Self::Int16 => "16-bit, 2-bit LSB",
+        }
+    }
+}
+
 // Kept for legacy/non-streaming small files if needed, but implemented via iterator now to reduce duplication logic.
 pub fn read_and_normalize_wav(path: &PathBuf) -> Result<(WavSpec, Vec<i16>)> {
     let iter = WavIterator::new(path)?;
@@ -0,0 +1,675 @@
+use anyhow::{anyhow, Context, Result};
+use sound_png_api::{ByteSource, ContainerDecoder, ContainerEncoder, PluginMetadata};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use wasmtime::{Caller, Config, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, Trap, WasmParams, WasmResults, TypedFunc};
+use crate::plugin_host::{Info, Side};
+
+// WebAssembly plugins: one `.wasm` file for every platform, run by wasmtime in a sandbox.
+// A plugin gets no WASI and no ambient file access; it sees only the `spng` imports below,
+// and those only reach the paths of the current request (the container or input for reading,
+// the output for writing, each including what lies below it, compared after resolving
+// symlinks). Every call runs in a fresh instance, so a trap or a leak inside the plugin ends
+// with that call, and every export call gets a fuel budget, so a plugin stuck in a loop traps
+// instead of hanging the worker.
+//
+// Exports (i32 pointers into the plugin's `memory`; strings are UTF-8 pointer + length):
+//   spng_abi_version() -> i32                       must return ABI_VERSION
+//   spng_alloc(len) -> ptr                          memory the host writes arguments into
+//   spng_info() -> i64                              JSON `plugin_host::Info`, (ptr << 32) | len
+//   spng_encode(container, output, total_len: i64) -> i32 status    (encoder)
+//   spng_capacity(container) -> i64                 bytes, < 0 on error    (encoder, optional)
+//   spng_decode(input) -> i32 status                opens the payload stream    (decoder)
+//   spng_read(ptr, len) -> i32                      bytes read, 0 at the end, < 0 on error
+//   spng_restore(input, output) -> i32 status       (decoder, optional)
+// Status 0 is success; on failure the plugin reports the message through `set_error`.
+//
+// Imports (module "spng"); calls that can fail return -1 and set the error message:
+//   stream_read(ptr, len) -> i32                    next bytes of the byte stream, 0 at the end
+//   progress(f32), set_error(str), log(str)
+//   error_message(ptr, len) -> i32                  the recorded error; full length returned,
+//                                                   written only if it fits
+//   fs_open(path, mode) -> i32 handle               mode 0 read, 1 create/truncate
+//   fs_read(handle, ptr, len) -> i32, fs_write(handle, ptr, len) -> i32, fs_close(handle)
+//   fs_size(path) -> i64
+//   fs_list(path, ptr, len) -> i32                  sorted entry names joined by '\n'; returns
+//                                                   the full length, written only if it fits
+//   fs_mkdir(path) -> i32                           creates the directory and its parents
+
+/// Version of the export/import set above; bumped on any change.
+pub const ABI_VERSION: u32 = 2;
+
+const IMPORT_MODULE: &str = "spng";
+/// Linear memory a plugin may grow to.
+const MAX_MEMORY: usize = 1024 * 1024 * 1024;
+/// Largest single read/write a plugin may ask for.
+const MAX_IO: usize = 16 * 1024 * 1024;
+/// Buffer of the decode stream in plugin memory.
+const READ_CHUNK: usize = 64 * 1024;
+/// Fuel (roughly one unit per WebAssembly instruction) of a single export call; each
+/// `spng_read` of a decode stream gets its own.
+const FUEL_PER_CALL: u64 = 200_000_000_000;
+
+/// `path` with symlinks resolved. The part that does not exist yet is appended unchanged; it
+/// cannot contain links, and a dangling link is refused rather than followed.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(resolved) => return Ok(missing.iter().rev().fold(resolved, |p, name| p.join(name))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound && fs::symlink_metadata(existing).is_err() => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A path the current call may access.
+struct Grant {
+    /// Resolved path; `None` if it could not be resolved, which grants nothing.
+    path: Option<PathBuf>,
+    write: bool,
+}
+
+impl Grant {
+    fn read(path: &Path) -> Self {
+        Self { path: resolve(path).ok(), write: false }
+    }
+
+    fn write(path: &Path) -> Self {
+        Self { path: resolve(path).ok(), write: true }
+    }
+}
+
+/// Per-call state behind the imports.
+#[derive(Default)]
+struct Ctx {
+    /// The encode call's byte stream; only set while that call runs.
+    source: Option<*mut (dyn ByteSource + 'static)>,
+    remaining: u64,
+    progress: Option<Arc<dyn Fn(f32) + Send + Sync>>,
+    error: Option<String>,
+    grants: Vec<Grant>,
+    files: Vec<Option<File>>,
+    limits: StoreLimits,
+}
+
+// The raw `source` pointer is the only non-Send field and never outlives the encode call
+// that set it; decode readers, which are sent between threads, never have one.
+unsafe impl Send for Ctx {}
+
+impl Ctx {
+    /// Returns the resolved `path` if a grant covers it; a link below a granted directory
+    /// may point anywhere, so only the resolved paths are compared.
+    fn check(&self, path: &Path, write: bool) -> Result<PathBuf> {
+        let lexical = path.is_absolute() && !path.components().any(|c| c == Component::ParentDir);
+        if let Some(resolved) = lexical.then(|| resolve(path).ok()).flatten() {
+            let granted = |g: &Grant| (g.write || !write) && g.path.as_ref().is_some_and(|p| resolved.starts_with(p));
+            if self.grants.iter().any(granted) {
+                return Ok(resolved);
+            }
+        }
+        Err(anyhow!("Access to {:?} {} denied.", path, if write { "for writing" } else { "for reading" }))
+    }
+
+    /// Records `error` for the plugin to pick up and returns the failure value.
+    fn fail<T: From<i8>>(&mut self, error: impl std::fmt::Display) -> T {
+        self.error = Some(error.to_string());
+        T::from(-1)
+    }
+}
+
+fn memory(caller: &mut Caller<'_, Ctx>) -> Result<Memory> {
+    caller.get_export("memory").and_then(Extern::into_memory).ok_or_else(|| anyhow!("Plugin exports no memory."))
+}
+
+fn read_bytes(caller: &mut Caller<'_, Ctx>, ptr: i32, len: i32) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; (len.max(0) as usize).min(MAX_IO)];
+    memory(caller)?.read(&*caller, ptr as u32 as usize, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_str(caller: &mut Caller<'_, Ctx>, ptr: i32, len: i32) -> Result<String> {
+    Ok(String::from_utf8_lossy(&read_bytes(caller, ptr, len)?).into_owned())
+}
+
+fn write_bytes(caller: &mut Caller<'_, Ctx>, ptr: i32, bytes: &[u8]) -> Result<()> {
+    memory(caller)?.write(&mut *caller, ptr as u32 as usize, bytes)?;
+    Ok(())
+}
+
+/// Imports; bad pointers trap, refused or failed operations return -1.
+fn linker(engine: &Engine) -> Result<Linker<Ctx>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap(IMPORT_MODULE, "stream_read", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<i32> {
+        let ctx = caller.data_mut();
+        let Some(source) = ctx.source else { return Ok(ctx.fail(anyhow!("No byte stream in this call."))) };
+        let n = (len.max(0) as u64).min(MAX_IO as u64).min(ctx.remaining);
+        // SAFETY: `source` points to the byte stream borrowed by the running encode call
+        let source = unsafe { &mut *source };
+        let bytes: Vec<u8> = (0..n).map(|_| source.next_byte()).collect();
+        ctx.remaining -= n;
+        write_bytes(&mut caller, ptr, &bytes)?;
+        Ok(n as i32)
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "progress", |caller: Caller<'_, Ctx>, value: f32| {
+        if let Some(progress) = &caller.data().progress {
+            progress(value);
+        }
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "set_error", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<()> {
+        let message = read_str(&mut caller, ptr, len)?;
+        caller.data_mut().error = Some(message);
+        Ok(())
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "error_message", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<i32> {
+        let message = caller.data().error.clone().unwrap_or_default();
+        if message.len() <= len.max(0) as usize {
+            write_bytes(&mut caller, ptr, message.as_bytes())?;
+        }
+        Ok(message.len() as i32)
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "log", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<()> {
+        tracing::info!("[wasm plugin] {}", read_str(&mut caller, ptr, len)?);
+        Ok(())
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_open", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32, mode: i32| -> Result<i32> {
+        let path = PathBuf::from(read_str(&mut caller, ptr, len)?);
+        let write = mode == 1;
+        let ctx = caller.data_mut();
+        let opened = ctx.check(&path, write).and_then(|resolved| {
+            let file = if write { File::create(&resolved) } else { File::open(&resolved) };
+            file.with_context(|| format!("{:?}", path))
+        });
+        Ok(match opened {
+            Ok(file) => {
+                ctx.files.push(Some(file));
+                ctx.files.len() as i32 - 1
+            }
+            Err(e) => ctx.fail(format!("{:#}", e)),
+        })
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_read", |mut caller: Caller<'_, Ctx>, handle: i32, ptr: i32, len: i32| -> Result<i32> {
+        let mut buf = vec![0u8; (len.max(0) as usize).min(MAX_IO)];
+        let ctx = caller.data_mut();
+        let read = match ctx.files.get_mut(handle as usize).and_then(Option::as_mut) {
+            Some(file) => file.read(&mut buf),
+            None => return Ok(ctx.fail("Invalid file handle.")),
+        };
+        match read {
+            Ok(n) => {
+                write_bytes(&mut caller, ptr, &buf[..n])?;
+                Ok(n as i32)
+            }
+            Err(e) => Ok(ctx.fail(e)),
+        }
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_write", |mut caller: Caller<'_, Ctx>, handle: i32, ptr: i32, len: i32| -> Result<i32> {
+        let bytes = read_bytes(&mut caller, ptr, len)?;
+        let ctx = caller.data_mut();
+        let written = match ctx.files.get_mut(handle as usize).and_then(Option::as_mut) {
+            Some(file) => file.write_all(&bytes),
+            None => return Ok(ctx.fail("Invalid file handle.")),
+        };
+        Ok(match written {
+            Ok(()) => bytes.len() as i32,
+            Err(e) => ctx.fail(e),
+        })
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_close", |mut caller: Caller<'_, Ctx>, handle: i32| {
+        if let Some(file) = caller.data_mut().files.get_mut(handle as usize) {
+            *file = None;
+        }
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_size", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<i64> {
+        let path = PathBuf::from(read_str(&mut caller, ptr, len)?);
+        let ctx = caller.data_mut();
+        Ok(match ctx.check(&path, false).and_then(|resolved| Ok(fs::metadata(resolved)?.len())) {
+            Ok(size) => size as i64,
+            Err(e) => ctx.fail(e),
+        })
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_list", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32, out: i32, out_len: i32| -> Result<i32> {
+        let path = PathBuf::from(read_str(&mut caller, ptr, len)?);
+        let listed = caller.data().check(&path, false).and_then(|resolved| {
+            let mut names = fs::read_dir(resolved)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()?;
+            names.sort();
+            Ok(names.join("\n"))
+        });
+        match listed {
+            Ok(list) => {
+                if list.len() <= out_len.max(0) as usize {
+                    write_bytes(&mut caller, out, list.as_bytes())?;
+                }
+                Ok(list.len() as i32)
+            }
+            Err(e) => Ok(caller.data_mut().fail(e)),
+        }
+    })?;
+    linker.func_wrap(IMPORT_MODULE, "fs_mkdir", |mut caller: Caller<'_, Ctx>, ptr: i32, len: i32| -> Result<i32> {
+        let path = PathBuf::from(read_str(&mut caller, ptr, len)?);
+        let ctx = caller.data_mut();
+        Ok(match ctx.check(&path, true).and_then(|resolved| Ok(fs::create_dir_all(resolved)?)) {
+            Ok(()) => 0,
+            Err(e) => ctx.fail(e),
+        })
+    })?;
+    Ok(linker)
+}
+
+/// A compiled plugin; every call instantiates it afresh.
+struct WasmPlugin {
+    name: String,
+    engine: Engine,
+    module: Module,
+    linker: Linker<Ctx>,
+    /// Fuel of every export call, `FUEL_PER_CALL` for loaded plugins.
+    fuel: u64,
+}
+
+impl WasmPlugin {
+    fn compile(path: &Path) -> Result<Self> {
+        let engine = Engine::new(Config::new().consume_fuel(true))?;
+        let module = Module::from_file(&engine, path)?;
+        let linker = linker(&engine)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        Ok(Self { name, engine, module, linker, fuel: FUEL_PER_CALL })
+    }
+
+    fn session(&self, grants: Vec<Grant>, progress: Option<Arc<dyn Fn(f32) + Send + Sync>>) -> Result<Session> {
+        let ctx = Ctx {
+            grants,
+            progress,
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            ..Default::default()
+        };
+        let mut store = Store::new(&self.engine, ctx);
+        store.limiter(|ctx| &mut ctx.limits);
+        store.set_fuel(self.fuel)?;
+        let instance = self.linker.instantiate(&mut store, &self.module)
+            .with_context(|| format!("Failed to instantiate {}", self.name))?;
+        Ok(Session { name: self.name.clone(), store, instance, fuel: self.fuel })
+    }
+}
+
+/// One instance of a plugin, for the duration of a call (or of a decode stream).
+struct Session {
+    name: String,
+    store: Store<Ctx>,
+    instance: Instance,
+    fuel: u64,
+}
+
+impl Session {
+    fn func<P: WasmParams, R: WasmResults>(&mut self, name: &str) -> Result<TypedFunc<P, R>> {
+        self.instance.get_typed_func(&mut self.store, name)
+            .with_context(|| format!("{} does not export {}", self.name, name))
+    }
+
+    fn has(&mut self, name: &str) -> bool {
+        self.instance.get_func(&mut self.store, name).is_some()
+    }
+
+    fn call<P: WasmParams, R: WasmResults>(&mut self, name: &str, params: P) -> Result<R> {
+        let func = self.func::<P, R>(name)?;
+        self.store.set_fuel(self.fuel)?;
+        func.call(&mut self.store, params).map_err(|e| match e.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => anyhow!("Plugin {} ran out of fuel in {}; it may be stuck in a loop.", self.name, name),
+            _ => anyhow!("Plugin {} trapped in {}: {:#}", self.name, name, e),
+        })
+    }
+
+    /// Error reported by the plugin for a failed call.
+    fn error(&mut self) -> anyhow::Error {
+        let message = self.store.data_mut().error.take();
+        anyhow!(message.unwrap_or_else(|| format!("Plugin {} failed without a message.", self.name)))
+    }
+
+    fn status(&mut self, name: &str, params: impl WasmParams) -> Result<()> {
+        match self.call::<_, i32>(name, params)? {
+            0 => Ok(()),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn memory(&mut self) -> Result<Memory> {
+        self.instance.get_memory(&mut self.store, "memory").ok_or_else(|| anyhow!("{} exports no memory.", self.name))
+    }
+
+    /// Copies `bytes` into plugin memory allocated by `spng_alloc`.
+    fn put(&mut self, bytes: &[u8]) -> Result<(i32, i32)> {
+        let ptr = self.call::<i32, i32>("spng_alloc", bytes.len() as i32)?;
+        self.memory()?.write(&mut self.store, ptr as u32 as usize, bytes)?;
+        Ok((ptr, bytes.len() as i32))
+    }
+
+    fn put_path(&mut self, path: &Path) -> Result<(i32, i32)> {
+        let path = path.to_str().ok_or_else(|| anyhow!("Path {:?} is not valid UTF-8.", path))?;
+        self.put(path.as_bytes())
+    }
+
+    fn get(&mut self, ptr: i32, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0u8; len];
+        self.memory()?.read(&self.store, ptr as u32 as usize, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Compiles the plugin in `path`, checks its ABI version and returns its encoder and decoder.
+pub fn load(path: &Path) -> Result<(Option<WasmEncoder>, Option<WasmDecoder>)> {
+    let plugin = Arc::new(WasmPlugin::compile(path)?);
+
+    let mut session = plugin.session(Vec::new(), None)?;
+    if !session.has("spng_abi_version") {
+        return Err(anyhow!("No spng_abi_version export."));
+    }
+    let version = session.call::<(), i32>("spng_abi_version", ())? as u32;
+    if version != ABI_VERSION {
+        return Err(anyhow!("WebAssembly ABI version {} (expected {}).", version, ABI_VERSION));
+    }
+    let packed = session.call::<(), i64>("spng_info", ())?;
+    let info: Info = serde_json::from_slice(&session.get((packed >> 32) as i32, packed as u32 as usize)?)
+        .context("Invalid spng_info")?;
+
+    for (side, exports) in [(&info.encoder, &["spng_encode"][..]), (&info.decoder, &["spng_decode", "spng_read"][..])] {
+        if side.is_some() {
+            if let Some(missing) = exports.iter().find(|e| !session.has(e)) {
+                return Err(anyhow!("spng_info lists a plugin side whose {} is not exported.", missing));
+            }
+        }
+    }
+    let capacity = session.has("spng_capacity");
+    let restore = session.has("spng_restore");
+    let encoder = info.encoder.map(|side| WasmEncoder { plugin: plugin.clone(), side, capacity });
+    let decoder = info.decoder.map(|side| WasmDecoder { plugin: plugin.clone(), side, restore });
+    Ok((encoder, decoder))
+}
+
+pub struct WasmEncoder {
+    plugin: Arc<WasmPlugin>,
+    side: Side,
+    capacity: bool,
+}
+
+impl ContainerEncoder for WasmEncoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.side.metadata()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.side.extensions.clone()
+    }
+
+    fn encode(
+        &self,
+        container_path: &Path,
+        output_path: &Path,
+        byte_stream: &mut dyn ByteSource,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>,
+    ) -> Result<()> {
+        let grants = vec![Grant::read(container_path), Grant::write(output_path)];
+        let mut session = self.plugin.session(grants, Some(Arc::from(on_progress)))?;
+        let (container, container_len) = session.put_path(container_path)?;
+        let (output, output_len) = session.put_path(output_path)?;
+
+        let total_len = byte_stream.total_len();
+        // SAFETY: only the lifetime is erased; the session, and the pointer with it, is dropped
+        // before this function returns and the borrow of `byte_stream` ends
+        let source: &'static mut dyn ByteSource = unsafe { std::mem::transmute::<&mut dyn ByteSource, _>(byte_stream) };
+        let ctx = session.store.data_mut();
+        ctx.source = Some(source as *mut _);
+        ctx.remaining = total_len;
+        let result = session.status("spng_encode", (container, container_len, output, output_len, total_len as i64));
+        session.store.data_mut().source = None;
+        result
+    }
+
+    fn capacity(&self, container_path: &Path) -> Result<u64> {
+        if !self.capacity {
+            return Ok(0);
+        }
+        let mut session = self.plugin.session(vec![Grant::read(container_path)], None)?;
+        let (container, container_len) = session.put_path(container_path)?;
+        let capacity = session.call::<(i32, i32), i64>("spng_capacity", (container, container_len))?;
+        if capacity < 0 {
+            return Err(session.error());
+        }
+        Ok(capacity as u64)
+    }
+}
+
+pub struct WasmDecoder {
+    plugin: Arc<WasmPlugin>,
+    side: Side,
+    restore: bool,
+}
+
+impl ContainerDecoder for WasmDecoder {
+    fn metadata(&self) -> PluginMetadata {
+        self.side.metadata()
+    }
+
+    fn supported_extensions(&self) -> Vec<String> {
+        self.side.extensions.clone()
+    }
+
+    fn decode(&self, input_path: &Path, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<Box<dyn Read + Send>> {
+        let mut session = self.plugin.session(vec![Grant::read(input_path)], Some(Arc::from(on_progress)))?;
+        let (input, input_len) = session.put_path(input_path)?;
+        session.status("spng_decode", (input, input_len))?;
+        let buffer = session.call::<i32, i32>("spng_alloc", READ_CHUNK as i32)?;
+        Ok(Box::new(WasmReader { session, buffer, finished: false }))
+    }
+
+    fn restore_container(&self, input_path: &Path, output_path: &Path, on_progress: Box<dyn Fn(f32) + Send + Sync>) -> Result<()> {
+        if !self.restore {
+            return Err(anyhow!("Container restoration is not supported by this plugin."));
+        }
+        let grants = vec![Grant::read(input_path), Grant::write(output_path)];
+        let mut session = self.plugin.session(grants, Some(Arc::from(on_progress)))?;
+        let (input, input_len) = session.put_path(input_path)?;
+        let (output, output_len) = session.put_path(output_path)?;
+        session.status("spng_restore", (input, input_len, output, output_len))
+    }
+}
+
+/// The payload stream of `spng_decode`, read through `spng_read`.
+struct WasmReader {
+    session: Session,
+    buffer: i32,
+    finished: bool,
+}
+
+impl Read for WasmReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+        let want = buf.len().min(READ_CHUNK);
+        let result = self.session.call::<(i32, i32), i32>("spng_read", (self.buffer, want as i32))
+            .and_then(|n| if n < 0 { Err(self.session.error()) } else { Ok(n as usize) })
+            .and_then(|n| if n > want { Err(anyhow!("spng_read returned more than requested.")) } else { self.session.get(self.buffer, n) });
+        let data = result.map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.finished = data.is_empty();
+        Ok(data.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use sound_png_api::ByteStream;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tempfile::tempdir;
+
+    /// Copies the byte stream into the output and reads the input back as the payload; the
+    /// capacity is the container's size. `probe` opens any path and `spin` never returns.
+    const PLUGIN: &str = r#"
+        (module
+            (import "spng" "stream_read" (func $stream_read (param i32 i32) (result i32)))
+            (import "spng" "progress" (func $progress (param f32)))
+            (import "spng" "fs_open" (func $fs_open (param i32 i32 i32) (result i32)))
+            (import "spng" "fs_read" (func $fs_read (param i32 i32 i32) (result i32)))
+            (import "spng" "fs_write" (func $fs_write (param i32 i32 i32) (result i32)))
+            (import "spng" "fs_close" (func $fs_close (param i32)))
+            (import "spng" "fs_size" (func $fs_size (param i32 i32) (result i64)))
+            (memory (export "memory") 4)
+            (global $heap (mut i32) (i32.const 4096))
+            (global $reader (mut i32) (i32.const -1))
+            (data (i32.const 0) "INFO")
+            (func (export "spng_abi_version") (result i32) (i32.const ABI_VERSION))
+            (func (export "spng_alloc") (param $len i32) (result i32)
+                (global.get $heap)
+                (global.set $heap (i32.add (global.get $heap) (local.get $len))))
+            (func (export "spng_info") (result i64) (i64.const INFO_LEN))
+            (func (export "spng_encode") (param $container i32) (param $container_len i32)
+                    (param $output i32) (param $output_len i32) (param $total_len i64) (result i32)
+                (local $out i32) (local $n i32)
+                (local.set $out (call $fs_open (local.get $output) (local.get $output_len) (i32.const 1)))
+                (if (i32.lt_s (local.get $out) (i32.const 0)) (then (return (i32.const 1))))
+                (block $done
+                    (loop $copy
+                        ;; An odd chunk size, so reads do not line up with anything
+                        (local.set $n (call $stream_read (i32.const 1024) (i32.const 999)))
+                        (br_if $done (i32.le_s (local.get $n) (i32.const 0)))
+                        (drop (call $fs_write (local.get $out) (i32.const 1024) (local.get $n)))
+                        (br $copy)))
+                (call $fs_close (local.get $out))
+                (call $progress (f32.const 1))
+                (i32.const 0))
+            (func (export "spng_capacity") (param $container i32) (param $container_len i32) (result i64)
+                (call $fs_size (local.get $container) (local.get $container_len)))
+            (func (export "spng_decode") (param $input i32) (param $input_len i32) (result i32)
+                (global.set $reader (call $fs_open (local.get $input) (local.get $input_len) (i32.const 0)))
+                (i32.lt_s (global.get $reader) (i32.const 0)))
+            (func (export "spng_read") (param $ptr i32) (param $len i32) (result i32)
+                (call $fs_read (global.get $reader) (local.get $ptr) (local.get $len)))
+            (func (export "probe") (param $path i32) (param $path_len i32) (param $mode i32) (result i32)
+                (call $fs_open (local.get $path) (local.get $path_len) (local.get $mode)))
+            (func (export "spin")
+                (loop $forever (br $forever))))
+    "#;
+
+    /// Writes the plugin, named "Copy" and handling "bin" containers, to `dir/copy.wasm`.
+    pub(crate) fn write_plugin(dir: &Path) -> Result<PathBuf> {
+        let side = r#"{"name":"Copy","description":"Copies the stream","version":"1.0","author":"Test","extensions":["bin"]}"#;
+        let info = format!(r#"{{"encoder":{},"decoder":{}}}"#, side, side);
+        let wat = PLUGIN
+            .replace("ABI_VERSION", &ABI_VERSION.to_string())
+            .replace("INFO_LEN", &info.len().to_string())
+            .replace("INFO", &info.replace('"', "\\\""));
+        let path = dir.join("copy.wasm");
+        fs::write(&path, wat::parse_str(wat)?)?;
+        Ok(path)
+    }
+
+    /// Opens `path` through the plugin's `probe` in a session holding `grants`.
+    fn probe(plugin: &WasmPlugin, grants: Vec<Grant>, path: &Path, write: bool) -> Result<i32> {
+        let mut session = plugin.session(grants, None)?;
+        let (ptr, len) = session.put_path(path)?;
+        match session.call::<(i32, i32, i32), i32>("probe", (ptr, len, write as i32))? {
+            handle if handle < 0 => Err(session.error()),
+            handle => Ok(handle),
+        }
+    }
+
+    #[test]
+    fn test_stream_roundtrip() -> Result<()> {
+        let dir = tempdir()?;
+        let (encoder, decoder) = load(&write_plugin(dir.path())?)?;
+        let (encoder, decoder) = (encoder.unwrap(), decoder.unwrap());
+        assert_eq!(encoder.metadata().name, "Copy");
+        assert_eq!(decoder.supported_extensions(), ["bin"]);
+
+        let container = dir.path().join("container.bin");
+        fs::write(&container, b"cover")?;
+        for len in [0usize, 1, 999, 5000] {
+            let stream: Vec<u8> = (0..len).map(|i| (i * 7 % 256) as u8).collect();
+            let output = dir.path().join(format!("output{}.bin", len));
+            let mut byte_stream = ByteStream::new(Vec::new(), Cursor::new(stream.clone()), 0, None, false, 4096, len as u64);
+            let calls = Arc::new(AtomicU32::new(0));
+            let counter = calls.clone();
+            encoder.encode(&container, &output, &mut byte_stream, Box::new(move |_| { counter.fetch_add(1, Ordering::SeqCst); }))?;
+            assert_eq!(fs::read(&output)?, stream);
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+            let mut decoded = Vec::new();
+            decoder.decode(&output, Box::new(|_| {}))?.read_to_end(&mut decoded)?;
+            assert_eq!(decoded, stream);
+        }
+        // Failures inside the imports come back as the plugin's error
+        let e = decoder.decode(&dir.path().join("missing.bin"), Box::new(|_| {})).err().unwrap();
+        assert!(e.to_string().contains("missing.bin"), "{:#}", e);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fs_open_is_confined() -> Result<()> {
+        let dir = tempdir()?;
+        let plugin = WasmPlugin::compile(&write_plugin(dir.path())?)?;
+        let granted = dir.path().join("granted");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(granted.join("sub"))?;
+        fs::create_dir(&outside)?;
+        fs::write(granted.join("sub/file"), b"in")?;
+        fs::write(outside.join("secret"), b"out")?;
+        let grants = || vec![Grant::read(&granted)];
+
+        assert!(probe(&plugin, grants(), &granted.join("sub/file"), false)? >= 0);
+        assert!(probe(&plugin, vec![Grant::write(&granted)], &granted.join("new"), true)? >= 0);
+        let denied = [
+            (outside.join("secret"), false),
+            (granted.join("sub/file"), true),
+            (granted.join("sub/../../outside/secret"), false),
+            (PathBuf::from("granted/sub/file"), false),
+        ];
+        for (path, write) in denied {
+            let e = probe(&plugin, grants(), &path, write).unwrap_err();
+            assert!(e.to_string().starts_with("Access to"), "{:?}: {:#}", path, e);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+            // Links below a granted directory are followed before the check
+            symlink(&outside, granted.join("escape"))?;
+            symlink(outside.join("missing"), granted.join("dangling"))?;
+            symlink(granted.join("sub"), outside.join("back"))?;
+            for (path, write) in [(granted.join("escape/secret"), false), (granted.join("escape/new"), true), (granted.join("dangling"), true)] {
+                let e = probe(&plugin, vec![Grant::write(&granted)], &path, write).unwrap_err();
+                assert!(e.to_string().starts_with("Access to"), "{:?}: {:#}", path, e);
+            }
+            assert!(!outside.join("new").exists() && !outside.join("missing").exists());
+            // A link that leads back into the grant is fine
+            assert!(probe(&plugin, grants(), &outside.join("back/file"), false)? >= 0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_runaway_call_runs_out_of_fuel() -> Result<()> {
+        let dir = tempdir()?;
+        let mut plugin = WasmPlugin::compile(&write_plugin(dir.path())?)?;
+        plugin.fuel = 10_000_000;
+        let mut session = plugin.session(Vec::new(), None)?;
+        let e = session.call::<(), ()>("spin", ()).unwrap_err();
+        assert!(e.to_string().contains("ran out of fuel in spin"), "{:#}", e);
+        // Every call starts with a full budget
+        assert_eq!(session.call::<(), i32>("spng_abi_version", ())? as u32, ABI_VERSION);
+        Ok(())
+    }
+}
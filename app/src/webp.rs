@@ -0,0 +1,115 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Maximum width / height of a VP8L image.
+pub const MAX_DIMENSION: u32 = 16384;
+
+/// Order in which code length code lengths are stored (VP8L spec).
+const CODE_LENGTH_ORDER: [usize; 19] = [17, 18, 0, 1, 2, 3, 4, 5, 16, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+/// Streaming lossless WebP (VP8L) writer for opaque 8-bit RGB rows.
+///
+/// Every channel uses a fixed 8-bit prefix code and there are no transforms or
+/// backward references, so each pixel is written as soon as its row arrives and
+/// the file stays bit-exact. The price is a file size close to raw RGB.
+pub struct WebpWriter {
+    out: BufWriter<File>,
+    acc: u64,
+    nbits: u32,
+    data_bytes: u64,
+}
+
+impl WebpWriter {
+    pub fn create(path: &Path, width: u32, height: u32) -> Result<Self> {
+        if width == 0 || height == 0 || width > MAX_DIMENSION || height > MAX_DIMENSION {
+            return Err(anyhow!("WebP images are limited to {0}x{0} pixels.", MAX_DIMENSION));
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(b"RIFF")?;
+        out.write_all(&0u32.to_le_bytes())?; // patched on finish
+        out.write_all(b"WEBPVP8L")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        let mut writer = Self { out, acc: 0, nbits: 0, data_bytes: 0 };
+
+        writer.bits(0x2F, 8)?; // signature
+        writer.bits(width as u64 - 1, 14)?;
+        writer.bits(height as u64 - 1, 14)?;
+        writer.bits(0, 1)?; // alpha unused
+        writer.bits(0, 3)?; // version
+        writer.bits(0, 1)?; // no transforms
+        writer.bits(0, 1)?; // no color cache
+        writer.bits(0, 1)?; // no meta prefix codes
+
+        writer.full_code(256 + 24)?; // green + length prefixes
+        writer.full_code(256)?; // red
+        writer.full_code(256)?; // blue
+        writer.single_symbol(255)?; // alpha, always opaque
+        writer.single_symbol(0)?; // distance, never used
+        Ok(writer)
+    }
+
+    /// Writes one row of 8-bit RGB values.
+    pub fn write_row(&mut self, rgb: &[u16]) -> Result<()> {
+        for px in rgb.chunks_exact(3) {
+            // Prefix codes are read MSB first; all codes are 8 bits and equal to the symbol
+            for channel in [px[1], px[0], px[2]] {
+                self.bits((channel as u8).reverse_bits() as u64, 8)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if self.nbits > 0 {
+            self.bits(0, 8 - self.nbits)?;
+        }
+        if self.data_bytes % 2 == 1 {
+            self.out.write_all(&[0])?;
+        }
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&((12 + self.data_bytes + self.data_bytes % 2) as u32).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(16))?;
+        self.out.write_all(&(self.data_bytes as u32).to_le_bytes())?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// Appends `count` bits, least significant first.
+    fn bits(&mut self, value: u64, count: u32) -> Result<()> {
+        self.acc |= (value & ((1 << count) - 1)) << self.nbits;
+        self.nbits += count;
+        while self.nbits >= 8 {
+            self.out.write_all(&[self.acc as u8])?;
+            self.acc >>= 8;
+            self.nbits -= 8;
+            self.data_bytes += 1;
+        }
+        Ok(())
+    }
+
+    /// Normal prefix code giving symbols 0-255 length 8 and the rest of `alphabet` length 0.
+    fn full_code(&mut self, alphabet: usize) -> Result<()> {
+        self.bits(0, 1)?; // normal code
+        // Code length code: lengths 0 and 8 only, one bit each
+        let used = CODE_LENGTH_ORDER.iter().position(|&s| s == 8).unwrap() + 1;
+        self.bits(used as u64 - 4, 4)?;
+        for &symbol in &CODE_LENGTH_ORDER[..used] {
+            self.bits(if symbol == 0 || symbol == 8 { 1 } else { 0 }, 3)?;
+        }
+        self.bits(0, 1)?; // max_symbol = alphabet size
+        for symbol in 0..alphabet {
+            self.bits(if symbol < 256 { 1 } else { 0 }, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Simple prefix code with one 8-bit symbol; it takes no bits per pixel.
+    fn single_symbol(&mut self, symbol: u8) -> Result<()> {
+        self.bits(1, 1)?; // simple code
+        self.bits(0, 1)?; // one symbol
+        self.bits(1, 1)?; // 8-bit symbol
+        self.bits(symbol as u64, 8)
+    }
+}
@@ -1,4 +1,4 @@
-use sound_png_api::{ContainerEncoder, ContainerDecoder, ByteStream, PluginMetadata};
+use sound_png_api::{ContainerEncoder, ContainerDecoder, ByteSource, PluginMetadata};
 use anyhow::{Result, Context, anyhow};
 use std::fs::{self, File};
 use std::path::Path;
@@ -31,7 +31,7 @@ impl ContainerEncoder for SequenceFramePlugin {
         &self,
         container_path: &Path, // This should be a DIRECTORY containing sorted PNGs
         output_path: &Path,    // This should be a DIRECTORY to write output PNGs
-        byte_stream: &mut ByteStream<File>,
+        byte_stream: &mut dyn ByteSource,
         on_progress: Box<dyn Fn(f32) + Send + Sync>
     ) -> Result<()> {
         // 1. Scan Container Directory
@@ -170,6 +170,24 @@ impl ContainerEncoder for SequenceFramePlugin {
         
         Ok(())
     }
+
+    fn capacity(&self, container_path: &Path) -> Result<u64> {
+        if !container_path.is_dir() {
+            return Err(anyhow!("Container path must be a directory for Sequence Plugin."));
+        }
+
+        let mut total = 0u64;
+        for entry in fs::read_dir(container_path)?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "png") {
+                let reader = png::Decoder::new(File::open(&path)?).read_info()?;
+                let info = reader.info();
+                // 3 bytes per pixel (R, G, B LSBs), same as the core PNG mode
+                total += info.width as u64 * info.height as u64 * 3;
+            }
+        }
+        Ok(total)
+    }
 }
 
 // Decoder Implementation
@@ -205,6 +223,48 @@ impl ContainerDecoder for SequenceFramePlugin {
         
         Ok(Box::new(SequenceReader::new(png_files, on_progress)))
     }
+
+    fn restore_container(
+        &self,
+        input_path: &Path,
+        output_path: &Path,
+        on_progress: Box<dyn Fn(f32) + Send + Sync>
+    ) -> Result<()> {
+        if !input_path.is_dir() {
+            return Err(anyhow!("Input must be a directory."));
+        }
+
+        let mut png_files: Vec<_> = fs::read_dir(input_path)?
+            .filter_map(|entry| entry.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "png"))
+            .collect();
+        png_files.sort();
+
+        if !output_path.exists() {
+            fs::create_dir_all(output_path)?;
+        }
+
+        for (i, input_png) in png_files.iter().enumerate() {
+            let decoder = png::Decoder::new(File::open(input_png)?);
+            let mut reader = decoder.read_info()?;
+            let mut buf = vec![0u8; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut buf)?;
+
+            // 16-bit RGBA (Big Endian): the original 8-bit value is the high byte
+            let restored: Vec<u8> = buf[..info.buffer_size()].iter().step_by(2).copied().collect();
+
+            let file_out = File::create(output_path.join(input_png.file_name().unwrap()))?;
+            let mut encoder = png::Encoder::new(file_out, info.width, info.height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&restored)?;
+
+            on_progress((i + 1) as f32 / png_files.len() as f32);
+        }
+        Ok(())
+    }
 }
 
 struct SequenceReader {
@@ -288,12 +348,4 @@ impl Read for SequenceReader {
     }
 }
 
-#[no_mangle]
-pub extern "Rust" fn _create_encoder() -> Box<dyn ContainerEncoder> {
-    Box::new(SequenceFramePlugin)
-}
-
-#[no_mangle]
-pub extern "Rust" fn _create_decoder() -> Box<dyn ContainerDecoder> {
-    Box::new(SequenceFramePlugin)
-}
\ No newline at end of file
+sound_png_api::export_plugin!(encoder: SequenceFramePlugin, decoder: SequenceFramePlugin);
\ No newline at end of file
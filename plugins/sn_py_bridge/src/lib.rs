@@ -1,4 +1,4 @@
-use sound_png_api::{ContainerEncoder, ContainerDecoder, ByteStream, PluginMetadata};
+use sound_png_api::{ContainerEncoder, ContainerDecoder, ByteSource, PluginMetadata};
 use anyhow::{Result, anyhow};
 use std::path::Path;
 use std::process::{Command, Stdio};
@@ -73,7 +73,7 @@ impl ContainerEncoder for PyBridgePlugin {
         &self,
         container_path: &Path,
         output_path: &Path,
-        byte_stream: &mut ByteStream<std::fs::File>,
+        byte_stream: &mut dyn ByteSource,
         _on_progress: Box<dyn Fn(f32) + Send + Sync>
     ) -> Result<()> {
         ensure_server_running()?;
@@ -151,12 +151,4 @@ impl ContainerDecoder for PyBridgePlugin {
     }
 }
 
-#[no_mangle]
-pub extern "Rust" fn _create_encoder() -> Box<dyn ContainerEncoder> {
-    Box::new(PyBridgePlugin)
-}
-
-#[no_mangle]
-pub extern "Rust" fn _create_decoder() -> Box<dyn ContainerDecoder> {
-    Box::new(PyBridgePlugin)
-}
+sound_png_api::export_plugin!(encoder: PyBridgePlugin, decoder: PyBridgePlugin);